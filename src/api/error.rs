@@ -0,0 +1,107 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::service::oauth::OAuthError;
+use crate::service::tenant::TenantError;
+use crate::store::StoreError;
+use crate::telemetry::FailureReason;
+
+/// Every error answered by the API, the body is always `{"error": code, "error_description": message}`
+#[derive(thiserror::Error, Debug)]
+pub enum ApiError {
+    #[error("no such route")]
+    NotFound,
+    #[error("method not allowed")]
+    MethodNotAllowed,
+    #[error("expected a {0} body")]
+    UnsupportedMediaType(&'static str),
+    #[error("the body is too large")]
+    PayloadTooLarge,
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("invalid credentials")]
+    InvalidCredentials,
+    /// The access token is valid but its login is older than the route accepts
+    #[error("the user must have logged in less than {} seconds ago", max_age.as_secs())]
+    StaleAuthentication { max_age: Duration },
+    #[error("{0}")]
+    Conflict(String),
+    #[error("too many attempts, retry in {} seconds", retry_after.as_secs_f64().ceil())]
+    TooManyRequests { retry_after: Duration },
+    #[error(transparent)]
+    OAuth(#[from] OAuthError),
+    #[error(transparent)]
+    Tenant(#[from] TenantError),
+    #[error("{0}")]
+    Internal(String),
+}
+
+#[derive(Serialize)]
+pub(crate) struct ErrorBody {
+    pub error: &'static str,
+    pub error_description: String,
+}
+
+impl ApiError {
+    pub fn status(&self) -> u16 {
+        match self {
+            ApiError::NotFound => 404,
+            ApiError::MethodNotAllowed => 405,
+            ApiError::UnsupportedMediaType(_) => 415,
+            ApiError::PayloadTooLarge => 413,
+            ApiError::InvalidRequest(_) => 400,
+            ApiError::InvalidCredentials | ApiError::StaleAuthentication { .. } => 401,
+            ApiError::Conflict(_) => 409,
+            ApiError::TooManyRequests { .. } => 429,
+            ApiError::OAuth(error) => match error {
+                OAuthError::InvalidClient(_) | OAuthError::InvalidToken(_) => 401,
+                OAuthError::InsufficientScope(_) => 403,
+                OAuthError::ServerError(_) => 500,
+                _ => 400,
+            },
+            ApiError::Tenant(TenantError::Store(StoreError::NotFound)) => 404,
+            ApiError::Tenant(TenantError::Disabled(_)) => 403,
+            ApiError::Tenant(_) => 500,
+            ApiError::Internal(_) => 500,
+        }
+    }
+
+    /// Machine readable code, the OAuth errors keep their RFC 6749 names
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::NotFound | ApiError::Tenant(TenantError::Store(StoreError::NotFound)) => "not_found",
+            ApiError::MethodNotAllowed => "method_not_allowed",
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type",
+            ApiError::PayloadTooLarge => "payload_too_large",
+            ApiError::InvalidRequest(_) => "invalid_request",
+            ApiError::InvalidCredentials => "invalid_credentials",
+            ApiError::StaleAuthentication { .. } => "insufficient_user_authentication",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::TooManyRequests { .. } => "too_many_requests",
+            ApiError::OAuth(error) => error.code(),
+            ApiError::Tenant(TenantError::Disabled(_)) => "client_disabled",
+            ApiError::Tenant(_) | ApiError::Internal(_) => "server_error",
+        }
+    }
+
+    pub(crate) fn body(&self) -> ErrorBody {
+        let error_description = match self {
+            ApiError::OAuth(OAuthError::InvalidRequest(message) | OAuthError::InvalidClient(message)
+                | OAuthError::InvalidGrant(message) | OAuthError::UnauthorizedClient(message)
+                | OAuthError::UnsupportedGrantType(message) | OAuthError::UnsupportedResponseType(message)
+                | OAuthError::InvalidScope(message) | OAuthError::InvalidToken(message)
+                | OAuthError::InsufficientScope(message)) => message.clone(),
+            // NOTE: Internal details stay in the logs of the caller
+            error if error.status() == 500 => "internal error".to_string(),
+            error => error.to_string(),
+        };
+        ErrorBody { error: self.code(), error_description }
+    }
+}
+
+impl FailureReason for ApiError {
+    fn failure_reason(&self) -> &str {
+        self.code()
+    }
+}
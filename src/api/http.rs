@@ -0,0 +1,566 @@
+//! axum adapter of the API, the route table of [`super::Api::handle`] with the bodies read by the `Json`
+//! and `Form` extractors and the query strings by `Query`. The bearer token is checked before the body is
+//! read, and the passkey routes only exist when [`super::ApiConfig::relying_party`] is set. The address of
+//! the peer is only known when the server is started with
+//! `into_make_service_with_connect_info::<SocketAddr>()`, otherwise the login limits per IP are skipped
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::async_trait;
+use axum::body::Body;
+use axum::extract::rejection::{FormRejection, JsonRejection, QueryRejection};
+use axum::extract::{ConnectInfo, DefaultBodyLimit, FromRequestParts, Query, State};
+use axum::http::request::Parts;
+use axum::http::{header, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{on, MethodFilter, MethodRouter};
+use axum::{Form, Json, Router};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::{
+    Api, ApiError, ApiResponse, ApiStore, Caller, KeyRevocationRequest, Operation, PasskeyOptionsRequest, SpecQuery,
+    TotpConfirmationRequest, FORM, JSON, MAX_AUTHENTICATION_AGE,
+};
+use crate::service::oauth::{AuthorizationRequest, OAuthError, TokenRequest};
+use crate::service::oidc::AuthenticationContext;
+use crate::service::user_keys::{KeyRotationRequest, NewUserKey};
+use crate::service::webauthn::RegistrationCredential;
+use crate::service::{PasskeyLoginRequest, SecondFactorRequest, UserLoginRequest, UserRegisterRequest};
+use crate::store::UserMetadataOf;
+
+/// Bigger bodies are answered with 413 before reaching the routes
+pub const MAX_BODY_SIZE: usize = 64 * 1024;
+
+type ApiState<S> = State<Arc<Api<S>>>;
+
+/// The routes of [`super::ROUTES`] served by the configuration of `api`
+pub fn router<S: ApiStore>(api: Api<S>) -> Router
+where
+    UserMetadataOf<S>: Serialize + DeserializeOwned,
+{
+    let mut router = Router::new();
+    for (path, methods) in api.routes() {
+        let methods = methods
+            .iter()
+            .map(|&(method, operation)| handler::<S>(method_filter(method), operation))
+            .fold(MethodRouter::new(), MethodRouter::merge);
+        router = router.route(path, methods.fallback(method_not_allowed));
+    }
+    router
+        .fallback(not_found)
+        .layer(DefaultBodyLimit::max(MAX_BODY_SIZE))
+        .with_state(Arc::new(api))
+}
+
+fn method_filter(method: &str) -> MethodFilter {
+    Method::from_bytes(method.as_bytes())
+        .ok()
+        .and_then(|method| MethodFilter::try_from(method).ok())
+        .expect("the route table only has standard methods")
+}
+
+fn handler<S: ApiStore>(filter: MethodFilter, operation: Operation) -> MethodRouter<Arc<Api<S>>>
+where
+    UserMetadataOf<S>: Serialize + DeserializeOwned,
+{
+    match operation {
+        Operation::Spec => on(filter, spec::<S>),
+        Operation::Register => on(filter, register::<S>),
+        Operation::Login => on(filter, login::<S>),
+        Operation::LoginSecondFactor => on(filter, login_second_factor::<S>),
+        Operation::EnrollTotp => on(filter, enroll_totp::<S>),
+        Operation::ConfirmTotp => on(filter, confirm_totp::<S>),
+        Operation::PasskeyRegistrationOptions => on(filter, passkey_registration_options::<S>),
+        Operation::RegisterPasskey => on(filter, register_passkey::<S>),
+        Operation::PasskeyLoginOptions => on(filter, passkey_login_options::<S>),
+        Operation::LoginWithPasskey => on(filter, login_with_passkey::<S>),
+        Operation::ListKeys => on(filter, list_keys::<S>),
+        Operation::RotateKey => on(filter, rotate_key::<S>),
+        Operation::EnrollKey => on(filter, enroll_key::<S>),
+        Operation::RevokeKey => on(filter, revoke_key::<S>),
+        Operation::Authorize => on(filter, authorize::<S>),
+        Operation::Token => on(filter, token::<S>),
+    }
+}
+
+impl IntoResponse for ApiResponse {
+    fn into_response(self) -> Response {
+        let mut builder = Response::builder().status(self.status);
+        for (name, value) in self.headers {
+            builder = builder.header(name, value);
+        }
+        builder.body(Body::from(self.body)).unwrap()
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        ApiResponse::from(self).into_response()
+    }
+}
+
+fn caller(parts: &Parts) -> Caller {
+    Caller {
+        authorization: parts.headers.get(header::AUTHORIZATION).and_then(|value| value.to_str().ok()).map(str::to_string),
+        remote_addr: parts.extensions.get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(address)| address.ip()),
+    }
+}
+
+#[async_trait]
+impl<T: Send + Sync> FromRequestParts<T> for Caller {
+    type Rejection = std::convert::Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &T) -> Result<Self, Self::Rejection> {
+        Ok(caller(parts))
+    }
+}
+
+/// The user of the `Bearer` token, `RECENT` routes change credentials and want a login of the last
+/// [`MAX_AUTHENTICATION_AGE`]
+struct Authenticated<const RECENT: bool> {
+    caller: Caller,
+    authentication: AuthenticationContext,
+}
+
+#[async_trait]
+impl<S: ApiStore, const RECENT: bool> FromRequestParts<Arc<Api<S>>> for Authenticated<RECENT>
+where
+    UserMetadataOf<S>: Serialize + DeserializeOwned,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, api: &Arc<Api<S>>) -> Result<Self, Self::Rejection> {
+        let caller = caller(parts);
+        let authentication = api.authenticated_user(&caller, RECENT.then_some(MAX_AUTHENTICATION_AGE)).await?;
+        Ok(Self { caller, authentication })
+    }
+}
+
+/// The rejections of the extractors keep the codes of [`super::Api::handle`]
+fn rejection(status: StatusCode, message: String, media_type: &'static str) -> ApiError {
+    match status {
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => ApiError::UnsupportedMediaType(media_type),
+        StatusCode::PAYLOAD_TOO_LARGE => ApiError::PayloadTooLarge,
+        _ => ApiError::InvalidRequest(message),
+    }
+}
+
+fn json<T>(body: Result<Json<T>, JsonRejection>) -> Result<T, ApiError> {
+    body.map(|Json(body)| body).map_err(|error| rejection(error.status(), error.body_text(), JSON))
+}
+
+fn form<T>(body: Result<Form<T>, FormRejection>) -> Result<T, ApiError> {
+    body.map(|Form(body)| body).map_err(|error| match rejection(error.status(), error.body_text(), FORM) {
+        ApiError::InvalidRequest(message) => OAuthError::InvalidRequest(message).into(),
+        error => error,
+    })
+}
+
+fn query<T>(query: Result<Query<T>, QueryRejection>) -> Result<T, ApiError> {
+    query.map(|Query(query)| query).map_err(|error| ApiError::InvalidRequest(error.body_text()))
+}
+
+async fn not_found() -> ApiError {
+    ApiError::NotFound
+}
+
+async fn method_not_allowed() -> ApiError {
+    ApiError::MethodNotAllowed
+}
+
+async fn spec<S: ApiStore>(State(api): ApiState<S>, spec: Result<Query<SpecQuery>, QueryRejection>) -> Result<ApiResponse, ApiError>
+where
+    UserMetadataOf<S>: Serialize + DeserializeOwned,
+{
+    api.spec(query(spec)?).await
+}
+
+async fn register<S: ApiStore>(
+    State(api): ApiState<S>,
+    caller: Caller,
+    register: Result<Json<UserRegisterRequest<UserMetadataOf<S>>>, JsonRejection>,
+) -> Result<ApiResponse, ApiError>
+where
+    UserMetadataOf<S>: Serialize + DeserializeOwned,
+{
+    api.register(&caller, json(register)?).await
+}
+
+async fn login<S: ApiStore>(State(api): ApiState<S>, caller: Caller, login: Result<Json<UserLoginRequest>, JsonRejection>) -> Result<ApiResponse, ApiError>
+where
+    UserMetadataOf<S>: Serialize + DeserializeOwned,
+{
+    api.login(&caller, json(login)?).await
+}
+
+async fn login_second_factor<S: ApiStore>(
+    State(api): ApiState<S>,
+    caller: Caller,
+    second_factor: Result<Json<SecondFactorRequest>, JsonRejection>,
+) -> Result<ApiResponse, ApiError>
+where
+    UserMetadataOf<S>: Serialize + DeserializeOwned,
+{
+    api.login_second_factor(&caller, json(second_factor)?).await
+}
+
+async fn enroll_totp<S: ApiStore>(State(api): ApiState<S>, user: Authenticated<true>) -> Result<ApiResponse, ApiError>
+where
+    UserMetadataOf<S>: Serialize + DeserializeOwned,
+{
+    api.enroll_totp(user.authentication).await
+}
+
+async fn confirm_totp<S: ApiStore>(
+    State(api): ApiState<S>,
+    user: Authenticated<true>,
+    confirmation: Result<Json<TotpConfirmationRequest>, JsonRejection>,
+) -> Result<ApiResponse, ApiError>
+where
+    UserMetadataOf<S>: Serialize + DeserializeOwned,
+{
+    api.confirm_totp(&user.caller, user.authentication, json(confirmation)?).await
+}
+
+async fn passkey_registration_options<S: ApiStore>(State(api): ApiState<S>, user: Authenticated<true>) -> Result<ApiResponse, ApiError>
+where
+    UserMetadataOf<S>: Serialize + DeserializeOwned,
+{
+    api.passkey_registration_options(user.authentication).await
+}
+
+async fn register_passkey<S: ApiStore>(
+    State(api): ApiState<S>,
+    user: Authenticated<true>,
+    credential: Result<Json<RegistrationCredential>, JsonRejection>,
+) -> Result<ApiResponse, ApiError>
+where
+    UserMetadataOf<S>: Serialize + DeserializeOwned,
+{
+    api.register_passkey(&user.caller, user.authentication, json(credential)?).await
+}
+
+async fn passkey_login_options<S: ApiStore>(
+    State(api): ApiState<S>,
+    options: Result<Json<PasskeyOptionsRequest>, JsonRejection>,
+) -> Result<ApiResponse, ApiError>
+where
+    UserMetadataOf<S>: Serialize + DeserializeOwned,
+{
+    api.passkey_login_options(json(options)?).await
+}
+
+async fn login_with_passkey<S: ApiStore>(
+    State(api): ApiState<S>,
+    caller: Caller,
+    login: Result<Json<PasskeyLoginRequest>, JsonRejection>,
+) -> Result<ApiResponse, ApiError>
+where
+    UserMetadataOf<S>: Serialize + DeserializeOwned,
+{
+    api.login_with_passkey(&caller, json(login)?).await
+}
+
+async fn list_keys<S: ApiStore>(State(api): ApiState<S>, user: Authenticated<false>) -> Result<ApiResponse, ApiError>
+where
+    UserMetadataOf<S>: Serialize + DeserializeOwned,
+{
+    api.list_keys(user.authentication).await
+}
+
+async fn rotate_key<S: ApiStore>(
+    State(api): ApiState<S>,
+    caller: Caller,
+    rotation: Result<Json<KeyRotationRequest>, JsonRejection>,
+) -> Result<ApiResponse, ApiError>
+where
+    UserMetadataOf<S>: Serialize + DeserializeOwned,
+{
+    api.rotate_key(&caller, json(rotation)?).await
+}
+
+async fn enroll_key<S: ApiStore>(
+    State(api): ApiState<S>,
+    user: Authenticated<true>,
+    key: Result<Json<NewUserKey>, JsonRejection>,
+) -> Result<ApiResponse, ApiError>
+where
+    UserMetadataOf<S>: Serialize + DeserializeOwned,
+{
+    api.enroll_key(&user.caller, user.authentication, json(key)?).await
+}
+
+async fn revoke_key<S: ApiStore>(
+    State(api): ApiState<S>,
+    user: Authenticated<true>,
+    revocation: Result<Json<KeyRevocationRequest>, JsonRejection>,
+) -> Result<ApiResponse, ApiError>
+where
+    UserMetadataOf<S>: Serialize + DeserializeOwned,
+{
+    api.revoke_key(&user.caller, user.authentication, json(revocation)?).await
+}
+
+/// The query is read first, a malformed authorization request is answered before the user is checked
+async fn authorize<S: ApiStore>(
+    State(api): ApiState<S>,
+    authorization: Result<Query<AuthorizationRequest>, QueryRejection>,
+    user: Authenticated<false>,
+) -> Result<ApiResponse, ApiError>
+where
+    UserMetadataOf<S>: Serialize + DeserializeOwned,
+{
+    api.authorize(user.authentication, query(authorization)?).await
+}
+
+async fn token<S: ApiStore>(State(api): ApiState<S>, caller: Caller, token: Result<Form<TokenRequest>, FormRejection>) -> Result<ApiResponse, ApiError>
+where
+    UserMetadataOf<S>: Serialize + DeserializeOwned,
+{
+    api.token(&caller, form(token)?).await
+}
+
+#[cfg(all(test, feature = "in-memory"))]
+mod tests {
+    use axum::body::to_bytes;
+    use axum::extract::Request;
+    use axum::http::request::Builder;
+    use tower::ServiceExt;
+
+    use std::time::SystemTime;
+
+    use serde_json::{json, Value};
+
+    use crate::api::{ApiConfig, UserToken, ROUTES};
+    use crate::client::UserKey;
+    use crate::crypto::token::TokenSigner;
+    use crate::crypto::{jwt, totp};
+    use crate::data::id::Identifier;
+    use crate::model::{ClientSettings, Curve};
+    use crate::service::oidc::{self, IdTokenClaims};
+    use crate::service::{UserLoginPayload, UserTokenPayload};
+    use crate::store::memory::{InMemoryState, InMemoryStore};
+    use crate::store::{ClientStore, NewClient};
+
+    use super::*;
+
+    const ISSUER: &str = "https://iam0.example.com";
+    const REDIRECT_URI: &str = "https://app.example.com/callback";
+    const VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    const CHALLENGE: &str = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+
+    /// Status, headers and JSON body of the response of the router
+    async fn send(router: &Router, request: Request) -> (StatusCode, header::HeaderMap, Value) {
+        let response = router.clone().oneshot(request).await.unwrap();
+        let (parts, body) = response.into_parts();
+        let body = to_bytes(body, MAX_BODY_SIZE).await.unwrap();
+        (parts.status, parts.headers, serde_json::from_slice(&body).unwrap_or_default())
+    }
+
+    fn with_bearer(request: Builder, bearer: Option<&str>) -> Builder {
+        match bearer {
+            Some(token) => request.header(header::AUTHORIZATION, format!("Bearer {token}")),
+            None => request,
+        }
+    }
+
+    fn get(path: &str, bearer: Option<&str>) -> Request {
+        with_bearer(Request::get(path), bearer).body(Body::empty()).unwrap()
+    }
+
+    fn post_json(path: &str, bearer: Option<&str>, body: &Value) -> Request {
+        let request = Request::post(path).header(header::CONTENT_TYPE, "application/json");
+        with_bearer(request, bearer).body(Body::from(body.to_string())).unwrap()
+    }
+
+    async fn error(response: Response) -> (StatusCode, String) {
+        let status = response.status();
+        let body = to_bytes(response.into_body(), MAX_BODY_SIZE).await.unwrap();
+        (status, serde_json::from_slice::<serde_json::Value>(&body).unwrap()["error"].as_str().unwrap().to_string())
+    }
+
+    #[tokio::test]
+    async fn requests_reach_the_api() {
+        let config = ApiConfig {
+            issuer: "https://iam0.example.com".to_string(),
+            token_endpoint: "https://iam0.example.com/oauth/token".to_string(),
+            relying_party: None,
+            login_limits: Default::default(),
+            audit: None,
+        };
+        let router = router(Api::<InMemoryStore>::new(config, InMemoryState::new()));
+
+        let request = Request::get("/api/v1/spec").body(Body::empty()).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        let body = to_bytes(response.into_body(), MAX_BODY_SIZE).await.unwrap();
+        let spec: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(spec["hash_functions"], serde_json::json!(["sha512"]));
+        let request = Request::get("/api/v1/spec?client_id=zz").body(Body::empty()).unwrap();
+        assert_eq!(error(router.clone().oneshot(request).await.unwrap()).await, (StatusCode::BAD_REQUEST, "invalid_request".to_string()));
+
+        let request = Request::post("/oauth/token")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from("grant_type=client_credentials"))
+            .unwrap();
+        assert_eq!(error(router.clone().oneshot(request).await.unwrap()).await, (StatusCode::UNAUTHORIZED, "invalid_client".to_string()));
+        let request = Request::post("/oauth/token")
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from("grant_type=a&grant_type=b"))
+            .unwrap();
+        assert_eq!(error(router.clone().oneshot(request).await.unwrap()).await, (StatusCode::BAD_REQUEST, "invalid_request".to_string()));
+        let request = Request::post("/oauth/token").body(Body::from("grant_type=client_credentials")).unwrap();
+        let expected = (StatusCode::UNSUPPORTED_MEDIA_TYPE, "unsupported_media_type".to_string());
+        assert_eq!(error(router.clone().oneshot(request).await.unwrap()).await, expected);
+
+        let request = Request::post("/login")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(vec![b'x'; MAX_BODY_SIZE + 1]))
+            .unwrap();
+        let expected = (StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large".to_string());
+        assert_eq!(error(router.clone().oneshot(request).await.unwrap()).await, expected);
+        let request = Request::post("/login").header(header::CONTENT_TYPE, "application/json").body(Body::from("{")).unwrap();
+        assert_eq!(error(router.clone().oneshot(request).await.unwrap()).await, (StatusCode::BAD_REQUEST, "invalid_request".to_string()));
+    }
+
+    #[tokio::test]
+    async fn login_flows() {
+        let state = InMemoryState::new();
+        let settings = ClientSettings { redirect_uris: vec![REDIRECT_URI.to_string()], ..ClientSettings::default() };
+        let client = NewClient { parent_id: None, name: "app".to_string(), settings };
+        let client_id = InMemoryStore::create_client(state.clone(), client).await.unwrap().id;
+        let signing_key = state.seed_signing_key(client_id);
+        let config = ApiConfig {
+            issuer: ISSUER.to_string(),
+            token_endpoint: format!("{ISSUER}/oauth/token"),
+            relying_party: None,
+            login_limits: Default::default(),
+            audit: None,
+        };
+        let router = router(Api::<InMemoryStore>::new(config, state));
+
+        let key = UserKey::generate(Curve::NistP256);
+        let register = json!({
+            "client_id": client_id.as_hex(),
+            "email": "alice@example.com",
+            "key": key.registration_key(client_id, "alice@example.com", "laptop"),
+        });
+        let (status, _, body) = send(&router, post_json("/register", None, &register)).await;
+        assert_eq!(status, StatusCode::CREATED, "{body}");
+        let user_id = Identifier::from_hex(body["id"].as_str().unwrap()).unwrap();
+        let payload = UserLoginPayload { client_id, email: "alice@example.com".to_string() };
+        let login = serde_json::to_value(key.prove(payload, None)).unwrap();
+        let (status, headers, body) = send(&router, post_json("/login", None, &login)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(headers[header::CACHE_CONTROL], "no-store");
+        let access_token = body["access_token"].as_str().unwrap().to_string();
+        let (status, _, body) = send(&router, get("/keys", Some(&access_token))).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["keys"][0]["label"], "laptop");
+
+        // An old login reads the keys but can't enroll a second factor
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+        let payload = UserTokenPayload {
+            user_id,
+            client_id,
+            auth_time: now - MAX_AUTHENTICATION_AGE.as_secs() - 1,
+            amr: vec![oidc::AMR_SCHNORR.to_string()],
+            expires_at: now + 60,
+        };
+        let stale: UserToken = TokenSigner::sign(&signing_key, payload);
+        let (status, headers, body) = send(&router, post_json("/mfa/totp", Some(&stale.encode()), &json!({}))).await;
+        assert_eq!((status, body["error"].as_str()), (StatusCode::UNAUTHORIZED, Some("insufficient_user_authentication")));
+        assert_eq!(headers[header::WWW_AUTHENTICATE], "Bearer error=\"insufficient_user_authentication\", max_age=300");
+
+        let (status, _, body) = send(&router, post_json("/mfa/totp", Some(&access_token), &json!({}))).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let secret = totp::base32_decode(body["secret"].as_str().unwrap()).unwrap();
+        let confirm = json!({ "code": totp::hotp(&secret, totp::time_step(SystemTime::now())) });
+        let (status, _, body) = send(&router, post_json("/mfa/totp/confirm", Some(&access_token), &confirm)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let recovery_code = body["recovery_codes"][0].clone();
+        let (status, _, body) = send(&router, post_json("/mfa/totp/confirm", Some(&access_token), &confirm)).await;
+        assert_eq!((status, body["error"].as_str()), (StatusCode::CONFLICT, Some("conflict")));
+
+        let (status, _, body) = send(&router, post_json("/login", None, &login)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let mfa_token = body["mfa_token"].as_str().unwrap().to_string();
+        let wrong = json!({ "mfa_token": mfa_token, "code": "AAAA-AAAA-AAAA-AAAA" });
+        let (status, _, body) = send(&router, post_json("/login/mfa", None, &wrong)).await;
+        assert_eq!((status, body["error"].as_str()), (StatusCode::UNAUTHORIZED, Some("invalid_credentials")));
+        let second_factor = json!({ "mfa_token": mfa_token, "code": recovery_code });
+        let (status, _, body) = send(&router, post_json("/login/mfa", None, &second_factor)).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let claims: IdTokenClaims = jwt::decode_unverified(body["id_token"].as_str().unwrap()).unwrap();
+        assert_eq!(claims.amr, [oidc::AMR_SCHNORR, oidc::AMR_OTP, oidc::AMR_MFA]);
+        let access_token = body["access_token"].as_str().unwrap().to_string();
+
+        let authorize = format!(
+            "/authorize?client_id={}&redirect_uri=https%3A%2F%2Fapp.example.com%2Fcallback&response_type=code&scope=openid&state=xyz&code_challenge={CHALLENGE}&code_challenge_method=S256",
+            client_id.as_hex(),
+        );
+        let (status, headers, body) = send(&router, get(&authorize, None)).await;
+        assert_eq!((status, body["error"].as_str()), (StatusCode::UNAUTHORIZED, Some("invalid_token")));
+        assert_eq!(headers[header::WWW_AUTHENTICATE], "Bearer error=\"invalid_token\"");
+        let (status, _, body) = send(&router, get(&authorize, Some(&access_token))).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["state"], "xyz");
+
+        let form = format!(
+            "grant_type=authorization_code&client_id={}&code={}&redirect_uri=https%3A%2F%2Fapp.example.com%2Fcallback&code_verifier={VERIFIER}",
+            client_id.as_hex(),
+            body["code"].as_str().unwrap(),
+        );
+        let token = || {
+            let request = Request::post("/oauth/token").header(header::CONTENT_TYPE, "application/x-www-form-urlencoded");
+            request.body(Body::from(form.clone())).unwrap()
+        };
+        let (status, headers, body) = send(&router, token()).await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(headers[header::CACHE_CONTROL], "no-store");
+        assert_eq!(body["token_type"], "Bearer");
+        assert!(body["id_token"].is_string());
+        let (status, _, body) = send(&router, token()).await;
+        assert_eq!((status, body["error"].as_str()), (StatusCode::BAD_REQUEST, Some("invalid_grant")));
+    }
+
+    #[tokio::test]
+    async fn routes() {
+        let config = ApiConfig {
+            issuer: "https://iam0.example.com".to_string(),
+            token_endpoint: "https://iam0.example.com/oauth/token".to_string(),
+            relying_party: None,
+            login_limits: Default::default(),
+            audit: None,
+        };
+        let router = router(Api::<InMemoryStore>::new(config, InMemoryState::new()));
+        let call = |method: &str, path: &str| Request::builder().method(method).uri(path).body(Body::empty()).unwrap();
+
+        let expected = (StatusCode::NOT_FOUND, "not_found".to_string());
+        assert_eq!(error(router.clone().oneshot(call("GET", "/missing")).await.unwrap()).await, expected);
+        // NOTE: Without a relying party the passkey routes don't exist
+        assert_eq!(error(router.clone().oneshot(call("POST", "/login/passkey/options")).await.unwrap()).await, expected);
+        let expected = (StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed".to_string());
+        assert_eq!(error(router.clone().oneshot(call("DELETE", "/login")).await.unwrap()).await, expected);
+        assert_eq!(error(router.clone().oneshot(call("DELETE", "/keys")).await.unwrap()).await, expected);
+
+        // Every operation of the route table is reachable, the other methods of its path aren't
+        for (path, methods) in ROUTES.iter().filter(|(_, methods)| !methods.iter().any(|(_, operation)| operation.needs_relying_party())) {
+            for (method, _) in methods.iter() {
+                let status = router.clone().oneshot(call(method, path)).await.unwrap().status();
+                assert!(status != StatusCode::NOT_FOUND && status != StatusCode::METHOD_NOT_ALLOWED, "{method} {path}: {status}");
+            }
+            let response = router.clone().oneshot(call("PUT", path)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED, "{path}");
+        }
+
+        // The bearer token is checked before the body
+        let expected = (StatusCode::UNAUTHORIZED, "invalid_token".to_string());
+        for (method, path) in [("GET", "/keys"), ("POST", "/mfa/totp"), ("POST", "/keys/enroll"), ("POST", "/keys/revoke")] {
+            assert_eq!(error(router.clone().oneshot(call(method, path)).await.unwrap()).await, expected, "{path}");
+        }
+    }
+}
@@ -0,0 +1,1189 @@
+//! HTTP API of `openapi-spec.yml` independent of the web framework. An [`ApiRequest`] carries the parts
+//! of the request the routes read and [`Api::handle`] answers it with an [`ApiResponse`], so plugging the
+//! API into a server is a matter of converting the request and response types. The `http` feature does it
+//! for axum with [`router`].
+//!
+//! The routes acting for a user take the `access_token` of one of its logins as a `Bearer` token. Logins,
+//! registrations, key changes and issued tokens are reported to [`ApiConfig::audit`] when it is set
+
+mod error;
+#[cfg(feature = "http")]
+mod http;
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+pub use error::ApiError;
+#[cfg(feature = "http")]
+pub use http::{router, MAX_BODY_SIZE};
+
+use crate::crypto::token::{Token, TokenVerifier};
+use crate::data::id::Identifier;
+use crate::model::{Curve, User};
+use crate::service::audit::{self, AuditSink};
+use crate::service::oauth::{AuthorizationRequest, AuthorizationService, OAuthError, TokenRequest};
+use crate::service::oidc::AuthenticationContext;
+use crate::service::spec::SpecService;
+use crate::service::mfa::{self, MfaError};
+use crate::service::rate_limit::LoginLimits;
+use crate::service::user_keys::{self, KeyRotationRequest, NewUserKey, UserKeyError};
+use crate::service::webauthn::{self, RegistrationCredential, RelyingParty, WebAuthnError};
+use crate::service::{
+    tenant, LoginError, PasskeyLoginRequest, RegistrationError, SecondFactorRequest, UserAuthentication, UserLoginOutcome,
+    UserLoginPayload, UserLoginRequest, UserLoginResponse, UserRegisterRequest, UserRegistration, UserTokenPayload,
+};
+use crate::store::{ClientStore, RateLimitStore, StoreError, TokenStore, Transaction, UserMetadataOf, UserPublicKey, UserStore};
+
+const JSON: &str = "application/json";
+const FORM: &str = "application/x-www-form-urlencoded";
+
+/// How long after a login its access token can still change the credentials of the user
+pub const MAX_AUTHENTICATION_AGE: Duration = Duration::from_secs(5 * 60);
+
+type UserToken = Token<UserTokenPayload, p256::NistP256>;
+
+/// Every store trait the routes use
+pub trait ApiStore: UserStore + ClientStore + TokenStore + RateLimitStore + Transaction
+where
+    UserMetadataOf<Self>: Serialize + DeserializeOwned {}
+
+impl<S> ApiStore for S
+where
+    S: UserStore + ClientStore + TokenStore + RateLimitStore + Transaction,
+    UserMetadataOf<S>: Serialize + DeserializeOwned {}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ApiRequest<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub query: Option<&'a str>,
+    pub content_type: Option<&'a str>,
+    /// Raw value of the `Authorization` header
+    pub authorization: Option<&'a str>,
+    pub body: &'a [u8],
+    /// Address of the peer, or of the client behind a trusted proxy, used for the login limits per IP
+    pub remote_addr: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApiResponse {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+impl ApiResponse {
+    fn json<T: Serialize>(status: u16, body: &T) -> Self {
+        Self {
+            status,
+            headers: vec![("content-type", JSON.to_string())],
+            body: serde_json::to_vec(body).unwrap(),
+        }
+    }
+
+    fn header(mut self, name: &'static str, value: &str) -> Self {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+}
+
+impl From<ApiError> for ApiResponse {
+    fn from(error: ApiError) -> Self {
+        let response = ApiResponse::json(error.status(), &error.body());
+        match error {
+            ApiError::OAuth(OAuthError::InvalidToken(_)) => response.header("www-authenticate", "Bearer error=\"invalid_token\""),
+            // RFC 9470, the client sends the user through a login again
+            ApiError::StaleAuthentication { max_age } => response.header(
+                "www-authenticate",
+                &format!("Bearer error=\"insufficient_user_authentication\", max_age={}", max_age.as_secs()),
+            ),
+            ApiError::TooManyRequests { retry_after } => response.header("retry-after", &retry_after.as_secs_f64().ceil().to_string()),
+            _ => response,
+        }
+    }
+}
+
+/// Deployment settings of the API
+#[derive(Debug, Clone)]
+pub struct ApiConfig {
+    /// `iss` of the ID tokens
+    pub issuer: String,
+    /// Absolute URL of `/oauth/token`, the audience of the client assertions
+    pub token_endpoint: String,
+    /// Relying party of the passkeys, `None` turns the passkey routes off
+    pub relying_party: Option<RelyingParty>,
+    /// Throttling of the login routes, answered with 429 and a `Retry-After` header
+    pub login_limits: LoginLimits,
+    /// Where the logins, registrations, key changes and issued tokens are reported, see [`audit`]
+    pub audit: Option<Arc<dyn AuditSink>>,
+}
+
+impl<S: UserStore + ClientStore + TokenStore + RateLimitStore + Transaction> UserAuthentication<S> for ApiConfig {
+    fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    fn relying_party(&self) -> Option<&RelyingParty> {
+        self.relying_party.as_ref()
+    }
+
+    fn login_limits(&self) -> LoginLimits {
+        self.login_limits
+    }
+
+    fn audit_sink(&self) -> Option<&dyn AuditSink> {
+        self.audit.as_deref()
+    }
+}
+
+impl<S: UserStore + Transaction> UserRegistration<S> for ApiConfig {
+    fn audit_sink(&self) -> Option<&dyn AuditSink> {
+        self.audit.as_deref()
+    }
+}
+
+impl<S: UserStore + ClientStore + TokenStore> AuthorizationService<S> for ApiConfig {
+    fn token_endpoint(&self) -> &str {
+        &self.token_endpoint
+    }
+
+    fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    fn audit_sink(&self) -> Option<&dyn AuditSink> {
+        self.audit.as_deref()
+    }
+}
+
+impl<S: ClientStore> SpecService<S> for ApiConfig {}
+
+#[derive(Serialize)]
+struct RegisterResponse<M> {
+    id: String,
+    email: String,
+    metadata: Option<M>,
+}
+
+#[derive(Serialize)]
+struct LoginResponse {
+    id_token: String,
+    access_token: String,
+    refresh_token: Option<String>,
+}
+
+impl From<UserLoginResponse> for LoginResponse {
+    fn from(response: UserLoginResponse) -> Self {
+        Self {
+            id_token: response.id_token,
+            access_token: response.token.encode(),
+            refresh_token: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SecondFactorRequiredResponse {
+    mfa_token: String,
+}
+
+#[derive(Serialize)]
+struct TotpEnrollmentResponse {
+    secret: String,
+    uri: String,
+}
+
+#[derive(Deserialize)]
+struct TotpConfirmationRequest {
+    code: String,
+}
+
+#[derive(Serialize)]
+struct TotpConfirmationResponse {
+    recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct PasskeyRegistrationResponse {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct PasskeyOptionsRequest {
+    client_id: Identifier,
+    email: String,
+}
+
+/// Times in seconds since the Unix epoch
+#[derive(Serialize)]
+struct UserKeyResponse {
+    id: String,
+    label: String,
+    curve: Curve,
+    created_at: u64,
+    last_used_at: Option<u64>,
+    revoked_at: Option<u64>,
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+impl From<UserPublicKey> for UserKeyResponse {
+    fn from(key: UserPublicKey) -> Self {
+        Self {
+            id: key.id,
+            label: key.label,
+            curve: key.curve,
+            created_at: unix_seconds(key.created_at),
+            last_used_at: key.last_used_at.map(unix_seconds),
+            revoked_at: key.revoked_at.map(unix_seconds),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct UserKeysResponse {
+    keys: Vec<UserKeyResponse>,
+}
+
+#[derive(Deserialize)]
+struct KeyRevocationRequest {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct SpecQuery {
+    client_id: Option<Identifier>,
+}
+
+/// The parts of a request the routes read besides its body
+#[derive(Debug, Clone, Default)]
+struct Caller {
+    /// Raw value of the `Authorization` header
+    authorization: Option<String>,
+    remote_addr: Option<IpAddr>,
+}
+
+/// Operations of `openapi-spec.yml`, both [`Api::handle`] and the axum router dispatch on them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operation {
+    Spec,
+    Register,
+    Login,
+    LoginSecondFactor,
+    EnrollTotp,
+    ConfirmTotp,
+    PasskeyRegistrationOptions,
+    RegisterPasskey,
+    PasskeyLoginOptions,
+    LoginWithPasskey,
+    ListKeys,
+    RotateKey,
+    EnrollKey,
+    RevokeKey,
+    Authorize,
+    Token,
+}
+
+impl Operation {
+    /// The passkey operations only exist when [`ApiConfig::relying_party`] is set
+    fn needs_relying_party(self) -> bool {
+        matches!(
+            self,
+            Self::PasskeyRegistrationOptions | Self::RegisterPasskey | Self::PasskeyLoginOptions | Self::LoginWithPasskey
+        )
+    }
+}
+
+/// The route table, every path with the operation of each of its methods. Other methods are answered
+/// with 405 and other paths with 404
+const ROUTES: &[(&str, &[(&str, Operation)])] = &[
+    ("/api/v1/spec", &[("GET", Operation::Spec)]),
+    ("/register", &[("POST", Operation::Register)]),
+    ("/login", &[("POST", Operation::Login)]),
+    ("/login/mfa", &[("POST", Operation::LoginSecondFactor)]),
+    ("/mfa/totp", &[("POST", Operation::EnrollTotp)]),
+    ("/mfa/totp/confirm", &[("POST", Operation::ConfirmTotp)]),
+    ("/passkeys/register/options", &[("POST", Operation::PasskeyRegistrationOptions)]),
+    ("/passkeys/register", &[("POST", Operation::RegisterPasskey)]),
+    ("/login/passkey/options", &[("POST", Operation::PasskeyLoginOptions)]),
+    ("/login/passkey", &[("POST", Operation::LoginWithPasskey)]),
+    ("/keys", &[("GET", Operation::ListKeys), ("POST", Operation::RotateKey)]),
+    ("/keys/enroll", &[("POST", Operation::EnrollKey)]),
+    ("/keys/revoke", &[("POST", Operation::RevokeKey)]),
+    ("/authorize", &[("GET", Operation::Authorize)]),
+    ("/oauth/token", &[("POST", Operation::Token)]),
+];
+
+pub struct Api<S: ApiStore>
+where
+    UserMetadataOf<S>: Serialize + DeserializeOwned,
+{
+    config: ApiConfig,
+    state: S::State,
+}
+
+fn media_type(content_type: Option<&str>) -> Option<String> {
+    content_type.map(|content_type| content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase())
+}
+
+fn json_body<T: DeserializeOwned>(request: &ApiRequest) -> Result<T, ApiError> {
+    if media_type(request.content_type).as_deref() != Some(JSON) {
+        return Err(ApiError::UnsupportedMediaType(JSON));
+    }
+    serde_json::from_slice(request.body).map_err(|error| ApiError::InvalidRequest(error.to_string()))
+}
+
+fn query<T: DeserializeOwned>(request: &ApiRequest) -> Result<T, ApiError> {
+    serde_urlencoded::from_str(request.query.unwrap_or_default()).map_err(|error| ApiError::InvalidRequest(error.to_string()))
+}
+
+/// The OAuth form bodies, a repeated name is an error as RFC 6749 section 3.1 requires
+fn form_body<T: DeserializeOwned>(request: &ApiRequest) -> Result<T, ApiError> {
+    if media_type(request.content_type).as_deref() != Some(FORM) {
+        return Err(ApiError::UnsupportedMediaType(FORM));
+    }
+    serde_urlencoded::from_bytes(request.body).map_err(|error| OAuthError::InvalidRequest(error.to_string()).into())
+}
+
+fn mfa_error(error: MfaError) -> ApiError {
+    match error {
+        MfaError::AlreadyConfirmed => ApiError::Conflict(error.to_string()),
+        MfaError::NotEnrolled | MfaError::InvalidCode => ApiError::InvalidRequest(error.to_string()),
+        MfaError::Store(StoreError::NotFound) => ApiError::InvalidRequest("the user doesn't exist anymore".to_string()),
+        MfaError::Store(error) => ApiError::Internal(error.to_string()),
+    }
+}
+
+fn webauthn_error(error: WebAuthnError) -> ApiError {
+    match error {
+        WebAuthnError::DuplicateCredential => ApiError::Conflict(error.to_string()),
+        WebAuthnError::Store(StoreError::NotFound) => ApiError::InvalidRequest("the user doesn't exist anymore".to_string()),
+        WebAuthnError::Store(error) => ApiError::Internal(error.to_string()),
+        _ => ApiError::InvalidRequest(error.to_string()),
+    }
+}
+
+fn user_key_error(error: UserKeyError) -> ApiError {
+    match error {
+        UserKeyError::DuplicateKey | UserKeyError::TooManyKeys | UserKeyError::LastKey | UserKeyError::AlreadyEnrolled => {
+            ApiError::Conflict(error.to_string())
+        }
+        UserKeyError::Store(StoreError::NotFound) => ApiError::InvalidRequest("the user doesn't exist anymore".to_string()),
+        UserKeyError::Store(error) => ApiError::Internal(error.to_string()),
+        _ => ApiError::InvalidRequest(error.to_string()),
+    }
+}
+
+fn login_error(error: LoginError) -> ApiError {
+    match error {
+        LoginError::RateLimited { retry_after } => ApiError::TooManyRequests { retry_after },
+        // NOTE: Unknown users and wrong proofs look the same to not disclose who is registered
+        LoginError::InvalidProof
+        | LoginError::UserNotFound
+        | LoginError::InvalidPasskey
+        | LoginError::InvalidMfaToken
+        | LoginError::InvalidCode
+        | LoginError::SecondFactorNotEnrolled => ApiError::InvalidCredentials,
+        LoginError::DuplicateKey | LoginError::TooManyKeys => ApiError::Conflict(error.to_string()),
+        LoginError::ClientNotFound | LoginError::ClientDisabled | LoginError::CurveNotAllowed | LoginError::InvalidLabel => {
+            ApiError::InvalidRequest(error.to_string())
+        }
+        LoginError::PasskeysNotConfigured => ApiError::NotFound,
+        LoginError::Internal(message) => ApiError::Internal(message.to_string()),
+    }
+}
+
+fn registration_error(error: RegistrationError) -> ApiError {
+    match error {
+        RegistrationError::EmailTaken => ApiError::Conflict(error.to_string()),
+        RegistrationError::ClientNotFound | RegistrationError::InvalidProof | RegistrationError::InvalidLabel => {
+            ApiError::InvalidRequest(error.to_string())
+        }
+        RegistrationError::Internal(message) => ApiError::Internal(message.to_string()),
+    }
+}
+
+impl<S: ApiStore> Api<S>
+where
+    UserMetadataOf<S>: Serialize + DeserializeOwned,
+{
+    pub fn new(config: ApiConfig, state: S::State) -> Self {
+        Self { config, state }
+    }
+
+    pub async fn handle(&self, request: ApiRequest<'_>) -> ApiResponse {
+        self.route(&request).await.unwrap_or_else(ApiResponse::from)
+    }
+
+    /// The paths of [`ROUTES`] this configuration serves
+    fn routes(&self) -> impl Iterator<Item = (&'static str, &'static [(&'static str, Operation)])> + '_ {
+        ROUTES.iter().copied().filter(|(_, methods)| {
+            self.config.relying_party.is_some() || !methods.iter().any(|(_, operation)| operation.needs_relying_party())
+        })
+    }
+
+    /// Parses the request for its operation, the bearer token is checked before the body is read
+    async fn route(&self, request: &ApiRequest<'_>) -> Result<ApiResponse, ApiError> {
+        let (_, methods) = self.routes().find(|(path, _)| *path == request.path).ok_or(ApiError::NotFound)?;
+        let (_, operation) = methods.iter().find(|(method, _)| *method == request.method).ok_or(ApiError::MethodNotAllowed)?;
+        let caller = Caller { authorization: request.authorization.map(str::to_string), remote_addr: request.remote_addr };
+        let fresh = Some(MAX_AUTHENTICATION_AGE);
+        match operation {
+            Operation::Spec => self.spec(query(request)?).await,
+            Operation::Register => self.register(&caller, json_body(request)?).await,
+            Operation::Login => self.login(&caller, json_body(request)?).await,
+            Operation::LoginSecondFactor => self.login_second_factor(&caller, json_body(request)?).await,
+            Operation::EnrollTotp => self.enroll_totp(self.authenticated_user(&caller, fresh).await?).await,
+            Operation::ConfirmTotp => {
+                let authentication = self.authenticated_user(&caller, fresh).await?;
+                self.confirm_totp(&caller, authentication, json_body(request)?).await
+            }
+            Operation::PasskeyRegistrationOptions => {
+                self.passkey_registration_options(self.authenticated_user(&caller, fresh).await?).await
+            }
+            Operation::RegisterPasskey => {
+                let authentication = self.authenticated_user(&caller, fresh).await?;
+                self.register_passkey(&caller, authentication, json_body(request)?).await
+            }
+            Operation::PasskeyLoginOptions => self.passkey_login_options(json_body(request)?).await,
+            Operation::LoginWithPasskey => self.login_with_passkey(&caller, json_body(request)?).await,
+            Operation::ListKeys => self.list_keys(self.authenticated_user(&caller, None).await?).await,
+            Operation::RotateKey => self.rotate_key(&caller, json_body(request)?).await,
+            Operation::EnrollKey => {
+                let authentication = self.authenticated_user(&caller, fresh).await?;
+                self.enroll_key(&caller, authentication, json_body(request)?).await
+            }
+            Operation::RevokeKey => {
+                let authentication = self.authenticated_user(&caller, fresh).await?;
+                self.revoke_key(&caller, authentication, json_body(request)?).await
+            }
+            Operation::Authorize => {
+                let authorization = query(request)?;
+                self.authorize(self.authenticated_user(&caller, None).await?, authorization).await
+            }
+            Operation::Token => self.token(&caller, form_body(request)?).await,
+        }
+    }
+
+    async fn spec(&self, query: SpecQuery) -> Result<ApiResponse, ApiError> {
+        let spec = SpecService::<S>::spec(&self.config, query.client_id, self.state.clone()).await?;
+        Ok(ApiResponse::json(200, &spec))
+    }
+
+    async fn register(&self, caller: &Caller, register: UserRegisterRequest<UserMetadataOf<S>>) -> Result<ApiResponse, ApiError> {
+        let register = UserRegisterRequest { client_ip: caller.remote_addr, ..register };
+        let user = UserRegistration::<S>::register(&self.config, register, self.state.clone())
+            .await
+            .map_err(registration_error)?;
+        let response = RegisterResponse {
+            id: user.get_id().as_hex(),
+            email: user.get_email().to_string(),
+            metadata: user.get_user_metadata(),
+        };
+        Ok(ApiResponse::json(201, &response))
+    }
+
+    /// Users with a second factor get an `mfa_token` instead of the tokens, to exchange at `/login/mfa`
+    async fn login(&self, caller: &Caller, login: UserLoginRequest) -> Result<ApiResponse, ApiError> {
+        let login = UserLoginRequest { client_ip: caller.remote_addr, ..login };
+        let response = UserAuthentication::<S>::login(&self.config, login, self.state.clone())
+            .await
+            .map_err(login_error)?;
+        let response = match response {
+            UserLoginOutcome::Authenticated(response) => ApiResponse::json(200, &LoginResponse::from(response)),
+            UserLoginOutcome::SecondFactorRequired { mfa_token } => ApiResponse::json(200, &SecondFactorRequiredResponse { mfa_token }),
+        };
+        Ok(response.header("cache-control", "no-store"))
+    }
+
+    /// The tokens of a login started at `/login`, once the code of its second factor is accepted
+    async fn login_second_factor(&self, caller: &Caller, second_factor: SecondFactorRequest) -> Result<ApiResponse, ApiError> {
+        let second_factor = SecondFactorRequest { client_ip: caller.remote_addr, ..second_factor };
+        let response = UserAuthentication::<S>::verify_second_factor(&self.config, second_factor, self.state.clone())
+            .await
+            .map_err(login_error)?;
+        Ok(ApiResponse::json(200, &LoginResponse::from(response)).header("cache-control", "no-store"))
+    }
+
+    async fn enroll_totp(&self, authentication: AuthenticationContext) -> Result<ApiResponse, ApiError> {
+        let enrollment = mfa::enroll_totp::<S>(self.state.clone(), authentication.user_id, &self.config.issuer)
+            .await
+            .map_err(mfa_error)?;
+        let response = TotpEnrollmentResponse { secret: enrollment.secret, uri: enrollment.uri };
+        Ok(ApiResponse::json(200, &response).header("cache-control", "no-store"))
+    }
+
+    async fn confirm_totp(
+        &self,
+        caller: &Caller,
+        authentication: AuthenticationContext,
+        confirmation: TotpConfirmationRequest,
+    ) -> Result<ApiResponse, ApiError> {
+        let result = mfa::confirm_totp::<S>(self.state.clone(), authentication.user_id, &confirmation.code, SystemTime::now())
+            .await
+            .map_err(mfa_error);
+        self.report_key_change(caller, &authentication, "totp", &result).await?;
+        let recovery_codes = result?;
+        Ok(ApiResponse::json(200, &TotpConfirmationResponse { recovery_codes }).header("cache-control", "no-store"))
+    }
+
+    fn relying_party(&self) -> Result<&RelyingParty, ApiError> {
+        self.config.relying_party.as_ref().ok_or(ApiError::NotFound)
+    }
+
+    async fn passkey_registration_options(&self, authentication: AuthenticationContext) -> Result<ApiResponse, ApiError> {
+        let relying_party = self.relying_party()?;
+        let options = webauthn::start_registration::<S>(self.state.clone(), relying_party, authentication.user_id, SystemTime::now())
+            .await
+            .map_err(webauthn_error)?;
+        Ok(ApiResponse::json(200, &options).header("cache-control", "no-store"))
+    }
+
+    async fn register_passkey(
+        &self,
+        caller: &Caller,
+        authentication: AuthenticationContext,
+        credential: RegistrationCredential,
+    ) -> Result<ApiResponse, ApiError> {
+        let relying_party = self.relying_party()?;
+        let result = webauthn::finish_registration::<S>(self.state.clone(), relying_party, authentication.user_id, &credential, SystemTime::now())
+            .await
+            .map_err(webauthn_error);
+        self.report_key_change(caller, &authentication, &credential.id, &result).await?;
+        result?;
+        Ok(ApiResponse::json(201, &PasskeyRegistrationResponse { id: credential.id }))
+    }
+
+    async fn passkey_login_options(&self, options: PasskeyOptionsRequest) -> Result<ApiResponse, ApiError> {
+        let relying_party = self.relying_party()?;
+        let options = webauthn::start_authentication::<S>(self.state.clone(), relying_party, options.client_id, &options.email, SystemTime::now())
+            .await
+            .map_err(webauthn_error)?;
+        Ok(ApiResponse::json(200, &options).header("cache-control", "no-store"))
+    }
+
+    async fn login_with_passkey(&self, caller: &Caller, login: PasskeyLoginRequest) -> Result<ApiResponse, ApiError> {
+        self.relying_party()?;
+        let login = PasskeyLoginRequest { client_ip: caller.remote_addr, ..login };
+        let response = UserAuthentication::<S>::login_with_passkey(&self.config, login, self.state.clone())
+            .await
+            .map_err(login_error)?;
+        let response = match response {
+            UserLoginOutcome::Authenticated(response) => ApiResponse::json(200, &LoginResponse::from(response)),
+            UserLoginOutcome::SecondFactorRequired { mfa_token } => ApiResponse::json(200, &SecondFactorRequiredResponse { mfa_token }),
+        };
+        Ok(response.header("cache-control", "no-store"))
+    }
+
+    async fn list_keys(&self, authentication: AuthenticationContext) -> Result<ApiResponse, ApiError> {
+        let keys = user_keys::list_keys::<S>(self.state.clone(), authentication.user_id)
+            .await
+            .map_err(|error| user_key_error(error.into()))?;
+        let response = UserKeysResponse { keys: keys.into_iter().map(UserKeyResponse::from).collect() };
+        Ok(ApiResponse::json(200, &response))
+    }
+
+    /// `POST /keys` takes no bearer token, the request carries proofs from a key of the user and from the
+    /// new key instead
+    async fn rotate_key(&self, caller: &Caller, rotation: KeyRotationRequest) -> Result<ApiResponse, ApiError> {
+        let rotation = KeyRotationRequest { client_ip: caller.remote_addr, ..rotation };
+        let key = UserAuthentication::<S>::rotate_key(&self.config, rotation, self.state.clone())
+            .await
+            .map_err(login_error)?;
+        Ok(ApiResponse::json(201, &UserKeyResponse::from(key)))
+    }
+
+    /// Gives its first key to an account without any, created before the keys were recorded, after a
+    /// passkey login
+    async fn enroll_key(&self, caller: &Caller, authentication: AuthenticationContext, key: NewUserKey) -> Result<ApiResponse, ApiError> {
+        let user = S::get_user(self.state.clone(), authentication.user_id)
+            .await
+            .map_err(|error| user_key_error(UserKeyError::Store(error.into())))?;
+        let payload = UserLoginPayload { client_id: user.get_client_id(), email: user.get_email().to_string() };
+        let result = user_keys::enroll_key::<S>(self.state.clone(), authentication.user_id, &key, &payload, SystemTime::now())
+            .await
+            .map_err(user_key_error);
+        self.report_key_change(caller, &authentication, &user_keys::key_id(&key.proof.public_key()), &result).await?;
+        Ok(ApiResponse::json(201, &UserKeyResponse::from(result?)))
+    }
+
+    async fn revoke_key(
+        &self,
+        caller: &Caller,
+        authentication: AuthenticationContext,
+        revocation: KeyRevocationRequest,
+    ) -> Result<ApiResponse, ApiError> {
+        let result = user_keys::revoke_key::<S>(self.state.clone(), authentication.user_id, &revocation.id, SystemTime::now())
+            .await
+            .map_err(user_key_error);
+        self.report_key_change(caller, &authentication, &revocation.id, &result).await?;
+        Ok(ApiResponse::json(200, &UserKeyResponse::from(result?)))
+    }
+
+    /// The user of the `Bearer` access token of a first party login, verified with the key of the client it
+    /// was issued through. ID tokens and the access tokens of `/oauth/token` are handed to clients and
+    /// aren't accepted. Credential changes pass a `max_age` the login must not be older than, older ones
+    /// are answered with an RFC 9470 `insufficient_user_authentication` challenge
+    async fn authenticated_user(&self, caller: &Caller, max_age: Option<Duration>) -> Result<AuthenticationContext, ApiError> {
+        let invalid = || ApiError::OAuth(OAuthError::InvalidToken("a valid access token of the user is required".to_string()));
+        let encoded = caller.authorization.as_deref().and_then(|header| header.strip_prefix("Bearer ")).ok_or_else(invalid)?;
+        let token = UserToken::decode(encoded).ok_or_else(invalid)?;
+        let payload = token.payload();
+        // NOTE: Tokens of a disabled client, or of a client below a disabled one, are refused like unknown ones
+        let lineage = tenant::lineage::<S>(self.state.clone(), payload.client_id).await.map_err(|_| invalid())?;
+        tenant::check_enabled(&lineage).map_err(|_| invalid())?;
+        let signing_key = tenant::signing_key_of::<S>(self.state.clone(), &lineage).await.map_err(|_| invalid())?;
+        let now = unix_seconds(SystemTime::now());
+        if !TokenVerifier::verify(signing_key.verifying_key(), &token) || payload.expires_at <= now {
+            return Err(invalid());
+        }
+        // NOTE: A client only vouches for its own users
+        let user = S::get_user(self.state.clone(), payload.user_id).await.map_err(|_| invalid())?;
+        if user.get_client_id() != payload.client_id {
+            return Err(invalid());
+        }
+        if let Some(max_age) = max_age.filter(|max_age| payload.auth_time.saturating_add(max_age.as_secs()) < now) {
+            return Err(ApiError::StaleAuthentication { max_age });
+        }
+        Ok(AuthenticationContext { user_id: payload.user_id, auth_time: payload.auth_time, amr: payload.amr.clone() })
+    }
+
+    /// Reports the enrollment of a second factor or passkey or the revocation of a key, `subject` names the key
+    async fn report_key_change<T>(
+        &self,
+        caller: &Caller,
+        authentication: &AuthenticationContext,
+        subject: &str,
+        result: &Result<T, ApiError>,
+    ) -> Result<(), ApiError> {
+        let event = audit::AuditEvent {
+            actor: Some(authentication.user_id),
+            subject: Some(subject.to_string()),
+            request: audit::RequestMetadata { ip: caller.remote_addr },
+            ..audit::AuditEvent::new(audit::AuditAction::KeyChange, SystemTime::now())
+        };
+        audit::report(self.config.audit.as_deref(), event, result, false)
+            .await
+            .map_err(|_| ApiError::Internal("failed to record audit event".to_string()))
+    }
+
+    async fn authorize(&self, authentication: AuthenticationContext, authorization: AuthorizationRequest) -> Result<ApiResponse, ApiError> {
+        let response = AuthorizationService::<S>::authorize(&self.config, authorization, authentication, self.state.clone()).await?;
+        Ok(ApiResponse::json(200, &response).header("cache-control", "no-store"))
+    }
+
+    async fn token(&self, caller: &Caller, token: TokenRequest) -> Result<ApiResponse, ApiError> {
+        let token = TokenRequest { client_ip: caller.remote_addr, ..token };
+        let authorization = caller.authorization.as_deref();
+        match AuthorizationService::<S>::token(&self.config, token, authorization, self.state.clone()).await {
+            Ok(response) => Ok(ApiResponse::json(200, &response).header("cache-control", "no-store")),
+            // RFC 6749 section 5.2, a failed `Basic` authentication is answered with a challenge
+            Err(error @ OAuthError::InvalidClient(_)) if authorization.is_some() => {
+                Ok(ApiResponse::from(ApiError::OAuth(error)).header("www-authenticate", "Basic"))
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "in-memory"))]
+mod tests {
+    use std::time::Duration;
+
+    use elliptic_curve::sec1::ToEncodedPoint;
+    use elliptic_curve::Field;
+    use p256::ecdsa::SigningKey;
+    use p256::{NistP256, ProjectivePoint, Scalar};
+    use serde_json::{json, Value};
+
+    use crate::client::UserKey;
+    use crate::crypto::jwt;
+    use crate::crypto::schnorr::Shnorr;
+    use crate::crypto::secret::Secret;
+    use crate::crypto::token::TokenSigner;
+    use crate::crypto::totp;
+    use crate::model::ClientSettings;
+    use crate::service::audit::{verify_log, AuditAction, AuditEvent, AuditLog, AuditOutcome};
+    use crate::service::oidc::{self, IdTokenClaims};
+    use crate::service::webauthn::fixtures;
+    use crate::service::UserLoginPayload;
+    use crate::store::memory::{InMemoryState, InMemoryStore};
+    use crate::store::{AuditStore, ClientUpdate, LockoutPolicy, NewClient, RateLimit};
+    use crate::telemetry::fixtures::TestRecorder;
+
+    use super::*;
+
+    const ISSUER: &str = "https://iam0.example.com";
+    const REDIRECT_URI: &str = "https://app.example.com/callback";
+    const VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    const CHALLENGE: &str = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+
+    struct Fixture {
+        api: Api<InMemoryStore>,
+        client_id: Identifier,
+    }
+
+    async fn fixture() -> Fixture {
+        let state = InMemoryState::new();
+        let settings = ClientSettings { redirect_uris: vec![REDIRECT_URI.to_string()], ..ClientSettings::default() };
+        let client = NewClient { parent_id: None, name: "app".to_string(), settings };
+        let client = InMemoryStore::create_client(state.clone(), client).await.unwrap();
+        state.seed_signing_key(client.id);
+        let config = ApiConfig {
+            issuer: ISSUER.to_string(),
+            token_endpoint: format!("{ISSUER}/oauth/token"),
+            relying_party: Some(fixtures::rp()),
+            login_limits: LoginLimits::default(),
+            audit: None,
+        };
+        Fixture { api: Api::new(config, state), client_id: client.id }
+    }
+
+    fn request<'a>(method: &'a str, path: &'a str) -> ApiRequest<'a> {
+        ApiRequest { method, path, query: None, content_type: None, authorization: None, body: b"", remote_addr: None }
+    }
+
+    fn json_request<'a>(path: &'a str, body: &'a [u8]) -> ApiRequest<'a> {
+        ApiRequest { content_type: Some("application/json; charset=utf-8"), body, ..request("POST", path) }
+    }
+
+    fn body(response: &ApiResponse) -> Value {
+        serde_json::from_slice(&response.body).unwrap()
+    }
+
+    /// `key` of a registration with the key of [`login_body`]
+    fn registration_key(client_id: Identifier, email: &str, private_key: &Secret<Scalar>) -> NewUserKey {
+        let key = UserKey::from_bytes(Curve::NistP256, &private_key.expose_secret().to_bytes()).unwrap();
+        key.registration_key(client_id, email, "laptop")
+    }
+
+    fn seed_user_with_key(state: &InMemoryState, client_id: Identifier, email: &str, private_key: &Secret<Scalar>) -> Identifier {
+        let user_id = state.seed_user(client_id, email).get_id();
+        let public_key: p256::AffinePoint = (ProjectivePoint::GENERATOR * private_key.expose_secret()).into();
+        state.seed_user_key(user_id, public_key.to_encoded_point(false).as_bytes());
+        user_id
+    }
+
+    fn login_body(client_id: Identifier, email: &str, private_key: &Secret<Scalar>) -> Vec<u8> {
+        let public_key: p256::AffinePoint = (ProjectivePoint::GENERATOR * private_key.expose_secret()).into();
+        let payload = UserLoginPayload { client_id, email: email.to_string() };
+        let (proof, commitment) = NistP256.proof(&Vec::from(&payload), private_key);
+        serde_json::to_vec(&json!({
+            "client_id": client_id.as_hex(),
+            "email": email,
+            "spec": "p256",
+            "commitment": hex::encode(commitment.to_encoded_point(false)),
+            "proof": hex::encode(proof.to_bytes()),
+            "public_key": hex::encode(public_key.to_encoded_point(false)),
+            "nonce": "n-0S6_WzA2Mj",
+        })).unwrap()
+    }
+
+    #[tokio::test]
+    async fn routing() {
+        let Fixture { api, .. } = fixture().await;
+        assert_eq!(api.handle(request("GET", "/missing")).await.status, 404);
+        assert_eq!(api.handle(request("DELETE", "/login")).await.status, 405);
+        let response = api.handle(ApiRequest { content_type: Some("text/plain"), ..request("POST", "/login") }).await;
+        assert_eq!(response.status, 415);
+        assert_eq!(body(&response)["error"], "unsupported_media_type");
+        let response = api.handle(json_request("/login", b"{")).await;
+        assert_eq!((response.status, body(&response)["error"].clone()), (400, json!("invalid_request")));
+
+        let response = api.handle(request("GET", "/api/v1/spec")).await;
+        assert_eq!(response.status, 200);
+        assert_eq!(body(&response)["finite_cyclic_groups"]["elliptic_curves"], json!(["p256"]));
+        let response = api.handle(ApiRequest { query: Some("client_id=zz"), ..request("GET", "/api/v1/spec") }).await;
+        assert_eq!(response.status, 400);
+        let query = format!("client_id={}", Identifier::from(42u128).as_hex());
+        let response = api.handle(ApiRequest { query: Some(&query), ..request("GET", "/api/v1/spec") }).await;
+        assert_eq!(response.status, 404);
+    }
+
+    #[tokio::test]
+    async fn register_login_and_code_flow() {
+        let Fixture { api, client_id } = fixture().await;
+
+        let private_key = Secret::new(Scalar::random(&mut rand::thread_rng()));
+        let key = registration_key(client_id, "alice@example.com", &private_key);
+        let register = serde_json::to_vec(&json!({ "client_id": client_id.as_hex(), "email": "alice@example.com", "metadata": { "name": "Alice" }, "key": key })).unwrap();
+        let response = api.handle(json_request("/register", &register)).await;
+        assert_eq!(response.status, 201, "{}", body(&response));
+        assert_eq!(body(&response)["email"], "alice@example.com");
+        assert_eq!(body(&response)["metadata"], json!({ "name": "Alice" }));
+        let response = api.handle(json_request("/register", &register)).await;
+        assert_eq!((response.status, body(&response)["error"].clone()), (409, json!("conflict")));
+
+        let login = login_body(client_id, "alice@example.com", &private_key);
+        let response = api.handle(json_request("/login", &login)).await;
+        assert_eq!(response.status, 200, "{}", body(&response));
+        let access_token = body(&response)["access_token"].as_str().unwrap().to_string();
+        let response = api.handle(json_request("/login", &login_body(client_id, "bob@example.com", &private_key))).await;
+        assert_eq!((response.status, body(&response)["error"].clone()), (401, json!("invalid_credentials")));
+
+        let query = format!(
+            "client_id={}&redirect_uri={}&response_type=code&scope=openid+email&state=xyz&code_challenge={CHALLENGE}&code_challenge_method=S256",
+            client_id.as_hex(),
+            "https%3A%2F%2Fapp.example.com%2Fcallback",
+        );
+        let authorize = ApiRequest { query: Some(&query), ..request("GET", "/authorize") };
+        let response = api.handle(authorize).await;
+        assert_eq!(response.status, 401);
+        assert!(response.headers.iter().any(|(name, _)| *name == "www-authenticate"));
+        let bearer = format!("Bearer {access_token}");
+        let response = api.handle(ApiRequest { authorization: Some(&bearer), ..authorize }).await;
+        assert_eq!(response.status, 200, "{}", body(&response));
+        assert_eq!(body(&response)["state"], "xyz");
+        let code = body(&response)["code"].as_str().unwrap().to_string();
+
+        let form = format!(
+            "grant_type=authorization_code&client_id={}&code={code}&redirect_uri=https%3A%2F%2Fapp.example.com%2Fcallback&code_verifier={VERIFIER}",
+            client_id.as_hex(),
+        );
+        let token = ApiRequest { content_type: Some(FORM), body: form.as_bytes(), ..request("POST", "/oauth/token") };
+        let response = api.handle(ApiRequest { content_type: Some(JSON), ..token }).await;
+        assert_eq!(response.status, 415);
+        let response = api.handle(token).await;
+        assert_eq!(response.status, 200, "{}", body(&response));
+        assert!(response.headers.contains(&("cache-control", "no-store".to_string())));
+        assert_eq!(body(&response)["token_type"], "Bearer");
+        assert_eq!(body(&response)["scope"], "openid email");
+        assert!(body(&response)["id_token"].is_string());
+
+        let response = api.handle(token).await;
+        assert_eq!((response.status, body(&response)["error"].clone()), (400, json!("invalid_grant")));
+    }
+
+    #[tokio::test]
+    async fn bearer_tokens() {
+        let Fixture { api, client_id } = fixture().await;
+        let private_key = Secret::new(Scalar::random(&mut rand::thread_rng()));
+        let user_id = seed_user_with_key(&api.state, client_id, "alice@example.com", &private_key);
+        let response = api.handle(json_request("/login", &login_body(client_id, "alice@example.com", &private_key))).await;
+        let api = &api;
+        let with_bearer = |token: &str, method, path| (format!("Bearer {token}"), method, path);
+        let call = |(bearer, method, path): (String, &'static str, &'static str)| async move {
+            api.handle(ApiRequest { authorization: Some(&bearer), ..request(method, path) }).await
+        };
+
+        // ID tokens are meant for the client and don't authenticate to the API
+        let id_token = body(&response)["id_token"].as_str().unwrap().to_string();
+        let response = call(with_bearer(&id_token, "GET", "/keys")).await;
+        assert_eq!((response.status, body(&response)["error"].clone()), (401, json!("invalid_token")));
+
+        let signing_key = InMemoryStore::get_signing_key(api.state.clone(), client_id).await.unwrap();
+        let now = unix_seconds(SystemTime::now());
+        let sign = |key: &SigningKey, auth_time: u64, expires_at: u64| {
+            let payload = UserTokenPayload { user_id, client_id, auth_time, amr: vec![oidc::AMR_SCHNORR.to_string()], expires_at };
+            let token: UserToken = TokenSigner::sign(key, payload);
+            token.encode()
+        };
+        let forged = sign(&SigningKey::random(&mut rand::thread_rng()), now, now + 60);
+        assert_eq!(call(with_bearer(&forged, "GET", "/keys")).await.status, 401);
+        assert_eq!(call(with_bearer(&sign(&signing_key, now - 120, now - 60), "GET", "/keys")).await.status, 401);
+
+        // An old login still reads the keys but has to be renewed to change the credentials
+        let stale = sign(&signing_key, now - MAX_AUTHENTICATION_AGE.as_secs() - 1, now + 60);
+        assert_eq!(call(with_bearer(&stale, "GET", "/keys")).await.status, 200);
+        for path in ["/mfa/totp", "/mfa/totp/confirm", "/passkeys/register/options", "/passkeys/register", "/keys/enroll", "/keys/revoke"] {
+            let response = call(with_bearer(&stale, "POST", path)).await;
+            assert_eq!((response.status, body(&response)["error"].clone()), (401, json!("insufficient_user_authentication")), "{path}");
+            let challenge = "Bearer error=\"insufficient_user_authentication\", max_age=300".to_string();
+            assert!(response.headers.contains(&("www-authenticate", challenge)), "{:?}", response.headers);
+        }
+        assert_eq!(call(with_bearer(&sign(&signing_key, now, now + 60), "POST", "/mfa/totp")).await.status, 200);
+    }
+
+    #[tokio::test]
+    async fn disabled_clients() {
+        let Fixture { api, client_id: parent_id } = fixture().await;
+        let child = NewClient { parent_id: Some(parent_id), name: "child".to_string(), settings: ClientSettings::default() };
+        let client_id = InMemoryStore::create_client(api.state.clone(), child).await.unwrap().id;
+        let private_key = Secret::new(Scalar::random(&mut rand::thread_rng()));
+        seed_user_with_key(&api.state, client_id, "alice@example.com", &private_key);
+        let response = api.handle(json_request("/login", &login_body(client_id, "alice@example.com", &private_key))).await;
+        assert_eq!(response.status, 200, "{}", body(&response));
+        let bearer = format!("Bearer {}", body(&response)["access_token"].as_str().unwrap());
+        let keys = ApiRequest { authorization: Some(&bearer), ..request("GET", "/keys") };
+        assert_eq!(api.handle(keys).await.status, 200);
+
+        // Access tokens issued before the parent was disabled stop working with it
+        let update = ClientUpdate { disabled: Some(true), ..ClientUpdate::default() };
+        InMemoryStore::update_client(api.state.clone(), parent_id, update).await.unwrap();
+        let response = api.handle(keys).await;
+        assert_eq!((response.status, body(&response)["error"].clone()), (401, json!("invalid_token")));
+    }
+
+    #[tokio::test]
+    async fn second_factor_login() {
+        let Fixture { api, client_id } = fixture().await;
+        let private_key = Secret::new(Scalar::random(&mut rand::thread_rng()));
+        let key = registration_key(client_id, "alice@example.com", &private_key);
+        let register = serde_json::to_vec(&json!({ "client_id": client_id.as_hex(), "email": "alice@example.com", "key": key })).unwrap();
+        assert_eq!(api.handle(json_request("/register", &register)).await.status, 201);
+        let login = login_body(client_id, "alice@example.com", &private_key);
+        let response = api.handle(json_request("/login", &login)).await;
+        let bearer = format!("Bearer {}", body(&response)["access_token"].as_str().unwrap());
+
+        assert_eq!(api.handle(request("POST", "/mfa/totp")).await.status, 401);
+        let response = api.handle(ApiRequest { authorization: Some(&bearer), ..request("POST", "/mfa/totp") }).await;
+        assert_eq!(response.status, 200, "{}", body(&response));
+        assert!(body(&response)["uri"].as_str().unwrap().starts_with("otpauth://totp/"));
+        let secret = totp::base32_decode(body(&response)["secret"].as_str().unwrap()).unwrap();
+        let code = totp::hotp(&secret, totp::time_step(SystemTime::now()));
+        let confirm = serde_json::to_vec(&json!({ "code": code })).unwrap();
+        let response = api.handle(ApiRequest { authorization: Some(&bearer), ..json_request("/mfa/totp/confirm", &confirm) }).await;
+        assert_eq!(response.status, 200, "{}", body(&response));
+        let recovery_code = body(&response)["recovery_codes"][0].as_str().unwrap().to_string();
+        let response = api.handle(ApiRequest { authorization: Some(&bearer), ..json_request("/mfa/totp/confirm", &confirm) }).await;
+        assert_eq!(response.status, 409);
+
+        let response = api.handle(json_request("/login", &login)).await;
+        assert_eq!(response.status, 200, "{}", body(&response));
+        assert!(body(&response)["id_token"].is_null());
+        let mfa_token = body(&response)["mfa_token"].as_str().unwrap().to_string();
+        let second_factor = serde_json::to_vec(&json!({ "mfa_token": mfa_token, "code": "AAAA-AAAA-AAAA-AAAA" })).unwrap();
+        let response = api.handle(json_request("/login/mfa", &second_factor)).await;
+        assert_eq!((response.status, body(&response)["error"].clone()), (401, json!("invalid_credentials")));
+        let second_factor = serde_json::to_vec(&json!({ "mfa_token": mfa_token, "code": recovery_code })).unwrap();
+        let response = api.handle(json_request("/login/mfa", &second_factor)).await;
+        assert_eq!(response.status, 200, "{}", body(&response));
+        assert!(response.headers.contains(&("cache-control", "no-store".to_string())));
+        let id_token = body(&response)["id_token"].as_str().unwrap().to_string();
+        let claims: IdTokenClaims = jwt::decode_unverified(&id_token).unwrap();
+        assert_eq!(claims.amr, [oidc::AMR_SCHNORR, oidc::AMR_OTP, oidc::AMR_MFA]);
+    }
+
+    #[tokio::test]
+    async fn passkeys() {
+        let Fixture { api, client_id } = fixture().await;
+        let private_key = Secret::new(Scalar::random(&mut rand::thread_rng()));
+        let key = registration_key(client_id, "alice@example.com", &private_key);
+        let register = serde_json::to_vec(&json!({ "client_id": client_id.as_hex(), "email": "alice@example.com", "key": key })).unwrap();
+        let response = api.handle(json_request("/register", &register)).await;
+        let user_id = Identifier::from_hex(body(&response)["id"].as_str().unwrap()).unwrap();
+        let response = api.handle(json_request("/login", &login_body(client_id, "alice@example.com", &private_key))).await;
+        let bearer = format!("Bearer {}", body(&response)["access_token"].as_str().unwrap());
+        let seed_challenge = |fixture| InMemoryStore::insert_token(api.state.clone(), fixtures::challenge_token(fixture, user_id, client_id, SystemTime::now()));
+
+        assert_eq!(api.handle(request("POST", "/passkeys/register/options")).await.status, 401);
+        let response = api.handle(ApiRequest { authorization: Some(&bearer), ..request("POST", "/passkeys/register/options") }).await;
+        assert_eq!(response.status, 200, "{}", body(&response));
+        assert_eq!(body(&response)["rp"]["id"], "example.com");
+        assert_eq!(body(&response)["user"]["name"], "alice@example.com");
+        assert_eq!(body(&response)["pubKeyCredParams"], json!([{ "type": "public-key", "alg": -7 }]));
+
+        let registration = fixtures::NONE_REGISTRATION.as_bytes();
+        let response = api.handle(ApiRequest { authorization: Some(&bearer), ..json_request("/passkeys/register", registration) }).await;
+        assert_eq!(response.status, 400, "{}", body(&response));
+        seed_challenge(fixtures::NONE_REGISTRATION).await.unwrap();
+        let response = api.handle(ApiRequest { authorization: Some(&bearer), ..json_request("/passkeys/register", registration) }).await;
+        assert_eq!(response.status, 201, "{}", body(&response));
+        assert_eq!(body(&response)["id"], "AQIDBAUGBwgJCgsMDQ4PEA");
+
+        let options = serde_json::to_vec(&json!({ "client_id": client_id.as_hex(), "email": "alice@example.com" })).unwrap();
+        let response = api.handle(json_request("/login/passkey/options", &options)).await;
+        assert_eq!(response.status, 200, "{}", body(&response));
+        assert_eq!(body(&response)["rpId"], "example.com");
+        assert_eq!(body(&response)["allowCredentials"], json!([{ "type": "public-key", "id": "AQIDBAUGBwgJCgsMDQ4PEA" }]));
+
+        let credential: Value = serde_json::from_str(fixtures::NONE_ASSERTION).unwrap();
+        let login = serde_json::to_vec(&json!({ "credential": credential, "nonce": "n-0S6_WzA2Mj" })).unwrap();
+        seed_challenge(fixtures::NONE_ASSERTION).await.unwrap();
+        let response = api.handle(json_request("/login/passkey", &login)).await;
+        assert_eq!(response.status, 200, "{}", body(&response));
+        let claims: IdTokenClaims = jwt::decode_unverified(body(&response)["id_token"].as_str().unwrap()).unwrap();
+        assert_eq!(claims.amr, [oidc::AMR_POP, oidc::AMR_USER, oidc::AMR_MFA]);
+        let response = api.handle(json_request("/login/passkey", &login)).await;
+        assert_eq!((response.status, body(&response)["error"].clone()), (401, json!("invalid_credentials")));
+
+        let config = ApiConfig { relying_party: None, ..api.config.clone() };
+        let api = Api::<InMemoryStore>::new(config, api.state.clone());
+        assert_eq!(api.handle(json_request("/login/passkey/options", &options)).await.status, 404);
+    }
+
+    #[tokio::test]
+    async fn user_keys() {
+        let Fixture { api, client_id } = fixture().await;
+        let (laptop, phone) = (UserKey::generate(Curve::NistP256), UserKey::generate(Curve::NistP256));
+        let email = "alice@example.com";
+        let login = |key: &UserKey| serde_json::to_vec(&key.prove(UserLoginPayload { client_id, email: email.to_string() }, None)).unwrap();
+        let register = json!({ "client_id": client_id.as_hex(), "email": email, "key": laptop.registration_key(client_id, email, "laptop") });
+        let response = api.handle(json_request("/register", &serde_json::to_vec(&register).unwrap())).await;
+        assert_eq!(response.status, 201, "{}", body(&response));
+
+        assert_eq!(api.handle(json_request("/login", &login(&phone))).await.status, 401);
+        let response = api.handle(json_request("/login", &login(&laptop))).await;
+        assert_eq!(response.status, 200, "{}", body(&response));
+        let bearer = format!("Bearer {}", body(&response)["access_token"].as_str().unwrap());
+
+        let rotation = serde_json::to_vec(&phone.rotation_request(&laptop, client_id, email, "laptop")).unwrap();
+        let response = api.handle(json_request("/keys", &rotation)).await;
+        assert_eq!((response.status, body(&response)["error"].clone()), (401, json!("invalid_credentials")));
+        let rotation = serde_json::to_vec(&laptop.rotation_request(&phone, client_id, email, "phone")).unwrap();
+        let response = api.handle(json_request("/keys", &rotation)).await;
+        assert_eq!(response.status, 201, "{}", body(&response));
+        assert_eq!(body(&response)["label"], "phone");
+        let phone_id = body(&response)["id"].clone();
+        assert_eq!(api.handle(json_request("/keys", &rotation)).await.status, 409);
+        assert_eq!(api.handle(json_request("/login", &login(&phone))).await.status, 200);
+
+        assert_eq!(api.handle(request("GET", "/keys")).await.status, 401);
+        let response = api.handle(ApiRequest { authorization: Some(&bearer), ..request("GET", "/keys") }).await;
+        assert_eq!(response.status, 200, "{}", body(&response));
+        let keys = body(&response)["keys"].as_array().unwrap().clone();
+        let labels: Vec<_> = keys.iter().map(|key| key["label"].as_str().unwrap()).collect();
+        assert_eq!(labels, ["laptop", "phone"]);
+        assert!(keys.iter().all(|key| key["curve"] == "p256" && key["last_used_at"].is_u64() && key["revoked_at"].is_null()));
+
+        let revoke = |id: &Value| serde_json::to_vec(&json!({ "id": id })).unwrap();
+        let response = api.handle(ApiRequest { authorization: Some(&bearer), ..json_request("/keys/revoke", &revoke(&keys[0]["id"])) }).await;
+        assert_eq!(response.status, 200, "{}", body(&response));
+        assert!(body(&response)["revoked_at"].is_u64());
+        assert_eq!(api.handle(json_request("/login", &login(&laptop))).await.status, 401);
+        let response = api.handle(ApiRequest { authorization: Some(&bearer), ..json_request("/keys/revoke", &revoke(&phone_id)) }).await;
+        assert_eq!(response.status, 409, "{}", body(&response));
+        let response = api.handle(ApiRequest { authorization: Some(&bearer), ..json_request("/keys/revoke", &revoke(&json!("missing"))) }).await;
+        assert_eq!(response.status, 400, "{}", body(&response));
+    }
+
+    #[tokio::test]
+    async fn key_enrollment() {
+        let Fixture { api, client_id } = fixture().await;
+        let email = "alice@example.com";
+        let laptop = UserKey::generate(Curve::NistP256);
+        let register = serde_json::to_vec(&json!({ "client_id": client_id.as_hex(), "email": email })).unwrap();
+        let response = api.handle(json_request("/register", &register)).await;
+        assert_eq!((response.status, body(&response)["error"].clone()), (400, json!("invalid_request")));
+
+        // Accounts from before the keys were recorded can't log in with a proof, a passkey login enrolls their first key
+        let user_id = api.state.seed_user(client_id, email).get_id();
+        let login = serde_json::to_vec(&laptop.prove(UserLoginPayload { client_id, email: email.to_string() }, None)).unwrap();
+        assert_eq!(api.handle(json_request("/login", &login)).await.status, 401);
+        let signing_key = InMemoryStore::get_signing_key(api.state.clone(), client_id).await.unwrap();
+        let now = unix_seconds(SystemTime::now());
+        let payload = UserTokenPayload { user_id, client_id, auth_time: now, amr: vec![oidc::AMR_POP.to_string()], expires_at: now + 60 };
+        let token: UserToken = TokenSigner::sign(&signing_key, payload);
+        let bearer = format!("Bearer {}", token.encode());
+        let enroll = |key: &UserKey, email: &str| serde_json::to_vec(&key.registration_key(client_id, email, "laptop")).unwrap();
+
+        assert_eq!(api.handle(json_request("/keys/enroll", &enroll(&laptop, email))).await.status, 401);
+        let response = api.handle(ApiRequest { authorization: Some(&bearer), ..json_request("/keys/enroll", &enroll(&laptop, "bob@example.com")) }).await;
+        assert_eq!(response.status, 400, "{}", body(&response));
+        let response = api.handle(ApiRequest { authorization: Some(&bearer), ..json_request("/keys/enroll", &enroll(&laptop, email)) }).await;
+        assert_eq!(response.status, 201, "{}", body(&response));
+        assert_eq!(body(&response)["label"], "laptop");
+        assert_eq!(api.handle(json_request("/login", &login)).await.status, 200);
+
+        // The next keys need a proof of one the user holds
+        let phone = UserKey::generate(Curve::NistP256);
+        let response = api.handle(ApiRequest { authorization: Some(&bearer), ..json_request("/keys/enroll", &enroll(&phone, email)) }).await;
+        assert_eq!((response.status, body(&response)["error"].clone()), (409, json!("conflict")));
+    }
+
+    #[tokio::test]
+    async fn login_rate_limits() {
+        let Fixture { mut api, client_id } = fixture().await;
+        api.config.login_limits = LoginLimits {
+            per_ip: RateLimit { burst: 1, interval: Duration::from_secs(3600) },
+            lockout: LockoutPolicy { max_failures: 1, base: Duration::from_secs(60), ..LoginLimits::default().lockout },
+            ..LoginLimits::default()
+        };
+        let private_key = Secret::new(Scalar::random(&mut rand::thread_rng()));
+        seed_user_with_key(&api.state, client_id, "alice@example.com", &private_key);
+
+        // Unknown emails are locked like registered ones
+        let login = login_body(client_id, "bob@example.com", &private_key);
+        for _ in 0..2 {
+            let response = api.handle(json_request("/login", &login)).await;
+            assert_eq!((response.status, body(&response)["error"].clone()), (401, json!("invalid_credentials")));
+        }
+        let response = api.handle(json_request("/login", &login)).await;
+        assert_eq!((response.status, body(&response)["error"].clone()), (429, json!("too_many_requests")));
+        assert!(response.headers.contains(&("retry-after", "60".to_string())), "{:?}", response.headers);
+
+        let login = login_body(client_id, "alice@example.com", &private_key);
+        let from = |ip: &str| ApiRequest { remote_addr: Some(ip.parse().unwrap()), ..json_request("/login", &login) };
+        assert_eq!(api.handle(from("192.0.2.1")).await.status, 200);
+        let response = api.handle(from("192.0.2.1")).await;
+        assert_eq!(response.status, 429);
+        assert!(response.headers.contains(&("retry-after", "3600".to_string())), "{:?}", response.headers);
+        assert_eq!(api.handle(from("2001:db8::1")).await.status, 200);
+    }
+
+    #[tokio::test]
+    async fn audit_events() {
+        let Fixture { mut api, client_id } = fixture().await;
+        let log = AuditLog::<InMemoryStore>::new(api.state.clone(), SigningKey::random(&mut rand::thread_rng()));
+        let verifying_key = log.verifying_key();
+        api.config.audit = Some(Arc::new(log));
+
+        let private_key = Secret::new(Scalar::random(&mut rand::thread_rng()));
+        let key = registration_key(client_id, "alice@example.com", &private_key);
+        let register = serde_json::to_vec(&json!({ "client_id": client_id.as_hex(), "email": "alice@example.com", "key": key })).unwrap();
+        let response = api.handle(ApiRequest { remote_addr: Some("192.0.2.1".parse().unwrap()), ..json_request("/register", &register) }).await;
+        assert_eq!(response.status, 201);
+        let user_id = Identifier::from_hex(body(&response)["id"].as_str().unwrap()).unwrap();
+        assert_eq!(api.handle(json_request("/login", &login_body(client_id, "alice@example.com", &private_key))).await.status, 200);
+        assert_eq!(api.handle(json_request("/login", &login_body(client_id, "bob@example.com", &private_key))).await.status, 401);
+
+        verify_log::<InMemoryStore>(api.state.clone(), verifying_key, None).await.unwrap();
+        let records = InMemoryStore::list_audit_records(api.state.clone(), 0, 10).await.unwrap();
+        let events: Vec<_> = records.iter().map(|record| AuditEvent::decode(record).unwrap()).collect();
+        let summary: Vec<_> = events.iter().map(|event| (event.action, event.outcome, event.actor, event.subject.as_deref())).collect();
+        assert_eq!(summary, vec![
+            (AuditAction::Registration, AuditOutcome::Success, Some(user_id), Some("alice@example.com")),
+            (AuditAction::Login, AuditOutcome::Success, Some(user_id), Some("alice@example.com")),
+            (AuditAction::Login, AuditOutcome::Failure, None, Some("bob@example.com")),
+        ]);
+        assert_eq!(events[0].request.ip, Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(events[2].reason.as_deref(), Some("user not found"));
+        assert!(events.iter().all(|event| event.client_id == Some(client_id)));
+    }
+
+    #[tokio::test]
+    async fn auth_event_metrics() {
+        let Fixture { api, client_id } = fixture().await;
+        let recorder = TestRecorder::default();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let private_key = Secret::new(Scalar::random(&mut rand::thread_rng()));
+        seed_user_with_key(&api.state, client_id, "alice@example.com", &private_key);
+
+        assert_eq!(api.handle(json_request("/login", &login_body(client_id, "alice@example.com", &private_key))).await.status, 200);
+        for _ in 0..2 {
+            assert_eq!(api.handle(json_request("/login", &login_body(client_id, "bob@example.com", &private_key))).await.status, 401);
+        }
+        let response = api.handle(ApiRequest { content_type: Some(FORM), body: b"grant_type=password", ..request("POST", "/oauth/token") }).await;
+        assert_eq!(response.status, 401);
+
+        assert_eq!(recorder.counter("iam0_auth_events_total{action=login,outcome=success,reason=}"), 1);
+        assert_eq!(recorder.counter("iam0_auth_events_total{action=login,outcome=failure,reason=user not found}"), 2);
+        assert_eq!(recorder.counter("iam0_auth_events_total{action=token_issued,outcome=failure,reason=invalid_client}"), 1);
+    }
+
+    #[tokio::test]
+    async fn token_errors() {
+        let Fixture { api, client_id } = fixture().await;
+        let token = |form: &'static str| ApiRequest { content_type: Some(FORM), body: form.as_bytes(), ..request("POST", "/oauth/token") };
+
+        let response = api.handle(token("client_id=zz&grant_type=authorization_code")).await;
+        assert_eq!((response.status, body(&response)["error"].clone()), (400, json!("invalid_request")));
+        let response = api.handle(token("grant_type=a&grant_type=b")).await;
+        assert_eq!(response.status, 400);
+
+        let form = format!("grant_type=password&client_id={}", client_id.as_hex());
+        let response = api.handle(ApiRequest { body: form.as_bytes(), ..token("") }).await;
+        assert_eq!((response.status, body(&response)["error"].clone()), (400, json!("unsupported_grant_type")));
+
+        let basic = "Basic bm90LWhleDpzZWNyZXQ=";
+        let response = api.handle(ApiRequest { authorization: Some(basic), ..token("grant_type=client_credentials") }).await;
+        assert_eq!((response.status, body(&response)["error"].clone()), (401, json!("invalid_client")));
+        assert!(response.headers.contains(&("www-authenticate", "Basic".to_string())));
+    }
+}
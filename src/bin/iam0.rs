@@ -0,0 +1,366 @@
+//! `iam0` command line tool, generates keys and login proofs and inspects the tokens and identifiers
+//! issued by the server. Keys are hex strings, the private keys are the raw scalars and the public keys
+//! uncompressed SEC1 points, and every structured output is JSON
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use aes_gcm::{Aes256Gcm, KeyInit};
+use anyhow::{anyhow, bail, Context};
+use elliptic_curve::sec1::ToEncodedPoint;
+use p256::ecdsa::{SigningKey, VerifyingKey};
+use p256::NistP256;
+use serde_json::json;
+
+use iam0_core::crypto::schnorr::Shnorr;
+use iam0_core::crypto::secret::{Secret, SecretBytes};
+use iam0_core::crypto::token::{Token, TokenCipher, TokenSigner, TokenVerifier};
+use iam0_core::data::id::{Identifier, IdentifierGenerator};
+use iam0_core::model::Curve;
+use iam0_core::service::{UserLoginPayload, UserLoginRequest, UserTokenPayload};
+
+const USAGE: &str = "\
+usage: iam0 <command> [options]
+
+commands:
+  keygen [--curve p256]                              new keypair for proofs and token signatures
+  cipher-key                                         new AES-256-GCM key for the token encryption
+  proof --private-key HEX --client-id ID --email EMAIL
+                                                     login request with a Schnorr proof of the key
+  verify-proof <JSON | ->                            checks the proof of a login request
+  token sign --private-key HEX --payload JSON        signs a {\"user_id\", \"client_id\", \"auth_time\",
+                                                     \"amr\", \"expires_at\"} access token payload
+  token verify --public-key HEX <TOKEN>              checks the signature and prints the payload
+  token decode <TOKEN>                               prints the payload without checking it
+  token encrypt --cipher-key HEX <TOKEN>
+  token decrypt --cipher-key HEX <ENCRYPTED>
+  id inspect <ID>                                    hex or base64 identifier
+  id generate [--service-id N] [--worker-id N]
+
+identifiers (ID) are hex or base64, `-` reads the argument from stdin";
+
+type AccessToken = Token<UserTokenPayload, NistP256>;
+
+/// Wrong command line, answered with the usage
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+struct Usage(String);
+
+fn usage(message: impl Into<String>) -> anyhow::Error {
+    Usage(message.into()).into()
+}
+
+/// Positional arguments and `--name value` or `--name=value` options
+struct Args {
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+}
+
+impl Args {
+    fn parse(args: &[String]) -> anyhow::Result<Self> {
+        let mut positional = Vec::new();
+        let mut options = HashMap::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let Some(name) = arg.strip_prefix("--") else {
+                positional.push(arg.clone());
+                continue;
+            };
+            let (name, value) = match name.split_once('=') {
+                Some((name, value)) => (name.to_string(), value.to_string()),
+                None => {
+                    let value = args.next().ok_or_else(|| usage(format!("--{name} needs a value")))?;
+                    (name.to_string(), value.clone())
+                }
+            };
+            if options.insert(name.clone(), value).is_some() {
+                return Err(usage(format!("--{name} is repeated")));
+            }
+        }
+        Ok(Self { positional, options })
+    }
+
+    fn option(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(String::as_str)
+    }
+
+    fn required(&self, name: &str) -> anyhow::Result<&str> {
+        self.option(name).ok_or_else(|| usage(format!("--{name} is required")))
+    }
+
+    /// The only positional argument after the command, `-` is read from stdin
+    fn input(&self, name: &str) -> anyhow::Result<String> {
+        match self.positional.as_slice() {
+            [value] if value == "-" => {
+                let mut input = String::new();
+                std::io::stdin().read_to_string(&mut input)?;
+                Ok(input.trim().to_string())
+            }
+            [value] => Ok(value.clone()),
+            _ => Err(usage(format!("expected one <{name}>"))),
+        }
+    }
+
+    fn no_positional(&self) -> anyhow::Result<()> {
+        match self.positional.first() {
+            Some(unexpected) => Err(usage(format!("unexpected argument {unexpected}"))),
+            None => Ok(()),
+        }
+    }
+}
+
+fn curve(args: &Args) -> anyhow::Result<Curve> {
+    let name = args.option("curve").unwrap_or("p256");
+    Curve::from_name(name).ok_or_else(|| usage(format!("unsupported curve {name}")))
+}
+
+fn identifier(value: &str) -> anyhow::Result<Identifier> {
+    let value = value.trim();
+    let id = if value.len() == 32 { Identifier::from_hex(value) } else { None };
+    id.or_else(|| Identifier::from_base64(value))
+        .or_else(|| Identifier::from_hex(value))
+        .ok_or_else(|| anyhow!("{value} isn't a hex or base64 identifier"))
+}
+
+fn signing_key(hex: &str) -> anyhow::Result<SigningKey> {
+    let bytes = Secret::new(hex::decode(hex).context("the private key isn't hex")?);
+    SigningKey::from_slice(bytes.expose_secret()).map_err(|_| anyhow!("invalid private key"))
+}
+
+fn verifying_key(hex: &str) -> anyhow::Result<VerifyingKey> {
+    let bytes = hex::decode(hex).context("the public key isn't hex")?;
+    VerifyingKey::from_sec1_bytes(&bytes).map_err(|_| anyhow!("invalid public key"))
+}
+
+fn cipher(args: &Args) -> anyhow::Result<Aes256Gcm> {
+    let key = SecretBytes::new(hex::decode(args.required("cipher-key")?).context("the cipher key isn't hex")?);
+    <Aes256Gcm as TokenCipher<NistP256>>::from_secret_key(&key).map_err(|_| anyhow!("the cipher key must be 32 bytes"))
+}
+
+fn decode_token(encoded: &str) -> anyhow::Result<AccessToken> {
+    Token::decode(encoded).ok_or_else(|| anyhow!("malformed token"))
+}
+
+fn payload_json(token: &AccessToken) -> String {
+    serde_json::to_string_pretty(token.payload()).unwrap()
+}
+
+fn keygen(args: &Args) -> anyhow::Result<String> {
+    args.no_positional()?;
+    let curve = curve(args)?;
+    let keypair = match curve {
+        Curve::NistP256 => {
+            let signing_key = SigningKey::random(&mut rand::thread_rng());
+            json!({
+                "curve": curve,
+                "private_key": hex::encode(signing_key.to_bytes()),
+                "public_key": hex::encode(signing_key.verifying_key().to_encoded_point(false)),
+            })
+        }
+    };
+    Ok(serde_json::to_string_pretty(&keypair)?)
+}
+
+fn cipher_key(args: &Args) -> anyhow::Result<String> {
+    args.no_positional()?;
+    Ok(hex::encode(Aes256Gcm::generate_key(&mut rand::thread_rng())))
+}
+
+fn proof(args: &Args) -> anyhow::Result<String> {
+    args.no_positional()?;
+    let payload = UserLoginPayload {
+        client_id: identifier(args.required("client-id")?)?,
+        email: args.required("email")?.to_string(),
+    };
+    let request = match curve(args)? {
+        Curve::NistP256 => {
+            let signing_key = signing_key(args.required("private-key")?)?;
+            let private_key = Secret::new(*signing_key.as_nonzero_scalar().as_ref());
+            let (proof, commitment) = NistP256.proof(&Vec::from(&payload), &private_key);
+            json!({
+                "client_id": payload.client_id,
+                "email": payload.email,
+                "spec": Curve::NistP256,
+                "commitment": hex::encode(commitment.to_encoded_point(false)),
+                "proof": hex::encode(proof.to_bytes()),
+                "public_key": hex::encode(signing_key.verifying_key().to_encoded_point(false)),
+            })
+        }
+    };
+    Ok(serde_json::to_string_pretty(&request)?)
+}
+
+fn verify_proof(args: &Args) -> anyhow::Result<String> {
+    let request: UserLoginRequest = serde_json::from_str(&args.input("JSON")?).context("malformed login request")?;
+    if !request.proof.verify(&request.payload) {
+        bail!("invalid proof");
+    }
+    Ok("valid proof".to_string())
+}
+
+fn token(command: &str, args: &Args) -> anyhow::Result<String> {
+    match command {
+        "sign" => {
+            args.no_positional()?;
+            let signing_key = signing_key(args.required("private-key")?)?;
+            let payload: UserTokenPayload = serde_json::from_str(args.required("payload")?).context("malformed payload")?;
+            Ok(TokenSigner::<_, NistP256>::sign(&signing_key, payload).encode())
+        }
+        "verify" => {
+            let verifying_key = verifying_key(args.required("public-key")?)?;
+            let token = decode_token(&args.input("TOKEN")?)?;
+            if !TokenVerifier::verify(&verifying_key, &token) {
+                bail!("invalid signature");
+            }
+            Ok(payload_json(&token))
+        }
+        "decode" => Ok(payload_json(&decode_token(&args.input("TOKEN")?)?)),
+        "encrypt" => {
+            let token = decode_token(&args.input("TOKEN")?)?;
+            cipher(args)?.encrypt_token(&token).map_err(|_| anyhow!("encryption failed"))
+        }
+        "decrypt" => {
+            let encrypted = args.input("ENCRYPTED")?;
+            let token: AccessToken = cipher(args)?.decrypt_token(&encrypted).map_err(|_| anyhow!("decryption failed"))?;
+            Ok(token.encode())
+        }
+        command => Err(usage(format!("unknown token command {command}"))),
+    }
+}
+
+fn millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis()
+}
+
+fn id(command: &str, args: &Args) -> anyhow::Result<String> {
+    let id = match command {
+        "inspect" => identifier(&args.input("ID")?)?,
+        "generate" => {
+            args.no_positional()?;
+            let number = |name| args.option(name).map(str::parse::<u16>).transpose().map_err(|_| usage(format!("--{name} must be a u16")));
+            IdentifierGenerator::new(number("service-id")?.unwrap_or_default(), number("worker-id")?.unwrap_or_default()).generate()
+        }
+        command => return Err(usage(format!("unknown id command {command}"))),
+    };
+    let inspection = json!({
+        "hex": id.as_hex(),
+        "base64": id.as_base64(),
+        "timestamp_ms": millis(id.timestamp()) as u64,
+        "sequence": id.sequence(),
+        "service_id": id.service_id(),
+        "worker_id": id.worker_id(),
+        "random": id.random(),
+    });
+    Ok(serde_json::to_string_pretty(&inspection)?)
+}
+
+fn run(args: &[String]) -> anyhow::Result<String> {
+    let (command, rest) = args.split_first().ok_or_else(|| usage("missing command"))?;
+    match command.as_str() {
+        "keygen" => keygen(&Args::parse(rest)?),
+        "cipher-key" => cipher_key(&Args::parse(rest)?),
+        "proof" => proof(&Args::parse(rest)?),
+        "verify-proof" => verify_proof(&Args::parse(rest)?),
+        "token" | "id" => {
+            let (subcommand, rest) = rest.split_first().ok_or_else(|| usage(format!("missing {command} command")))?;
+            let args = Args::parse(rest)?;
+            if command == "token" { token(subcommand, &args) } else { id(subcommand, &args) }
+        }
+        "help" | "--help" | "-h" => Ok(USAGE.to_string()),
+        command => Err(usage(format!("unknown command {command}"))),
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(output) => {
+            println!("{output}");
+            ExitCode::SUCCESS
+        }
+        Err(error) if error.is::<Usage>() => {
+            eprintln!("error: {error}\n\n{USAGE}");
+            ExitCode::from(2)
+        }
+        Err(error) => {
+            eprintln!("error: {error:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    fn run(args: &[&str]) -> anyhow::Result<String> {
+        super::run(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    fn keypair() -> (String, String) {
+        let keypair: Value = serde_json::from_str(&run(&["keygen"]).unwrap()).unwrap();
+        (keypair["private_key"].as_str().unwrap().to_string(), keypair["public_key"].as_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn proofs() {
+        let (private_key, _) = keypair();
+        let client_id = Identifier::from(42u128).as_hex();
+        let request = run(&["proof", "--private-key", &private_key, "--client-id", &client_id, "--email=alice@example.com"]).unwrap();
+        assert_eq!(run(&["verify-proof", &request]).unwrap(), "valid proof");
+
+        let mut tampered: Value = serde_json::from_str(&request).unwrap();
+        tampered["email"] = json!("bob@example.com");
+        assert_eq!(run(&["verify-proof", &tampered.to_string()]).unwrap_err().to_string(), "invalid proof");
+
+        assert!(run(&["keygen", "--curve", "p384"]).unwrap_err().is::<Usage>());
+        assert!(run(&["proof", "--private-key", &private_key]).unwrap_err().is::<Usage>());
+        assert!(run(&["proof", "--email"]).unwrap_err().is::<Usage>());
+    }
+
+    #[test]
+    fn tokens() {
+        let (private_key, public_key) = keypair();
+        let (_, other_public_key) = keypair();
+        let payload = json!({
+            "user_id": Identifier::from(1u128),
+            "client_id": Identifier::from(2u128),
+            "auth_time": 1_700_000_000,
+            "amr": ["schnorr"],
+            "expires_at": 1_700_003_600,
+        }).to_string();
+        let token = run(&["token", "sign", "--private-key", &private_key, "--payload", &payload]).unwrap();
+
+        let verified: Value = serde_json::from_str(&run(&["token", "verify", "--public-key", &public_key, &token]).unwrap()).unwrap();
+        assert_eq!(verified, serde_json::from_str::<Value>(&payload).unwrap());
+        assert!(run(&["token", "verify", "--public-key", &other_public_key, &token]).is_err());
+        assert_eq!(run(&["token", "decode", &token]).unwrap(), run(&["token", "verify", "--public-key", &public_key, &token]).unwrap());
+
+        let cipher_key = run(&["cipher-key"]).unwrap();
+        let encrypted = run(&["token", "encrypt", "--cipher-key", &cipher_key, &token]).unwrap();
+        assert_eq!(run(&["token", "decrypt", "--cipher-key", &cipher_key, &encrypted]).unwrap(), token);
+        assert!(run(&["token", "decrypt", "--cipher-key", &run(&["cipher-key"]).unwrap(), &encrypted]).is_err());
+        assert!(run(&["token", "decrypt", "--cipher-key", &cipher_key, "AAAA"]).is_err());
+        assert!(run(&["token", "encrypt", "--cipher-key", "00", &token]).is_err());
+    }
+
+    #[test]
+    fn identifiers() {
+        let generated: Value = serde_json::from_str(&run(&["id", "generate", "--service-id", "3", "--worker-id", "4"]).unwrap()).unwrap();
+        assert_eq!((generated["service_id"].clone(), generated["worker_id"].clone()), (json!(3), json!(4)));
+
+        let hex = generated["hex"].as_str().unwrap();
+        let base64 = generated["base64"].as_str().unwrap();
+        assert_eq!(run(&["id", "inspect", hex]).unwrap(), run(&["id", "inspect", base64]).unwrap());
+        let inspected: Value = serde_json::from_str(&run(&["id", "inspect", hex]).unwrap()).unwrap();
+        assert_eq!(inspected, generated);
+        assert!(run(&["id", "inspect", "not an id"]).is_err());
+        assert!(run(&["id", "inspect"]).unwrap_err().is::<Usage>());
+        assert!(run(&["id", "generate", "--worker-id", "70000"]).unwrap_err().is::<Usage>());
+        assert!(run(&["nope"]).unwrap_err().is::<Usage>());
+    }
+}
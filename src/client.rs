@@ -0,0 +1,380 @@
+//! Client side of the login, it keeps the secret key of a user and turns it into the
+//! [`UserLoginRequest`] verified by [`crate::service::UserAuthentication::login`]. The proof signs the
+//! same payload bytes as the server checks, `Vec::from(&UserLoginPayload)`.
+//!
+//! Nothing here does I/O, the spec comes from a [`SpecSource`] so native and WebAssembly builds bring
+//! their own HTTP client. The futures aren't `Send` for the same reason, browser fetches aren't
+
+use elliptic_curve::ops::Reduce;
+use elliptic_curve::sec1::ToEncodedPoint;
+use elliptic_curve::Field;
+use p256::NistP256;
+
+use crate::api::{Api, ApiRequest, ApiStore};
+use crate::crypto::schnorr::{Shnorr, ShnorrProof};
+use crate::crypto::secret::{Secret, SecretBytes};
+use crate::data::id::Identifier;
+use crate::model::Curve;
+use crate::service::spec::GetSpecResponse;
+use crate::service::user_keys::{KeyRotationPayload, KeyRotationRequest, NewUserKey};
+use crate::service::{UserLoginPayload, UserLoginRequest, UserRegisterRequest};
+use crate::store::UserMetadataOf;
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ClientError {
+    #[error("the server accepts none of the compiled in curves")]
+    NoSupportedCurve,
+    #[error("the server doesn't accept {0:?} keys")]
+    UnsupportedCurve(Curve),
+    #[error("unknown curve {0:?}")]
+    UnknownCurve(String),
+    #[error("the server doesn't derive the challenges with a supported hash function")]
+    UnsupportedHashFunction,
+    #[error("invalid secret key")]
+    InvalidKey,
+    #[error("failed to fetch the spec: {0}")]
+    Spec(String),
+}
+
+/// Where the client gets the `getSpec` response from, usually `GET /api/v1/spec`
+#[async_trait::async_trait(?Send)]
+pub trait SpecSource {
+    async fn fetch_spec(&self, client_id: Option<Identifier>) -> Result<GetSpecResponse, ClientError>;
+}
+
+/// The API itself, for clients running in the same process as the server
+#[async_trait::async_trait(?Send)]
+impl<S: ApiStore> SpecSource for Api<S>
+where
+    UserMetadataOf<S>: serde::Serialize + serde::de::DeserializeOwned,
+{
+    async fn fetch_spec(&self, client_id: Option<Identifier>) -> Result<GetSpecResponse, ClientError> {
+        let query = client_id.map(|client_id| format!("client_id={}", client_id.as_hex()));
+        let request = ApiRequest {
+            method: "GET",
+            path: "/api/v1/spec",
+            query: query.as_deref(),
+            content_type: None,
+            authorization: None,
+            body: b"",
+            remote_addr: None,
+        };
+        let response = self.handle(request).await;
+        if response.status != 200 {
+            return Err(ClientError::Spec(String::from_utf8_lossy(&response.body).into_owned()));
+        }
+        serde_json::from_slice(&response.body).map_err(|error| ClientError::Spec(error.to_string()))
+    }
+}
+
+/// First compiled in curve accepted by the server
+pub fn choose_curve(spec: &GetSpecResponse) -> Result<Curve, ClientError> {
+    check_hash_functions(spec)?;
+    ShnorrProof::CURVES
+        .iter()
+        .copied()
+        .find(|curve| spec.finite_cyclic_groups.elliptic_curves.contains(curve))
+        .ok_or(ClientError::NoSupportedCurve)
+}
+
+fn check_hash_functions(spec: &GetSpecResponse) -> Result<(), ClientError> {
+    match ShnorrProof::HASH_FUNCTIONS.iter().any(|hash| spec.hash_functions.contains(hash)) {
+        true => Ok(()),
+        false => Err(ClientError::UnsupportedHashFunction),
+    }
+}
+
+/// Salt of [`UserKey::from_password`] unique to the account, the client and the lowercase email
+pub fn password_salt(client_id: Identifier, email: &str) -> Vec<u8> {
+    [u128::from(client_id).to_le_bytes().as_slice(), email.to_lowercase().as_bytes()].concat()
+}
+
+/// `POST /register` request with `key` as the first key of the user, the metadata is whatever the store of
+/// the server keeps for its users
+pub fn register_request<M>(key: &UserKey, label: &str, client_id: Identifier, email: &str, metadata: Option<M>) -> UserRegisterRequest<M> {
+    let key = key.registration_key(client_id, email, label);
+    UserRegisterRequest { client_id, email: email.to_string(), metadata, key, client_ip: None }
+}
+
+enum KeyMaterial {
+    NistP256(Secret<p256::Scalar>),
+}
+
+/// Secret key of a user, the server only ever sees its public key
+pub struct UserKey {
+    key: KeyMaterial,
+}
+
+impl UserKey {
+    pub fn generate(curve: Curve) -> Self {
+        let key = match curve {
+            Curve::NistP256 => {
+                let scalar = p256::NonZeroScalar::random(&mut rand::rngs::OsRng);
+                KeyMaterial::NistP256(Secret::new(*scalar.as_ref()))
+            }
+        };
+        Self { key }
+    }
+
+    /// Derives the key from a password with Argon2id, the same password and salt always give the same
+    /// key so nothing has to be stored on the device. See [`password_salt`]
+    pub fn from_password(curve: Curve, password: &[u8], salt: &[u8]) -> Result<Self, ClientError> {
+        let key = match curve {
+            Curve::NistP256 => {
+                let mut bytes = Secret::new(p256::FieldBytes::default());
+                argon2::Argon2::default()
+                    .hash_password_into(password, salt, bytes.expose_secret_mut())
+                    .map_err(|_| ClientError::InvalidKey)?;
+                // NOTE: The bias of reducing 256 bits modulo the order of P-256 is below 2^-32
+                let scalar = <p256::Scalar as Reduce<p256::U256>>::reduce_bytes(bytes.expose_secret());
+                if bool::from(scalar.is_zero()) {
+                    return Err(ClientError::InvalidKey);
+                }
+                KeyMaterial::NistP256(Secret::new(scalar))
+            }
+        };
+        Ok(Self { key })
+    }
+
+    /// Restores a key exported with [`UserKey::to_bytes`]
+    pub fn from_bytes(curve: Curve, bytes: &[u8]) -> Result<Self, ClientError> {
+        let key = match curve {
+            Curve::NistP256 => {
+                let scalar = p256::NonZeroScalar::try_from(bytes).map_err(|_| ClientError::InvalidKey)?;
+                KeyMaterial::NistP256(Secret::new(*scalar.as_ref()))
+            }
+        };
+        Ok(Self { key })
+    }
+
+    /// Big endian scalar, store it like any other secret
+    pub fn to_bytes(&self) -> SecretBytes {
+        match &self.key {
+            KeyMaterial::NistP256(scalar) => SecretBytes::new(scalar.expose_secret().to_bytes().to_vec()),
+        }
+    }
+
+    pub fn curve(&self) -> Curve {
+        match &self.key {
+            KeyMaterial::NistP256(_) => Curve::NistP256,
+        }
+    }
+
+    /// Uncompressed SEC1 encoding, the `public_key` of the login requests
+    pub fn public_key(&self) -> Vec<u8> {
+        match &self.key {
+            KeyMaterial::NistP256(scalar) => {
+                let public_key = (p256::ProjectivePoint::GENERATOR * scalar.expose_secret()).to_affine();
+                public_key.to_encoded_point(false).as_bytes().to_vec()
+            }
+        }
+    }
+
+    /// Proves the knowledge of the key over the payload bytes, a new commitment is drawn on every call
+    fn proof(&self, payload: &[u8]) -> ShnorrProof {
+        match &self.key {
+            KeyMaterial::NistP256(scalar) => {
+                let public_key = (p256::ProjectivePoint::GENERATOR * scalar.expose_secret()).to_affine();
+                let (proof, commitment) = NistP256.proof(&payload, scalar);
+                ShnorrProof::CurveNistP256 { commitment, proof, public_key }
+            }
+        }
+    }
+
+    /// Proves the knowledge of the key over the payload, a new commitment is drawn on every call
+    pub fn prove(&self, payload: UserLoginPayload, nonce: Option<String>) -> UserLoginRequest {
+        let proof = self.proof(&Vec::from(&payload));
+        UserLoginRequest { payload, proof, nonce, client_ip: None }
+    }
+
+    /// The `key` of a `POST /register` request, registering this key to the new account
+    pub fn registration_key(&self, client_id: Identifier, email: &str, label: &str) -> NewUserKey {
+        let payload = UserLoginPayload { client_id, email: email.to_string() };
+        NewUserKey { label: label.to_string(), proof: self.proof(&Vec::from(&payload)) }
+    }
+
+    /// `POST /keys` request adding `new_key` to the account this key is registered to
+    pub fn rotation_request(&self, new_key: &UserKey, client_id: Identifier, email: &str, label: &str) -> KeyRotationRequest {
+        let payload = KeyRotationPayload {
+            client_id,
+            email: email.to_string(),
+            new_public_key: hex::encode(new_key.public_key()),
+            label: label.to_string(),
+        };
+        let bytes = Vec::from(&payload);
+        KeyRotationRequest { proof: self.proof(&bytes), new_key_proof: new_key.proof(&bytes), payload, client_ip: None }
+    }
+
+    /// Login request for `client_id`, checking first that the server accepts the key
+    pub fn login_request(
+        &self,
+        spec: &GetSpecResponse,
+        client_id: Identifier,
+        email: &str,
+        nonce: Option<String>,
+    ) -> Result<UserLoginRequest, ClientError> {
+        check_hash_functions(spec)?;
+        if !spec.finite_cyclic_groups.elliptic_curves.contains(&self.curve()) {
+            return Err(ClientError::UnsupportedCurve(self.curve()));
+        }
+        let payload = UserLoginPayload { client_id, email: email.to_string() };
+        Ok(self.prove(payload, nonce))
+    }
+}
+
+/// Client of one application, it fetches the spec of the application before every operation so the
+/// changes of its allowed curves are picked up
+pub struct LoginClient<F: SpecSource> {
+    source: F,
+    client_id: Identifier,
+}
+
+impl<F: SpecSource> LoginClient<F> {
+    pub fn new(source: F, client_id: Identifier) -> Self {
+        Self { source, client_id }
+    }
+
+    pub async fn spec(&self) -> Result<GetSpecResponse, ClientError> {
+        self.source.fetch_spec(Some(self.client_id)).await
+    }
+
+    /// New key on a curve the application accepts, registered users keep theirs with
+    /// [`UserKey::from_bytes`]
+    pub async fn generate_key(&self) -> Result<UserKey, ClientError> {
+        Ok(UserKey::generate(choose_curve(&self.spec().await?)?))
+    }
+
+    pub async fn login_request(&self, key: &UserKey, email: &str, nonce: Option<String>) -> Result<UserLoginRequest, ClientError> {
+        key.login_request(&self.spec().await?, self.client_id, email, nonce)
+    }
+
+    /// JSON body of `POST /login`
+    pub async fn login_body(&self, key: &UserKey, email: &str, nonce: Option<String>) -> Result<String, ClientError> {
+        let request = self.login_request(key, email, nonce).await?;
+        Ok(serde_json::to_string(&request).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::schnorr::HashFunction;
+    use crate::service::spec::FiniteCyclicGroups;
+
+    use super::*;
+
+    fn spec(elliptic_curves: Vec<Curve>, hash_functions: Vec<HashFunction>) -> GetSpecResponse {
+        GetSpecResponse {
+            finite_cyclic_groups: FiniteCyclicGroups { elliptic_curves, prime_modulus: Vec::new() },
+            hash_functions,
+        }
+    }
+
+    #[test]
+    fn login_requests_match_the_server_encoding() {
+        let spec = spec(vec![Curve::NistP256], vec![HashFunction::Sha512]);
+        let key = UserKey::generate(choose_curve(&spec).unwrap());
+        let client_id = Identifier::from(42u128);
+        let request = key.login_request(&spec, client_id, "alice@example.com", Some("nonce".to_string())).unwrap();
+        assert!(request.proof.verify(&request.payload));
+
+        let json: serde_json::Value = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["spec"], "p256");
+        assert_eq!(json["client_id"], client_id.as_hex());
+        assert_eq!(json["public_key"], hex::encode(key.public_key()));
+        let parsed: UserLoginRequest = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.proof, request.proof);
+        assert!(parsed.proof.verify(&parsed.payload));
+        assert_eq!(parsed.nonce.as_deref(), Some("nonce"));
+
+        let restored = UserKey::from_bytes(key.curve(), key.to_bytes().expose_secret()).unwrap();
+        assert_eq!(restored.public_key(), key.public_key());
+        assert_eq!(UserKey::from_bytes(Curve::NistP256, &[0; 32]).err(), Some(ClientError::InvalidKey));
+        assert_eq!(UserKey::from_bytes(Curve::NistP256, b"short").err(), Some(ClientError::InvalidKey));
+    }
+
+    #[test]
+    fn password_keys() {
+        let salt = password_salt(Identifier::from(42u128), "Alice@Example.com");
+        assert_eq!(salt, password_salt(Identifier::from(42u128), "alice@example.com"));
+        assert_ne!(salt, password_salt(Identifier::from(43u128), "alice@example.com"));
+
+        let key = UserKey::from_password(Curve::NistP256, b"correct horse", &salt).unwrap();
+        let again = UserKey::from_password(Curve::NistP256, b"correct horse", &salt).unwrap();
+        assert_eq!(key.public_key(), again.public_key());
+        let other = UserKey::from_password(Curve::NistP256, b"battery staple", &salt).unwrap();
+        assert_ne!(key.public_key(), other.public_key());
+        assert_eq!(UserKey::from_password(Curve::NistP256, b"correct horse", b"short").err(), Some(ClientError::InvalidKey));
+
+        let request = register_request(&key, "laptop", Identifier::from(42u128), "alice@example.com", Some(serde_json::json!({ "name": "Alice" })));
+        let value = serde_json::to_value(&request).unwrap();
+        assert_eq!(value["client_id"], Identifier::from(42u128).as_hex());
+        assert_eq!((&value["email"], &value["metadata"], &value["key"]["label"]), (&serde_json::json!("alice@example.com"), &serde_json::json!({ "name": "Alice" }), &serde_json::json!("laptop")));
+        let payload = UserLoginPayload { client_id: Identifier::from(42u128), email: "alice@example.com".to_string() };
+        assert!(request.key.proof.verify(&payload));
+        assert_eq!(request.key.proof.public_key(), key.public_key());
+    }
+
+    #[test]
+    fn unsupported_specs() {
+        let key = UserKey::generate(Curve::NistP256);
+        let id = Identifier::from(1u128);
+        let no_curves = spec(Vec::new(), vec![HashFunction::Sha512]);
+        assert_eq!(choose_curve(&no_curves), Err(ClientError::NoSupportedCurve));
+        assert_eq!(key.login_request(&no_curves, id, "a@b.c", None).err(), Some(ClientError::UnsupportedCurve(Curve::NistP256)));
+        let no_hashes = spec(vec![Curve::NistP256], Vec::new());
+        assert_eq!(choose_curve(&no_hashes), Err(ClientError::UnsupportedHashFunction));
+        assert_eq!(key.login_request(&no_hashes, id, "a@b.c", None).err(), Some(ClientError::UnsupportedHashFunction));
+    }
+
+    #[cfg(feature = "in-memory")]
+    mod in_memory {
+        use crate::api::ApiConfig;
+        use crate::model::ClientSettings;
+        use crate::service::UserAuthentication;
+        use crate::store::memory::{InMemoryState, InMemoryStore};
+        use crate::store::{ClientStore, ClientUpdate};
+
+        use super::*;
+
+        const ISSUER: &str = "https://iam0.example.com";
+
+        struct Authentication;
+
+        impl UserAuthentication<InMemoryStore> for Authentication {
+            fn issuer(&self) -> &str {
+                ISSUER
+            }
+        }
+
+        #[tokio::test]
+        async fn round_trip_through_the_login() {
+            let state = InMemoryState::new();
+            let client = state.seed_client("app");
+            let user = state.seed_user(client.id, "alice@example.com");
+            let config = ApiConfig {
+                issuer: ISSUER.to_string(),
+                token_endpoint: format!("{ISSUER}/oauth/token"),
+                relying_party: None,
+                login_limits: Default::default(),
+                audit: None,
+            };
+            let login = LoginClient::new(Api::<InMemoryStore>::new(config, state.clone()), client.id);
+
+            let key = login.generate_key().await.unwrap();
+            state.seed_user_key(user.id, &key.public_key());
+            let body = login.login_body(&key, "alice@example.com", None).await.unwrap();
+            let request: UserLoginRequest = serde_json::from_str(&body).unwrap();
+            let response = Authentication.login(request, state.clone()).await.unwrap().authenticated().unwrap();
+            assert_eq!(response.token.payload().client_id, client.id);
+
+            let settings = ClientSettings { allowed_curves: Some(Vec::new()), ..ClientSettings::default() };
+            let update = ClientUpdate { settings: Some(settings), ..ClientUpdate::default() };
+            InMemoryStore::update_client(state.clone(), client.id, update).await.unwrap();
+            let error = login.login_request(&key, "alice@example.com", None).await.err();
+            assert_eq!(error, Some(ClientError::UnsupportedCurve(Curve::NistP256)));
+
+            let unknown = LoginClient::new(login.source, Identifier::from(7u128));
+            assert!(matches!(unknown.generate_key().await, Err(ClientError::Spec(_))));
+        }
+    }
+}
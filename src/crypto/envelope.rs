@@ -0,0 +1,280 @@
+//! Envelope encryption of secrets at rest. Every secret is encrypted with its own random data key using
+//! AES-256-GCM, and the data key is wrapped by a key encryption key (KEK) held by a
+//! [`KeyEncryptionProvider`]. Rotating the KEK only needs the data keys to be re-wrapped, the encrypted
+//! secrets stay untouched. [`LocalKeyProvider`] keeps the KEK in memory, the `pkcs11` feature adds
+//! [`Pkcs11KeyProvider`](crate::crypto::pkcs11::Pkcs11KeyProvider) keeping it in an HSM
+
+use std::path::Path;
+use std::sync::Arc;
+
+use aead::{Aead, AeadCore, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use cipher::KeyInit;
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::secret::{Secret, SecretBytes};
+
+pub const DATA_KEY_SIZE: usize = 32;
+
+pub type DataKey = Secret<[u8; DATA_KEY_SIZE]>;
+const NONCE_SIZE: usize = 12;
+
+#[derive(thiserror::Error, Debug)]
+pub enum EnvelopeError {
+    #[error("unknown key encryption key {0:?}")]
+    UnknownKey(String),
+    #[error("invalid key material")]
+    InvalidKey,
+    #[error("failed to encrypt")]
+    Encryption,
+    #[error("failed to decrypt, the data was tampered with or the key is wrong")]
+    Decryption,
+    #[error("malformed envelope")]
+    Format(#[source] bincode::Error),
+    #[error("failed to read the key")]
+    Io(#[from] std::io::Error),
+    #[cfg(feature = "pkcs11")]
+    #[error("PKCS#11 error")]
+    Pkcs11(#[source] cryptoki::error::Error),
+}
+
+/// Data key wrapped by the KEK identified by `key_id`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WrappedKey {
+    pub key_id: String,
+    pub bytes: Vec<u8>,
+}
+
+/// Holds a key encryption key, the key material itself never has to leave the provider
+pub trait KeyEncryptionProvider: Send + Sync {
+    /// Stable identifier of the KEK, stored next to every key it wraps
+    fn key_id(&self) -> &str;
+    fn wrap(&self, data_key: &DataKey) -> Result<WrappedKey, EnvelopeError>;
+    fn unwrap(&self, wrapped: &WrappedKey) -> Result<DataKey, EnvelopeError>;
+}
+
+/// AES-256-GCM encryption with a random nonce prepended to the ciphertext, `aad` binds the ciphertext to
+/// its context
+fn seal_bytes(key: &DataKey, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, EnvelopeError> {
+    let cipher = Aes256Gcm::new(key.expose_secret().into());
+    let nonce = Aes256Gcm::generate_nonce(&mut rand::thread_rng());
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg: plaintext, aad })
+        .map_err(|_| EnvelopeError::Encryption)?;
+    Ok([nonce.as_slice(), ciphertext.as_slice()].concat())
+}
+
+fn open_bytes(key: &DataKey, sealed: &[u8], aad: &[u8]) -> Result<SecretBytes, EnvelopeError> {
+    if sealed.len() < NONCE_SIZE {
+        return Err(EnvelopeError::Decryption);
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+    Aes256Gcm::new(key.expose_secret().into())
+        .decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map(SecretBytes::new)
+        .map_err(|_| EnvelopeError::Decryption)
+}
+
+/// KEK kept in process memory, loaded from a key file or derived from a passphrase
+pub struct LocalKeyProvider {
+    key_id: String,
+    key: DataKey,
+}
+
+impl LocalKeyProvider {
+    pub fn new(key_id: impl Into<String>, key: DataKey) -> Self {
+        Self { key_id: key_id.into(), key }
+    }
+
+    pub fn generate(key_id: impl Into<String>) -> Self {
+        Self::new(key_id, DataKey::new(rand::random()))
+    }
+
+    /// Reads a hex encoded 256 bit key, surrounding whitespace is ignored
+    pub fn from_file(key_id: impl Into<String>, path: impl AsRef<Path>) -> Result<Self, EnvelopeError> {
+        let contents = Secret::new(std::fs::read_to_string(path)?);
+        let mut key = DataKey::default();
+        hex::decode_to_slice(contents.expose_secret().trim(), key.expose_secret_mut()).map_err(|_| EnvelopeError::InvalidKey)?;
+        Ok(Self::new(key_id, key))
+    }
+
+    /// Derives the key with Argon2id, the salt must be stored to derive the same key again
+    pub fn from_passphrase(key_id: impl Into<String>, passphrase: &[u8], salt: &[u8]) -> Result<Self, EnvelopeError> {
+        let mut key = DataKey::default();
+        argon2::Argon2::default()
+            .hash_password_into(passphrase, salt, key.expose_secret_mut())
+            .map_err(|_| EnvelopeError::InvalidKey)?;
+        Ok(Self::new(key_id, key))
+    }
+}
+
+impl KeyEncryptionProvider for LocalKeyProvider {
+    fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    fn wrap(&self, data_key: &DataKey) -> Result<WrappedKey, EnvelopeError> {
+        Ok(WrappedKey {
+            key_id: self.key_id.clone(),
+            bytes: seal_bytes(&self.key, data_key.expose_secret(), self.key_id.as_bytes())?,
+        })
+    }
+
+    fn unwrap(&self, wrapped: &WrappedKey) -> Result<DataKey, EnvelopeError> {
+        if wrapped.key_id != self.key_id {
+            return Err(EnvelopeError::UnknownKey(wrapped.key_id.clone()));
+        }
+        let data_key = open_bytes(&self.key, &wrapped.bytes, self.key_id.as_bytes())?;
+        let mut key = DataKey::default();
+        if data_key.expose_secret().len() != DATA_KEY_SIZE {
+            return Err(EnvelopeError::InvalidKey);
+        }
+        key.expose_secret_mut().copy_from_slice(data_key.expose_secret());
+        Ok(key)
+    }
+}
+
+/// Secret encrypted with its own data key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EncryptedSecret {
+    pub wrapped_key: WrappedKey,
+    pub ciphertext: Vec<u8>,
+}
+
+impl EncryptedSecret {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EnvelopeError> {
+        bincode::deserialize(bytes).map_err(EnvelopeError::Format)
+    }
+}
+
+/// Current KEK used to seal new secrets, plus the retired ones that can still open older secrets until
+/// they are re-wrapped
+#[derive(Clone)]
+pub struct KeyRing {
+    current: Arc<dyn KeyEncryptionProvider>,
+    retired: Vec<Arc<dyn KeyEncryptionProvider>>,
+}
+
+impl KeyRing {
+    pub fn new(current: Arc<dyn KeyEncryptionProvider>) -> Self {
+        Self { current, retired: Vec::new() }
+    }
+
+    pub fn with_retired(mut self, provider: Arc<dyn KeyEncryptionProvider>) -> Self {
+        self.retired.push(provider);
+        self
+    }
+
+    pub fn current(&self) -> &Arc<dyn KeyEncryptionProvider> {
+        &self.current
+    }
+
+    fn provider(&self, key_id: &str) -> Result<&dyn KeyEncryptionProvider, EnvelopeError> {
+        std::iter::once(&self.current)
+            .chain(&self.retired)
+            .find(|provider| provider.key_id() == key_id)
+            .map(AsRef::as_ref)
+            .ok_or_else(|| EnvelopeError::UnknownKey(key_id.to_string()))
+    }
+
+    /// Encrypts `plaintext` with a fresh data key wrapped by the current KEK, `aad` has to be given again
+    /// to open it
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<EncryptedSecret, EnvelopeError> {
+        let data_key = DataKey::new(rand::random());
+        Ok(EncryptedSecret {
+            wrapped_key: self.current.wrap(&data_key)?,
+            ciphertext: seal_bytes(&data_key, plaintext, aad)?,
+        })
+    }
+
+    pub fn open(&self, secret: &EncryptedSecret, aad: &[u8]) -> Result<SecretBytes, EnvelopeError> {
+        let data_key = self.provider(&secret.wrapped_key.key_id)?.unwrap(&secret.wrapped_key)?;
+        open_bytes(&data_key, &secret.ciphertext, aad)
+    }
+
+    /// Re-wraps the data key with the current KEK, `None` if it already uses it
+    pub fn rewrap(&self, secret: &EncryptedSecret) -> Result<Option<EncryptedSecret>, EnvelopeError> {
+        if secret.wrapped_key.key_id == self.current.key_id() {
+            return Ok(None);
+        }
+        let data_key = self.provider(&secret.wrapped_key.key_id)?.unwrap(&secret.wrapped_key)?;
+        Ok(Some(EncryptedSecret {
+            wrapped_key: self.current.wrap(&data_key)?,
+            ciphertext: secret.ciphertext.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local_key_ring(key_id: &str) -> KeyRing {
+        KeyRing::new(Arc::new(LocalKeyProvider::generate(key_id)))
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let key_ring = local_key_ring("kek-1");
+        let secret = key_ring.seal(b"signing key", b"client").unwrap();
+        assert_eq!(secret.wrapped_key.key_id, "kek-1");
+        assert!(!secret.ciphertext.windows(11).any(|window| window == b"signing key"));
+
+        let secret = EncryptedSecret::from_bytes(&secret.to_bytes()).unwrap();
+        assert_eq!(key_ring.open(&secret, b"client").unwrap().expose_secret(), b"signing key");
+        assert!(matches!(key_ring.open(&secret, b"other client"), Err(EnvelopeError::Decryption)));
+        assert!(matches!(local_key_ring("kek-2").open(&secret, b"client"), Err(EnvelopeError::UnknownKey(_))));
+    }
+
+    #[test]
+    fn tampering_is_detected() {
+        let key_ring = local_key_ring("kek-1");
+        let mut secret = key_ring.seal(b"signing key", b"client").unwrap();
+        *secret.ciphertext.last_mut().unwrap() ^= 1;
+        assert!(matches!(key_ring.open(&secret, b"client"), Err(EnvelopeError::Decryption)));
+
+        let mut secret = key_ring.seal(b"signing key", b"client").unwrap();
+        secret.wrapped_key.bytes[NONCE_SIZE] ^= 1;
+        assert!(matches!(key_ring.open(&secret, b"client"), Err(EnvelopeError::Decryption)));
+    }
+
+    #[test]
+    fn rotation_rewraps_the_data_key() {
+        let old: Arc<dyn KeyEncryptionProvider> = Arc::new(LocalKeyProvider::generate("kek-1"));
+        let secret = KeyRing::new(old.clone()).seal(b"signing key", b"client").unwrap();
+
+        let key_ring = KeyRing::new(Arc::new(LocalKeyProvider::generate("kek-2"))).with_retired(old);
+        assert_eq!(key_ring.open(&secret, b"client").unwrap().expose_secret(), b"signing key");
+
+        let rewrapped = key_ring.rewrap(&secret).unwrap().unwrap();
+        assert_eq!(rewrapped.wrapped_key.key_id, "kek-2");
+        assert_eq!(rewrapped.ciphertext, secret.ciphertext);
+        assert!(key_ring.rewrap(&rewrapped).unwrap().is_none());
+
+        let key_ring = KeyRing::new(Arc::new(LocalKeyProvider::new("kek-2", DataKey::default())));
+        assert!(key_ring.open(&rewrapped, b"client").is_err());
+    }
+
+    #[test]
+    fn local_keys_from_passphrase_and_file() {
+        let provider = LocalKeyProvider::from_passphrase("kek", b"correct horse", b"a fixed salt").unwrap();
+        let same = LocalKeyProvider::from_passphrase("kek", b"correct horse", b"a fixed salt").unwrap();
+        let wrapped = provider.wrap(&DataKey::new([7; DATA_KEY_SIZE])).unwrap();
+        assert_eq!(same.unwrap(&wrapped).unwrap().expose_secret(), &[7; DATA_KEY_SIZE]);
+        let other = LocalKeyProvider::from_passphrase("kek", b"wrong horse", b"a fixed salt").unwrap();
+        assert!(matches!(other.unwrap(&wrapped), Err(EnvelopeError::Decryption)));
+
+        let path = std::env::temp_dir().join(format!("iam0-kek-{}", std::process::id()));
+        std::fs::write(&path, format!("{}\n", hex::encode([9; DATA_KEY_SIZE]))).unwrap();
+        let provider = LocalKeyProvider::from_file("file", &path);
+        std::fs::remove_file(&path).unwrap();
+        let provider = provider.unwrap();
+        let wrapped = provider.wrap(&DataKey::new([1; DATA_KEY_SIZE])).unwrap();
+        assert_eq!(provider.unwrap(&wrapped).unwrap().expose_secret(), &[1; DATA_KEY_SIZE]);
+        assert!(matches!(LocalKeyProvider::from_file("file", &path), Err(EnvelopeError::Io(_))));
+    }
+}
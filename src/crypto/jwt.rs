@@ -0,0 +1,175 @@
+//! Compact ES256 JSON Web Tokens (RFC 7519) and the public JSON Web Keys verifying them (RFC 7517). Unlike
+//! the bincode [`Token`](crate::crypto::token::Token) they are meant for other parties, like the ID tokens
+//! read by the relying parties
+
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use p256::ecdsa::signature::{Signer, Verifier};
+use p256::ecdsa::{Signature, SigningKey, VerifyingKey};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub const ES256: &str = "ES256";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JwtHeader {
+    pub alg: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub typ: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+}
+
+/// Signs the claims, the `kid` of the header is the thumbprint of the public key
+pub fn encode<T: Serialize>(signing_key: &SigningKey, claims: &T) -> String {
+    let header = JwtHeader {
+        alg: ES256.to_string(),
+        typ: Some("JWT".to_string()),
+        kid: Some(Jwk::from(signing_key.verifying_key()).thumbprint()),
+    };
+    let header = BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header).unwrap());
+    let claims = BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).unwrap());
+    let signing_input = format!("{header}.{claims}");
+    let signature: Signature = signing_key.sign(signing_input.as_bytes());
+    format!("{signing_input}.{}", BASE64_URL_SAFE_NO_PAD.encode(signature.to_bytes()))
+}
+
+/// Checks the ES256 signature and parses the claims, it doesn't validate any claim
+pub fn decode<T: DeserializeOwned>(verifying_key: &VerifyingKey, token: &str) -> Option<T> {
+    let (signing_input, signature) = token.rsplit_once('.')?;
+    let (header, claims) = signing_input.split_once('.')?;
+    if claims.contains('.') {
+        return None;
+    }
+    let header: JwtHeader = serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(header).ok()?).ok()?;
+    if header.alg != ES256 {
+        return None;
+    }
+    let signature = Signature::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(signature).ok()?).ok()?;
+    verifying_key.verify(signing_input.as_bytes(), &signature).ok()?;
+    serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(claims).ok()?).ok()
+}
+
+/// Claims of a token without checking the signature, only meant to pick the key that verifies it
+pub fn decode_unverified<T: DeserializeOwned>(token: &str) -> Option<T> {
+    let claims = token.split('.').nth(1)?;
+    serde_json::from_slice(&BASE64_URL_SAFE_NO_PAD.decode(claims).ok()?).ok()
+}
+
+/// Public P-256 key in the JWK format
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Jwk {
+    pub kty: String,
+    pub crv: String,
+    pub x: String,
+    pub y: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none", rename = "use")]
+    pub key_use: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alg: Option<String>,
+}
+
+impl Jwk {
+    /// RFC 7638 thumbprint, the SHA-256 of the required members in lexicographic order
+    pub fn thumbprint(&self) -> String {
+        let canonical = format!(r#"{{"crv":"{}","kty":"{}","x":"{}","y":"{}"}}"#, self.crv, self.kty, self.x, self.y);
+        BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(canonical.as_bytes()))
+    }
+
+    /// Signature key with its thumbprint as `kid`, as published on the JWKS endpoint
+    pub fn for_signatures(verifying_key: &VerifyingKey) -> Self {
+        let jwk = Jwk::from(verifying_key);
+        Jwk {
+            kid: Some(jwk.thumbprint()),
+            key_use: Some("sig".to_string()),
+            alg: Some(ES256.to_string()),
+            ..jwk
+        }
+    }
+
+    pub fn verifying_key(&self) -> Option<VerifyingKey> {
+        if self.kty != "EC" || self.crv != "P-256" {
+            return None;
+        }
+        let x = BASE64_URL_SAFE_NO_PAD.decode(&self.x).ok()?;
+        let y = BASE64_URL_SAFE_NO_PAD.decode(&self.y).ok()?;
+        if x.len() != 32 || y.len() != 32 {
+            return None;
+        }
+        let point = p256::EncodedPoint::from_affine_coordinates(x.as_slice().into(), y.as_slice().into(), false);
+        VerifyingKey::from_encoded_point(&point).ok()
+    }
+}
+
+impl From<&VerifyingKey> for Jwk {
+    fn from(verifying_key: &VerifyingKey) -> Self {
+        let point = verifying_key.to_encoded_point(false);
+        Jwk {
+            kty: "EC".to_string(),
+            crv: "P-256".to_string(),
+            x: BASE64_URL_SAFE_NO_PAD.encode(point.x().unwrap()),
+            y: BASE64_URL_SAFE_NO_PAD.encode(point.y().unwrap()),
+            kid: None,
+            key_use: None,
+            alg: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Claims {
+        sub: String,
+        exp: u64,
+    }
+
+    #[test]
+    fn test_jwt() {
+        let signing_key = SigningKey::random(&mut rand::thread_rng());
+        let verifying_key = VerifyingKey::from(&signing_key);
+        let claims = Claims { sub: "alice".to_string(), exp: 42 };
+        let token = encode(&signing_key, &claims);
+        assert_eq!(decode::<Claims>(&verifying_key, &token), Some(claims));
+
+        let other = VerifyingKey::from(&SigningKey::random(&mut rand::thread_rng()));
+        assert_eq!(decode::<Claims>(&other, &token), None);
+
+        let (signing_input, _) = token.rsplit_once('.').unwrap();
+        let (header, _) = signing_input.split_once('.').unwrap();
+        let forged = format!("{header}.{}.", BASE64_URL_SAFE_NO_PAD.encode(r#"{"sub":"mallory","exp":42}"#));
+        assert_eq!(decode::<Claims>(&verifying_key, &forged), None);
+        assert_eq!(decode::<Claims>(&verifying_key, &format!("{token}.extra")), None);
+    }
+
+    #[test]
+    fn test_jwk() {
+        let verifying_key = VerifyingKey::from(&SigningKey::random(&mut rand::thread_rng()));
+        let jwk = Jwk::for_signatures(&verifying_key);
+        assert_eq!(jwk.verifying_key(), Some(verifying_key));
+        assert_eq!(jwk.kid, Some(Jwk::from(&verifying_key).thumbprint()));
+
+        // RFC 7638 section 3.1 uses an RSA key, this is the P-256 key of RFC 7515 appendix A.3
+        let jwk = Jwk {
+            kty: "EC".to_string(),
+            crv: "P-256".to_string(),
+            x: "f83OJ3D2xF1Bg8vub9tLe1gHMzV76e8Tus9uPHvRVEU".to_string(),
+            y: "x_FEzRu9m36HLN_tue659LNpXW6pCyStikYjKIWI5a0".to_string(),
+            kid: None,
+            key_use: None,
+            alg: None,
+        };
+        assert!(jwk.verifying_key().is_some());
+        assert_eq!(jwk.thumbprint().len(), 43);
+    }
+}
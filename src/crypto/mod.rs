@@ -1,2 +1,8 @@
+pub mod envelope;
+pub mod jwt;
+#[cfg(feature = "pkcs11")]
+pub mod pkcs11;
+pub mod secret;
 pub mod schnorr;
 pub mod token;
+pub mod totp;
@@ -0,0 +1,204 @@
+//! [`KeyEncryptionProvider`] backed by a PKCS#11 token, like an HSM or SoftHSM. The KEK is an AES-256 key
+//! generated on the token as sensitive and non extractable, the data keys are sent to the token to be
+//! wrapped and unwrapped with AES-GCM so the KEK itself never reaches the process
+
+use std::path::Path;
+use std::sync::Mutex;
+
+use cryptoki::context::{CInitializeArgs, CInitializeFlags, Pkcs11};
+use cryptoki::error::{Error, RvError};
+use cryptoki::mechanism::aead::GcmParams;
+use cryptoki::mechanism::Mechanism;
+use cryptoki::object::{Attribute, ObjectClass, ObjectHandle};
+use cryptoki::session::{Session, UserType};
+use cryptoki::types::AuthPin;
+
+use crate::crypto::envelope::{DataKey, EnvelopeError, KeyEncryptionProvider, WrappedKey, DATA_KEY_SIZE};
+use crate::crypto::secret::SecretBytes;
+
+const NONCE_SIZE: usize = 12;
+const TAG_BITS: u64 = 128;
+
+/// KEK held by a PKCS#11 token, identified by the label of the key on the token
+pub struct Pkcs11KeyProvider {
+    key_id: String,
+    key: ObjectHandle,
+    // NOTE: Sessions can't be shared between threads, and logging in again on every call would fail
+    // because the login is per application
+    session: Mutex<Session>,
+}
+
+impl Pkcs11KeyProvider {
+    /// Loads the PKCS#11 module, logs in as the user of the token labelled `token_label` and looks up the
+    /// AES key labelled `key_label`
+    pub fn open(module: impl AsRef<Path>, token_label: &str, pin: &AuthPin, key_label: &str) -> Result<Self, EnvelopeError> {
+        let pkcs11 = Pkcs11::new(module)?;
+        match pkcs11.initialize(CInitializeArgs::new(CInitializeFlags::OS_LOCKING_OK)) {
+            Ok(()) | Err(Error::Pkcs11(RvError::CryptokiAlreadyInitialized, _)) => {}
+            Err(error) => return Err(error.into()),
+        }
+        let slot = pkcs11
+            .get_slots_with_initialized_token()?
+            .into_iter()
+            .find(|slot| pkcs11.get_token_info(*slot).is_ok_and(|token| token.label() == token_label))
+            .ok_or_else(|| EnvelopeError::UnknownKey(format!("{token_label}/{key_label}")))?;
+        let session = pkcs11.open_rw_session(slot)?;
+        match session.login(UserType::User, Some(pin)) {
+            Ok(()) | Err(Error::Pkcs11(RvError::UserAlreadyLoggedIn, _)) => {}
+            Err(error) => return Err(error.into()),
+        }
+        Self::from_session(session, key_label)
+    }
+
+    /// Uses a session the caller already logged in, for applications sharing the module with other code
+    pub fn from_session(session: Session, key_label: &str) -> Result<Self, EnvelopeError> {
+        let template = [Attribute::Class(ObjectClass::SECRET_KEY), Attribute::Label(key_label.as_bytes().to_vec())];
+        let key = match session.find_objects(&template)?.as_slice() {
+            [key] => *key,
+            _ => return Err(EnvelopeError::UnknownKey(key_label.to_string())),
+        };
+        Ok(Self { key_id: key_label.to_string(), key, session: Mutex::new(session) })
+    }
+
+    /// Generates a KEK on the token of the logged in `session`, it can't be read back from the token
+    pub fn generate_key(session: &Session, key_label: &str) -> Result<(), EnvelopeError> {
+        let template = [
+            Attribute::Token(true),
+            Attribute::Private(true),
+            Attribute::Sensitive(true),
+            Attribute::Extractable(false),
+            Attribute::Encrypt(true),
+            Attribute::Decrypt(true),
+            Attribute::ValueLen((DATA_KEY_SIZE as u64).into()),
+            Attribute::Label(key_label.as_bytes().to_vec()),
+        ];
+        session.generate_key(&Mechanism::AesKeyGen, &template)?;
+        Ok(())
+    }
+
+    fn session(&self) -> std::sync::MutexGuard<'_, Session> {
+        self.session.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl From<Error> for EnvelopeError {
+    fn from(error: Error) -> Self {
+        EnvelopeError::Pkcs11(error)
+    }
+}
+
+impl KeyEncryptionProvider for Pkcs11KeyProvider {
+    fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    fn wrap(&self, data_key: &DataKey) -> Result<WrappedKey, EnvelopeError> {
+        let mut nonce: [u8; NONCE_SIZE] = rand::random();
+        let params = GcmParams::new(&mut nonce, self.key_id.as_bytes(), TAG_BITS.into())?;
+        let ciphertext = self.session().encrypt(&Mechanism::AesGcm(params), self.key, data_key.expose_secret())?;
+        Ok(WrappedKey {
+            key_id: self.key_id.clone(),
+            bytes: [nonce.as_slice(), ciphertext.as_slice()].concat(),
+        })
+    }
+
+    fn unwrap(&self, wrapped: &WrappedKey) -> Result<DataKey, EnvelopeError> {
+        if wrapped.key_id != self.key_id {
+            return Err(EnvelopeError::UnknownKey(wrapped.key_id.clone()));
+        }
+        if wrapped.bytes.len() < NONCE_SIZE {
+            return Err(EnvelopeError::Decryption);
+        }
+        let (nonce, ciphertext) = wrapped.bytes.split_at(NONCE_SIZE);
+        let mut nonce: [u8; NONCE_SIZE] = nonce.try_into().unwrap();
+        let params = GcmParams::new(&mut nonce, self.key_id.as_bytes(), TAG_BITS.into())?;
+        // NOTE: Tokens report a wrong tag with different return values, any failure here is one
+        let data_key = self
+            .session()
+            .decrypt(&Mechanism::AesGcm(params), self.key, ciphertext)
+            .map(SecretBytes::new)
+            .map_err(|_| EnvelopeError::Decryption)?;
+        if data_key.expose_secret().len() != DATA_KEY_SIZE {
+            return Err(EnvelopeError::InvalidKey);
+        }
+        let mut key = DataKey::default();
+        key.expose_secret_mut().copy_from_slice(data_key.expose_secret());
+        Ok(key)
+    }
+}
+
+/// These need SoftHSM: `SOFTHSM2_MODULE` is the path of `libsofthsm2.so`, and the tests give it their own
+/// token directory. Run them with `cargo test --features pkcs11 -- --ignored pkcs11`
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use cryptoki::object::AttributeType;
+
+    use crate::crypto::envelope::{KeyRing, LocalKeyProvider};
+
+    use super::*;
+
+    const TOKEN: &str = "iam0";
+
+    fn user_pin() -> AuthPin {
+        AuthPin::new("1234".into())
+    }
+
+    /// Initializes a token in a new SoftHSM token directory, the returned session is logged in as its user
+    fn softhsm() -> (String, Session) {
+        let module = std::env::var("SOFTHSM2_MODULE").unwrap_or_else(|_| "/usr/lib/softhsm/libsofthsm2.so".to_string());
+        let directory = std::env::temp_dir().join(format!("iam0-softhsm-{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("tokens")).unwrap();
+        let config = directory.join("softhsm2.conf");
+        std::fs::write(&config, format!("directories.tokendir = {}\n", directory.join("tokens").display())).unwrap();
+        std::env::set_var("SOFTHSM2_CONF", &config);
+
+        let pkcs11 = Pkcs11::new(&module).unwrap();
+        pkcs11.initialize(CInitializeArgs::new(CInitializeFlags::OS_LOCKING_OK)).unwrap();
+        let slot = pkcs11.get_slots_with_token().unwrap()[0];
+        let so_pin = AuthPin::new("5678".into());
+        pkcs11.init_token(slot, &so_pin, TOKEN).unwrap();
+        let session = pkcs11.open_rw_session(slot).unwrap();
+        session.login(UserType::So, Some(&so_pin)).unwrap();
+        session.init_pin(&user_pin()).unwrap();
+        session.logout().unwrap();
+        session.login(UserType::User, Some(&user_pin())).unwrap();
+        (module, session)
+    }
+
+    #[test]
+    #[ignore = "needs SoftHSM"]
+    fn pkcs11_wraps_with_a_token_key() {
+        let (module, session) = softhsm();
+        Pkcs11KeyProvider::generate_key(&session, "kek-1").unwrap();
+        Pkcs11KeyProvider::generate_key(&session, "kek-2").unwrap();
+        let kek = session.find_objects(&[Attribute::Label(b"kek-1".to_vec())]).unwrap()[0];
+        let attributes = session.get_attributes(kek, &[AttributeType::Extractable, AttributeType::Sensitive]).unwrap();
+        assert!(matches!(attributes.as_slice(), [Attribute::Extractable(false), Attribute::Sensitive(true)]), "{attributes:?}");
+
+        let provider = Pkcs11KeyProvider::from_session(session, "kek-1").unwrap();
+        let wrapped = provider.wrap(&DataKey::new([7; DATA_KEY_SIZE])).unwrap();
+        assert_eq!(wrapped.key_id, "kek-1");
+        assert_eq!(provider.unwrap(&wrapped).unwrap().expose_secret(), &[7; DATA_KEY_SIZE]);
+        let mut tampered = wrapped.clone();
+        *tampered.bytes.last_mut().unwrap() ^= 1;
+        assert!(matches!(provider.unwrap(&tampered), Err(EnvelopeError::Decryption)));
+
+        // Another KEK on the same token, opened the way a deployment would
+        let other = Pkcs11KeyProvider::open(&module, TOKEN, &user_pin(), "kek-2").unwrap();
+        let relabelled = WrappedKey { key_id: "kek-2".to_string(), ..wrapped.clone() };
+        assert!(matches!(other.unwrap(&relabelled), Err(EnvelopeError::Decryption)));
+        assert!(matches!(other.unwrap(&wrapped), Err(EnvelopeError::UnknownKey(_))));
+        assert!(matches!(Pkcs11KeyProvider::open(&module, TOKEN, &user_pin(), "missing"), Err(EnvelopeError::UnknownKey(_))));
+
+        // Moving from a local KEK to the token
+        let local: Arc<dyn KeyEncryptionProvider> = Arc::new(LocalKeyProvider::generate("local"));
+        let secret = KeyRing::new(local.clone()).seal(b"signing key", b"client").unwrap();
+        let key_ring = KeyRing::new(Arc::new(provider)).with_retired(local);
+        let rewrapped = key_ring.rewrap(&secret).unwrap().unwrap();
+        assert_eq!(rewrapped.wrapped_key.key_id, "kek-1");
+        let key_ring = KeyRing::new(key_ring.current().clone());
+        assert_eq!(key_ring.open(&rewrapped, b"client").unwrap().expose_secret(), b"signing key");
+    }
+}
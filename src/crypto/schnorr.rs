@@ -5,11 +5,17 @@ use digest::{Digest, Update};
 use elliptic_curve::{AffinePoint, CurveArithmetic, Field, Group, ProjectivePoint, Scalar, ScalarPrimitive};
 use elliptic_curve::point::PointCompression;
 use elliptic_curve::sec1::{FromEncodedPoint, ModulusSize, ToEncodedPoint};   
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
 
-fn commitment<Curve: CurveArithmetic>() -> (Scalar<Curve>, AffinePoint<Curve>) {
-    let nonce = Scalar::<Curve>::random(&mut rand::thread_rng());
-    let commitment = ProjectivePoint::<Curve>::generator() * nonce;
+use crate::crypto::secret::Secret;
+use crate::model::Curve;
+
+/// The nonce comes straight from the OS through `getrandom`, which works in the browser too with its `js`
+/// feature, so proving has no thread local RNG to set up
+fn commitment<Curve: CurveArithmetic>() -> (Secret<Scalar<Curve>>, AffinePoint<Curve>) {
+    let nonce = Secret::new(Scalar::<Curve>::random(&mut rand::rngs::OsRng));
+    let commitment = ProjectivePoint::<Curve>::generator() * nonce.expose_secret();
     (nonce, commitment.into())
 }
 
@@ -28,8 +34,16 @@ fn challenge<Curve, T>(public_key: &AffinePoint<Curve>, payload: &T) -> Scalar<C
     result.into()
 }
 
-pub trait Shnorr<PrivateKey, PublicKey> {
-    fn proof<T>(&self, payload: &T, x: &PrivateKey) -> (PrivateKey, PublicKey)
+/// Hash functions deriving the challenges of the proofs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum HashFunction {
+    #[serde(rename = "sha512")]
+    Sha512,
+}
+
+pub trait Shnorr<PrivateKey: Zeroize, PublicKey> {
+    /// The private key and the nonce are wiped once the proof is computed
+    fn proof<T>(&self, payload: &T, x: &Secret<PrivateKey>) -> (PrivateKey, PublicKey)
         where
             T: AsRef<[u8]>;
     fn verify<T>(&self, payload: &T, public_key: &PublicKey, proof: &PrivateKey, commitment: &PublicKey) -> bool
@@ -59,24 +73,49 @@ where
     }
 }
 
-#[derive(Debug, serde::Deserialize, PartialEq)]
+fn serialize_p256_affine_point_to_ec1<S>(point: &AffinePoint<p256::NistP256>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer
+{
+    serializer.serialize_str(&hex::encode(point.to_encoded_point(false)))
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, PartialEq)]
 #[serde(tag = "spec")]
 pub enum ShnorrProof {
     /// This variant is selected with the "spec" field "p256"
     #[serde(rename = "p256")]
     CurveNistP256 {
-        #[serde(deserialize_with = "deserialize_p256_affine_point_from_ec1")]
+        #[serde(serialize_with = "serialize_p256_affine_point_to_ec1", deserialize_with = "deserialize_p256_affine_point_from_ec1")]
         commitment: AffinePoint<p256::NistP256>,
 
         // TODO
         proof: Scalar<p256::NistP256>,
 
-        #[serde(deserialize_with = "deserialize_p256_affine_point_from_ec1")]
+        #[serde(serialize_with = "serialize_p256_affine_point_to_ec1", deserialize_with = "deserialize_p256_affine_point_from_ec1")]
         public_key: AffinePoint<p256::NistP256> 
     },
 }
 
 impl ShnorrProof {
+    /// Curves of the compiled in variants, their serialized names are the `spec` tags of the variants
+    pub const CURVES: &'static [Curve] = &[Curve::NistP256];
+    /// The challenge hash is the same for every curve
+    pub const HASH_FUNCTIONS: &'static [HashFunction] = &[HashFunction::Sha512];
+
+    pub fn curve(&self) -> Curve {
+        match self {
+            Self::CurveNistP256 { .. } => Curve::NistP256,
+        }
+    }
+
+    /// Uncompressed SEC1 encoding of the key the proof was made with
+    pub fn public_key(&self) -> Vec<u8> {
+        match self {
+            Self::CurveNistP256 { public_key, .. } => public_key.to_encoded_point(false).as_bytes().to_vec(),
+        }
+    }
+
     pub fn verify<'a, T>(&self, payload: &'a T) -> bool 
     where
         Vec<u8>: From<&'a T> 
@@ -85,9 +124,9 @@ impl ShnorrProof {
             Self::CurveNistP256 { commitment, proof, public_key } => {
                 p256::NistP256.verify(
                     &Vec::from(payload),
-                    &public_key,
-                    &proof,
-                    &commitment,
+                    public_key,
+                    proof,
+                    commitment,
                 )
             }
         }
@@ -100,13 +139,13 @@ impl<Curve> Shnorr<Scalar<Curve>, AffinePoint<Curve>> for Curve
         <Curve as CurveArithmetic>::AffinePoint: FromEncodedPoint<Curve> + ToEncodedPoint<Curve>,
         <Curve as elliptic_curve::Curve>::FieldBytesSize: ModulusSize
 {
-    fn proof<T>(&self, payload: &T, x: &Scalar<Curve>) -> (Scalar<Curve>, AffinePoint<Curve>)
+    fn proof<T>(&self, payload: &T, x: &Secret<Scalar<Curve>>) -> (Scalar<Curve>, AffinePoint<Curve>)
         where
             T: AsRef<[u8]>,
     {
         let (c, commitment) = commitment::<Curve>();
-        let challenge = challenge::<Curve, T>(&commitment.into(), payload);
-        let proof = c + x.mul(&challenge);
+        let challenge = challenge::<Curve, T>(&commitment, payload);
+        let proof = *c.expose_secret() + x.expose_secret().mul(&challenge);
         (proof, commitment)
     }
 
@@ -114,7 +153,7 @@ impl<Curve> Shnorr<Scalar<Curve>, AffinePoint<Curve>> for Curve
         where
             T: AsRef<[u8]>,
     {
-        let challenge = challenge::<Curve, T>(commitment.into(), payload);
+        let challenge = challenge::<Curve, T>(commitment, payload);
         let lhs = ProjectivePoint::<Curve>::generator() * proof;
         let commitment = ProjectivePoint::<Curve>::from(*commitment);
         let public_key = ProjectivePoint::<Curve>::from(*public_key);
@@ -150,12 +189,12 @@ mod tests {
             b"payload",
             &private_key,
         );
-        assert!(NistP256.verify(
+        assert!(!NistP256.verify(
             b"corrupted_payload",
             &public_key,
             &proof,
             &commitment
-        ) == false);
+        ));
     }
         
     #[test]
@@ -192,6 +231,23 @@ mod tests {
         );
     }
 
+    #[test]
+    fn compiled_curves_match_the_spec_tags() {
+        let (private_key, public_key) = commitment::<NistP256>();
+        let (proof, commitment) = NistP256.proof(b"payload", &private_key);
+        for curve in ShnorrProof::CURVES {
+            let json_request = serde_json::json!({
+                "spec": curve,
+                "commitment": hex::encode(commitment.to_encoded_point(false)),
+                "proof": hex::encode(proof.to_bytes()),
+                "public_key": hex::encode(public_key.to_encoded_point(false)),
+            }).to_string();
+            let proof = serde_json::from_str::<ShnorrProof>(&json_request).unwrap();
+            assert_eq!(proof.curve(), *curve);
+        }
+        assert_eq!(ShnorrProof::CURVES, Curve::ALL);
+    }
+
     #[test]
     fn invalid_deserialize_shnorr_proof() {        
         let (private_key, public_key) = commitment::<NistP256>();
@@ -8,6 +8,8 @@ use ecdsa::{Signature, SignatureSize};
 use serde::{Deserialize, Serialize};
 use signature::{Signer, Verifier};
 
+use crate::crypto::secret::SecretBytes;
+
 pub struct Token<T, Curve: elliptic_curve::PrimeCurve>
 where
     SignatureSize<Curve>: ArrayLength<u8>
@@ -26,6 +28,39 @@ where
             signature: Some(signature),
         }
     }
+
+    pub fn payload(&self) -> &T {
+        &self.payload
+    }
+
+    /// URL safe text form of the signed token, the payload isn't encrypted so it can be read by anyone
+    pub fn encode(&self) -> String
+    where
+        T: Serialize,
+    {
+        let payload_bytes = bincode::serialize(&self.payload).unwrap();
+        let signature_bytes = self.signature.as_ref().map(|signature| signature.to_bytes()).unwrap_or_default();
+        let bytes = [
+            &(payload_bytes.len() as u32).to_le_bytes(),
+            payload_bytes.as_slice(),
+            signature_bytes.as_slice(),
+        ].concat();
+        BASE64_URL_SAFE.encode(bytes)
+    }
+
+    /// Parses a token created by [`Token::encode`], the signature still has to be verified
+    pub fn decode(encoded: &str) -> Option<Self>
+    where
+        T: for<'de> Deserialize<'de>,
+    {
+        let bytes = BASE64_URL_SAFE.decode(encoded).ok()?;
+        let length = u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?) as usize;
+        let payload_bytes = bytes.get(4..4usize.checked_add(length)?)?;
+        let signature_bytes = &bytes[4 + length..];
+        let payload = bincode::deserialize(payload_bytes).ok()?;
+        let signature = Signature::from_slice(signature_bytes).ok()?;
+        Some(Self::new(payload, signature))
+    }
 }
 
 pub trait TokenSigner<T: Serialize, Curve: elliptic_curve::PrimeCurve>: Signer<Signature<Curve>>
@@ -53,6 +88,11 @@ pub trait TokenCipher<Curve: elliptic_curve::PrimeCurve>: KeyInit + AeadInPlace
 where
     SignatureSize<Curve>: ArrayLength<u8>
 {
+    /// Builds the cipher from raw key bytes without leaving extra copies of them around
+    fn from_secret_key(key: &SecretBytes) -> Result<Self, cipher::InvalidLength> {
+        Self::new_from_slice(key.expose_secret())
+    }
+
     fn encrypt_token<T: Serialize>(&self, token: &Token<T, Curve>) -> aead::Result<String> {
         let nonce = Self::generate_nonce(&mut rand::thread_rng());
         let payload_bytes = bincode::serialize(&token.payload).unwrap();
@@ -73,9 +113,12 @@ where
 
     fn decrypt_token<T: for<'de> Deserialize<'de>>(&self, encrypted: &str) -> aead::Result<Token<T, Curve>> {
         let bytes = BASE64_URL_SAFE.decode(encrypted).map_err(|_| aead::Error)?;
+        if bytes.len() < Self::NonceSize::to_usize() {
+            return Err(aead::Error);
+        }
         let nonce = Nonce::<Self>::from_slice(&bytes[..Self::NonceSize::to_usize()]);
         let bytes = &bytes[Self::NonceSize::to_usize()..];
-        let bytes = self.decrypt(&nonce, bytes)?;
+        let bytes = self.decrypt(nonce, bytes)?;
         let length_bytes = &bytes[..4];
         let length = u32::from_le_bytes(length_bytes.try_into().unwrap()) as usize;
         let bytes = &bytes[4..];
@@ -131,10 +174,17 @@ mod tests {
         let token = TokenSigner::sign(&signing_key, payload);
         assert!(TokenVerifier::verify(&verifying_key, &token));
 
-        let key = Aes256Gcm::generate_key(&mut rng);
-        let cipher = Aes256Gcm::new(&key);
+        let key = SecretBytes::new(Aes256Gcm::generate_key(&mut rng).to_vec());
+        let cipher = <Aes256Gcm as TokenCipher<NistP256>>::from_secret_key(&key).unwrap();
         let encrypted = cipher.encrypt_token(&token).unwrap();
         let decrypted: Token<String, NistP256> = cipher.decrypt_token(encrypted.as_str()).unwrap();
         assert!(TokenVerifier::verify(&verifying_key, &decrypted));
+        let truncated: aead::Result<Token<String, NistP256>> = cipher.decrypt_token("AAAA");
+        assert!(truncated.is_err());
+
+        let decoded: Token<String, NistP256> = Token::decode(&token.encode()).unwrap();
+        assert_eq!(decoded.payload(), "Hello, World!");
+        assert!(TokenVerifier::verify(&verifying_key, &decoded));
+        assert!(Token::<String, NistP256>::decode("not a token").is_none());
     }
 }
\ No newline at end of file
@@ -0,0 +1,172 @@
+//! Time-based one-time passwords (RFC 6238) over the HOTP algorithm of RFC 4226. Only HMAC-SHA1, 6 digits
+//! and 30 second steps are supported, the defaults of the `otpauth` URI format that every authenticator
+//! app understands
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use elliptic_curve::subtle::ConstantTimeEq;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+use crate::crypto::secret::SecretBytes;
+
+pub const DIGITS: usize = 6;
+pub const STEP: Duration = Duration::from_secs(30);
+/// Size of the generated secrets, the 160 bits RFC 4226 section 4 recommends
+pub const SECRET_SIZE: usize = 20;
+/// Accepted steps before and after the current one, to make up for clock drift and typing time
+pub const DRIFT_WINDOW: u64 = 1;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+pub fn generate_secret() -> SecretBytes {
+    SecretBytes::new(rand::random::<[u8; SECRET_SIZE]>().to_vec())
+}
+
+/// Number of steps since the unix epoch
+pub fn time_step(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / STEP.as_secs()
+}
+
+/// HOTP value of the `counter`, zero padded to [`DIGITS`]
+pub fn hotp(secret: &[u8], counter: u64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    // Dynamic truncation, RFC 4226 section 5.3
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([digest[offset] & 0x7f, digest[offset + 1], digest[offset + 2], digest[offset + 3]]);
+    format!("{:0width$}", binary % 10u32.pow(DIGITS as u32), width = DIGITS)
+}
+
+/// Step of the code generated at `time`, or within [`DRIFT_WINDOW`] steps of it. Only steps after
+/// `last_used_step` are tried so an accepted code can't be replayed
+pub fn verify(secret: &[u8], code: &str, time: SystemTime, last_used_step: Option<u64>) -> Option<u64> {
+    if code.len() != DIGITS || !code.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    let current = time_step(time);
+    let first = current.saturating_sub(DRIFT_WINDOW).max(last_used_step.map_or(0, |step| step + 1));
+    // NOTE: Every candidate is compared so the time taken doesn't tell which step matched
+    let mut matched = None;
+    for step in first..=current + DRIFT_WINDOW {
+        if bool::from(hotp(secret, step).as_bytes().ct_eq(code.as_bytes())) && matched.is_none() {
+            matched = Some(step);
+        }
+    }
+    matched
+}
+
+/// Unpadded RFC 4648 base32, the encoding of the secrets in `otpauth` URIs
+pub fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    for chunk in bytes.chunks(5) {
+        let mut buffer = [0u8; 5];
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        let bits = buffer.iter().fold(0u64, |bits, byte| bits << 8 | u64::from(*byte));
+        for index in 0..(chunk.len() * 8).div_ceil(5) {
+            encoded.push(BASE32_ALPHABET[(bits >> (35 - index * 5) & 0x1f) as usize] as char);
+        }
+    }
+    encoded
+}
+
+/// Decodes base32 ignoring padding, spaces and case, as users tend to type the secrets
+pub fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len() * 5 / 8);
+    let (mut bits, mut count) = (0u32, 0);
+    for character in encoded.bytes().filter(|byte| !b" =".contains(byte)) {
+        let value = BASE32_ALPHABET.iter().position(|symbol| *symbol == character.to_ascii_uppercase())?;
+        bits = bits << 5 | value as u32;
+        count += 5;
+        if count >= 8 {
+            count -= 8;
+            bytes.push((bits >> count) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            byte => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+/// Key URI of the secret, usually shown as a QR code for the authenticator app to scan
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &[u8]) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={DIGITS}&period={}",
+        percent_encode(issuer),
+        percent_encode(account),
+        base32_encode(secret),
+        percent_encode(issuer),
+        STEP.as_secs(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn rfc_vectors() {
+        // RFC 4226 appendix D
+        let expected = ["755224", "287082", "359152", "969429", "338314", "254676", "287922", "162583", "399871", "520489"];
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(hotp(SECRET, counter as u64), *code);
+        }
+        // RFC 6238 appendix B, truncated to 6 digits
+        for (seconds, code) in [(59, "287082"), (1111111109, "081804"), (1234567890, "005924"), (2000000000, "279037")] {
+            assert_eq!(hotp(SECRET, time_step(at(seconds))), code);
+        }
+    }
+
+    #[test]
+    fn drift_and_replay() {
+        let now = at(1111111109);
+        let step = time_step(now);
+        assert_eq!(verify(SECRET, &hotp(SECRET, step), now, None), Some(step));
+        assert_eq!(verify(SECRET, &hotp(SECRET, step - 1), now, None), Some(step - 1));
+        assert_eq!(verify(SECRET, &hotp(SECRET, step + 1), now, None), Some(step + 1));
+        assert_eq!(verify(SECRET, &hotp(SECRET, step - 2), now, None), None);
+        assert_eq!(verify(SECRET, &hotp(SECRET, step + 2), now, None), None);
+
+        assert_eq!(verify(SECRET, &hotp(SECRET, step), now, Some(step)), None);
+        assert_eq!(verify(SECRET, &hotp(SECRET, step - 1), now, Some(step - 1)), None);
+        assert_eq!(verify(SECRET, &hotp(SECRET, step + 1), now, Some(step)), Some(step + 1));
+
+        assert_eq!(verify(SECRET, "12345", now, None), None);
+        assert_eq!(verify(SECRET, "12345a", now, None), None);
+    }
+
+    #[test]
+    fn base32() {
+        // RFC 4648 section 10
+        for (decoded, encoded) in [("", ""), ("f", "MY"), ("fo", "MZXQ"), ("foo", "MZXW6"), ("foob", "MZXW6YQ"), ("fooba", "MZXW6YTB"), ("foobar", "MZXW6YTBOI")] {
+            assert_eq!(base32_encode(decoded.as_bytes()), encoded);
+            assert_eq!(base32_decode(encoded).unwrap(), decoded.as_bytes());
+        }
+        assert_eq!(base32_decode("mzxw 6ytb oi==").unwrap(), b"foobar");
+        assert_eq!(base32_decode("MZXW1"), None);
+        assert_eq!(base32_encode(SECRET), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    }
+
+    #[test]
+    fn key_uri() {
+        assert_eq!(
+            otpauth_uri("ACME Co", "alice@example.com", SECRET),
+            "otpauth://totp/ACME%20Co:alice%40example.com?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=ACME%20Co&algorithm=SHA1&digits=6&period=30",
+        );
+    }
+}
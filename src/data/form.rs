@@ -0,0 +1,53 @@
+//! `application/x-www-form-urlencoded` decoding, as used by the OAuth token requests and the `Basic`
+//! authorization credentials
+
+use std::collections::HashMap;
+
+/// Decodes one name or value, `+` is a space and `%XX` an escaped byte
+pub fn decode_component(value: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut input = value.bytes();
+    while let Some(byte) = input.next() {
+        bytes.push(match byte {
+            b'+' => b' ',
+            b'%' => {
+                let hex = [input.next()?, input.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            byte => byte,
+        });
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Parses a whole form or query string, a repeated name is an error as RFC 6749 section 3.1 requires
+pub fn parse(form: &str) -> Option<HashMap<String, String>> {
+    let mut fields = HashMap::new();
+    for pair in form.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        if fields.insert(decode_component(name)?, decode_component(value)?).is_some() {
+            return None;
+        }
+    }
+    Some(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_form() {
+        assert_eq!(decode_component("a%3Ab+c").as_deref(), Some("a:b c"));
+        assert_eq!(decode_component("%zz"), None);
+        assert_eq!(decode_component("%f"), None);
+
+        let form = parse("grant_type=authorization_code&code=Spl%2Fx&empty=&flag").unwrap();
+        assert_eq!(form["grant_type"], "authorization_code");
+        assert_eq!(form["code"], "Spl/x");
+        assert_eq!(form["empty"], "");
+        assert_eq!(form["flag"], "");
+        assert_eq!(parse("").unwrap().len(), 0);
+        assert_eq!(parse("code=a&code=b"), None);
+    }
+}
@@ -1,8 +1,8 @@
 use std::fmt::Display;
 use std::time::{Duration, SystemTime};
 use base64::Engine;
-use rand::Rng;
-use rand::rngs::ThreadRng;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
 use base64::prelude::BASE64_URL_SAFE;
 use serde::{Deserialize, Serialize};
 
@@ -27,29 +27,170 @@ const SERVICE_ID_MASK: u128 = (1 << SERVICE_ID_BITS) - 1;
 const WORKER_ID_MASK: u128 = (1 << WORKER_ID_BITS) - 1;
 const RANDOM_MASK: u128 = (1 << RANDOM_BITS) - 1;
 
+/// Both RFC 9562 UUIDv7 and ULID only reserve 48 bits for the unix timestamp in milliseconds
+const SHORT_TIMESTAMP_BITS: u8 = 48;
+const SHORT_TIMESTAMP_OFFSET: u8 = 128 - SHORT_TIMESTAMP_BITS;
+const SHORT_TIMESTAMP_MASK: u128 = (1 << SHORT_TIMESTAMP_BITS) - 1;
+
+/// Service, worker and random bits packed together, they are laid out the same way on every format
+const NODE_BITS: u8 = SERVICE_ID_BITS + WORKER_ID_BITS + RANDOM_BITS;
+const NODE_MASK: u128 = (1 << NODE_BITS) - 1;
+
+const _: () = assert!(NODE_BITS == SEQUENCE_OFFSET);
+
+const UUID_VERSION_OFFSET: u8 = 76;
+const UUID_VERSION_MASK: u128 = 0xf << UUID_VERSION_OFFSET;
+const UUID_VERSION_7: u128 = 0x7 << UUID_VERSION_OFFSET;
+const UUID_RAND_A_OFFSET: u8 = 64;
+const UUID_VARIANT_OFFSET: u8 = 62;
+const UUID_VARIANT_MASK: u128 = 0x3 << UUID_VARIANT_OFFSET;
+const UUID_VARIANT_RFC9562: u128 = 0x2 << UUID_VARIANT_OFFSET;
+const UUID_RAND_B_MASK: u128 = (1 << UUID_VARIANT_OFFSET) - 1;
+
+// NOTE: The sequence fits exactly on the 12 bits of `rand_a`, that makes the generated UUIDv7 monotonic
+// following the method 1 (fixed length dedicated counter) of RFC 9562 section 6.2
+const _: () = assert!(UUID_VERSION_OFFSET - UUID_RAND_A_OFFSET == SEQUENCE_BITS);
+const _: () = assert!(NODE_BITS <= UUID_VARIANT_OFFSET);
+const _: () = assert!(SEQUENCE_BITS + NODE_BITS <= SHORT_TIMESTAMP_OFFSET);
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, thiserror::Error)]
+pub enum IdentifierError {
+    #[error("the timestamp doesn't fit in 48 bits")]
+    TimestampOverflow,
+
+    #[error("the value isn't a RFC 9562 UUIDv7")]
+    NotUuidV7,
+
+    /// The value carries random bits that have no place on the identifier, use the lossy conversion to
+    /// drop them explicitly
+    #[error("the value can't be converted without losing information")]
+    Lossy,
+}
+
 #[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
 pub struct Identifier {
     timestamp: SystemTime,
     sequence: u16,
     service_id: u16,
     worker_id: u16,
-    random: u16,
+    random: u32,
 }
 
 impl Identifier {
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    pub fn sequence(&self) -> u16 {
+        self.sequence
+    }
+
+    pub fn service_id(&self) -> u16 {
+        self.service_id
+    }
+
+    pub fn worker_id(&self) -> u16 {
+        self.worker_id
+    }
+
+    pub fn random(&self) -> u32 {
+        self.random
+    }
+
+    fn timestamp_millis(&self) -> u128 {
+        self.timestamp.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis()
+    }
+
+    fn short_timestamp_millis(&self) -> Result<u128, IdentifierError> {
+        let timestamp = self.timestamp_millis();
+        if timestamp > SHORT_TIMESTAMP_MASK {
+            return Err(IdentifierError::TimestampOverflow);
+        }
+        Ok(timestamp)
+    }
+
+    fn node_bits(&self) -> u128 {
+        (self.service_id as u128) << SERVICE_ID_OFFSET |
+            (self.worker_id as u128) << WORKER_ID_OFFSET |
+            self.random as u128
+    }
+
+    fn from_parts(timestamp: u128, sequence: u128, node: u128) -> Self {
+        Self {
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_millis(timestamp as u64),
+            sequence: (sequence & SEQUENCE_MASK) as u16,
+            service_id: ((node >> SERVICE_ID_OFFSET) & SERVICE_ID_MASK) as u16,
+            worker_id: ((node >> WORKER_ID_OFFSET) & WORKER_ID_MASK) as u16,
+            random: (node & RANDOM_MASK) as u32,
+        }
+    }
+
+    /// Encodes the identifier as a RFC 9562 UUIDv7, the sequence goes into `rand_a` and the service, worker
+    /// and random bits into the low bits of `rand_b`, so the conversion is lossless
+    pub fn to_uuid_v7_bits(&self) -> Result<u128, IdentifierError> {
+        Ok(self.short_timestamp_millis()? << SHORT_TIMESTAMP_OFFSET |
+            UUID_VERSION_7 |
+            (self.sequence as u128) << UUID_RAND_A_OFFSET |
+            UUID_VARIANT_RFC9562 |
+            self.node_bits())
+    }
+
+    /// Decodes a UUIDv7, fails with [`IdentifierError::Lossy`] if the UUID has random bits set that don't
+    /// fit on the identifier, which is always the case for UUIDv7 not generated by this crate
+    pub fn from_uuid_v7_bits(bits: u128) -> Result<Self, IdentifierError> {
+        if bits & UUID_RAND_B_MASK & !NODE_MASK != 0 {
+            return Err(IdentifierError::Lossy);
+        }
+        Self::from_uuid_v7_bits_lossy(bits)
+    }
+
+    /// Same as [`Identifier::from_uuid_v7_bits`] but dropping the high bits of `rand_b`
+    pub fn from_uuid_v7_bits_lossy(bits: u128) -> Result<Self, IdentifierError> {
+        if bits & UUID_VERSION_MASK != UUID_VERSION_7 || bits & UUID_VARIANT_MASK != UUID_VARIANT_RFC9562 {
+            return Err(IdentifierError::NotUuidV7);
+        }
+        Ok(Self::from_parts(
+            bits >> SHORT_TIMESTAMP_OFFSET,
+            bits >> UUID_RAND_A_OFFSET,
+            bits & NODE_MASK,
+        ))
+    }
+
+    /// Encodes the identifier as a ULID, the 80 bits of randomness hold the sequence followed by the
+    /// service, worker and random bits, so the conversion is lossless
+    pub fn to_ulid_bits(&self) -> Result<u128, IdentifierError> {
+        Ok(self.short_timestamp_millis()? << SHORT_TIMESTAMP_OFFSET |
+            (self.sequence as u128) << NODE_BITS |
+            self.node_bits())
+    }
+
+    /// Decodes a ULID, fails with [`IdentifierError::Lossy`] if the high bits of the randomness are set
+    pub fn from_ulid_bits(bits: u128) -> Result<Self, IdentifierError> {
+        let randomness = bits & ((1 << SHORT_TIMESTAMP_OFFSET) - 1);
+        if randomness >> (SEQUENCE_BITS + NODE_BITS) != 0 {
+            return Err(IdentifierError::Lossy);
+        }
+        Ok(Self::from_ulid_bits_lossy(bits))
+    }
+
+    /// Same as [`Identifier::from_ulid_bits`] but dropping the high bits of the randomness
+    pub fn from_ulid_bits_lossy(bits: u128) -> Self {
+        Self::from_parts(
+            bits >> SHORT_TIMESTAMP_OFFSET,
+            bits >> NODE_BITS,
+            bits & NODE_MASK,
+        )
+    }
+
     pub fn as_base64(&self) -> String {
         let id: u128 = (*self).into();
         let bytes = id.to_be_bytes();
-        BASE64_URL_SAFE.encode(&bytes)
+        BASE64_URL_SAFE.encode(bytes)
     }
 
     pub fn from_base64(base64: &str) -> Option<Self> {
         let bytes = BASE64_URL_SAFE.decode(base64).ok()?;
-        let (bytes, rest) = bytes.split_at(std::mem::size_of::<u128>());
-        if !rest.is_empty() {
-            return None;
-        }
-        let id = u128::from_be_bytes(bytes.try_into().unwrap());
+        let id = u128::from_be_bytes(bytes.try_into().ok()?);
         Some(id.into())
     }
 
@@ -75,27 +216,67 @@ impl From<u128> for Identifier {
             timestamp: SystemTime::UNIX_EPOCH + Duration::from_millis(((id >> TIMESTAMP_OFFSET) & TIMESTAMP_MASK) as u64),
             sequence: ((id >> SEQUENCE_OFFSET) & SEQUENCE_MASK) as u16,
             service_id: ((id >> SERVICE_ID_OFFSET) & SERVICE_ID_MASK) as u16,
-            worker_id: ((id >> WORKER_ID_BITS) & WORKER_ID_MASK) as u16,
-            random: (id & RANDOM_MASK) as u16,
+            worker_id: ((id >> WORKER_ID_OFFSET) & WORKER_ID_MASK) as u16,
+            random: (id & RANDOM_MASK) as u32,
         }
     }
 }
 
 impl From<Identifier> for u128 {
     fn from(id: Identifier) -> u128 {
-        id.timestamp.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() << TIMESTAMP_OFFSET |
+        id.timestamp_millis() << TIMESTAMP_OFFSET |
             (id.sequence as u128) << SEQUENCE_OFFSET |
             (id.service_id as u128) << SERVICE_ID_OFFSET |
-            (id.worker_id as u128) << WORKER_ID_BITS |
+            (id.worker_id as u128) << WORKER_ID_OFFSET |
             id.random as u128
     }
 }
 
+#[cfg(feature = "uuid")]
+impl TryFrom<Identifier> for uuid::Uuid {
+    type Error = IdentifierError;
+
+    fn try_from(id: Identifier) -> Result<Self, Self::Error> {
+        Ok(uuid::Uuid::from_u128(id.to_uuid_v7_bits()?))
+    }
+}
+
+#[cfg(feature = "uuid")]
+impl TryFrom<uuid::Uuid> for Identifier {
+    type Error = IdentifierError;
+
+    fn try_from(uuid: uuid::Uuid) -> Result<Self, Self::Error> {
+        Identifier::from_uuid_v7_bits(uuid.as_u128())
+    }
+}
+
+#[cfg(feature = "ulid")]
+impl TryFrom<Identifier> for ulid::Ulid {
+    type Error = IdentifierError;
+
+    fn try_from(id: Identifier) -> Result<Self, Self::Error> {
+        Ok(ulid::Ulid(id.to_ulid_bits()?))
+    }
+}
+
+#[cfg(feature = "ulid")]
+impl TryFrom<ulid::Ulid> for Identifier {
+    type Error = IdentifierError;
+
+    fn try_from(ulid: ulid::Ulid) -> Result<Self, Self::Error> {
+        Identifier::from_ulid_bits(ulid.0)
+    }
+}
+
 impl Serialize for Identifier {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where S: serde::Serializer
     {
-        serializer.serialize_u128(u128::from(*self))
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.as_hex())
+        } else {
+            serializer.serialize_u128(u128::from(*self))
+        }
     }
 }
 
@@ -105,7 +286,7 @@ impl<'de> serde::de::Visitor<'de> for IdentifierVisitor {
     type Value = Identifier;
 
     fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-        formatter.write_str("a u128")
+        formatter.write_str("a u128 or its hex string")
     }
 
     fn visit_u128<E>(self, v: u128) -> Result<Self::Value, E>
@@ -113,57 +294,103 @@ impl<'de> serde::de::Visitor<'de> for IdentifierVisitor {
     {
         Ok(v.into())
     }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where E: serde::de::Error
+    {
+        Identifier::from_hex(v).ok_or_else(|| E::invalid_value(serde::de::Unexpected::Str(v), &self))
+    }
 }
 
 impl<'de> Deserialize<'de> for Identifier {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
         where D: serde::Deserializer<'de>
     {
-        deserializer.deserialize_u128(IdentifierVisitor)
+        // NOTE: JSON numbers can't carry 128 bits reliably, the text formats use the hex string
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(IdentifierVisitor)
+        } else {
+            deserializer.deserialize_u128(IdentifierVisitor)
+        }
     }
 }
 
+/// Layout of the raw bits emitted by [`IdentifierGenerator::generate_bits`]
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub enum GeneratorMode {
+    /// The identifier layout, with a 64 bits timestamp
+    #[default]
+    Native,
+
+    /// RFC 9562 UUIDv7 compatible values, see [`Identifier::to_uuid_v7_bits`]
+    UuidV7,
+}
+
 pub struct IdentifierGenerator {
     timestamp: u64,
     sequence: u16,
     service_id: u16,
     worker_id: u16,
-    rng: ThreadRng,
+    mode: GeneratorMode,
+    rng: StdRng,
 }
 
 impl IdentifierGenerator {
-    fn new(service_id: u16, worker_id: u16) -> Self {
+    pub fn new(service_id: u16, worker_id: u16) -> Self {
+        Self::with_mode(service_id, worker_id, GeneratorMode::Native)
+    }
+
+    pub fn with_mode(service_id: u16, worker_id: u16, mode: GeneratorMode) -> Self {
         Self {
             timestamp: 0,
             sequence: 0,
             service_id,
             worker_id,
-            rng: rand::thread_rng(),
+            mode,
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    pub fn mode(&self) -> GeneratorMode {
+        self.mode
+    }
+
+    pub fn generate(&mut self) -> Identifier {
+        self.tick();
+        Identifier {
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_millis(self.timestamp),
+            sequence: self.sequence,
+            service_id: self.service_id,
+            worker_id: self.worker_id,
+            random: self.rng.gen::<u32>() & RANDOM_MASK as u32,
         }
     }
 
-    fn generate(&mut self) -> Identifier {
-        self.generate_bits().into()
+    pub fn generate_bits(&mut self) -> u128 {
+        let id = self.generate();
+        match self.mode {
+            GeneratorMode::Native => id.into(),
+            // NOTE: The current time only overflows 48 bits of milliseconds on the year 10889
+            GeneratorMode::UuidV7 => id.to_uuid_v7_bits().unwrap(),
+        }
     }
 
-    fn generate_bits(&mut self) -> u128 {
+    /// Keeps the (timestamp, sequence) pair strictly increasing, when the sequence is exhausted or the
+    /// clock goes backwards the next millisecond is borrowed instead of repeating values
+    fn tick(&mut self) {
         let timestamp = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
             .as_millis() as u64;
-        let sequence = if timestamp == self.timestamp {
-            self.sequence + 1
+        if timestamp > self.timestamp {
+            self.timestamp = timestamp;
+            self.sequence = 0;
+        } else if (self.sequence as u128) < SEQUENCE_MASK {
+            self.sequence += 1;
         } else {
-            0
-        };
-        self.timestamp = timestamp;
-        self.sequence = sequence;
-        let random = self.rng.gen::<u16>();
-        (timestamp as u128) << TIMESTAMP_OFFSET |
-            (sequence as u128) << SEQUENCE_OFFSET |
-            (self.service_id as u128) << SERVICE_ID_OFFSET |
-            (self.worker_id as u128) << WORKER_ID_BITS |
-            random as u128
+            self.timestamp += 1;
+            self.sequence = 0;
+        }
     }
 }
 
@@ -210,6 +437,7 @@ mod tests {
         let base64 = id.as_base64();
         let id2 = Identifier::from_base64(&base64).unwrap();
         assert_eq!(id, id2);
+        assert_eq!(Identifier::from_base64("AAAA"), None);
     }
 
     #[test]
@@ -225,4 +453,97 @@ mod tests {
         let id2 = Identifier::from_hex(&hex).unwrap();
         assert_eq!(id, id2);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_serde() {
+        let id = Identifier::from(u128::MAX - 1);
+        let json = serde_json::to_string(&id).unwrap();
+        assert_eq!(json, format!("\"{}\"", id.as_hex()));
+        assert_eq!(serde_json::from_str::<Identifier>(&json).unwrap(), id);
+        assert!(serde_json::from_str::<Identifier>("\"not hex\"").is_err());
+        let bytes = bincode::serialize(&id).unwrap();
+        assert_eq!(bytes, (u128::MAX - 1).to_le_bytes());
+        assert_eq!(bincode::deserialize::<Identifier>(&bytes).unwrap(), id);
+    }
+
+    #[test]
+    fn test_getters() {
+        let id = Identifier::from(1u128 << TIMESTAMP_OFFSET | 2 << SEQUENCE_OFFSET | 3 << SERVICE_ID_OFFSET | 4 << WORKER_ID_OFFSET | 5);
+        assert_eq!(id.timestamp(), SystemTime::UNIX_EPOCH + Duration::from_millis(1));
+        assert_eq!(id.sequence(), 2);
+        assert_eq!(id.service_id(), 3);
+        assert_eq!(id.worker_id(), 4);
+        assert_eq!(id.random(), 5);
+        assert_eq!(Identifier::from(u128::from(id)), id);
+    }
+
+    #[test]
+    fn test_uuid_v7() {
+        let mut generator = IdentifierGenerator::new(3, 4);
+        let id = generator.generate();
+        let bits = id.to_uuid_v7_bits().unwrap();
+        assert_eq!((bits >> 76) & 0xf, 7);
+        assert_eq!((bits >> 62) & 0x3, 2);
+        assert_eq!(bits >> 80, u128::from(id) >> TIMESTAMP_OFFSET);
+        assert_eq!(Identifier::from_uuid_v7_bits(bits), Ok(id));
+    }
+
+    #[test]
+    fn test_uuid_v7_lossy() {
+        // 0190b8a4-7c3e-7def-bf12-3456789abcde
+        let bits = 0x0190b8a4_7c3e_7def_bf12_3456789abcde_u128;
+        assert_eq!(Identifier::from_uuid_v7_bits(bits), Err(IdentifierError::Lossy));
+        let id = Identifier::from_uuid_v7_bits_lossy(bits).unwrap();
+        assert_eq!(id.timestamp(), SystemTime::UNIX_EPOCH + Duration::from_millis(0x0190b8a47c3e));
+        assert_eq!(id.sequence(), 0xdef);
+        assert_eq!(id.to_uuid_v7_bits().unwrap(), bits & !(0x3ff << NODE_BITS));
+
+        // Version 4 UUID
+        let bits = 0x9f2c4a1e_0b7d_4c3a_8e5f_1a2b3c4d5e6f_u128;
+        assert_eq!(Identifier::from_uuid_v7_bits_lossy(bits), Err(IdentifierError::NotUuidV7));
+    }
+
+    #[test]
+    fn test_ulid() {
+        let mut generator = IdentifierGenerator::new(3, 4);
+        let id = generator.generate();
+        let bits = id.to_ulid_bits().unwrap();
+        assert_eq!(bits >> 80, u128::from(id) >> TIMESTAMP_OFFSET);
+        assert_eq!(Identifier::from_ulid_bits(bits), Ok(id));
+        assert_eq!(Identifier::from_ulid_bits(bits | 1 << 79), Err(IdentifierError::Lossy));
+        assert_eq!(Identifier::from_ulid_bits_lossy(bits | 1 << 79), id);
+    }
+
+    #[test]
+    fn test_timestamp_overflow() {
+        let id = Identifier::from(1u128 << (TIMESTAMP_OFFSET + SHORT_TIMESTAMP_BITS));
+        assert_eq!(id.to_uuid_v7_bits(), Err(IdentifierError::TimestampOverflow));
+        assert_eq!(id.to_ulid_bits(), Err(IdentifierError::TimestampOverflow));
+    }
+
+    #[test]
+    fn test_generate_uuid_v7_monotonic() {
+        let mut generator = IdentifierGenerator::with_mode(0, 0, GeneratorMode::UuidV7);
+        let ids = (0..10000).map(|_| generator.generate_bits()).collect::<Vec<_>>();
+        assert!(ids.windows(2).all(|pair| pair[0] >> NODE_BITS < pair[1] >> NODE_BITS));
+        assert!(ids.iter().all(|bits| Identifier::from_uuid_v7_bits(*bits).is_ok()));
+    }
+
+    #[cfg(feature = "uuid")]
+    #[test]
+    fn test_uuid_crate() {
+        let id = IdentifierGenerator::new(3, 4).generate();
+        let uuid = uuid::Uuid::try_from(id).unwrap();
+        assert_eq!(uuid.get_version(), Some(uuid::Version::SortRand));
+        assert_eq!(Identifier::try_from(uuid), Ok(id));
+    }
+
+    #[cfg(feature = "ulid")]
+    #[test]
+    fn test_ulid_crate() {
+        let id = IdentifierGenerator::new(3, 4).generate();
+        let ulid = ulid::Ulid::try_from(id).unwrap();
+        assert_eq!(ulid.timestamp_ms() as u128, id.timestamp_millis());
+        assert_eq!(Identifier::try_from(ulid), Ok(id));
+    }
+}
@@ -1 +1,2 @@
+pub mod form;
 pub mod id;
\ No newline at end of file
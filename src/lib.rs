@@ -1,4 +1,10 @@
+pub mod api;
+pub mod client;
 pub mod crypto;
 pub mod data;
+pub mod model;
 pub mod store;
-pub mod service;
\ No newline at end of file
+pub mod service;
+pub mod telemetry;
+#[cfg(feature = "wasm")]
+pub mod wasm;
@@ -1,13 +1,91 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
 use crate::data::id::Identifier;
-use crate::model::store::UserStore;
-use crate::model::store::StoreError;
-
-#[async_trait::async_trait]
-pub trait Client: UserStore {
-    async fn get_id(&self) -> Result<Identifier, StoreError>;
-    async fn get_parent_id(&self) -> Result<Identifier, StoreError>;
-
-    async fn get_encryption_key(&self) -> Result<Vec<u8>, StoreError>;
-    async fn get_signing_key_bytes(&self) -> Result<Vec<u8>, StoreError>;
-    async fn get_verification_key(&self) -> Result<Vec<u8>, StoreError>;
-}
\ No newline at end of file
+
+pub trait Client {
+    fn get_id(&self) -> Identifier;
+    fn get_parent_id(&self) -> Option<Identifier>;
+    fn get_name(&self) -> &str;
+    /// Incremented by the store on every update, see [`crate::store::ClientUpdate::expected_version`]
+    fn get_version(&self) -> u64;
+    /// Settings set on this client only, the unset ones are inherited from the parent clients
+    fn get_settings(&self) -> &ClientSettings;
+    /// Disabling a client disables all of its descendants too
+    fn is_disabled(&self) -> bool;
+}
+
+/// Curves accepted for the Schnorr proofs of the users
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Curve {
+    #[serde(rename = "p256")]
+    NistP256,
+}
+
+impl Curve {
+    pub const ALL: &'static [Curve] = &[Curve::NistP256];
+
+    /// Parses the serialized name of the curve, the `spec` tag of the proofs
+    pub fn from_name(name: &str) -> Option<Curve> {
+        Curve::ALL.iter().copied().find(|curve| curve.name() == name)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Curve::NistP256 => "p256",
+        }
+    }
+
+    /// JWS algorithm of the signatures made with keys of the curve, RFC 7518 section 3.1
+    pub fn jws_algorithm(&self) -> &'static str {
+        match self {
+            Curve::NistP256 => "ES256",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Branding {
+    pub display_name: Option<String>,
+    pub logo_url: Option<String>,
+    pub primary_color: Option<String>,
+}
+
+impl Branding {
+    fn inherit(self, parent: &Branding) -> Self {
+        Self {
+            display_name: self.display_name.or_else(|| parent.display_name.clone()),
+            logo_url: self.logo_url.or_else(|| parent.logo_url.clone()),
+            primary_color: self.primary_color.or_else(|| parent.primary_color.clone()),
+        }
+    }
+}
+
+/// Per client configuration, every `None` field falls back to the parent client
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientSettings {
+    pub allowed_curves: Option<Vec<Curve>>,
+    pub access_token_lifetime: Option<Duration>,
+    pub refresh_token_lifetime: Option<Duration>,
+    pub branding: Branding,
+    /// Exact redirect URIs accepted by the authorization endpoint, unlike the rest of the settings they
+    /// aren't inherited
+    pub redirect_uris: Vec<String>,
+    /// Scopes granted to the client itself by the `client_credentials` grant, not inherited either
+    pub scopes: Vec<String>,
+}
+
+impl ClientSettings {
+    /// Fills the unset fields with the ones of the parent
+    pub fn inherit(self, parent: &ClientSettings) -> Self {
+        Self {
+            allowed_curves: self.allowed_curves.or_else(|| parent.allowed_curves.clone()),
+            access_token_lifetime: self.access_token_lifetime.or(parent.access_token_lifetime),
+            refresh_token_lifetime: self.refresh_token_lifetime.or(parent.refresh_token_lifetime),
+            branding: self.branding.inherit(&parent.branding),
+            redirect_uris: self.redirect_uris,
+            scopes: self.scopes,
+        }
+    }
+}
@@ -1,6 +1,5 @@
-pub mod store;
 mod client;
 mod user;
 
-pub use client::Client;
-pub use user::User;
\ No newline at end of file
+pub use client::{Branding, Client, ClientSettings, Curve};
+pub use user::User;
@@ -1,9 +1,12 @@
 use crate::data::id::Identifier;
 
 pub trait User {
-    type UserMetadata;
+    type UserMetadata: Clone + Send + Sync;
 
     fn get_id(&self) -> Identifier;
     fn get_client_id(&self) -> Identifier;
+    fn get_email(&self) -> &str;
+    /// Incremented by the store on every update, see [`crate::store::UserUpdate::expected_version`]
+    fn get_version(&self) -> u64;
     fn get_user_metadata(&self) -> Option<Self::UserMetadata>;
-}
\ No newline at end of file
+}
@@ -0,0 +1,436 @@
+//! Audit trail of the authentication events. The services report every login, registration, key change
+//! and token issuance to the [`AuditSink`] they are configured with, failures included. An operation
+//! whose event can't be recorded fails with an internal error, even when the attempt itself failed.
+//!
+//! [`AuditLog`] is a sink writing to an [`AuditStore`] as a hash chain: every record commits to the hash
+//! of the previous one and the hash is signed, so [`verify_log`] notices records that were modified,
+//! removed or inserted. Removing the newest records only shows against a [`Checkpoint`] kept somewhere
+//! else, like a copy of the last head published from time to time
+
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use elliptic_curve::subtle::ConstantTimeEq;
+use p256::ecdsa::signature::{Signer, Verifier};
+use p256::ecdsa::{Signature, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::data::id::Identifier;
+use crate::store::{retry, AuditRecord, AuditStore, RetryPolicy, StoreError};
+use crate::telemetry::{self, FailureReason};
+
+/// Hash the first record of a log links to
+pub const GENESIS_HASH: [u8; 32] = [0; 32];
+/// Appends racing with other writers are retried right away up to this many times
+pub const MAX_APPEND_ATTEMPTS: u32 = 16;
+/// Records read at once by [`verify_log`]
+const PAGE_SIZE: usize = 500;
+
+#[derive(thiserror::Error, Debug)]
+pub enum AuditError {
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error("malformed audit event: {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error(transparent)]
+    Chain(#[from] ChainError),
+}
+
+/// Why a log doesn't verify, the sequence is the first record found wrong
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum ChainError {
+    #[error("expected the record {expected}, found {found}")]
+    Missing { expected: u64, found: u64 },
+    #[error("the record {0} doesn't link to the previous one")]
+    BrokenLink(u64),
+    #[error("the record {0} doesn't match its hash")]
+    Modified(u64),
+    #[error("the signature of the record {0} is invalid")]
+    InvalidSignature(u64),
+    #[error("the log ends before the checkpoint at {0}")]
+    Truncated(u64),
+    #[error("the log differs from the checkpoint at {0}")]
+    CheckpointMismatch(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Login,
+    SecondFactor,
+    PasskeyLogin,
+    Registration,
+    /// A passkey, a second factor or a public key was added to the account, or a public key revoked
+    KeyChange,
+    TokenIssued,
+}
+
+impl AuditAction {
+    /// Serialized name, also the `action` label of the metrics
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Login => "login",
+            AuditAction::SecondFactor => "second_factor",
+            AuditAction::PasskeyLogin => "passkey_login",
+            AuditAction::Registration => "registration",
+            AuditAction::KeyChange => "key_change",
+            AuditAction::TokenIssued => "token_issued",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOutcome {
+    Success,
+    /// The first factor of a login was accepted, the second one is still missing
+    Pending,
+    Failure,
+}
+
+impl AuditOutcome {
+    /// Serialized name, also the `outcome` label of the metrics
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditOutcome::Success => "success",
+            AuditOutcome::Pending => "pending",
+            AuditOutcome::Failure => "failure",
+        }
+    }
+}
+
+/// What is known about the request that caused the event
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestMetadata {
+    pub ip: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEvent {
+    /// Milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub action: AuditAction,
+    /// User who acted, when known
+    pub actor: Option<Identifier>,
+    pub client_id: Option<Identifier>,
+    /// What the action was about: the email of a login or a registration, the key that was added, the
+    /// scope of the issued tokens
+    pub subject: Option<String>,
+    pub outcome: AuditOutcome,
+    /// Error of a failure
+    pub reason: Option<String>,
+    pub request: RequestMetadata,
+}
+
+impl AuditEvent {
+    /// Successful event at `now`, the other fields are filled in by the caller
+    pub fn new(action: AuditAction, now: SystemTime) -> Self {
+        Self {
+            timestamp: now.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            action,
+            actor: None,
+            client_id: None,
+            subject: None,
+            outcome: AuditOutcome::Success,
+            reason: None,
+            request: RequestMetadata::default(),
+        }
+    }
+
+    pub fn time(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(self.timestamp)
+    }
+
+    /// Event stored in a record of an [`AuditLog`], the record itself is checked by [`verify_log`]
+    pub fn decode(record: &AuditRecord) -> Result<Self, AuditError> {
+        Ok(serde_json::from_slice(&record.data)?)
+    }
+}
+
+/// Destination of the audit events
+#[async_trait::async_trait]
+pub trait AuditSink: Send + Sync {
+    async fn record(&self, event: &AuditEvent) -> Result<(), AuditError>;
+}
+
+impl fmt::Debug for dyn AuditSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuditSink")
+    }
+}
+
+/// Counts the outcome of an attempt and records it on the sink, if there is one. An event the sink
+/// can't record is logged, counted on [`telemetry::DROPPED_AUDIT_EVENTS`] and returned, the caller then
+/// answers the attempt with an internal error
+pub(crate) async fn report<T, E: fmt::Display + FailureReason>(
+    sink: Option<&dyn AuditSink>,
+    mut event: AuditEvent,
+    result: &Result<T, E>,
+    pending: bool,
+) -> Result<(), AuditError> {
+    event.outcome = match result {
+        Ok(_) if pending => AuditOutcome::Pending,
+        Ok(_) => AuditOutcome::Success,
+        Err(_) => AuditOutcome::Failure,
+    };
+    event.reason = result.as_ref().err().map(ToString::to_string);
+    let reason = result.as_ref().err().map_or("", |error| error.failure_reason()).to_string();
+    tracing::info!(action = event.action.as_str(), outcome = event.outcome.as_str(), reason, "authentication event");
+    metrics::counter!(
+        telemetry::AUTH_EVENTS,
+        "action" => event.action.as_str(),
+        "outcome" => event.outcome.as_str(),
+        "reason" => reason,
+    )
+    .increment(1);
+
+    let Some(sink) = sink else {
+        return Ok(());
+    };
+    // NOTE: Failed attempts are refused too, otherwise a broken sink would hide a brute force
+    sink.record(&event).await.inspect_err(|error| {
+        tracing::error!(action = event.action.as_str(), outcome = event.outcome.as_str(), %error, "failed to record audit event");
+        metrics::counter!(telemetry::DROPPED_AUDIT_EVENTS, "action" => event.action.as_str()).increment(1);
+    })
+}
+
+fn chain_hash(previous_hash: &[u8], sequence: u64, data: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(previous_hash);
+    hasher.update(sequence.to_be_bytes());
+    hasher.update(data);
+    hasher.finalize().to_vec()
+}
+
+/// Hash chained and signed log on top of an [`AuditStore`]
+pub struct AuditLog<S: AuditStore> {
+    state: S::State,
+    signing_key: SigningKey,
+}
+
+impl<S: AuditStore> AuditLog<S> {
+    pub fn new(state: S::State, signing_key: SigningKey) -> Self {
+        Self { state, signing_key }
+    }
+
+    /// Key to give to [`verify_log`]
+    pub fn verifying_key(&self) -> VerifyingKey {
+        *self.signing_key.verifying_key()
+    }
+
+    /// Appends the event after the last record, the record is returned so its hash can be kept as a
+    /// [`Checkpoint`]
+    pub async fn append(&self, event: &AuditEvent) -> Result<AuditRecord, AuditError> {
+        let data = serde_json::to_vec(event)?;
+        let policy = RetryPolicy { max_attempts: MAX_APPEND_ATTEMPTS, initial_backoff: Duration::ZERO, max_backoff: Duration::ZERO };
+        let record = retry(&policy, |_| std::future::ready(()), || async {
+            let last = S::last_audit_record(self.state.clone()).await.map_err(Into::into)?;
+            let (sequence, previous_hash) = match last {
+                Some(last) => (last.sequence + 1, last.hash),
+                None => (0, GENESIS_HASH.to_vec()),
+            };
+            let hash = chain_hash(&previous_hash, sequence, &data);
+            let signature: Signature = self.signing_key.sign(&hash);
+            let record = AuditRecord { sequence, data: data.clone(), previous_hash, hash, signature: signature.to_bytes().to_vec() };
+            S::append_audit_record(self.state.clone(), record.clone()).await.map_err(Into::into)?;
+            Ok::<_, StoreError>(record)
+        }).await?;
+        Ok(record)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S: AuditStore> AuditSink for AuditLog<S> {
+    async fn record(&self, event: &AuditEvent) -> Result<(), AuditError> {
+        self.append(event).await.map(|_| ())
+    }
+}
+
+/// Head of a log known to be genuine, a log that was cut short or rewritten before it doesn't verify
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub sequence: u64,
+    pub hash: Vec<u8>,
+}
+
+impl From<&AuditRecord> for Checkpoint {
+    fn from(record: &AuditRecord) -> Self {
+        Self { sequence: record.sequence, hash: record.hash.clone() }
+    }
+}
+
+/// Checks the records one by one in order, starting at the first record of the log
+pub struct ChainVerifier {
+    verifying_key: VerifyingKey,
+    next_sequence: u64,
+    previous_hash: Vec<u8>,
+}
+
+impl ChainVerifier {
+    pub fn new(verifying_key: VerifyingKey) -> Self {
+        Self { verifying_key, next_sequence: 0, previous_hash: GENESIS_HASH.to_vec() }
+    }
+
+    pub fn push(&mut self, record: &AuditRecord) -> Result<(), ChainError> {
+        let sequence = record.sequence;
+        if sequence != self.next_sequence {
+            return Err(ChainError::Missing { expected: self.next_sequence, found: sequence });
+        }
+        if record.previous_hash != self.previous_hash {
+            return Err(ChainError::BrokenLink(sequence));
+        }
+        if !bool::from(chain_hash(&record.previous_hash, sequence, &record.data).ct_eq(&record.hash)) {
+            return Err(ChainError::Modified(sequence));
+        }
+        let signature = Signature::from_slice(&record.signature).map_err(|_| ChainError::InvalidSignature(sequence))?;
+        self.verifying_key.verify(&record.hash, &signature).map_err(|_| ChainError::InvalidSignature(sequence))?;
+        self.next_sequence += 1;
+        self.previous_hash = record.hash.clone();
+        Ok(())
+    }
+
+    /// Last record checked, `None` before the first one
+    pub fn head(&self) -> Option<Checkpoint> {
+        let sequence = self.next_sequence.checked_sub(1)?;
+        Some(Checkpoint { sequence, hash: self.previous_hash.clone() })
+    }
+}
+
+/// Reads the whole log and checks the chain, the signatures and the `checkpoint` when given. Returns the
+/// head of the log, to compare with later runs
+pub async fn verify_log<S: AuditStore>(
+    state: S::State,
+    verifying_key: VerifyingKey,
+    checkpoint: Option<&Checkpoint>,
+) -> Result<Option<Checkpoint>, AuditError> {
+    let mut verifier = ChainVerifier::new(verifying_key);
+    loop {
+        let from = verifier.next_sequence;
+        let records = S::list_audit_records(state.clone(), from, PAGE_SIZE).await.map_err(Into::into)?;
+        for record in &records {
+            verifier.push(record)?;
+            if let Some(checkpoint) = checkpoint.filter(|checkpoint| checkpoint.sequence == record.sequence) {
+                if checkpoint.hash != record.hash {
+                    return Err(ChainError::CheckpointMismatch(checkpoint.sequence).into());
+                }
+            }
+        }
+        if records.len() < PAGE_SIZE {
+            break;
+        }
+    }
+    if let Some(checkpoint) = checkpoint.filter(|checkpoint| checkpoint.sequence >= verifier.next_sequence) {
+        return Err(ChainError::Truncated(checkpoint.sequence).into());
+    }
+    Ok(verifier.head())
+}
+
+#[cfg(all(test, feature = "in-memory"))]
+mod tests {
+    use crate::service::LoginError;
+    use crate::store::memory::{InMemoryState, InMemoryStore};
+    use crate::telemetry::fixtures::TestRecorder;
+
+    use super::*;
+
+    struct UnavailableSink;
+
+    #[async_trait::async_trait]
+    impl AuditSink for UnavailableSink {
+        async fn record(&self, _: &AuditEvent) -> Result<(), AuditError> {
+            Err(StoreError::Unknown.into())
+        }
+    }
+
+    fn event(index: u64) -> AuditEvent {
+        AuditEvent {
+            subject: Some(format!("user{index}@example.com")),
+            ..AuditEvent::new(AuditAction::Login, UNIX_EPOCH + Duration::from_secs(1_700_000_000 + index))
+        }
+    }
+
+    async fn log_with(state: &InMemoryState, count: u64) -> (AuditLog<InMemoryStore>, Vec<AuditRecord>) {
+        let log = AuditLog::<InMemoryStore>::new(state.clone(), SigningKey::random(&mut rand::thread_rng()));
+        let mut records = vec![];
+        for index in 0..count {
+            records.push(log.append(&event(index)).await.unwrap());
+        }
+        (log, records)
+    }
+
+    fn verify(log: &AuditLog<InMemoryStore>, records: &[AuditRecord]) -> Result<(), ChainError> {
+        let mut verifier = ChainVerifier::new(log.verifying_key());
+        records.iter().try_for_each(|record| verifier.push(record))
+    }
+
+    #[tokio::test]
+    async fn chain() {
+        let state = InMemoryState::new();
+        let (log, records) = log_with(&state, 3).await;
+        assert_eq!(records[0].previous_hash, GENESIS_HASH);
+        assert_eq!(records[1].previous_hash, records[0].hash);
+        assert_eq!(AuditEvent::decode(&records[2]).unwrap(), event(2));
+
+        let head = verify_log::<InMemoryStore>(state.clone(), log.verifying_key(), None).await.unwrap();
+        assert_eq!(head, Some(Checkpoint::from(&records[2])));
+        let checkpoint = Checkpoint::from(&records[1]);
+        verify_log::<InMemoryStore>(state.clone(), log.verifying_key(), Some(&checkpoint)).await.unwrap();
+        let empty = InMemoryState::new();
+        assert_eq!(verify_log::<InMemoryStore>(empty, log.verifying_key(), None).await.unwrap(), None);
+
+        // Anyone without the signing key is told apart
+        let other = SigningKey::random(&mut rand::thread_rng());
+        let result = verify_log::<InMemoryStore>(state, *other.verifying_key(), None).await;
+        assert!(matches!(result, Err(AuditError::Chain(ChainError::InvalidSignature(0)))));
+    }
+
+    #[tokio::test]
+    async fn tampering() {
+        let state = InMemoryState::new();
+        let (log, records) = log_with(&state, 4).await;
+        verify(&log, &records).unwrap();
+
+        let mut modified = records.clone();
+        modified[1].data = serde_json::to_vec(&event(9)).unwrap();
+        assert_eq!(verify(&log, &modified), Err(ChainError::Modified(1)));
+        // Recomputing the hash breaks the signature, and the link of the next record
+        modified[1].hash = chain_hash(&modified[1].previous_hash, 1, &modified[1].data);
+        assert_eq!(verify(&log, &modified), Err(ChainError::InvalidSignature(1)));
+
+        let mut deleted = records.clone();
+        deleted.remove(2);
+        assert_eq!(verify(&log, &deleted), Err(ChainError::Missing { expected: 2, found: 3 }));
+        assert_eq!(verify(&log, &records[1..]), Err(ChainError::Missing { expected: 0, found: 1 }));
+        let mut renumbered = deleted.clone();
+        renumbered[2].sequence = 2;
+        assert_eq!(verify(&log, &renumbered), Err(ChainError::BrokenLink(2)));
+
+        // Cutting the end only shows against a checkpoint
+        let checkpoint = Checkpoint::from(&records[3]);
+        let truncated = InMemoryState::new();
+        for record in &records[..3] {
+            InMemoryStore::append_audit_record(truncated.clone(), record.clone()).await.unwrap();
+        }
+        verify_log::<InMemoryStore>(truncated.clone(), log.verifying_key(), None).await.unwrap();
+        let result = verify_log::<InMemoryStore>(truncated, log.verifying_key(), Some(&checkpoint)).await;
+        assert!(matches!(result, Err(AuditError::Chain(ChainError::Truncated(3)))));
+        let result = verify_log::<InMemoryStore>(state, log.verifying_key(), Some(&Checkpoint { hash: vec![0; 32], ..checkpoint })).await;
+        assert!(matches!(result, Err(AuditError::Chain(ChainError::CheckpointMismatch(3)))));
+    }
+
+    #[tokio::test]
+    async fn unrecorded_events() {
+        let recorder = TestRecorder::default();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let sink: &dyn AuditSink = &UnavailableSink;
+
+        let success: Result<(), LoginError> = Ok(());
+        assert!(report(Some(sink), event(0), &success, false).await.is_err());
+        let failure: Result<(), LoginError> = Err(LoginError::InvalidProof);
+        assert!(report(Some(sink), event(1), &failure, false).await.is_err());
+        assert!(report(None, event(2), &failure, false).await.is_ok());
+        assert_eq!(recorder.counter("iam0_audit_events_dropped_total{action=login}"), 2);
+    }
+}
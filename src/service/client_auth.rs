@@ -0,0 +1,352 @@
+//! Authentication of confidential clients at the token endpoint, RFC 6749 section 2.3 and RFC 7523.
+//! Secrets are only stored as Argon2id hashes, and `private_key_jwt` assertions are ES256 JWTs signed
+//! with the key registered on the client.
+//!
+//! NOTE: The `jti` of the assertions isn't tracked, replays are bounded by rejecting assertions that
+//! expire later than [`MAX_ASSERTION_LIFETIME`]
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use base64::Engine;
+use base64::prelude::{BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD};
+use p256::ecdsa::VerifyingKey;
+use serde::Deserialize;
+
+use crate::crypto::jwt;
+use crate::crypto::secret::Secret;
+use crate::data::form;
+use crate::data::id::Identifier;
+use crate::model::Client;
+use crate::service::oauth::{OAuthError, TokenRequest};
+use crate::store::{ClientCredentials, ClientStore, NewClient, StoreError, Transaction};
+
+pub const CLIENT_ASSERTION_TYPE: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+/// Names of the supported methods as registered by OpenID Connect Core 1.0 section 9
+pub const TOKEN_ENDPOINT_AUTH_METHODS: &[&str] = &["none", "client_secret_basic", "client_secret_post", "private_key_jwt"];
+pub const MAX_ASSERTION_LIFETIME: Duration = Duration::from_secs(5 * 60);
+/// Random bytes of the generated client secrets
+const CLIENT_SECRET_SIZE: usize = 32;
+
+/// How the client authenticated on a token request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientAuthentication {
+    /// Public clients only send their identifier
+    None { client_id: Identifier },
+    /// `client_secret_basic`, the credentials come in the `Authorization` header
+    SecretBasic { client_id: Identifier, client_secret: String },
+    /// `client_secret_post`, the credentials come in the request body
+    SecretPost { client_id: Identifier, client_secret: String },
+    /// `private_key_jwt`, the body carries a JWT signed by the client
+    PrivateKeyJwt { client_id: Identifier, assertion: String },
+}
+
+impl ClientAuthentication {
+    /// Reads the authentication method of the request, using more than one method is an error
+    pub fn from_request(request: &TokenRequest, authorization_header: Option<&str>) -> Result<Self, OAuthError> {
+        let basic = authorization_header.map(parse_basic_authorization).transpose()?;
+        let methods = [basic.is_some(), request.client_secret.is_some(), request.client_assertion.is_some()];
+        if methods.into_iter().filter(|used| *used).count() > 1 {
+            return Err(OAuthError::InvalidRequest("more than one client authentication method was used".to_string()));
+        }
+
+        if let Some((client_id, client_secret)) = basic {
+            if request.client_id.is_some_and(|id| id != client_id) {
+                return Err(OAuthError::InvalidRequest("the client_id doesn't match the Authorization header".to_string()));
+            }
+            return Ok(ClientAuthentication::SecretBasic { client_id, client_secret });
+        }
+        let Some(client_id) = request.client_id else {
+            return Err(OAuthError::InvalidClient("the client_id is required".to_string()));
+        };
+        if let Some(assertion) = &request.client_assertion {
+            if request.client_assertion_type.as_deref() != Some(CLIENT_ASSERTION_TYPE) {
+                return Err(OAuthError::InvalidRequest("unsupported client_assertion_type".to_string()));
+            }
+            return Ok(ClientAuthentication::PrivateKeyJwt { client_id, assertion: assertion.clone() });
+        }
+        Ok(match &request.client_secret {
+            Some(client_secret) => ClientAuthentication::SecretPost { client_id, client_secret: client_secret.clone() },
+            None => ClientAuthentication::None { client_id },
+        })
+    }
+
+    pub fn client_id(&self) -> Identifier {
+        match self {
+            ClientAuthentication::None { client_id }
+            | ClientAuthentication::SecretBasic { client_id, .. }
+            | ClientAuthentication::SecretPost { client_id, .. }
+            | ClientAuthentication::PrivateKeyJwt { client_id, .. } => *client_id,
+        }
+    }
+
+    /// Checks the credentials against the registered ones. Public clients can't authenticate, and
+    /// confidential clients must, so the returned flag tells if the client is confidential
+    pub fn verify(&self, credentials: &ClientCredentials, audience: &str, now: SystemTime) -> Result<bool, OAuthError> {
+        let verified = match self {
+            ClientAuthentication::None { .. } => {
+                return match credentials.is_confidential() {
+                    true => Err(OAuthError::InvalidClient("the client must authenticate".to_string())),
+                    false => Ok(false),
+                };
+            }
+            ClientAuthentication::SecretBasic { client_secret, .. } | ClientAuthentication::SecretPost { client_secret, .. } => {
+                credentials.secret_hash.as_deref().is_some_and(|hash| verify_client_secret(hash, client_secret))
+            }
+            ClientAuthentication::PrivateKeyJwt { client_id, assertion } => {
+                credentials.public_key.as_deref().is_some_and(|key| verify_assertion(key, assertion, *client_id, audience, now))
+            }
+        };
+        match verified {
+            true => Ok(true),
+            false => Err(OAuthError::InvalidClient("client authentication failed".to_string())),
+        }
+    }
+}
+
+fn invalid_authorization() -> OAuthError {
+    OAuthError::InvalidClient("malformed Authorization header".to_string())
+}
+
+/// `Basic base64(client_id:client_secret)` where both parts are form URL encoded, RFC 6749 section 2.3.1
+fn parse_basic_authorization(header: &str) -> Result<(Identifier, String), OAuthError> {
+    let (scheme, credentials) = header.split_once(' ').ok_or_else(invalid_authorization)?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return Err(invalid_authorization());
+    }
+    let credentials = BASE64_STANDARD.decode(credentials.trim()).map_err(|_| invalid_authorization())?;
+    let credentials = String::from_utf8(credentials).map_err(|_| invalid_authorization())?;
+    let (client_id, client_secret) = credentials.split_once(':').ok_or_else(invalid_authorization)?;
+    let client_id = form::decode_component(client_id).and_then(|id| Identifier::from_hex(&id)).ok_or_else(invalid_authorization)?;
+    let client_secret = form::decode_component(client_secret).ok_or_else(invalid_authorization)?;
+    Ok((client_id, client_secret))
+}
+
+pub fn hash_client_secret(client_secret: &str) -> String {
+    let salt = SaltString::generate(&mut rand::rngs::OsRng);
+    Argon2::default()
+        .hash_password(client_secret.as_bytes(), &salt)
+        .expect("the default Argon2 parameters are valid")
+        .to_string()
+}
+
+/// Unparseable hashes never match
+pub fn verify_client_secret(secret_hash: &str, client_secret: &str) -> bool {
+    PasswordHash::new(secret_hash)
+        .is_ok_and(|hash| Argon2::default().verify_password(client_secret.as_bytes(), &hash).is_ok())
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Audience {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct AssertionClaims {
+    iss: String,
+    sub: String,
+    aud: Audience,
+    /// Seconds since the unix epoch
+    exp: u64,
+}
+
+/// Verifies an ES256 client assertion, both `iss` and `sub` must be the hex client id and `aud` must
+/// contain the token endpoint, RFC 7523 section 3
+fn verify_assertion(public_key: &[u8], assertion: &str, client_id: Identifier, audience: &str, now: SystemTime) -> bool {
+    let Ok(verifying_key) = VerifyingKey::from_sec1_bytes(public_key) else {
+        return false;
+    };
+    let Some(claims) = jwt::decode::<AssertionClaims>(&verifying_key, assertion) else {
+        return false;
+    };
+    let client_id = client_id.as_hex();
+    let audience_matches = match &claims.aud {
+        Audience::One(aud) => aud == audience,
+        Audience::Many(auds) => auds.iter().any(|aud| aud == audience),
+    };
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    claims.iss == client_id
+        && claims.sub == client_id
+        && audience_matches
+        && claims.exp > now
+        && claims.exp <= now + MAX_ASSERTION_LIFETIME.as_secs()
+}
+
+/// Generated secrets are only returned once, the store keeps their hash
+pub fn generate_client_secret() -> Secret<String> {
+    Secret::new(BASE64_URL_SAFE_NO_PAD.encode(rand::random::<[u8; CLIENT_SECRET_SIZE]>()))
+}
+
+/// Creates a client together with a generated secret
+pub async fn register_confidential_client<S>(state: S::State, client: NewClient) -> Result<(S::Client, Secret<String>), StoreError>
+where
+    S: ClientStore + Transaction,
+{
+    let client_secret = generate_client_secret();
+    let credentials = ClientCredentials {
+        secret_hash: Some(hash_client_secret(client_secret.expose_secret())),
+        public_key: None,
+    };
+    let transaction = S::begin(state).await.map_err(Into::into)?;
+    let client = S::create_client(transaction.clone(), client).await.map_err(Into::into)?;
+    S::set_credentials(transaction.clone(), client.get_id(), credentials).await.map_err(Into::into)?;
+    S::commit(transaction).await.map_err(Into::into)?;
+    Ok((client, client_secret))
+}
+
+/// Replaces the secret of the client, the previous one stops working right away
+pub async fn rotate_client_secret<S>(state: S::State, client_id: Identifier) -> Result<Secret<String>, StoreError>
+where
+    S: ClientStore + Transaction,
+{
+    let client_secret = generate_client_secret();
+    let transaction = S::begin(state).await.map_err(Into::into)?;
+    let mut credentials = S::get_credentials(transaction.clone(), client_id).await.map_err(Into::into)?;
+    credentials.secret_hash = Some(hash_client_secret(client_secret.expose_secret()));
+    S::set_credentials(transaction.clone(), client_id, credentials).await.map_err(Into::into)?;
+    S::commit(transaction).await.map_err(Into::into)?;
+    Ok(client_secret)
+}
+
+/// Registers the key verifying the `private_key_jwt` assertions of the client, `None` removes it
+pub async fn set_client_public_key<S>(state: S::State, client_id: Identifier, public_key: Option<&VerifyingKey>) -> Result<(), StoreError>
+where
+    S: ClientStore + Transaction,
+{
+    let transaction = S::begin(state).await.map_err(Into::into)?;
+    let mut credentials = S::get_credentials(transaction.clone(), client_id).await.map_err(Into::into)?;
+    credentials.public_key = public_key.map(|key| key.to_encoded_point(false).as_bytes().to_vec());
+    S::set_credentials(transaction.clone(), client_id, credentials).await.map_err(Into::into)?;
+    S::commit(transaction).await.map_err(Into::into)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use p256::ecdsa::SigningKey;
+
+    use super::*;
+
+    pub(crate) const AUDIENCE: &str = "https://iam0.example.com/oauth/token";
+
+    pub(crate) fn client_assertion(signing_key: &SigningKey, claims: serde_json::Value) -> String {
+        jwt::encode(signing_key, &claims)
+    }
+
+    pub(crate) fn assertion_claims(client_id: Identifier, now: SystemTime) -> serde_json::Value {
+        let exp = now.duration_since(UNIX_EPOCH).unwrap().as_secs() + 60;
+        serde_json::json!({ "iss": client_id.as_hex(), "sub": client_id.as_hex(), "aud": AUDIENCE, "exp": exp, "jti": "1" })
+    }
+
+    fn request(client_id: Option<Identifier>) -> TokenRequest {
+        TokenRequest {
+            grant_type: "client_credentials".to_string(),
+            client_id,
+            client_secret: None,
+            client_assertion_type: None,
+            client_assertion: None,
+            code: None,
+            redirect_uri: None,
+            code_verifier: None,
+            refresh_token: None,
+            scope: None,
+            client_ip: None,
+        }
+    }
+
+    fn basic(client_id: &str, client_secret: &str) -> String {
+        format!("Basic {}", BASE64_STANDARD.encode(format!("{client_id}:{client_secret}")))
+    }
+
+    #[test]
+    fn secret_hashes() {
+        let hash = hash_client_secret("s3cret");
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_client_secret(&hash, "s3cret"));
+        assert!(!verify_client_secret(&hash, "s3cre"));
+        assert!(!verify_client_secret("not a hash", "s3cret"));
+        assert_ne!(hash_client_secret("s3cret"), hash);
+    }
+
+    #[test]
+    fn authentication_methods() {
+        let client_id = Identifier::from(42u128);
+        let header = basic(&client_id.as_hex(), "a%3Ab+c");
+        let authentication = ClientAuthentication::from_request(&request(None), Some(&header)).unwrap();
+        assert_eq!(authentication, ClientAuthentication::SecretBasic { client_id, client_secret: "a:b c".to_string() });
+
+        let post = TokenRequest { client_secret: Some("secret".to_string()), ..request(Some(client_id)) };
+        let authentication = ClientAuthentication::from_request(&post, None).unwrap();
+        assert_eq!(authentication, ClientAuthentication::SecretPost { client_id, client_secret: "secret".to_string() });
+        assert!(matches!(ClientAuthentication::from_request(&post, Some(&header)), Err(OAuthError::InvalidRequest(_))));
+
+        let jwt = TokenRequest { client_assertion: Some("a.b.c".to_string()), ..request(Some(client_id)) };
+        assert!(matches!(ClientAuthentication::from_request(&jwt, None), Err(OAuthError::InvalidRequest(_))));
+        let jwt = TokenRequest { client_assertion_type: Some(CLIENT_ASSERTION_TYPE.to_string()), ..jwt };
+        assert!(matches!(ClientAuthentication::from_request(&jwt, None), Ok(ClientAuthentication::PrivateKeyJwt { .. })));
+
+        assert_eq!(ClientAuthentication::from_request(&request(Some(client_id)), None).unwrap(), ClientAuthentication::None { client_id });
+        assert!(matches!(ClientAuthentication::from_request(&request(None), None), Err(OAuthError::InvalidClient(_))));
+        let other = Identifier::from(43u128);
+        assert!(matches!(ClientAuthentication::from_request(&request(Some(other)), Some(&header)), Err(OAuthError::InvalidRequest(_))));
+        assert!(matches!(ClientAuthentication::from_request(&request(None), Some("Bearer x")), Err(OAuthError::InvalidClient(_))));
+    }
+
+    #[test]
+    fn confidential_clients_must_authenticate() {
+        let client_id = Identifier::from(42u128);
+        let now = SystemTime::now();
+        let public = ClientCredentials::default();
+        let confidential = ClientCredentials { secret_hash: Some(hash_client_secret("secret")), public_key: None };
+
+        let none = ClientAuthentication::None { client_id };
+        assert!(!none.verify(&public, AUDIENCE, now).unwrap());
+        assert!(matches!(none.verify(&confidential, AUDIENCE, now), Err(OAuthError::InvalidClient(_))));
+
+        let post = ClientAuthentication::SecretPost { client_id, client_secret: "secret".to_string() };
+        assert!(post.verify(&confidential, AUDIENCE, now).unwrap());
+        assert!(matches!(post.verify(&public, AUDIENCE, now), Err(OAuthError::InvalidClient(_))));
+        let wrong = ClientAuthentication::SecretBasic { client_id, client_secret: "wrong".to_string() };
+        assert!(matches!(wrong.verify(&confidential, AUDIENCE, now), Err(OAuthError::InvalidClient(_))));
+    }
+
+    #[test]
+    fn client_assertions() {
+        let client_id = Identifier::from(42u128);
+        let now = SystemTime::now();
+        let signing_key = SigningKey::random(&mut rand::thread_rng());
+        let public_key = VerifyingKey::from(&signing_key).to_encoded_point(false).as_bytes().to_vec();
+        let credentials = ClientCredentials { secret_hash: None, public_key: Some(public_key) };
+        let verify = |claims: serde_json::Value, key: &SigningKey| {
+            let assertion = client_assertion(key, claims);
+            ClientAuthentication::PrivateKeyJwt { client_id, assertion }.verify(&credentials, AUDIENCE, now).is_ok()
+        };
+
+        let claims = assertion_claims(client_id, now);
+        assert!(verify(claims.clone(), &signing_key));
+        let mut many_audiences = claims.clone();
+        many_audiences["aud"] = serde_json::json!(["other", AUDIENCE]);
+        assert!(verify(many_audiences, &signing_key));
+        assert!(!verify(claims.clone(), &SigningKey::random(&mut rand::thread_rng())));
+
+        for (claim, value) in [
+            ("iss", serde_json::json!("other")),
+            ("sub", serde_json::json!(Identifier::from(43u128).as_hex())),
+            ("aud", serde_json::json!("https://other.example.com/token")),
+            ("exp", serde_json::json!(now.duration_since(UNIX_EPOCH).unwrap().as_secs())),
+            ("exp", serde_json::json!(now.duration_since(UNIX_EPOCH).unwrap().as_secs() + 3600)),
+        ] {
+            let mut claims = claims.clone();
+            claims[claim] = value;
+            assert!(!verify(claims, &signing_key), "{claim}");
+        }
+
+        let assertion = client_assertion(&signing_key, claims);
+        let (signing_input, _) = assertion.rsplit_once('.').unwrap();
+        let unsigned = format!("{signing_input}.");
+        let authentication = ClientAuthentication::PrivateKeyJwt { client_id, assertion: unsigned };
+        assert!(authentication.verify(&credentials, AUDIENCE, now).is_err());
+    }
+}
@@ -0,0 +1,439 @@
+//! Optional second factor of the users, TOTP codes (RFC 6238) with recovery codes as a fallback. A secret
+//! enrolled with [`enroll_totp`] only protects the login once [`confirm_totp`] accepted a code generated
+//! with it, which also hands out the recovery codes. [`verify`] checks the code of a login: every TOTP
+//! code is accepted once and recovery codes are burnt when used.
+//!
+//! Changes to the second factor aren't authenticated here, the caller must make sure the user is logged in
+
+use std::time::SystemTime;
+
+use elliptic_curve::subtle::ConstantTimeEq;
+use sha2::{Digest, Sha256};
+
+use crate::crypto::totp;
+use crate::data::id::Identifier;
+use crate::model::User;
+use crate::service::update_credentials;
+use crate::store::{StoreError, TotpCredential, Transaction, UserStore};
+
+pub const RECOVERY_CODE_COUNT: usize = 10;
+/// Random bytes of the recovery codes, shown as four groups of four base32 characters
+const RECOVERY_CODE_SIZE: usize = 10;
+
+#[derive(thiserror::Error, Debug)]
+pub enum MfaError {
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error("the user has no TOTP secret")]
+    NotEnrolled,
+    #[error("the TOTP secret of the user is already confirmed")]
+    AlreadyConfirmed,
+    #[error("invalid code")]
+    InvalidCode,
+}
+
+/// Secret to add to the authenticator app, typed in as base32 or scanned from the `otpauth` URI
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub uri: String,
+}
+
+/// How a second factor was verified
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecondFactor {
+    Totp,
+    RecoveryCode,
+}
+
+fn generate_recovery_code() -> String {
+    let encoded = totp::base32_encode(&rand::random::<[u8; RECOVERY_CODE_SIZE]>());
+    encoded.as_bytes().chunks(4).map(|group| std::str::from_utf8(group).unwrap()).collect::<Vec<_>>().join("-")
+}
+
+/// Dashes, spaces and case are ignored, the codes are often typed by hand
+fn recovery_code_hash(code: &str) -> Vec<u8> {
+    let normalized: String = code.chars().filter(|character| !matches!(character, '-' | ' ')).collect();
+    Sha256::digest(normalized.to_ascii_uppercase().as_bytes()).to_vec()
+}
+
+/// Whether the login of the user needs a second factor
+pub async fn is_enabled<S: UserStore>(state: S::State, user_id: Identifier) -> Result<bool, StoreError> {
+    let credentials = S::get_user_credentials(state, user_id).await.map_err(Into::into)?;
+    Ok(credentials.totp.is_some_and(|totp| totp.confirmed))
+}
+
+/// Generates a new TOTP secret for the user, replacing an unconfirmed one. `issuer` names the service in
+/// the authenticator app
+pub async fn enroll_totp<S>(state: S::State, user_id: Identifier, issuer: &str) -> Result<TotpEnrollment, MfaError>
+where
+    S: UserStore + Transaction,
+{
+    let user = S::get_user(state.clone(), user_id).await.map_err(|error| MfaError::Store(error.into()))?;
+    let secret = totp::generate_secret();
+    let enrollment = TotpEnrollment {
+        secret: totp::base32_encode(secret.expose_secret()),
+        uri: totp::otpauth_uri(issuer, user.get_email(), secret.expose_secret()),
+    };
+    update_credentials::<S, _, _, _>(state, user_id, |credentials| {
+        if credentials.totp.as_ref().is_some_and(|totp| totp.confirmed) {
+            return Err(MfaError::AlreadyConfirmed);
+        }
+        credentials.totp = Some(TotpCredential { secret, confirmed: false, last_used_step: None });
+        Ok(())
+    }).await?;
+    Ok(enrollment)
+}
+
+/// Turns on the second factor once the user proves the authenticator app works, returning the recovery
+/// codes. They are only stored hashed, so this is the only time they can be shown
+pub async fn confirm_totp<S>(state: S::State, user_id: Identifier, code: &str, now: SystemTime) -> Result<Vec<String>, MfaError>
+where
+    S: UserStore + Transaction,
+{
+    update_credentials::<S, _, _, _>(state, user_id, |credentials| {
+        let totp = credentials.totp.as_mut().ok_or(MfaError::NotEnrolled)?;
+        if totp.confirmed {
+            return Err(MfaError::AlreadyConfirmed);
+        }
+        let step = totp::verify(totp.secret.expose_secret(), code, now, totp.last_used_step).ok_or(MfaError::InvalidCode)?;
+        totp.confirmed = true;
+        totp.last_used_step = Some(step);
+        let codes = (0..RECOVERY_CODE_COUNT).map(|_| generate_recovery_code()).collect::<Vec<_>>();
+        credentials.recovery_codes = codes.iter().map(|code| recovery_code_hash(code)).collect();
+        Ok(codes)
+    }).await
+}
+
+/// Replaces the recovery codes of a user with a confirmed secret, the old ones stop working
+pub async fn regenerate_recovery_codes<S>(state: S::State, user_id: Identifier) -> Result<Vec<String>, MfaError>
+where
+    S: UserStore + Transaction,
+{
+    update_credentials::<S, _, _, _>(state, user_id, |credentials| {
+        if !credentials.totp.as_ref().is_some_and(|totp| totp.confirmed) {
+            return Err(MfaError::NotEnrolled);
+        }
+        let codes = (0..RECOVERY_CODE_COUNT).map(|_| generate_recovery_code()).collect::<Vec<_>>();
+        credentials.recovery_codes = codes.iter().map(|code| recovery_code_hash(code)).collect();
+        Ok(codes)
+    }).await
+}
+
+/// Removes the TOTP secret and the recovery codes, the login goes back to a single factor
+pub async fn disable_totp<S>(state: S::State, user_id: Identifier) -> Result<(), MfaError>
+where
+    S: UserStore + Transaction,
+{
+    update_credentials::<S, _, _, _>(state, user_id, |credentials| {
+        credentials.totp = None;
+        credentials.recovery_codes.clear();
+        Ok(())
+    }).await
+}
+
+/// Checks a TOTP code, or a recovery code when it isn't made of digits only. The step of an accepted TOTP
+/// code is recorded and a recovery code removed in the same transaction, so concurrent logins can't use
+/// the same code twice
+pub async fn verify<S>(state: S::State, user_id: Identifier, code: &str, now: SystemTime) -> Result<SecondFactor, MfaError>
+where
+    S: UserStore + Transaction,
+{
+    update_credentials::<S, _, _, _>(state, user_id, |credentials| {
+        let totp = credentials.totp.as_mut().filter(|totp| totp.confirmed).ok_or(MfaError::NotEnrolled)?;
+        if code.bytes().all(|byte| byte.is_ascii_digit()) {
+            let step = totp::verify(totp.secret.expose_secret(), code, now, totp.last_used_step).ok_or(MfaError::InvalidCode)?;
+            totp.last_used_step = Some(step);
+            return Ok(SecondFactor::Totp);
+        }
+        let hash = recovery_code_hash(code);
+        let index = credentials
+            .recovery_codes
+            .iter()
+            .position(|stored| bool::from(stored.ct_eq(&hash)))
+            .ok_or(MfaError::InvalidCode)?;
+        credentials.recovery_codes.remove(index);
+        Ok(SecondFactor::RecoveryCode)
+    }).await
+}
+
+#[cfg(all(test, feature = "in-memory"))]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use p256::ecdsa::VerifyingKey;
+
+    use crate::service::audit::{AuditError, AuditEvent, AuditSink};
+    use crate::service::rate_limit::LoginLimits;
+    use crate::service::tests::{login_request_with_key, seed_user_with_key, ISSUER};
+    use crate::service::{
+        oidc, LoginError, SecondFactorRequest, UserAuthentication, UserLoginOutcome, MAX_SECOND_FACTOR_ATTEMPTS, PENDING_LOGIN_LIFETIME,
+    };
+    use crate::store::memory::{InMemoryState, InMemoryStore};
+    use crate::store::{Lockout, LockoutPolicy, RateLimitKey, RateLimitStore};
+
+    use super::*;
+
+    fn code_at(enrollment: &TotpEnrollment, time: SystemTime) -> String {
+        totp::hotp(&totp::base32_decode(&enrollment.secret).unwrap(), totp::time_step(time))
+    }
+
+    #[tokio::test]
+    async fn enrollment() {
+        let state = InMemoryState::new();
+        let client = state.seed_client("app");
+        let user = state.seed_user(client.id, "alice@example.com");
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        let enrollment = enroll_totp::<InMemoryStore>(state.clone(), user.id, "ACME").await.unwrap();
+        assert!(enrollment.uri.starts_with("otpauth://totp/ACME:alice%40example.com?secret="));
+        assert!(enrollment.uri.contains(&enrollment.secret));
+        assert!(!is_enabled::<InMemoryStore>(state.clone(), user.id).await.unwrap());
+        // Unconfirmed secrets can be replaced and don't count for the login
+        let enrollment = enroll_totp::<InMemoryStore>(state.clone(), user.id, "ACME").await.unwrap();
+        let result = verify::<InMemoryStore>(state.clone(), user.id, &code_at(&enrollment, now), now).await;
+        assert!(matches!(result, Err(MfaError::NotEnrolled)));
+
+        let result = confirm_totp::<InMemoryStore>(state.clone(), user.id, "000000", now - Duration::from_secs(3600)).await;
+        assert!(matches!(result, Err(MfaError::InvalidCode)));
+        let codes = confirm_totp::<InMemoryStore>(state.clone(), user.id, &code_at(&enrollment, now), now).await.unwrap();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert!(is_enabled::<InMemoryStore>(state.clone(), user.id).await.unwrap());
+        let result = enroll_totp::<InMemoryStore>(state.clone(), user.id, "ACME").await;
+        assert!(matches!(result, Err(MfaError::AlreadyConfirmed)));
+
+        disable_totp::<InMemoryStore>(state.clone(), user.id).await.unwrap();
+        assert!(!is_enabled::<InMemoryStore>(state.clone(), user.id).await.unwrap());
+        let result = regenerate_recovery_codes::<InMemoryStore>(state, user.id).await;
+        assert!(matches!(result, Err(MfaError::NotEnrolled)));
+    }
+
+    #[tokio::test]
+    async fn codes_are_single_use() {
+        let state = InMemoryState::new();
+        let client = state.seed_client("app");
+        let user = state.seed_user(client.id, "alice@example.com");
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let enrollment = enroll_totp::<InMemoryStore>(state.clone(), user.id, "ACME").await.unwrap();
+        let codes = confirm_totp::<InMemoryStore>(state.clone(), user.id, &code_at(&enrollment, now), now).await.unwrap();
+
+        // The confirmation code was already used, the next step is accepted once
+        let result = verify::<InMemoryStore>(state.clone(), user.id, &code_at(&enrollment, now), now).await;
+        assert!(matches!(result, Err(MfaError::InvalidCode)));
+        let later = now + totp::STEP;
+        let factor = verify::<InMemoryStore>(state.clone(), user.id, &code_at(&enrollment, later), later).await.unwrap();
+        assert_eq!(factor, SecondFactor::Totp);
+        let result = verify::<InMemoryStore>(state.clone(), user.id, &code_at(&enrollment, later), later).await;
+        assert!(matches!(result, Err(MfaError::InvalidCode)));
+
+        let code = codes[3].to_lowercase().replace('-', " ");
+        let factor = verify::<InMemoryStore>(state.clone(), user.id, &code, later).await.unwrap();
+        assert_eq!(factor, SecondFactor::RecoveryCode);
+        let result = verify::<InMemoryStore>(state.clone(), user.id, &codes[3], later).await;
+        assert!(matches!(result, Err(MfaError::InvalidCode)));
+        let result = verify::<InMemoryStore>(state.clone(), user.id, "AAAA-AAAA-AAAA-AAAA", later).await;
+        assert!(matches!(result, Err(MfaError::InvalidCode)));
+
+        let new_codes = regenerate_recovery_codes::<InMemoryStore>(state.clone(), user.id).await.unwrap();
+        let result = verify::<InMemoryStore>(state.clone(), user.id, &codes[0], later).await;
+        assert!(matches!(result, Err(MfaError::InvalidCode)));
+        assert_eq!(verify::<InMemoryStore>(state, user.id, &new_codes[0], later).await.unwrap(), SecondFactor::RecoveryCode);
+    }
+
+    #[tokio::test]
+    async fn login_with_second_factor() {
+        struct Clock(SystemTime);
+
+        impl UserAuthentication<InMemoryStore> for Clock {
+            fn issuer(&self) -> &str {
+                ISSUER
+            }
+
+            fn now(&self) -> SystemTime {
+                self.0
+            }
+        }
+
+        let state = InMemoryState::new();
+        let client = state.seed_client("app");
+        let (user, private_key) = seed_user_with_key(&state, client.id, "alice@example.com");
+        let signing_key = state.seed_signing_key(client.id);
+        let enrolled_at = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let enrollment = enroll_totp::<InMemoryStore>(state.clone(), user.id, "ACME").await.unwrap();
+        let secret = totp::base32_decode(&enrollment.secret).unwrap();
+        let code_at = |time| totp::hotp(&secret, totp::time_step(time));
+        let recovery_codes = confirm_totp::<InMemoryStore>(state.clone(), user.id, &code_at(enrolled_at), enrolled_at).await.unwrap();
+
+        let now = enrolled_at + totp::STEP;
+        let login_request = |client_id, email| login_request_with_key(client_id, email, &private_key);
+        let outcome = Clock(now).login(login_request(client.id, "alice@example.com"), state.clone()).await.unwrap();
+        let UserLoginOutcome::SecondFactorRequired { mfa_token } = outcome else {
+            panic!("expected a pending login");
+        };
+        let second_factor = |code: &str| SecondFactorRequest { mfa_token: mfa_token.clone(), code: code.to_string(), client_ip: None };
+        let error = Clock(now).verify_second_factor(second_factor("000000"), state.clone()).await.err();
+        assert_eq!(error, Some(LoginError::InvalidCode));
+        let response = Clock(now).verify_second_factor(second_factor(&code_at(now)), state.clone()).await.unwrap();
+        let claims = oidc::verify_id_token(&VerifyingKey::from(&signing_key), &response.id_token, ISSUER, client.id, now).unwrap();
+        assert_eq!(claims.amr, [oidc::AMR_SCHNORR, oidc::AMR_OTP, oidc::AMR_MFA]);
+        assert_eq!(claims.auth_time, 1_700_000_030);
+        assert_eq!(claims.nonce.as_deref(), Some("n-0S6_WzA2Mj"));
+        // The pending login is finished, and the TOTP code was used
+        let error = Clock(now).verify_second_factor(second_factor(&code_at(now)), state.clone()).await.err();
+        assert_eq!(error, Some(LoginError::InvalidMfaToken));
+        let outcome = Clock(now).login(login_request(client.id, "alice@example.com"), state.clone()).await.unwrap();
+        let UserLoginOutcome::SecondFactorRequired { mfa_token } = outcome else {
+            panic!("expected a pending login");
+        };
+        let second_factor = |code: &str| SecondFactorRequest { mfa_token: mfa_token.clone(), code: code.to_string(), client_ip: None };
+        let error = Clock(now).verify_second_factor(second_factor(&code_at(now)), state.clone()).await.err();
+        assert_eq!(error, Some(LoginError::InvalidCode));
+        assert!(Clock(now).verify_second_factor(second_factor(&recovery_codes[0]), state.clone()).await.is_ok());
+
+        // Too many wrong codes drop the pending login
+        let outcome = Clock(now).login(login_request(client.id, "alice@example.com"), state.clone()).await.unwrap();
+        let UserLoginOutcome::SecondFactorRequired { mfa_token } = outcome else {
+            panic!("expected a pending login");
+        };
+        let second_factor = |code: &str| SecondFactorRequest { mfa_token: mfa_token.clone(), code: code.to_string(), client_ip: None };
+        for _ in 0..MAX_SECOND_FACTOR_ATTEMPTS {
+            let error = Clock(now).verify_second_factor(second_factor("AAAA-AAAA"), state.clone()).await.err();
+            assert_eq!(error, Some(LoginError::InvalidCode));
+        }
+        let error = Clock(now).verify_second_factor(second_factor(&recovery_codes[1]), state.clone()).await.err();
+        assert_eq!(error, Some(LoginError::InvalidMfaToken));
+
+        // And so do expired ones
+        let outcome = Clock(now).login(login_request(client.id, "alice@example.com"), state.clone()).await.unwrap();
+        let UserLoginOutcome::SecondFactorRequired { mfa_token } = outcome else {
+            panic!("expected a pending login");
+        };
+        let later = now + PENDING_LOGIN_LIFETIME;
+        let request = SecondFactorRequest { mfa_token, code: recovery_codes[1].clone(), client_ip: None };
+        let error = Clock(later).verify_second_factor(request, state).await.err();
+        assert_eq!(error, Some(LoginError::InvalidMfaToken));
+    }
+
+    #[tokio::test]
+    async fn second_factor_lockout() {
+        struct Limited(SystemTime);
+
+        impl UserAuthentication<InMemoryStore> for Limited {
+            fn issuer(&self) -> &str {
+                ISSUER
+            }
+
+            fn now(&self) -> SystemTime {
+                self.0
+            }
+
+            fn login_limits(&self) -> LoginLimits {
+                let lockout = LockoutPolicy { max_failures: 1, ..LoginLimits::default().lockout };
+                LoginLimits { lockout, ..LoginLimits::default() }
+            }
+        }
+
+        let state = InMemoryState::new();
+        let client = state.seed_client("app");
+        let (user, private_key) = seed_user_with_key(&state, client.id, "alice@example.com");
+        state.seed_signing_key(client.id);
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let enrollment = enroll_totp::<InMemoryStore>(state.clone(), user.id, "ACME").await.unwrap();
+        let secret = totp::base32_decode(&enrollment.secret).unwrap();
+        confirm_totp::<InMemoryStore>(state.clone(), user.id, &totp::hotp(&secret, totp::time_step(now)), now).await.unwrap();
+
+        let later = now + totp::STEP;
+        let login_request = |client_id, email| login_request_with_key(client_id, email, &private_key);
+        let outcome = Limited(later).login(login_request(client.id, "alice@example.com"), state.clone()).await.unwrap();
+        let UserLoginOutcome::SecondFactorRequired { mfa_token } = outcome else {
+            panic!("expected a pending login");
+        };
+        let second_factor = |code: &str| SecondFactorRequest { mfa_token: mfa_token.clone(), code: code.to_string(), client_ip: None };
+        for _ in 0..2 {
+            let error = Limited(later).verify_second_factor(second_factor("000000"), state.clone()).await.err();
+            assert_eq!(error, Some(LoginError::InvalidCode));
+        }
+        // The right code waits for the lockout, and so does a new login
+        let code = totp::hotp(&secret, totp::time_step(later));
+        let error = Limited(later).verify_second_factor(second_factor(&code), state.clone()).await.err();
+        let retry_after = LoginLimits::default().lockout.base;
+        assert_eq!(error, Some(LoginError::RateLimited { retry_after }));
+        let error = Limited(later).login(login_request(client.id, "alice@example.com"), state.clone()).await.err();
+        assert_eq!(error, Some(LoginError::RateLimited { retry_after }));
+
+        let unlocked = later + retry_after;
+        let code = totp::hotp(&secret, totp::time_step(unlocked));
+        Limited(unlocked).verify_second_factor(second_factor(&code), state.clone()).await.unwrap();
+        let lockout = InMemoryStore::get_lockout(state, &RateLimitKey::email(client.id, "alice@example.com")).await.unwrap();
+        assert_eq!(lockout, Lockout::default());
+    }
+
+    #[tokio::test]
+    async fn second_factor_with_an_unavailable_sink() {
+        struct Audited(SystemTime, UnreliableSink);
+
+        impl UserAuthentication<InMemoryStore> for Audited {
+            fn issuer(&self) -> &str {
+                ISSUER
+            }
+
+            fn now(&self) -> SystemTime {
+                self.0
+            }
+
+            fn audit_sink(&self) -> Option<&dyn AuditSink> {
+                Some(&self.1)
+            }
+        }
+
+        /// Fails to record the events while `available` is false
+        struct UnreliableSink {
+            available: AtomicBool,
+        }
+
+        #[async_trait::async_trait]
+        impl AuditSink for UnreliableSink {
+            async fn record(&self, _: &AuditEvent) -> Result<(), AuditError> {
+                if !self.available.load(Ordering::SeqCst) {
+                    return Err(StoreError::Unknown.into());
+                }
+                Ok(())
+            }
+        }
+
+        let state = InMemoryState::new();
+        let client = state.seed_client("app");
+        let (user, private_key) = seed_user_with_key(&state, client.id, "alice@example.com");
+        state.seed_signing_key(client.id);
+        let enrolled_at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let enrollment = enroll_totp::<InMemoryStore>(state.clone(), user.id, "ACME").await.unwrap();
+        let code = code_at(&enrollment, enrolled_at);
+        let recovery_codes = confirm_totp::<InMemoryStore>(state.clone(), user.id, &code, enrolled_at).await.unwrap();
+        let now = enrolled_at + totp::STEP;
+        let audited = Audited(now, UnreliableSink { available: AtomicBool::new(true) });
+        let login = || audited.login(login_request_with_key(client.id, "alice@example.com", &private_key), state.clone());
+        let Ok(UserLoginOutcome::SecondFactorRequired { mfa_token }) = login().await else {
+            panic!("expected a pending login");
+        };
+
+        audited.1.available.store(false, Ordering::SeqCst);
+        let request = SecondFactorRequest { mfa_token: mfa_token.clone(), code: code_at(&enrollment, now), client_ip: None };
+        let error = audited.verify_second_factor(request, state.clone()).await.err();
+        assert_eq!(error, Some(LoginError::Internal("failed to record audit event")));
+
+        // The pending login and the code are spent, the login starts over with another code
+        audited.1.available.store(true, Ordering::SeqCst);
+        let request = SecondFactorRequest { mfa_token, code: code_at(&enrollment, now), client_ip: None };
+        let error = audited.verify_second_factor(request, state.clone()).await.err();
+        assert_eq!(error, Some(LoginError::InvalidMfaToken));
+        let Ok(UserLoginOutcome::SecondFactorRequired { mfa_token }) = login().await else {
+            panic!("expected a pending login");
+        };
+        let request = SecondFactorRequest { mfa_token: mfa_token.clone(), code: code_at(&enrollment, now), client_ip: None };
+        let error = audited.verify_second_factor(request, state.clone()).await.err();
+        assert_eq!(error, Some(LoginError::InvalidCode));
+        let request = SecondFactorRequest { mfa_token, code: recovery_codes[0].clone(), client_ip: None };
+        assert!(audited.verify_second_factor(request, state).await.is_ok());
+    }
+}
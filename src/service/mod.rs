@@ -1,11 +1,30 @@
-use p256::ecdsa::SigningKey;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
 use serde::{Deserialize, Serialize};
-use crate::crypto::schnorr::{Shnorr, ShnorrProof};
+use crate::crypto::schnorr::ShnorrProof;
 use crate::crypto::token::{Token, TokenSigner};
 use crate::data::id::Identifier;
-use crate::store::ClientStore;
+use crate::model::{Client, User};
+use crate::service::oauth::{opaque_token, token_hash};
+use crate::store::{
+    ClientStore, NewUser, RateLimitStore, StoreError, TokenKind, TokenRecord, TokenStore, Transaction, UserCredentials, UserMetadataOf,
+    UserPublicKey, UserStore,
+};
+use crate::telemetry;
+
+pub mod audit;
+pub mod client_auth;
+pub mod mfa;
+pub mod oauth;
+pub mod oidc;
+pub mod rate_limit;
+pub mod spec;
+pub mod tenant;
+pub mod user_keys;
+pub mod webauthn;
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct UserLoginPayload {
     pub client_id: Identifier,
     pub email: String,
@@ -20,57 +39,787 @@ impl From<&UserLoginPayload> for Vec<u8> {
     }
 }
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct UserLoginRequest {
     #[serde(flatten)]
     pub payload: UserLoginPayload,
 
     #[serde(flatten)]
     pub proof: ShnorrProof,
+
+    /// Copied to the ID token
+    #[serde(default)]
+    pub nonce: Option<String>,
+
+    /// Address the request comes from, set by the server for the per IP limits
+    #[serde(skip)]
+    pub client_ip: Option<IpAddr>,
 }
 
+/// Access token of a first party login, the bearer credential of the routes acting on the user's own
+/// account. It's returned by the login routes only, unlike the ID tokens and the OAuth access tokens handed to
+/// clients
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserTokenPayload {
     pub user_id: Identifier,
     pub client_id: Identifier,
+    /// Seconds since the unix epoch, when the user proved the credentials
+    pub auth_time: u64,
+    pub amr: Vec<String>,
+    /// Seconds since the unix epoch
+    pub expires_at: u64,
     // roles: Vec<String>,
     // permissions: Vec<String>,
 }
 
 pub struct UserLoginResponse {
     pub token: Token<UserTokenPayload, p256::NistP256>,
+    /// OpenID Connect ID token issued to the client, see [`oidc::verify_id_token`]
+    pub id_token: String,
+}
+
+/// Lifetime of the `mfa_token` of a login waiting for its second factor
+pub const PENDING_LOGIN_LIFETIME: Duration = Duration::from_secs(5 * 60);
+/// Wrong codes tried on a pending login before it's dropped and the proof must be sent again
+pub const MAX_SECOND_FACTOR_ATTEMPTS: u32 = 5;
+
+pub enum UserLoginOutcome {
+    Authenticated(UserLoginResponse),
+    /// The user turned on a second factor, the login is finished by
+    /// [`UserAuthentication::verify_second_factor`] with the `mfa_token`
+    SecondFactorRequired { mfa_token: String },
+}
+
+impl UserLoginOutcome {
+    /// The tokens, `None` when the second factor is still missing
+    pub fn authenticated(self) -> Option<UserLoginResponse> {
+        match self {
+            UserLoginOutcome::Authenticated(response) => Some(response),
+            UserLoginOutcome::SecondFactorRequired { .. } => None,
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct PasskeyLoginRequest {
+    /// Assertion signed over the challenge of [`webauthn::start_authentication`]
+    pub credential: webauthn::AuthenticationCredential,
+
+    /// Copied to the ID token
+    #[serde(default)]
+    pub nonce: Option<String>,
+
+    /// Address the request comes from, set by the server for the per IP limits
+    #[serde(skip)]
+    pub client_ip: Option<IpAddr>,
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct SecondFactorRequest {
+    pub mfa_token: String,
+    /// TOTP code or recovery code
+    pub code: String,
+
+    /// Address the request comes from, set by the server for the per IP limits
+    #[serde(skip)]
+    pub client_ip: Option<IpAddr>,
+}
+
+/// Failure of a [`UserAuthentication`] login
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum LoginError {
+    /// Too many attempts from the address, for the email or on the client, or the account is locked
+    #[error("rate limited, retry after {retry_after:?}")]
+    RateLimited { retry_after: Duration },
+    /// The proof is wrong or made with a key the user doesn't have
+    #[error("invalid proof")]
+    InvalidProof,
+    #[error("user not found")]
+    UserNotFound,
+    #[error("invalid passkey")]
+    InvalidPasskey,
+    /// Unknown, expired or out of attempts
+    #[error("invalid mfa token")]
+    InvalidMfaToken,
+    #[error("invalid code")]
+    InvalidCode,
+    #[error("second factor not enrolled")]
+    SecondFactorNotEnrolled,
+    #[error("client not found")]
+    ClientNotFound,
+    #[error("client disabled")]
+    ClientDisabled,
+    #[error("curve not allowed")]
+    CurveNotAllowed,
+    #[error("passkeys not configured")]
+    PasskeysNotConfigured,
+    #[error("key already registered")]
+    DuplicateKey,
+    #[error("too many keys")]
+    TooManyKeys,
+    #[error("invalid label")]
+    InvalidLabel,
+    /// The store or the signing failed, the message names the step
+    #[error("{0}")]
+    Internal(&'static str),
+}
+
+impl LoginError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoginError::RateLimited { .. } => "rate limited",
+            LoginError::InvalidProof => "invalid proof",
+            LoginError::UserNotFound => "user not found",
+            LoginError::InvalidPasskey => "invalid passkey",
+            LoginError::InvalidMfaToken => "invalid mfa token",
+            LoginError::InvalidCode => "invalid code",
+            LoginError::SecondFactorNotEnrolled => "second factor not enrolled",
+            LoginError::ClientNotFound => "client not found",
+            LoginError::ClientDisabled => "client disabled",
+            LoginError::CurveNotAllowed => "curve not allowed",
+            LoginError::PasskeysNotConfigured => "passkeys not configured",
+            LoginError::DuplicateKey => "key already registered",
+            LoginError::TooManyKeys => "too many keys",
+            LoginError::InvalidLabel => "invalid label",
+            LoginError::Internal(message) => message,
+        }
+    }
+}
+
+impl telemetry::FailureReason for LoginError {
+    fn failure_reason(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<rate_limit::RateLimitError> for LoginError {
+    fn from(error: rate_limit::RateLimitError) -> Self {
+        match error {
+            rate_limit::RateLimitError::Limited { retry_after } => LoginError::RateLimited { retry_after },
+            rate_limit::RateLimitError::Store(_) => LoginError::Internal("failed to check rate limits"),
+        }
+    }
+}
+
+/// Failure of a [`UserRegistration`]
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum RegistrationError {
+    #[error("email already registered")]
+    EmailTaken,
+    #[error("client not found")]
+    ClientNotFound,
+    /// The proof of the first key is wrong
+    #[error("invalid proof")]
+    InvalidProof,
+    #[error("invalid label")]
+    InvalidLabel,
+    /// The store failed, the message names the step
+    #[error("{0}")]
+    Internal(&'static str),
+}
+
+impl telemetry::FailureReason for RegistrationError {
+    fn failure_reason(&self) -> &str {
+        match self {
+            RegistrationError::EmailTaken => "email already registered",
+            RegistrationError::ClientNotFound => "client not found",
+            RegistrationError::InvalidProof => "invalid proof",
+            RegistrationError::InvalidLabel => "invalid label",
+            RegistrationError::Internal(message) => message,
+        }
+    }
+}
+
+/// Data of the [`TokenKind::PendingLogin`] tokens
+#[derive(Serialize, Deserialize)]
+struct PendingLogin {
+    authentication: oidc::AuthenticationContext,
+    nonce: Option<String>,
+    attempts: u32,
+}
+
+/// Reads, changes and writes back the credentials of the user in a transaction, nothing is written when
+/// `update` fails
+pub(crate) async fn update_credentials<S, T, E, Update>(state: S::State, user_id: Identifier, update: Update) -> Result<T, E>
+where
+    S: UserStore + Transaction,
+    T: Send,
+    E: From<StoreError> + Send,
+    Update: FnOnce(&mut UserCredentials) -> Result<T, E> + Send,
+{
+    let transaction = S::begin(state).await.map_err(|error| E::from(error.into()))?;
+    let result = async {
+        let mut credentials = S::get_user_credentials(transaction.clone(), user_id).await.map_err(|error| E::from(error.into()))?;
+        let value = update(&mut credentials)?;
+        S::set_user_credentials(transaction.clone(), user_id, credentials).await.map_err(|error| E::from(error.into()))?;
+        Ok(value)
+    }.await;
+    match result {
+        Ok(value) => {
+            S::commit(transaction).await.map_err(|error| E::from(error.into()))?;
+            Ok(value)
+        }
+        Err(error) => {
+            // NOTE: The error of `update` is the one worth returning, a failed rollback is only logged
+            if let Err(rollback_error) = S::rollback(transaction).await.map_err(Into::<StoreError>::into) {
+                tracing::warn!(error = %rollback_error, "failed to roll back transaction");
+            }
+            Err(error)
+        }
+    }
+}
+
+/// Signs the access and ID tokens of a finished login with the key of the client, `lineage` starts with it
+async fn sign_login<S: ClientStore>(
+    issuer: &str,
+    store_state: S::State,
+    lineage: &[S::Client],
+    authentication: &oidc::AuthenticationContext,
+    nonce: Option<String>,
+    now: SystemTime,
+) -> Result<UserLoginResponse, LoginError> {
+    let client_id = lineage[0].get_id();
+    let signing_key = tenant::signing_key_of::<S>(store_state, lineage)
+        .await
+        .map_err(|error| match error {
+            tenant::TenantError::Store(StoreError::Serialization(_)) => LoginError::Internal("invalid signing key"),
+            _ => LoginError::Internal("failed to retrieve signing key"),
+        })?;
+    let settings = tenant::settings_of(lineage);
+    let token_payload = UserTokenPayload {
+        user_id: authentication.user_id,
+        client_id,
+        auth_time: authentication.auth_time,
+        amr: authentication.amr.clone(),
+        expires_at: oidc::unix_seconds(now + settings.access_token_lifetime),
+        // TOOD: roles,
+    };
+    let claims = oidc::id_token_claims(issuer, client_id, authentication, nonce, now, settings.access_token_lifetime);
+    let (token, id_token) = tracing::debug_span!("sign_tokens", %client_id).in_scope(|| {
+        telemetry::timed(telemetry::TOKEN_SIGNING_SECONDS, &[], || {
+            (TokenSigner::sign(&signing_key, token_payload), oidc::sign_id_token(&signing_key, &claims))
+        })
+    });
+
+    Ok(UserLoginResponse { token, id_token })
+}
+
+/// Finishes a login whose first factor was checked, with the tokens or with a pending login when the user
+/// turned on a second factor the authentication doesn't already include
+async fn complete_login<S>(
+    issuer: &str,
+    store_state: S::State,
+    lineage: &[S::Client],
+    authentication: oidc::AuthenticationContext,
+    nonce: Option<String>,
+    now: SystemTime,
+) -> Result<UserLoginOutcome, LoginError>
+where
+    S: UserStore + ClientStore + TokenStore,
+{
+    let second_factor = !authentication.amr.iter().any(|method| method == oidc::AMR_MFA)
+        && mfa::is_enabled::<S>(store_state.clone(), authentication.user_id)
+            .await
+            .map_err(|_| LoginError::Internal("failed to look up second factor"))?;
+    if second_factor {
+        let mfa_token = opaque_token();
+        let record = TokenRecord {
+            hash: token_hash(&mfa_token),
+            kind: TokenKind::PendingLogin,
+            user_id: authentication.user_id,
+            client_id: lineage[0].get_id(),
+            expires_at: now + PENDING_LOGIN_LIFETIME,
+            data: bincode::serialize(&PendingLogin { authentication, nonce, attempts: 0 }).unwrap(),
+        };
+        S::insert_token(store_state, record)
+            .await
+            .map_err(|_| LoginError::Internal("failed to store pending login"))?;
+        return Ok(UserLoginOutcome::SecondFactorRequired { mfa_token });
+    }
+
+    let response = sign_login::<S>(issuer, store_state, lineage, &authentication, nonce, now).await?;
+    Ok(UserLoginOutcome::Authenticated(response))
 }
 
 #[async_trait::async_trait]
-pub trait UserAuthentication<CS>
+pub trait UserAuthentication<S>
 where
-    CS: ClientStore {
+    S: UserStore + ClientStore + TokenStore + RateLimitStore + Transaction {
+    /// `iss` of the ID tokens
+    fn issuer(&self) -> &str;
+
+    /// Clock used for the tokens and the TOTP codes
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    /// Relying party of the passkeys, `None` turns off [`UserAuthentication::login_with_passkey`]
+    fn relying_party(&self) -> Option<&webauthn::RelyingParty> {
+        None
+    }
+
+    /// Throttling of the logins, see [`rate_limit`]
+    fn login_limits(&self) -> rate_limit::LoginLimits {
+        rate_limit::LoginLimits::default()
+    }
+
+    /// Where the attempts are reported, see [`audit`]
+    fn audit_sink(&self) -> Option<&dyn audit::AuditSink> {
+        None
+    }
+
+    /// Checks the proof of the user, users with a second factor get an `mfa_token` instead of the tokens.
+    /// Wrong proofs and unknown emails count towards the lockout of the email
+    #[tracing::instrument(
+        skip_all,
+        fields(client_id = %request.payload.client_id, email = %telemetry::redact_email(&request.payload.email)),
+    )]
     async fn login(
         &self,
         request: UserLoginRequest,
-        client_store_state: CS::State,
-    ) -> Result<UserLoginResponse, String> {
-        if !request.proof.verify(&request.payload) {
-            return Err("invalid proof".to_string());
+        store_state: S::State,
+    ) -> Result<UserLoginOutcome, LoginError> {
+        let now = self.now();
+        let mut event = audit::AuditEvent {
+            client_id: Some(request.payload.client_id),
+            subject: Some(request.payload.email.clone()),
+            request: audit::RequestMetadata { ip: request.client_ip },
+            ..audit::AuditEvent::new(audit::AuditAction::Login, now)
+        };
+        // NOTE: A block so the early returns are reported too
+        let result = async {
+            let limits = self.login_limits();
+            let (client_id, email) = (request.payload.client_id, request.payload.email.as_str());
+            rate_limit::acquire_login::<S>(store_state.clone(), &limits, client_id, email, request.client_ip, now).await?;
+            let failed = |error: LoginError| {
+                let store_state = store_state.clone();
+                async move {
+                    rate_limit::record_failure::<S>(store_state, &limits, client_id, email, now)
+                        .await
+                        .map_err(|_| LoginError::Internal("failed to record failure"))?;
+                    Err::<UserLoginOutcome, _>(error)
+                }
+            };
+
+            let curve = request.proof.curve().name();
+            let verified = tracing::debug_span!("verify_proof", curve).in_scope(|| {
+                telemetry::timed(telemetry::PROOF_VERIFICATION_SECONDS, &[("curve", curve)], || request.proof.verify(&request.payload))
+            });
+            if !verified {
+                return failed(LoginError::InvalidProof).await;
+            }
+
+            let lineage = tenant::lineage::<S>(store_state.clone(), request.payload.client_id)
+                .await
+                .map_err(|_| LoginError::ClientNotFound)?;
+            tenant::check_enabled(&lineage).map_err(|_| LoginError::ClientDisabled)?;
+            let settings = tenant::settings_of(&lineage);
+            if !settings.allowed_curves.contains(&request.proof.curve()) {
+                return Err(LoginError::CurveNotAllowed);
+            }
+
+            let user = match S::get_user_by_email(store_state.clone(), client_id, email).await {
+                Ok(user) => user,
+                Err(_) => return failed(LoginError::UserNotFound).await,
+            };
+            match user_keys::check_login_key::<S>(store_state.clone(), user.get_id(), &request.proof, now).await {
+                Ok(_) => {}
+                Err(user_keys::UserKeyError::UnknownKey) => return failed(LoginError::InvalidProof).await,
+                Err(_) => return Err(LoginError::Internal("failed to check key")),
+            }
+
+            event.actor = Some(user.get_id());
+            let authentication = oidc::AuthenticationContext::schnorr(user.get_id(), now);
+            let outcome = complete_login::<S>(self.issuer(), store_state.clone(), &lineage, authentication, request.nonce, now).await?;
+            if let UserLoginOutcome::Authenticated(_) = outcome {
+                rate_limit::clear_failures::<S>(store_state, client_id, email)
+                    .await
+                    .map_err(|_| LoginError::Internal("failed to clear failures"))?;
+            }
+            Ok(outcome)
+        }.await;
+        let pending = matches!(result, Ok(UserLoginOutcome::SecondFactorRequired { .. }));
+        audit::report(self.audit_sink(), event, &result, pending).await.map_err(|_| LoginError::Internal("failed to record audit event"))?;
+        result
+    }
+
+    /// Checks a passkey assertion, a passkey without user verification is only the first factor of users
+    /// with a second one. The challenge is spent before the login is reported, a login failing because
+    /// the audit sink can't record it starts over from the passkey options
+    #[tracing::instrument(skip_all, fields(credential_id = %request.credential.id))]
+    async fn login_with_passkey(
+        &self,
+        request: PasskeyLoginRequest,
+        store_state: S::State,
+    ) -> Result<UserLoginOutcome, LoginError> {
+        let now = self.now();
+        let mut event = audit::AuditEvent {
+            subject: Some(request.credential.id.clone()),
+            request: audit::RequestMetadata { ip: request.client_ip },
+            ..audit::AuditEvent::new(audit::AuditAction::PasskeyLogin, now)
+        };
+        // NOTE: A block so the early returns are reported too
+        let result = async {
+            let relying_party = self.relying_party().ok_or(LoginError::PasskeysNotConfigured)?;
+            rate_limit::acquire_ip::<S>(store_state.clone(), &self.login_limits(), request.client_ip, now).await?;
+            let login = webauthn::finish_authentication::<S>(store_state.clone(), relying_party, &request.credential, now)
+                .await
+                .map_err(|error| match error {
+                    webauthn::WebAuthnError::Store(_) => LoginError::Internal("failed to verify passkey"),
+                    _ => LoginError::InvalidPasskey,
+                })?;
+            event.actor = Some(login.user_id);
+            event.client_id = Some(login.client_id);
+
+            let lineage = tenant::lineage::<S>(store_state.clone(), login.client_id)
+                .await
+                .map_err(|_| LoginError::ClientNotFound)?;
+            tenant::check_enabled(&lineage).map_err(|_| LoginError::ClientDisabled)?;
+            let authentication = oidc::AuthenticationContext::passkey(login.user_id, now, login.user_verified);
+            complete_login::<S>(self.issuer(), store_state, &lineage, authentication, request.nonce, now).await
+        }.await;
+        let pending = matches!(result, Ok(UserLoginOutcome::SecondFactorRequired { .. }));
+        audit::report(self.audit_sink(), event, &result, pending).await.map_err(|_| LoginError::Internal("failed to record audit event"))?;
+        result
+    }
+
+    /// Second step of the login of a user with a second factor. Every wrong code counts as an attempt
+    /// and the `mfa_token` stops working after [`MAX_SECOND_FACTOR_ATTEMPTS`] of them, the wrong codes
+    /// also count towards the lockout of the email of the user.
+    ///
+    /// The `mfa_token` and the code are spent before the login is reported, a login failing because the
+    /// audit sink can't record it starts over from the first factor and takes another code
+    #[tracing::instrument(skip_all)]
+    async fn verify_second_factor(
+        &self,
+        request: SecondFactorRequest,
+        store_state: S::State,
+    ) -> Result<UserLoginResponse, LoginError> {
+        let now = self.now();
+        let mut event = audit::AuditEvent {
+            request: audit::RequestMetadata { ip: request.client_ip },
+            ..audit::AuditEvent::new(audit::AuditAction::SecondFactor, now)
+        };
+        // NOTE: A block so the early returns are reported too
+        let result = async {
+            let limits = self.login_limits();
+            rate_limit::acquire_ip::<S>(store_state.clone(), &limits, request.client_ip, now).await?;
+            // NOTE: Consumed so concurrent attempts with the same token are serialized, a wrong code puts it back
+            let record = match S::consume_token(store_state.clone(), &token_hash(&request.mfa_token)).await.map_err(Into::into) {
+                Ok(record) => record,
+                Err(StoreError::NotFound) => return Err(LoginError::InvalidMfaToken),
+                Err(_) => return Err(LoginError::Internal("failed to retrieve pending login")),
+            };
+            if record.kind != TokenKind::PendingLogin || record.expires_at <= now {
+                return Err(LoginError::InvalidMfaToken);
+            }
+            event.actor = Some(record.user_id);
+            event.client_id = Some(record.client_id);
+            let mut pending: PendingLogin = bincode::deserialize(&record.data).map_err(|_| LoginError::Internal("invalid pending login"))?;
+            let user = S::get_user(store_state.clone(), record.user_id)
+                .await
+                .map_err(|_| LoginError::UserNotFound)?;
+            let email = user.get_email();
+            event.subject = Some(email.to_string());
+            if let Err(error) = rate_limit::check_lockout::<S>(store_state.clone(), record.client_id, email, now).await {
+                // NOTE: The pending login can be finished once the lockout is over
+                S::insert_token(store_state, record)
+                    .await
+                    .map_err(|_| LoginError::Internal("failed to store pending login"))?;
+                return Err(error.into());
+            }
+
+            match mfa::verify::<S>(store_state.clone(), record.user_id, &request.code, now).await {
+                Ok(_) => {}
+                Err(mfa::MfaError::InvalidCode) => {
+                    rate_limit::record_failure::<S>(store_state.clone(), &limits, record.client_id, email, now)
+                        .await
+                        .map_err(|_| LoginError::Internal("failed to record failure"))?;
+                    pending.attempts += 1;
+                    if pending.attempts < MAX_SECOND_FACTOR_ATTEMPTS {
+                        let record = TokenRecord { data: bincode::serialize(&pending).unwrap(), ..record };
+                        S::insert_token(store_state, record)
+                            .await
+                            .map_err(|_| LoginError::Internal("failed to store pending login"))?;
+                    }
+                    return Err(LoginError::InvalidCode);
+                }
+                Err(mfa::MfaError::NotEnrolled) => return Err(LoginError::SecondFactorNotEnrolled),
+                Err(_) => return Err(LoginError::Internal("failed to verify code")),
+            }
+            rate_limit::clear_failures::<S>(store_state.clone(), record.client_id, email)
+                .await
+                .map_err(|_| LoginError::Internal("failed to clear failures"))?;
+
+            let lineage = tenant::lineage::<S>(store_state.clone(), record.client_id)
+                .await
+                .map_err(|_| LoginError::ClientNotFound)?;
+            tenant::check_enabled(&lineage).map_err(|_| LoginError::ClientDisabled)?;
+            let authentication = pending.authentication.with_one_time_password();
+            sign_login::<S>(self.issuer(), store_state, &lineage, &authentication, pending.nonce, now).await
+        }.await;
+        audit::report(self.audit_sink(), event, &result, false).await.map_err(|_| LoginError::Internal("failed to record audit event"))?;
+        result
+    }
+
+    /// Adds a key to the user, given proofs from an active key of the user and from the new key, see
+    /// [`user_keys`]. Wrong proofs and unknown emails count towards the lockout of the email
+    #[tracing::instrument(
+        skip_all,
+        fields(client_id = %request.payload.client_id, email = %telemetry::redact_email(&request.payload.email)),
+    )]
+    async fn rotate_key(
+        &self,
+        request: user_keys::KeyRotationRequest,
+        store_state: S::State,
+    ) -> Result<UserPublicKey, LoginError> {
+        let now = self.now();
+        let mut event = audit::AuditEvent {
+            client_id: Some(request.payload.client_id),
+            subject: Some(user_keys::key_id(&request.new_key_proof.public_key())),
+            request: audit::RequestMetadata { ip: request.client_ip },
+            ..audit::AuditEvent::new(audit::AuditAction::KeyChange, now)
+        };
+        // NOTE: A block so the early returns are reported too
+        let result = async {
+            let limits = self.login_limits();
+            let (client_id, email) = (request.payload.client_id, request.payload.email.as_str());
+            rate_limit::acquire_login::<S>(store_state.clone(), &limits, client_id, email, request.client_ip, now).await?;
+            let failed = |error: LoginError| {
+                let store_state = store_state.clone();
+                async move {
+                    rate_limit::record_failure::<S>(store_state, &limits, client_id, email, now)
+                        .await
+                        .map_err(|_| LoginError::Internal("failed to record failure"))?;
+                    Err::<UserPublicKey, _>(error)
+                }
+            };
+
+            let lineage = tenant::lineage::<S>(store_state.clone(), client_id)
+                .await
+                .map_err(|_| LoginError::ClientNotFound)?;
+            tenant::check_enabled(&lineage).map_err(|_| LoginError::ClientDisabled)?;
+            let settings = tenant::settings_of(&lineage);
+            if !settings.allowed_curves.contains(&request.new_key_proof.curve()) {
+                return Err(LoginError::CurveNotAllowed);
+            }
+
+            let user = match S::get_user_by_email(store_state.clone(), client_id, email).await {
+                Ok(user) => user,
+                Err(_) => return failed(LoginError::UserNotFound).await,
+            };
+            event.actor = Some(user.get_id());
+            match user_keys::rotate_key::<S>(store_state.clone(), user.get_id(), &request, now).await {
+                Ok(key) => Ok(key),
+                Err(user_keys::UserKeyError::InvalidProof | user_keys::UserKeyError::UnknownKey) => failed(LoginError::InvalidProof).await,
+                Err(user_keys::UserKeyError::DuplicateKey) => Err(LoginError::DuplicateKey),
+                Err(user_keys::UserKeyError::TooManyKeys) => Err(LoginError::TooManyKeys),
+                Err(user_keys::UserKeyError::InvalidLabel) => Err(LoginError::InvalidLabel),
+                Err(_) => Err(LoginError::Internal("failed to add key")),
+            }
+        }.await;
+        audit::report(self.audit_sink(), event, &result, false).await.map_err(|_| LoginError::Internal("failed to record audit event"))?;
+        result
+    }
+
+}
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct UserRegisterRequest<M> {
+    pub client_id: Identifier,
+    pub email: String,
+    pub metadata: Option<M>,
+    /// First key of the user, proven over the [`UserLoginPayload`] of the new account
+    pub key: user_keys::NewUserKey,
+
+    /// Address the request comes from, set by the server for the audit events
+    #[serde(skip)]
+    pub client_ip: Option<IpAddr>,
+}
+
+#[async_trait::async_trait]
+pub trait UserRegistration<S>
+where
+    S: UserStore + Transaction {
+    /// Clock of the audit events
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    /// Where the registrations are reported, see [`audit`]
+    fn audit_sink(&self) -> Option<&dyn audit::AuditSink> {
+        None
+    }
+
+    /// Checks that the email is free and creates the user in the same transaction, with the key of the
+    /// request
+    #[tracing::instrument(
+        skip_all,
+        fields(client_id = %request.client_id, email = %telemetry::redact_email(&request.email)),
+    )]
+    async fn register(
+        &self,
+        request: UserRegisterRequest<UserMetadataOf<S>>,
+        store_state: S::State,
+    ) -> Result<S::User, RegistrationError> {
+        let now = UserRegistration::now(self);
+        let mut event = audit::AuditEvent {
+            client_id: Some(request.client_id),
+            subject: Some(request.email.clone()),
+            request: audit::RequestMetadata { ip: request.client_ip },
+            ..audit::AuditEvent::new(audit::AuditAction::Registration, now)
+        };
+        // NOTE: A block so the early returns are reported too
+        let result = async {
+            let payload = UserLoginPayload { client_id: request.client_id, email: request.email.clone() };
+            let key = user_keys::verify_new_key(&request.key, &payload, now).map_err(|error| match error {
+                user_keys::UserKeyError::InvalidLabel => RegistrationError::InvalidLabel,
+                _ => RegistrationError::InvalidProof,
+            })?;
+            let transaction = S::begin(store_state)
+                .await
+                .map_err(|_| RegistrationError::Internal("failed to start transaction"))?;
+
+            match S::get_user_by_email(transaction.clone(), request.client_id, &request.email).await.map_err(Into::into) {
+                Err(StoreError::NotFound) => {}
+                Ok(_) => return Err(RegistrationError::EmailTaken),
+                Err(_) => return Err(RegistrationError::Internal("failed to look up user")),
+            }
+
+            let user = NewUser {
+                client_id: request.client_id,
+                email: request.email,
+                metadata: request.metadata,
+            };
+            let user = S::create_user(transaction.clone(), user)
+                .await
+                .map_err(|error| match error.into() {
+                    StoreError::Conflict => RegistrationError::EmailTaken,
+                    StoreError::NotFound => RegistrationError::ClientNotFound,
+                    _ => RegistrationError::Internal("failed to create user"),
+                })?;
+            let credentials = UserCredentials { public_keys: vec![key], ..UserCredentials::default() };
+            S::set_user_credentials(transaction.clone(), user.get_id(), credentials)
+                .await
+                .map_err(|_| RegistrationError::Internal("failed to store key"))?;
+
+            S::commit(transaction)
+                .await
+                .map_err(|_| RegistrationError::Internal("failed to commit transaction"))?;
+
+            event.actor = Some(user.get_id());
+            Ok(user)
+        }.await;
+        audit::report(UserRegistration::audit_sink(self), event, &result, false).await.map_err(|_| RegistrationError::Internal("failed to record audit event"))?;
+        result
+    }
+}
+
+#[cfg(all(test, feature = "in-memory"))]
+pub(crate) mod tests {
+    use elliptic_curve::sec1::ToEncodedPoint;
+    use elliptic_curve::Field;
+    use p256::ecdsa::VerifyingKey;
+    use p256::{NistP256, ProjectivePoint, Scalar};
+
+    use crate::crypto::schnorr::Shnorr;
+    use crate::crypto::secret::Secret;
+    use crate::crypto::token::TokenVerifier;
+    use crate::store::memory::{InMemoryState, InMemoryStore, InMemoryUser};
+    use crate::store::{ClientUpdate, NewClient};
+
+    use super::*;
+
+    pub(crate) struct Authentication;
+
+    pub(crate) const ISSUER: &str = "https://iam0.example.com";
+
+    impl UserAuthentication<InMemoryStore> for Authentication {
+        fn issuer(&self) -> &str {
+            ISSUER
         }
+    }
 
-        let user = CS::get_user_by_email(client_store_state.clone(), &request.payload.email)
-            .await
-            .map_err(|_| "user not found".to_string())?;
+    /// Login with a new key no user holds
+    pub(crate) fn login_request(client_id: Identifier, email: &str) -> UserLoginRequest {
+        login_request_with_key(client_id, email, &Secret::new(Scalar::random(&mut rand::thread_rng())))
+    }
 
-        let token_payload = UserTokenPayload {
-            user_id: user.id,
-            client_id: request.payload.client_id,
-            // TOOD: roles,
+    pub(crate) fn login_request_with_key(client_id: Identifier, email: &str, private_key: &Secret<Scalar>) -> UserLoginRequest {
+        let public_key = (ProjectivePoint::GENERATOR * private_key.expose_secret()).into();
+        let payload = UserLoginPayload {
+            client_id,
+            email: email.to_string(),
         };
+        let (proof, commitment) = NistP256.proof(&Vec::from(&payload), private_key);
+        UserLoginRequest {
+            payload,
+            proof: ShnorrProof::CurveNistP256 { commitment, proof, public_key },
+            nonce: Some("n-0S6_WzA2Mj".to_string()),
+            client_ip: None,
+        }
+    }
 
-        let signing_key_bytes = CS::get_signing_key_bytes(client_store_state)
+    /// Seeds a user with a key, for the logins of [`login_request_with_key`]
+    pub(crate) fn seed_user_with_key(state: &InMemoryState, client_id: Identifier, email: &str) -> (InMemoryUser, Secret<Scalar>) {
+        let user = state.seed_user(client_id, email);
+        let private_key = Secret::new(Scalar::random(&mut rand::thread_rng()));
+        let public_key: p256::AffinePoint = (ProjectivePoint::GENERATOR * private_key.expose_secret()).into();
+        state.seed_user_key(user.id, public_key.to_encoded_point(false).as_bytes());
+        (user, private_key)
+    }
+
+    #[tokio::test]
+    async fn login_with_seeded_user() {
+        let state = InMemoryState::new();
+        let client = state.seed_client("app");
+        let (_, private_key) = seed_user_with_key(&state, client.id, "alice@example.com");
+        let signing_key = state.seed_signing_key(client.id);
+
+        let response = Authentication
+            .login(login_request_with_key(client.id, "Alice@Example.com", &private_key), state.clone())
             .await
-            .map_err(|_| "failed to retrieve signing key")?;
+            .unwrap()
+            .authenticated()
+            .unwrap();
+        assert!(TokenVerifier::verify(&VerifyingKey::from(&signing_key), &response.token));
+        let claims = oidc::verify_id_token(&VerifyingKey::from(&signing_key), &response.id_token, ISSUER, client.id, SystemTime::now()).unwrap();
+        assert_eq!(claims.sub, response.token.payload().user_id.as_hex());
+        assert_eq!(claims.nonce.as_deref(), Some("n-0S6_WzA2Mj"));
+        assert_eq!(claims.amr, vec![oidc::AMR_SCHNORR.to_string()]);
+
+        assert!(Authentication.login(login_request(client.id, "bob@example.com"), state.clone()).await.is_err());
+        // Only the keys of the user are accepted, and a user without keys has none
+        let error = Authentication.login(login_request(client.id, "alice@example.com"), state.clone()).await.err();
+        assert_eq!(error, Some(LoginError::InvalidProof));
+        state.seed_user(client.id, "carol@example.com");
+        let error = Authentication.login(login_request(client.id, "carol@example.com"), state).await.err();
+        assert_eq!(error, Some(LoginError::InvalidProof));
+    }
+
+    #[tokio::test]
+    async fn login_uses_the_client_hierarchy() {
+        let state = InMemoryState::new();
+        let organisation = state.seed_client("organisation");
+        let application = InMemoryStore::create_client(state.clone(), NewClient {
+            parent_id: Some(organisation.id),
+            name: "app".to_string(),
+            ..NewClient::default()
+        }).await.unwrap();
+        let (_, private_key) = seed_user_with_key(&state, application.id, "alice@example.com");
+        let signing_key = state.seed_signing_key(organisation.id);
 
-        let signing_key = SigningKey::from_slice(signing_key_bytes.as_slice()).unwrap();
-        let token = TokenSigner::sign(&signing_key, token_payload);
+        let response = Authentication
+            .login(login_request_with_key(application.id, "alice@example.com", &private_key), state.clone())
+            .await
+            .unwrap()
+            .authenticated()
+            .unwrap();
+        assert!(TokenVerifier::verify(&VerifyingKey::from(&signing_key), &response.token));
 
-        Ok(UserLoginResponse { token })
+        let update = ClientUpdate { disabled: Some(true), ..Default::default() };
+        InMemoryStore::update_client(state.clone(), organisation.id, update).await.unwrap();
+        let error = Authentication.login(login_request_with_key(application.id, "alice@example.com", &private_key), state).await.err();
+        assert_eq!(error, Some(LoginError::ClientDisabled));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,791 @@
+//! OAuth 2.0 authorization code flow (RFC 6749) with mandatory PKCE (RFC 7636). Only the `S256` challenge
+//! method is accepted, codes are single use and expire after [`AUTHORIZATION_CODE_LIFETIME`], and refresh
+//! tokens are rotated on every use. Confidential clients must authenticate on every token request, see
+//! [`client_auth`](crate::service::client_auth), and can get tokens for themselves with the
+//! `client_credentials` grant
+
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use elliptic_curve::subtle::ConstantTimeEq;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::crypto::token::{Token, TokenSigner};
+use crate::data::id::Identifier;
+use crate::model::{Client, User};
+use crate::service::audit;
+use crate::service::client_auth::ClientAuthentication;
+use crate::service::oidc::{self, AuthenticationContext};
+use crate::service::tenant::{self, TenantError};
+use crate::store::{ClientStore, StoreError, TokenKind, TokenRecord, TokenStore, UserStore};
+use crate::telemetry;
+
+pub const GRANT_TYPES: &[&str] = &["authorization_code", "refresh_token", "client_credentials"];
+pub const AUTHORIZATION_CODE_LIFETIME: Duration = Duration::from_secs(60);
+/// Random bytes of the authorization codes and refresh tokens
+const OPAQUE_TOKEN_SIZE: usize = 32;
+
+/// Error codes of RFC 6749 section 4.1.2.1 and 5.2, serialized as the `error` field of the response
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "error", content = "error_description", rename_all = "snake_case")]
+pub enum OAuthError {
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("invalid client: {0}")]
+    InvalidClient(String),
+    #[error("invalid grant: {0}")]
+    InvalidGrant(String),
+    #[error("unauthorized client: {0}")]
+    UnauthorizedClient(String),
+    #[error("unsupported grant type: {0}")]
+    UnsupportedGrantType(String),
+    #[error("unsupported response type: {0}")]
+    UnsupportedResponseType(String),
+    #[error("invalid scope: {0}")]
+    InvalidScope(String),
+    #[error("server error: {0}")]
+    ServerError(String),
+    /// Bearer token errors of RFC 6750 section 3.1
+    #[error("invalid token: {0}")]
+    InvalidToken(String),
+    #[error("insufficient scope: {0}")]
+    InsufficientScope(String),
+}
+
+impl OAuthError {
+    /// Name of the error in RFC 6749
+    pub fn code(&self) -> &'static str {
+        match self {
+            OAuthError::InvalidRequest(_) => "invalid_request",
+            OAuthError::InvalidClient(_) => "invalid_client",
+            OAuthError::InvalidGrant(_) => "invalid_grant",
+            OAuthError::UnauthorizedClient(_) => "unauthorized_client",
+            OAuthError::UnsupportedGrantType(_) => "unsupported_grant_type",
+            OAuthError::UnsupportedResponseType(_) => "unsupported_response_type",
+            OAuthError::InvalidScope(_) => "invalid_scope",
+            OAuthError::ServerError(_) => "server_error",
+            OAuthError::InvalidToken(_) => "invalid_token",
+            OAuthError::InsufficientScope(_) => "insufficient_scope",
+        }
+    }
+}
+
+impl telemetry::FailureReason for OAuthError {
+    fn failure_reason(&self) -> &str {
+        self.code()
+    }
+}
+
+impl From<StoreError> for OAuthError {
+    fn from(error: StoreError) -> Self {
+        OAuthError::ServerError(error.to_string())
+    }
+}
+
+impl From<TenantError> for OAuthError {
+    fn from(error: TenantError) -> Self {
+        match error {
+            TenantError::Store(StoreError::NotFound) => OAuthError::InvalidClient("unknown client".to_string()),
+            TenantError::Disabled(_) => OAuthError::UnauthorizedClient("the client is disabled".to_string()),
+            error => OAuthError::ServerError(error.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthorizationRequest {
+    pub client_id: Identifier,
+    pub redirect_uri: String,
+    pub response_type: String,
+    pub scope: String,
+    pub state: String,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+    /// Copied to the ID token, OpenID Connect Core 1.0 section 3.1.2.1
+    pub nonce: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuthorizationResponse {
+    pub code: String,
+    pub state: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    /// Optional when the client authenticates with the `Authorization` header
+    pub client_id: Option<Identifier>,
+    pub client_secret: Option<String>,
+    pub client_assertion_type: Option<String>,
+    pub client_assertion: Option<String>,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub code_verifier: Option<String>,
+    pub refresh_token: Option<String>,
+    /// Requested scopes of the `client_credentials` grant, all the scopes of the client when unset
+    pub scope: Option<String>,
+
+    /// Address the request comes from, set by the server for the audit events
+    #[serde(skip)]
+    pub client_ip: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TokenResponse {
+    pub token_type: String,
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub expires_in: u64,
+    pub scope: String,
+    /// Issued when the `openid` scope was granted to a user
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessTokenPayload {
+    /// `None` on the machine to machine tokens of the `client_credentials` grant
+    pub user_id: Option<Identifier>,
+    pub client_id: Identifier,
+    pub scope: String,
+    /// Seconds since the unix epoch
+    pub expires_at: u64,
+}
+
+pub type AccessToken = Token<AccessTokenPayload, p256::NistP256>;
+
+/// What an authorization code was issued for, stored as the data of its [`TokenRecord`]
+#[derive(Debug, Serialize, Deserialize)]
+struct AuthorizationGrant {
+    redirect_uri: String,
+    scope: String,
+    code_challenge: String,
+    nonce: Option<String>,
+    authentication: AuthenticationContext,
+}
+
+/// Stored as the data of the refresh token [`TokenRecord`]
+#[derive(Debug, Serialize, Deserialize)]
+struct RefreshGrant {
+    scope: String,
+    authentication: AuthenticationContext,
+}
+
+/// What a redeemed grant gives access to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrantedAccess {
+    /// `None` for the `client_credentials` grant
+    pub authentication: Option<AuthenticationContext>,
+    pub scope: String,
+    pub nonce: Option<String>,
+}
+
+impl GrantedAccess {
+    fn has_scope(&self, scope: &str) -> bool {
+        self.scope.split(' ').any(|granted| granted == scope)
+    }
+}
+
+pub(crate) fn opaque_token() -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(rand::random::<[u8; OPAQUE_TOKEN_SIZE]>())
+}
+
+pub(crate) fn token_hash(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Verifiers are 43 to 128 characters of `[A-Za-z0-9-._~]`, RFC 7636 section 4.1
+fn valid_code_verifier(verifier: &str) -> bool {
+    (43..=128).contains(&verifier.len())
+        && verifier.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"-._~".contains(&byte))
+}
+
+/// `S256` transformation of the verifier compared in constant time with the challenge
+pub fn verify_pkce(code_verifier: &str, code_challenge: &str) -> bool {
+    if !valid_code_verifier(code_verifier) {
+        return false;
+    }
+    let computed = BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+    computed.as_bytes().ct_eq(code_challenge.as_bytes()).into()
+}
+
+/// Scopes of a `client_credentials` grant, the requested ones must be a subset of the client scopes
+fn client_scope(client_scopes: &[String], requested: Option<&str>) -> Result<String, OAuthError> {
+    let Some(requested) = requested else {
+        return match client_scopes.is_empty() {
+            true => Err(OAuthError::InvalidScope("the client has no scopes".to_string())),
+            false => Ok(client_scopes.join(" ")),
+        };
+    };
+    if !valid_scope(requested) {
+        return Err(OAuthError::InvalidScope(requested.to_string()));
+    }
+    match requested.split(' ').all(|scope| client_scopes.iter().any(|granted| granted == scope)) {
+        true => Ok(requested.to_string()),
+        false => Err(OAuthError::InvalidScope(requested.to_string())),
+    }
+}
+
+fn valid_scope(scope: &str) -> bool {
+    !scope.is_empty()
+        && scope.split(' ').all(|token| {
+            !token.is_empty() && token.bytes().all(|byte| byte == b'!' || (b'#'..=b'[').contains(&byte) || (b']'..=b'~').contains(&byte))
+        })
+}
+
+#[async_trait::async_trait]
+pub trait AuthorizationService<S>
+where
+    S: UserStore + ClientStore + TokenStore,
+{
+    /// Clock used for expirations
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    /// URL of the token endpoint, the audience expected on the `private_key_jwt` client assertions
+    fn token_endpoint(&self) -> &str;
+
+    /// `iss` of the ID tokens
+    fn issuer(&self) -> &str;
+
+    /// Where the issued tokens are reported, see [`audit`]
+    fn audit_sink(&self) -> Option<&dyn audit::AuditSink> {
+        None
+    }
+
+    /// Issues an authorization code for the already authenticated user.
+    ///
+    /// NOTE: Until the redirect URI has been validated errors must be shown to the user instead of being
+    /// sent to the redirect URI, every error returned before that check is [`OAuthError::InvalidClient`]
+    /// or [`OAuthError::InvalidRequest`]
+    async fn authorize(
+        &self,
+        request: AuthorizationRequest,
+        authentication: AuthenticationContext,
+        store_state: S::State,
+    ) -> Result<AuthorizationResponse, OAuthError> {
+        let lineage = tenant::lineage::<S>(store_state.clone(), request.client_id).await?;
+        let client = &lineage[0];
+        if !client.get_settings().redirect_uris.contains(&request.redirect_uri) {
+            return Err(OAuthError::InvalidRequest("the redirect URI isn't registered".to_string()));
+        }
+        tenant::check_enabled(&lineage)?;
+
+        if request.response_type != "code" {
+            return Err(OAuthError::UnsupportedResponseType(request.response_type));
+        }
+        if !valid_scope(&request.scope) {
+            return Err(OAuthError::InvalidScope(request.scope));
+        }
+        let Some(code_challenge) = request.code_challenge else {
+            return Err(OAuthError::InvalidRequest("a PKCE code challenge is required".to_string()));
+        };
+        if request.code_challenge_method.as_deref() != Some("S256") {
+            return Err(OAuthError::InvalidRequest("only the S256 code challenge method is supported".to_string()));
+        }
+
+        // Users of a parent client, like an organisation, can sign in to all of its applications
+        let user_id = authentication.user_id;
+        let user = S::get_user(store_state.clone(), user_id).await.map_err(Into::into)?;
+        if !lineage.iter().any(|client| client.get_id() == user.get_client_id()) {
+            return Err(OAuthError::UnauthorizedClient("the user doesn't belong to the client".to_string()));
+        }
+
+        let code = opaque_token();
+        let grant = AuthorizationGrant {
+            redirect_uri: request.redirect_uri,
+            scope: request.scope,
+            code_challenge,
+            nonce: request.nonce,
+            authentication,
+        };
+        let record = TokenRecord {
+            hash: token_hash(&code),
+            kind: TokenKind::AuthorizationCode,
+            user_id,
+            client_id: request.client_id,
+            expires_at: self.now() + AUTHORIZATION_CODE_LIFETIME,
+            data: bincode::serialize(&grant).unwrap(),
+        };
+        S::insert_token(store_state, record).await.map_err(Into::into)?;
+
+        Ok(AuthorizationResponse { code, state: request.state })
+    }
+
+    /// Token endpoint, supports the `authorization_code`, `refresh_token` and `client_credentials` grants.
+    /// The `authorization_header` is the raw value of the `Authorization` header of the request
+    #[tracing::instrument(skip_all, fields(grant_type = %request.grant_type))]
+    async fn token(
+        &self,
+        request: TokenRequest,
+        authorization_header: Option<&str>,
+        store_state: S::State,
+    ) -> Result<TokenResponse, OAuthError> {
+        let mut event = audit::AuditEvent {
+            client_id: request.client_id,
+            request: audit::RequestMetadata { ip: request.client_ip },
+            ..audit::AuditEvent::new(audit::AuditAction::TokenIssued, self.now())
+        };
+        // NOTE: A block so the early returns are reported too
+        let result = async {
+            let authentication = ClientAuthentication::from_request(&request, authorization_header)?;
+            let client_id = authentication.client_id();
+            event.client_id = Some(client_id);
+            let credentials = match S::get_credentials(store_state.clone(), client_id).await.map_err(Into::into) {
+                Ok(credentials) => credentials,
+                Err(StoreError::NotFound) => return Err(OAuthError::InvalidClient("unknown client".to_string())),
+                Err(error) => return Err(error.into()),
+            };
+            let confidential = authentication.verify(&credentials, self.token_endpoint(), self.now())?;
+
+            let granted = match request.grant_type.as_str() {
+                "authorization_code" => self.redeem_code(client_id, &request, store_state.clone()).await?,
+                "refresh_token" => self.redeem_refresh_token(client_id, &request, store_state.clone()).await?,
+                "client_credentials" if confidential => {
+                    let client = S::get_client(store_state.clone(), client_id).await.map_err(Into::into)?;
+                    GrantedAccess {
+                        authentication: None,
+                        scope: client_scope(client.get_settings().scopes.as_slice(), request.scope.as_deref())?,
+                        nonce: None,
+                    }
+                }
+                "client_credentials" => {
+                    return Err(OAuthError::UnauthorizedClient("public clients can't use the client_credentials grant".to_string()));
+                }
+                _ => return Err(OAuthError::UnsupportedGrantType(request.grant_type)),
+            };
+            event.actor = granted.authentication.as_ref().map(|authentication| authentication.user_id);
+            event.subject = Some(granted.scope.clone());
+            self.issue_tokens(client_id, granted, store_state).await
+        }.await;
+        audit::report(self.audit_sink(), event, &result, false)
+            .await
+            .map_err(|error| OAuthError::ServerError(error.to_string()))?;
+        result
+    }
+
+    async fn redeem_code(
+        &self,
+        client_id: Identifier,
+        request: &TokenRequest,
+        store_state: S::State,
+    ) -> Result<GrantedAccess, OAuthError> {
+        let (Some(code), Some(redirect_uri), Some(code_verifier)) = (&request.code, &request.redirect_uri, &request.code_verifier) else {
+            return Err(OAuthError::InvalidRequest("code, redirect_uri and code_verifier are required".to_string()));
+        };
+        // NOTE: The code is consumed before checking it, a failed attempt burns it
+        let record = match S::consume_token(store_state, &token_hash(code)).await.map_err(Into::into) {
+            Ok(record) => record,
+            Err(StoreError::NotFound) => return Err(OAuthError::InvalidGrant("unknown or already used code".to_string())),
+            Err(error) => return Err(error.into()),
+        };
+        if record.kind != TokenKind::AuthorizationCode || record.client_id != client_id {
+            return Err(OAuthError::InvalidGrant("the code was issued to another client".to_string()));
+        }
+        if record.expires_at <= self.now() {
+            return Err(OAuthError::InvalidGrant("the code has expired".to_string()));
+        }
+        let grant: AuthorizationGrant = bincode::deserialize(&record.data)
+            .map_err(|error| OAuthError::ServerError(error.to_string()))?;
+        if grant.redirect_uri != *redirect_uri {
+            return Err(OAuthError::InvalidGrant("the redirect URI doesn't match".to_string()));
+        }
+        if !verify_pkce(code_verifier, &grant.code_challenge) {
+            return Err(OAuthError::InvalidGrant("the code verifier doesn't match".to_string()));
+        }
+        Ok(GrantedAccess { authentication: Some(grant.authentication), scope: grant.scope, nonce: grant.nonce })
+    }
+
+    async fn redeem_refresh_token(
+        &self,
+        client_id: Identifier,
+        request: &TokenRequest,
+        store_state: S::State,
+    ) -> Result<GrantedAccess, OAuthError> {
+        let Some(refresh_token) = &request.refresh_token else {
+            return Err(OAuthError::InvalidRequest("refresh_token is required".to_string()));
+        };
+        let record = match S::consume_token(store_state, &token_hash(refresh_token)).await.map_err(Into::into) {
+            Ok(record) => record,
+            Err(StoreError::NotFound) => return Err(OAuthError::InvalidGrant("unknown or already used refresh token".to_string())),
+            Err(error) => return Err(error.into()),
+        };
+        if record.kind != TokenKind::Refresh || record.client_id != client_id {
+            return Err(OAuthError::InvalidGrant("the refresh token was issued to another client".to_string()));
+        }
+        if record.expires_at <= self.now() {
+            return Err(OAuthError::InvalidGrant("the refresh token has expired".to_string()));
+        }
+        let grant: RefreshGrant = bincode::deserialize(&record.data)
+            .map_err(|error| OAuthError::ServerError(error.to_string()))?;
+        Ok(GrantedAccess { authentication: Some(grant.authentication), scope: grant.scope, nonce: None })
+    }
+
+    /// Signs an access token with the client key. When the grant was issued to a user a new refresh token
+    /// is stored too, and an ID token is signed if the `openid` scope was granted
+    async fn issue_tokens(
+        &self,
+        client_id: Identifier,
+        granted: GrantedAccess,
+        store_state: S::State,
+    ) -> Result<TokenResponse, OAuthError> {
+        let lineage = tenant::lineage::<S>(store_state.clone(), client_id).await?;
+        tenant::check_enabled(&lineage)?;
+        let settings = tenant::settings_of(&lineage);
+        let signing_key = tenant::signing_key_of::<S>(store_state.clone(), &lineage).await?;
+
+        let now = self.now();
+        let claims = match &granted.authentication {
+            Some(authentication) if granted.has_scope("openid") => Some(oidc::id_token_claims(
+                self.issuer(),
+                client_id,
+                authentication,
+                granted.nonce.clone(),
+                now,
+                settings.access_token_lifetime,
+            )),
+            _ => None,
+        };
+        let scope = granted.scope;
+        let payload = AccessTokenPayload {
+            user_id: granted.authentication.as_ref().map(|authentication| authentication.user_id),
+            client_id,
+            scope: scope.clone(),
+            expires_at: unix_seconds(now + settings.access_token_lifetime),
+        };
+        let (access_token, id_token) = tracing::debug_span!("sign_tokens", %client_id).in_scope(|| {
+            telemetry::timed(telemetry::TOKEN_SIGNING_SECONDS, &[], || {
+                let access_token: AccessToken = TokenSigner::sign(&signing_key, payload);
+                (access_token, claims.map(|claims| oidc::sign_id_token(&signing_key, &claims)))
+            })
+        });
+
+        let refresh_token = match granted.authentication {
+            Some(authentication) => {
+                let refresh_token = opaque_token();
+                let record = TokenRecord {
+                    hash: token_hash(&refresh_token),
+                    kind: TokenKind::Refresh,
+                    user_id: authentication.user_id,
+                    client_id,
+                    expires_at: now + settings.refresh_token_lifetime,
+                    data: bincode::serialize(&RefreshGrant { scope: scope.clone(), authentication }).unwrap(),
+                };
+                S::insert_token(store_state, record).await.map_err(Into::into)?;
+                Some(refresh_token)
+            }
+            // NOTE: The client can authenticate again instead, RFC 6749 section 4.4.3
+            None => None,
+        };
+
+        Ok(TokenResponse {
+            token_type: "Bearer".to_string(),
+            access_token: access_token.encode(),
+            refresh_token,
+            expires_in: settings.access_token_lifetime.as_secs(),
+            scope,
+            id_token,
+        })
+    }
+}
+
+#[cfg(all(test, feature = "in-memory"))]
+mod tests {
+    use std::sync::Mutex;
+
+    use p256::ecdsa::VerifyingKey;
+
+    use crate::crypto::token::TokenVerifier;
+    use p256::ecdsa::SigningKey;
+
+    use crate::model::ClientSettings;
+    use crate::service::client_auth::tests::{assertion_claims, client_assertion, AUDIENCE};
+    use crate::service::client_auth::{register_confidential_client, set_client_public_key, CLIENT_ASSERTION_TYPE};
+    use crate::store::memory::{InMemoryState, InMemoryStore};
+    use crate::store::{ClientUpdate, NewClient};
+
+    use super::*;
+
+    const ISSUER: &str = "https://iam0.example.com";
+    const REDIRECT_URI: &str = "https://app.example.com/callback";
+    const VERIFIER: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+    const CHALLENGE: &str = "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM";
+
+    struct Authorization {
+        now: Mutex<SystemTime>,
+    }
+
+    impl Authorization {
+        fn new() -> Self {
+            Self { now: Mutex::new(SystemTime::now()) }
+        }
+
+        fn advance(&self, duration: Duration) {
+            *self.now.lock().unwrap() += duration;
+        }
+    }
+
+    impl AuthorizationService<InMemoryStore> for Authorization {
+        fn now(&self) -> SystemTime {
+            *self.now.lock().unwrap()
+        }
+
+        fn token_endpoint(&self) -> &str {
+            AUDIENCE
+        }
+
+        fn issuer(&self) -> &str {
+            ISSUER
+        }
+    }
+
+    struct Fixture {
+        state: InMemoryState,
+        client_id: Identifier,
+        user_id: Identifier,
+        verifying_key: VerifyingKey,
+    }
+
+    async fn fixture() -> Fixture {
+        let state = InMemoryState::new();
+        let settings = ClientSettings { redirect_uris: vec![REDIRECT_URI.to_string()], ..ClientSettings::default() };
+        let client = NewClient { parent_id: None, name: "app".to_string(), settings };
+        let client = InMemoryStore::create_client(state.clone(), client).await.unwrap();
+        let signing_key = state.seed_signing_key(client.id);
+        let user = state.seed_user(client.id, "alice@example.com");
+        Fixture {
+            state,
+            client_id: client.id,
+            user_id: user.id,
+            verifying_key: VerifyingKey::from(&signing_key),
+        }
+    }
+
+    fn authorization_request(client_id: Identifier) -> AuthorizationRequest {
+        AuthorizationRequest {
+            client_id,
+            redirect_uri: REDIRECT_URI.to_string(),
+            response_type: "code".to_string(),
+            scope: "openid profile".to_string(),
+            state: "xyz".to_string(),
+            code_challenge: Some(CHALLENGE.to_string()),
+            code_challenge_method: Some("S256".to_string()),
+            nonce: Some("n-0S6_WzA2Mj".to_string()),
+        }
+    }
+
+    fn logged_in(user_id: Identifier) -> AuthenticationContext {
+        AuthenticationContext::schnorr(user_id, SystemTime::now())
+    }
+
+    fn code_request(client_id: Identifier, code: &str) -> TokenRequest {
+        TokenRequest {
+            grant_type: "authorization_code".to_string(),
+            client_id: Some(client_id),
+            client_secret: None,
+            client_assertion_type: None,
+            client_assertion: None,
+            code: Some(code.to_string()),
+            redirect_uri: Some(REDIRECT_URI.to_string()),
+            code_verifier: Some(VERIFIER.to_string()),
+            refresh_token: None,
+            scope: None,
+            client_ip: None,
+        }
+    }
+
+    fn client_credentials_request(client_id: Identifier, client_secret: &str) -> TokenRequest {
+        TokenRequest {
+            grant_type: "client_credentials".to_string(),
+            client_secret: Some(client_secret.to_string()),
+            code: None,
+            redirect_uri: None,
+            code_verifier: None,
+            ..code_request(client_id, "")
+        }
+    }
+
+    /// Confidential client with the `jobs:read jobs:write` scopes, returns its id and secret
+    async fn machine_client(state: &InMemoryState) -> (Identifier, String) {
+        let settings = ClientSettings { scopes: vec!["jobs:read".to_string(), "jobs:write".to_string()], ..ClientSettings::default() };
+        let client = NewClient { parent_id: None, name: "worker".to_string(), settings };
+        let (client, client_secret) = register_confidential_client::<InMemoryStore>(state.clone(), client).await.unwrap();
+        state.seed_signing_key(client.id);
+        (client.id, client_secret.expose_secret().clone())
+    }
+
+    #[test]
+    fn pkce_rfc_example() {
+        assert!(verify_pkce(VERIFIER, CHALLENGE));
+        assert!(!verify_pkce(VERIFIER, "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cN"));
+        assert!(!verify_pkce("too-short", CHALLENGE));
+    }
+
+    #[tokio::test]
+    async fn code_flow() {
+        let Fixture { state, client_id, user_id, verifying_key } = fixture().await;
+        let service = Authorization::new();
+        let authentication = logged_in(user_id);
+        let auth_time = authentication.auth_time;
+
+        let response = service.authorize(authorization_request(client_id), authentication, state.clone()).await.unwrap();
+        assert_eq!(response.state, "xyz");
+
+        let tokens = service.token(code_request(client_id, &response.code), None, state.clone()).await.unwrap();
+        assert_eq!(tokens.token_type, "Bearer");
+        assert_eq!(tokens.scope, "openid profile");
+        let access_token = AccessToken::decode(&tokens.access_token).unwrap();
+        assert!(TokenVerifier::verify(&verifying_key, &access_token));
+        assert_eq!(access_token.payload().user_id, Some(user_id));
+        assert_eq!(access_token.payload().client_id, client_id);
+        let id_token = tokens.id_token.unwrap();
+        let claims = oidc::verify_id_token(&verifying_key, &id_token, ISSUER, client_id, service.now()).unwrap();
+        assert_eq!(claims.sub, user_id.as_hex());
+        assert_eq!(claims.nonce.as_deref(), Some("n-0S6_WzA2Mj"));
+
+        // Codes are single use
+        let error = service.token(code_request(client_id, &response.code), None, state.clone()).await.unwrap_err();
+        assert!(matches!(error, OAuthError::InvalidGrant(_)));
+
+        // Refresh tokens are rotated
+        let refresh_token = tokens.refresh_token.unwrap();
+        let refresh = TokenRequest {
+            grant_type: "refresh_token".to_string(),
+            refresh_token: Some(refresh_token),
+            code: None,
+            redirect_uri: None,
+            code_verifier: None,
+            ..code_request(client_id, "")
+        };
+        let refreshed = service.token(refresh.clone(), None, state.clone()).await.unwrap();
+        assert_eq!(refreshed.scope, "openid profile");
+        assert_ne!(refreshed.refresh_token, refresh.refresh_token);
+        let claims = oidc::verify_id_token(&verifying_key, &refreshed.id_token.unwrap(), ISSUER, client_id, service.now()).unwrap();
+        assert_eq!((claims.auth_time, claims.nonce), (auth_time, None));
+        assert!(matches!(service.token(refresh, None, state).await, Err(OAuthError::InvalidGrant(_))));
+    }
+
+    #[tokio::test]
+    async fn redirect_uris_are_matched_exactly() {
+        let Fixture { state, client_id, user_id, .. } = fixture().await;
+        let service = Authorization::new();
+        for redirect_uri in ["https://app.example.com/callback/", "https://app.example.com/callback?x=1", "https://evil.example.com/callback"] {
+            let request = AuthorizationRequest { redirect_uri: redirect_uri.to_string(), ..authorization_request(client_id) };
+            let error = service.authorize(request, logged_in(user_id), state.clone()).await.unwrap_err();
+            assert!(matches!(error, OAuthError::InvalidRequest(_)), "{redirect_uri}: {error}");
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_authorization_requests() {
+        let Fixture { state, client_id, user_id, .. } = fixture().await;
+        let service = Authorization::new();
+
+        let request = AuthorizationRequest { response_type: "token".to_string(), ..authorization_request(client_id) };
+        assert!(matches!(service.authorize(request, logged_in(user_id), state.clone()).await, Err(OAuthError::UnsupportedResponseType(_))));
+        let request = AuthorizationRequest { code_challenge: None, ..authorization_request(client_id) };
+        assert!(matches!(service.authorize(request, logged_in(user_id), state.clone()).await, Err(OAuthError::InvalidRequest(_))));
+        let request = AuthorizationRequest { code_challenge_method: Some("plain".to_string()), ..authorization_request(client_id) };
+        assert!(matches!(service.authorize(request, logged_in(user_id), state.clone()).await, Err(OAuthError::InvalidRequest(_))));
+        let request = AuthorizationRequest { scope: String::new(), ..authorization_request(client_id) };
+        assert!(matches!(service.authorize(request, logged_in(user_id), state.clone()).await, Err(OAuthError::InvalidScope(_))));
+
+        let other = state.seed_client("other");
+        let outsider = state.seed_user(other.id, "mallory@example.com");
+        let error = service.authorize(authorization_request(client_id), logged_in(outsider.id), state.clone()).await.unwrap_err();
+        assert!(matches!(error, OAuthError::UnauthorizedClient(_)));
+
+        let update = ClientUpdate { disabled: Some(true), ..ClientUpdate::default() };
+        InMemoryStore::update_client(state.clone(), client_id, update).await.unwrap();
+        let error = service.authorize(authorization_request(client_id), logged_in(user_id), state).await.unwrap_err();
+        assert!(matches!(error, OAuthError::UnauthorizedClient(_)));
+    }
+
+    #[tokio::test]
+    async fn codes_are_bound_to_the_request() {
+        let Fixture { state, client_id, user_id, .. } = fixture().await;
+        let service = Authorization::new();
+        let code = || async {
+            service.authorize(authorization_request(client_id), logged_in(user_id), state.clone()).await.unwrap().code
+        };
+
+        let request = TokenRequest { code_verifier: Some("a".repeat(43)), ..code_request(client_id, &code().await) };
+        assert!(matches!(service.token(request, None, state.clone()).await, Err(OAuthError::InvalidGrant(_))));
+
+        let request = TokenRequest { redirect_uri: Some("https://app.example.com/other".to_string()), ..code_request(client_id, &code().await) };
+        assert!(matches!(service.token(request, None, state.clone()).await, Err(OAuthError::InvalidGrant(_))));
+
+        let other = state.seed_client("other");
+        let request = code_request(other.id, &code().await);
+        assert!(matches!(service.token(request, None, state.clone()).await, Err(OAuthError::InvalidGrant(_))));
+
+        let request = code_request(client_id, &code().await);
+        service.advance(AUTHORIZATION_CODE_LIFETIME);
+        assert!(matches!(service.token(request, None, state.clone()).await, Err(OAuthError::InvalidGrant(_))));
+
+        let request = TokenRequest { grant_type: "password".to_string(), ..code_request(client_id, "") };
+        assert!(matches!(service.token(request, None, state).await, Err(OAuthError::UnsupportedGrantType(_))));
+    }
+
+    #[tokio::test]
+    async fn client_credentials_grant() {
+        let Fixture { state, client_id: public_client_id, .. } = fixture().await;
+        let service = Authorization::new();
+        let (client_id, client_secret) = machine_client(&state).await;
+
+        let tokens = service.token(client_credentials_request(client_id, &client_secret), None, state.clone()).await.unwrap();
+        assert_eq!(tokens.scope, "jobs:read jobs:write");
+        assert_eq!(tokens.refresh_token, None);
+        let access_token = AccessToken::decode(&tokens.access_token).unwrap();
+        assert_eq!(access_token.payload().user_id, None);
+        assert_eq!(access_token.payload().client_id, client_id);
+
+        let request = TokenRequest { scope: Some("jobs:read".to_string()), ..client_credentials_request(client_id, &client_secret) };
+        assert_eq!(service.token(request, None, state.clone()).await.unwrap().scope, "jobs:read");
+        let request = TokenRequest { scope: Some("jobs:delete".to_string()), ..client_credentials_request(client_id, &client_secret) };
+        assert!(matches!(service.token(request, None, state.clone()).await, Err(OAuthError::InvalidScope(_))));
+
+        let request = client_credentials_request(client_id, "wrong");
+        assert!(matches!(service.token(request, None, state.clone()).await, Err(OAuthError::InvalidClient(_))));
+        let request = TokenRequest { client_secret: None, ..client_credentials_request(client_id, "") };
+        assert!(matches!(service.token(request, None, state.clone()).await, Err(OAuthError::InvalidClient(_))));
+        let request = TokenRequest { client_secret: None, ..client_credentials_request(public_client_id, "") };
+        assert!(matches!(service.token(request, None, state).await, Err(OAuthError::UnauthorizedClient(_))));
+    }
+
+    #[tokio::test]
+    async fn client_authentication_methods() {
+        let Fixture { state, .. } = fixture().await;
+        let service = Authorization::new();
+        let (client_id, client_secret) = machine_client(&state).await;
+
+        let header = format!("Basic {}", base64::prelude::BASE64_STANDARD.encode(format!("{}:{client_secret}", client_id.as_hex())));
+        let request = TokenRequest { client_id: None, client_secret: None, ..client_credentials_request(client_id, "") };
+        assert!(service.token(request, Some(&header), state.clone()).await.is_ok());
+
+        let signing_key = SigningKey::random(&mut rand::thread_rng());
+        let assertion = client_assertion(&signing_key, assertion_claims(client_id, service.now()));
+        let request = TokenRequest {
+            client_secret: None,
+            client_assertion_type: Some(CLIENT_ASSERTION_TYPE.to_string()),
+            client_assertion: Some(assertion),
+            ..client_credentials_request(client_id, "")
+        };
+        assert!(matches!(service.token(request.clone(), None, state.clone()).await, Err(OAuthError::InvalidClient(_))));
+        let verifying_key = VerifyingKey::from(&signing_key);
+        set_client_public_key::<InMemoryStore>(state.clone(), client_id, Some(&verifying_key)).await.unwrap();
+        assert!(service.token(request, None, state.clone()).await.is_ok());
+
+        let request = client_credentials_request(Identifier::from(42u128), &client_secret);
+        assert!(matches!(service.token(request, None, state).await, Err(OAuthError::InvalidClient(_))));
+    }
+}
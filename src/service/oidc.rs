@@ -0,0 +1,369 @@
+//! OpenID Connect on top of the OAuth flows: ID tokens (OpenID Connect Core 1.0 section 2), the discovery
+//! document (OpenID Connect Discovery 1.0 section 3), the signing keys of a client as a JWK set and the
+//! userinfo response. ID tokens are ES256 JWTs signed with the signing key of the client, the same one
+//! that signs its access tokens
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use p256::ecdsa::{SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::jwt::{self, Jwk, JwkSet};
+use crate::crypto::token::TokenVerifier;
+use crate::data::id::Identifier;
+use crate::model::{Curve, User};
+use crate::service::client_auth::TOKEN_ENDPOINT_AUTH_METHODS;
+use crate::service::oauth::{AccessToken, OAuthError, GRANT_TYPES};
+use crate::service::tenant;
+use crate::store::{ClientStore, StoreError, UserStore};
+
+/// Authentication method reference of a Schnorr proof of knowledge of the private key of the user
+pub const AMR_SCHNORR: &str = "schnorr";
+/// One-time password, RFC 8176 section 2
+pub const AMR_OTP: &str = "otp";
+/// Multiple-factor authentication, RFC 8176 section 2
+pub const AMR_MFA: &str = "mfa";
+/// Proof-of-possession of a key, the signature of a passkey, RFC 8176 section 2
+pub const AMR_POP: &str = "pop";
+/// User presence test, RFC 8176 section 2
+pub const AMR_USER: &str = "user";
+/// Authentication context class of a login with a proof of knowledge of a private key
+pub const ACR_PROOF_OF_KNOWLEDGE: &str = "urn:iam0:acr:proof-of-knowledge";
+pub const SCOPES: &[&str] = &["openid", "profile", "email"];
+
+/// How and when the user authenticated, it travels with the grants so every ID token of a login carries
+/// the same `auth_time` and `amr`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthenticationContext {
+    pub user_id: Identifier,
+    /// Seconds since the unix epoch
+    pub auth_time: u64,
+    pub amr: Vec<String>,
+}
+
+impl AuthenticationContext {
+    /// Context of a Schnorr proof login that just happened
+    pub fn schnorr(user_id: Identifier, now: SystemTime) -> Self {
+        Self { user_id, auth_time: unix_seconds(now), amr: vec![AMR_SCHNORR.to_string()] }
+    }
+
+    /// Context of a passkey login that just happened. A passkey unlocked with a PIN or biometric counts as
+    /// two factors on its own
+    pub fn passkey(user_id: Identifier, now: SystemTime, user_verified: bool) -> Self {
+        let mut amr = vec![AMR_POP.to_string(), AMR_USER.to_string()];
+        if user_verified {
+            amr.push(AMR_MFA.to_string());
+        }
+        Self { user_id, auth_time: unix_seconds(now), amr }
+    }
+
+    /// The same login completed with a TOTP or recovery code, `auth_time` stays the one of the first factor
+    pub fn with_one_time_password(mut self) -> Self {
+        self.amr.extend([AMR_OTP.to_string(), AMR_MFA.to_string()]);
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    /// Hex identifier of the user
+    pub sub: String,
+    /// Hex identifier of the client
+    pub aud: String,
+    pub exp: u64,
+    pub iat: u64,
+    pub auth_time: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nonce: Option<String>,
+    pub amr: Vec<String>,
+    pub acr: String,
+}
+
+pub(crate) fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// ID token issued to `client_id`, it expires with the access token issued together with it
+pub fn id_token_claims(
+    issuer: &str,
+    client_id: Identifier,
+    context: &AuthenticationContext,
+    nonce: Option<String>,
+    now: SystemTime,
+    lifetime: std::time::Duration,
+) -> IdTokenClaims {
+    IdTokenClaims {
+        iss: issuer.to_string(),
+        sub: context.user_id.as_hex(),
+        aud: client_id.as_hex(),
+        exp: unix_seconds(now + lifetime),
+        iat: unix_seconds(now),
+        auth_time: context.auth_time,
+        nonce,
+        amr: context.amr.clone(),
+        acr: ACR_PROOF_OF_KNOWLEDGE.to_string(),
+    }
+}
+
+pub fn sign_id_token(signing_key: &SigningKey, claims: &IdTokenClaims) -> String {
+    jwt::encode(signing_key, claims)
+}
+
+/// Checks the signature, issuer, audience and expiration of an ID token, as a relying party would
+pub fn verify_id_token(
+    verifying_key: &VerifyingKey,
+    id_token: &str,
+    issuer: &str,
+    client_id: Identifier,
+    now: SystemTime,
+) -> Option<IdTokenClaims> {
+    let claims: IdTokenClaims = jwt::decode(verifying_key, id_token)?;
+    let valid = claims.iss == issuer && claims.aud == client_id.as_hex() && claims.exp > unix_seconds(now);
+    valid.then_some(claims)
+}
+
+/// URLs of the endpoints served by the deployment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoints {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+    pub jwks_uri: String,
+}
+
+/// The `/.well-known/openid-configuration` document
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProviderMetadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+    pub jwks_uri: String,
+    pub scopes_supported: Vec<String>,
+    pub response_types_supported: Vec<String>,
+    pub grant_types_supported: Vec<String>,
+    pub subject_types_supported: Vec<String>,
+    pub id_token_signing_alg_values_supported: Vec<String>,
+    pub token_endpoint_auth_methods_supported: Vec<String>,
+    pub code_challenge_methods_supported: Vec<String>,
+    pub claims_supported: Vec<String>,
+    pub acr_values_supported: Vec<String>,
+    /// Curves accepted for the Schnorr proofs of the login, not part of the standard
+    pub schnorr_curves_supported: Vec<Curve>,
+}
+
+fn strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|value| value.to_string()).collect()
+}
+
+pub fn provider_metadata(endpoints: &Endpoints, curves: &[Curve]) -> ProviderMetadata {
+    let mut algorithms: Vec<String> = curves.iter().map(|curve| curve.jws_algorithm().to_string()).collect();
+    algorithms.dedup();
+    ProviderMetadata {
+        issuer: endpoints.issuer.clone(),
+        authorization_endpoint: endpoints.authorization_endpoint.clone(),
+        token_endpoint: endpoints.token_endpoint.clone(),
+        userinfo_endpoint: endpoints.userinfo_endpoint.clone(),
+        jwks_uri: endpoints.jwks_uri.clone(),
+        scopes_supported: strings(SCOPES),
+        response_types_supported: strings(&["code"]),
+        grant_types_supported: strings(GRANT_TYPES),
+        subject_types_supported: strings(&["public"]),
+        id_token_signing_alg_values_supported: algorithms,
+        token_endpoint_auth_methods_supported: strings(TOKEN_ENDPOINT_AUTH_METHODS),
+        code_challenge_methods_supported: strings(&["S256"]),
+        claims_supported: strings(&["iss", "sub", "aud", "exp", "iat", "auth_time", "nonce", "amr", "acr", "email"]),
+        acr_values_supported: strings(&[ACR_PROOF_OF_KNOWLEDGE]),
+        schnorr_curves_supported: curves.to_vec(),
+    }
+}
+
+/// Public key verifying the tokens of the client, it's the key of the closest ancestor that has one
+pub async fn jwks<S: ClientStore>(state: S::State, client_id: Identifier) -> Result<JwkSet, OAuthError> {
+    let signing_key = tenant::effective_signing_key::<S>(state, client_id).await?;
+    Ok(JwkSet { keys: vec![Jwk::for_signatures(signing_key.verifying_key())] })
+}
+
+/// Claims released by the userinfo endpoint, the `profile` scope releases the user metadata
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UserInfo<M> {
+    pub sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<M>,
+}
+
+/// Claims of the user allowed by the granted `scope`, which must include `openid`
+pub fn userinfo<U: User>(user: &U, scope: &str) -> Result<UserInfo<U::UserMetadata>, OAuthError> {
+    let has_scope = |name: &str| scope.split(' ').any(|granted| granted == name);
+    if !has_scope("openid") {
+        return Err(OAuthError::InsufficientScope("openid".to_string()));
+    }
+    Ok(UserInfo {
+        sub: user.get_id().as_hex(),
+        email: has_scope("email").then(|| user.get_email().to_string()),
+        profile: if has_scope("profile") { user.get_user_metadata() } else { None },
+    })
+}
+
+/// Userinfo of the bearer of an access token, the token must have been issued to a user and still be
+/// valid
+pub async fn userinfo_of_token<S>(
+    state: S::State,
+    access_token: &str,
+    now: SystemTime,
+) -> Result<UserInfo<<S::User as User>::UserMetadata>, OAuthError>
+where
+    S: UserStore + ClientStore,
+{
+    let invalid_token = || OAuthError::InvalidToken("the access token isn't valid".to_string());
+    let token = AccessToken::decode(access_token).ok_or_else(invalid_token)?;
+    let payload = token.payload();
+    // NOTE: Tokens of a disabled client, or of a client below a disabled one, are refused like unknown ones
+    let signing_key = async {
+        let lineage = tenant::lineage::<S>(state.clone(), payload.client_id).await?;
+        tenant::check_enabled(&lineage)?;
+        tenant::signing_key_of::<S>(state.clone(), &lineage).await
+    };
+    let signing_key = match signing_key.await {
+        Ok(signing_key) => signing_key,
+        Err(tenant::TenantError::Store(StoreError::NotFound) | tenant::TenantError::Disabled(_)) => return Err(invalid_token()),
+        Err(error) => return Err(error.into()),
+    };
+    if !TokenVerifier::verify(signing_key.verifying_key(), &token) || payload.expires_at <= unix_seconds(now) {
+        return Err(invalid_token());
+    }
+    let user_id = payload.user_id.ok_or_else(invalid_token)?;
+    let user = match S::get_user(state, user_id).await.map_err(Into::into) {
+        Ok(user) => user,
+        Err(StoreError::NotFound) => return Err(invalid_token()),
+        Err(error) => return Err(error.into()),
+    };
+    userinfo(&user, &payload.scope)
+}
+
+#[cfg(all(test, feature = "in-memory"))]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    use crate::crypto::token::TokenSigner;
+    use crate::service::oauth::AccessTokenPayload;
+    use crate::store::memory::{InMemoryState, InMemoryStore};
+    use crate::model::ClientSettings;
+    use crate::store::{ClientUpdate, NewClient, UserUpdate};
+
+    use super::*;
+
+    const ISSUER: &str = "https://iam0.example.com";
+
+    #[test]
+    fn id_tokens() {
+        let signing_key = SigningKey::random(&mut rand::thread_rng());
+        let client_id = Identifier::from(1u128);
+        let now = SystemTime::now();
+        let context = AuthenticationContext::schnorr(Identifier::from(2u128), now - Duration::from_secs(30));
+        let claims = id_token_claims(ISSUER, client_id, &context, Some("n-0S6_WzA2Mj".to_string()), now, Duration::from_secs(60));
+        let id_token = sign_id_token(&signing_key, &claims);
+
+        let verified = verify_id_token(signing_key.verifying_key(), &id_token, ISSUER, client_id, now).unwrap();
+        assert_eq!(verified, claims);
+        assert_eq!(verified.amr, vec![AMR_SCHNORR.to_string()]);
+        assert_eq!(verified.auth_time + 30, verified.iat);
+        assert!(verify_id_token(signing_key.verifying_key(), &id_token, "https://other.example.com", client_id, now).is_none());
+        assert!(verify_id_token(signing_key.verifying_key(), &id_token, ISSUER, Identifier::from(3u128), now).is_none());
+        assert!(verify_id_token(signing_key.verifying_key(), &id_token, ISSUER, client_id, now + Duration::from_secs(60)).is_none());
+    }
+
+    #[test]
+    fn discovery_document() {
+        let endpoints = Endpoints {
+            issuer: ISSUER.to_string(),
+            authorization_endpoint: format!("{ISSUER}/authorize"),
+            token_endpoint: format!("{ISSUER}/oauth/token"),
+            userinfo_endpoint: format!("{ISSUER}/userinfo"),
+            jwks_uri: format!("{ISSUER}/.well-known/jwks.json"),
+        };
+        let metadata = provider_metadata(&endpoints, Curve::ALL);
+        assert_eq!(metadata.id_token_signing_alg_values_supported, vec!["ES256".to_string()]);
+        assert!(metadata.grant_types_supported.contains(&"client_credentials".to_string()));
+        assert!(metadata.token_endpoint_auth_methods_supported.contains(&"private_key_jwt".to_string()));
+        let json = serde_json::to_value(&metadata).unwrap();
+        assert_eq!(json["issuer"], ISSUER);
+        assert_eq!(json["schnorr_curves_supported"], serde_json::json!(["p256"]));
+        assert!(provider_metadata(&endpoints, &[]).id_token_signing_alg_values_supported.is_empty());
+    }
+
+    #[tokio::test]
+    async fn userinfo_follows_the_scopes() {
+        let state = InMemoryState::new();
+        let client = state.seed_client("app");
+        let signing_key = state.seed_signing_key(client.id);
+        let user = state.seed_user(client.id, "alice@example.com");
+        let metadata = BTreeMap::from([("name".to_string(), "Alice".to_string())]);
+        let update = UserUpdate { metadata: Some(metadata), ..UserUpdate::default() };
+        InMemoryStore::update_user(state.clone(), user.id, update).await.unwrap();
+
+        let now = SystemTime::now();
+        let access_token = |scope: &str, user_id: Option<Identifier>| {
+            let payload = AccessTokenPayload {
+                user_id,
+                client_id: client.id,
+                scope: scope.to_string(),
+                expires_at: unix_seconds(now) + 60,
+            };
+            let token: AccessToken = TokenSigner::sign(&signing_key, payload);
+            token.encode()
+        };
+        let userinfo = |token: String| {
+            let state = state.clone();
+            async move { userinfo_of_token::<InMemoryStore>(state, &token, now).await }
+        };
+
+        let info = userinfo(access_token("openid", Some(user.id))).await.unwrap();
+        assert_eq!(serde_json::to_value(&info).unwrap(), serde_json::json!({ "sub": user.id.as_hex() }));
+        let info = userinfo(access_token("openid email profile", Some(user.id))).await.unwrap();
+        assert_eq!(
+            serde_json::to_value(&info).unwrap(),
+            serde_json::json!({ "sub": user.id.as_hex(), "email": "alice@example.com", "name": "Alice" }),
+        );
+
+        assert!(matches!(userinfo(access_token("email", Some(user.id))).await, Err(OAuthError::InsufficientScope(_))));
+        assert!(matches!(userinfo(access_token("openid", None)).await, Err(OAuthError::InvalidToken(_))));
+        assert!(matches!(userinfo("garbage".to_string()).await, Err(OAuthError::InvalidToken(_))));
+        let token = access_token("openid", Some(user.id));
+        let expired = userinfo_of_token::<InMemoryStore>(state.clone(), &token, now + Duration::from_secs(60)).await;
+        assert!(matches!(expired, Err(OAuthError::InvalidToken(_))));
+
+        let jwks = jwks::<InMemoryStore>(state, client.id).await.unwrap();
+        assert_eq!(jwks.keys[0].verifying_key().as_ref(), Some(signing_key.verifying_key()));
+    }
+
+    #[tokio::test]
+    async fn userinfo_of_disabled_clients() {
+        let state = InMemoryState::new();
+        let parent = state.seed_client("organisation");
+        let signing_key = state.seed_signing_key(parent.id);
+        let child = NewClient { parent_id: Some(parent.id), name: "app".to_string(), settings: ClientSettings::default() };
+        let client = InMemoryStore::create_client(state.clone(), child).await.unwrap();
+        let user = state.seed_user(client.id, "alice@example.com");
+        let now = SystemTime::now();
+        let payload = AccessTokenPayload {
+            user_id: Some(user.id),
+            client_id: client.id,
+            scope: "openid".to_string(),
+            expires_at: unix_seconds(now) + 60,
+        };
+        let token: AccessToken = TokenSigner::sign(&signing_key, payload);
+        let access_token = token.encode();
+        assert!(userinfo_of_token::<InMemoryStore>(state.clone(), &access_token, now).await.is_ok());
+
+        let update = ClientUpdate { disabled: Some(true), ..ClientUpdate::default() };
+        InMemoryStore::update_client(state.clone(), parent.id, update).await.unwrap();
+        let disabled = userinfo_of_token::<InMemoryStore>(state, &access_token, now).await;
+        assert!(matches!(disabled, Err(OAuthError::InvalidToken(_))));
+    }
+}
@@ -0,0 +1,151 @@
+//! Throttling of the logins. Every attempt takes from the buckets of the IP address it comes from, of the
+//! email it is for and of the client, so a single source, a single account and the whole client are limited
+//! separately. Failed proofs and codes count against the email, and once there are too many of them the
+//! account is locked for longer and longer periods until a login succeeds or the failures are forgotten.
+//!
+//! The email keys don't depend on whether a user has the email, so the limits don't tell who is registered
+
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+use crate::data::id::Identifier;
+use crate::store::{Lockout, LockoutPolicy, RateLimit, RateLimitDecision, RateLimitKey, RateLimitStore, StoreError};
+
+#[derive(thiserror::Error, Debug)]
+pub enum RateLimitError {
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error("rate limited, retry after {retry_after:?}")]
+    Limited { retry_after: Duration },
+}
+
+/// Limits applied to the logins, see the [module](self) documentation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoginLimits {
+    pub per_ip: RateLimit,
+    pub per_email: RateLimit,
+    pub per_client: RateLimit,
+    pub lockout: LockoutPolicy,
+}
+
+impl Default for LoginLimits {
+    fn default() -> Self {
+        Self {
+            per_ip: RateLimit { burst: 30, interval: Duration::from_secs(2) },
+            per_email: RateLimit { burst: 10, interval: Duration::from_secs(30) },
+            per_client: RateLimit { burst: 1000, interval: Duration::from_millis(10) },
+            lockout: LockoutPolicy {
+                max_failures: 5,
+                base: Duration::from_secs(60),
+                max: Duration::from_secs(60 * 60),
+                reset_after: Duration::from_secs(24 * 60 * 60),
+            },
+        }
+    }
+}
+
+async fn take<S: RateLimitStore>(state: S::State, key: &RateLimitKey, limit: RateLimit, now: SystemTime) -> Result<(), RateLimitError> {
+    match S::acquire(state, key, limit, now).await.map_err(Into::into)? {
+        RateLimitDecision::Allowed => Ok(()),
+        RateLimitDecision::Limited { retry_after } => Err(RateLimitError::Limited { retry_after }),
+    }
+}
+
+/// Fails while the account is locked, without taking from any bucket
+pub async fn check_lockout<S: RateLimitStore>(state: S::State, client_id: Identifier, email: &str, now: SystemTime) -> Result<(), RateLimitError> {
+    let lockout = S::get_lockout(state, &RateLimitKey::email(client_id, email)).await.map_err(Into::into)?;
+    match lockout.remaining(now) {
+        Some(retry_after) => Err(RateLimitError::Limited { retry_after }),
+        None => Ok(()),
+    }
+}
+
+/// Takes from the bucket of the IP address, when it is known
+pub async fn acquire_ip<S: RateLimitStore>(state: S::State, limits: &LoginLimits, ip: Option<IpAddr>, now: SystemTime) -> Result<(), RateLimitError> {
+    match ip {
+        Some(ip) => take::<S>(state, &RateLimitKey::Ip(ip), limits.per_ip, now).await,
+        None => Ok(()),
+    }
+}
+
+/// Checks the lockout of the account and takes an attempt from every bucket of a login
+pub async fn acquire_login<S: RateLimitStore>(
+    state: S::State,
+    limits: &LoginLimits,
+    client_id: Identifier,
+    email: &str,
+    ip: Option<IpAddr>,
+    now: SystemTime,
+) -> Result<(), RateLimitError> {
+    check_lockout::<S>(state.clone(), client_id, email, now).await?;
+    acquire_ip::<S>(state.clone(), limits, ip, now).await?;
+    take::<S>(state.clone(), &RateLimitKey::email(client_id, email), limits.per_email, now).await?;
+    take::<S>(state, &RateLimitKey::Client(client_id), limits.per_client, now).await
+}
+
+/// Counts a failed proof or code against the account
+pub async fn record_failure<S: RateLimitStore>(
+    state: S::State,
+    limits: &LoginLimits,
+    client_id: Identifier,
+    email: &str,
+    now: SystemTime,
+) -> Result<Lockout, StoreError> {
+    S::record_failure(state, &RateLimitKey::email(client_id, email), limits.lockout, now).await.map_err(Into::into)
+}
+
+/// Forgets the failures of the account once a login succeeded
+pub async fn clear_failures<S: RateLimitStore>(state: S::State, client_id: Identifier, email: &str) -> Result<(), StoreError> {
+    S::clear_failures(state, &RateLimitKey::email(client_id, email)).await.map_err(Into::into)
+}
+
+#[cfg(all(test, feature = "in-memory"))]
+mod tests {
+    use crate::store::memory::{InMemoryState, InMemoryStore};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn login_limits() {
+        let state = InMemoryState::new();
+        let client_id = state.seed_client("app").id;
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let limits = LoginLimits {
+            per_ip: RateLimit { burst: 3, interval: Duration::from_secs(10) },
+            per_email: RateLimit { burst: 2, interval: Duration::from_secs(60) },
+            ..LoginLimits::default()
+        };
+        let ip = Some("192.0.2.1".parse().unwrap());
+
+        for _ in 0..2 {
+            acquire_login::<InMemoryStore>(state.clone(), &limits, client_id, "alice@example.com", ip, now).await.unwrap();
+        }
+        let result = acquire_login::<InMemoryStore>(state.clone(), &limits, client_id, "Alice@example.com", ip, now).await;
+        assert!(matches!(result, Err(RateLimitError::Limited { retry_after }) if retry_after == Duration::from_secs(60)));
+        // The IP address used its burst on the attempts above, other sources aren't affected
+        let result = acquire_login::<InMemoryStore>(state.clone(), &limits, client_id, "bob@example.com", ip, now).await;
+        assert!(matches!(result, Err(RateLimitError::Limited { retry_after }) if retry_after == Duration::from_secs(10)));
+        acquire_login::<InMemoryStore>(state.clone(), &limits, client_id, "bob@example.com", None, now).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn lockout() {
+        let state = InMemoryState::new();
+        let client_id = state.seed_client("app").id;
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let limits = LoginLimits::default();
+
+        for _ in 0..limits.lockout.max_failures {
+            record_failure::<InMemoryStore>(state.clone(), &limits, client_id, "alice@example.com", now).await.unwrap();
+        }
+        check_lockout::<InMemoryStore>(state.clone(), client_id, "alice@example.com", now).await.unwrap();
+        let lockout = record_failure::<InMemoryStore>(state.clone(), &limits, client_id, "alice@example.com", now).await.unwrap();
+        assert_eq!(lockout.remaining(now), Some(limits.lockout.base));
+        let result = acquire_login::<InMemoryStore>(state.clone(), &limits, client_id, "ALICE@example.com", None, now).await;
+        assert!(matches!(result, Err(RateLimitError::Limited { retry_after }) if retry_after == limits.lockout.base));
+        check_lockout::<InMemoryStore>(state.clone(), client_id, "alice@example.com", now + limits.lockout.base).await.unwrap();
+
+        clear_failures::<InMemoryStore>(state.clone(), client_id, "alice@example.com").await.unwrap();
+        acquire_login::<InMemoryStore>(state, &limits, client_id, "alice@example.com", None, now).await.unwrap();
+    }
+}
@@ -0,0 +1,97 @@
+//! The `getSpec` operation, it tells the client SDKs which groups and hash functions the Schnorr proofs
+//! can use before they generate the keys of a user
+
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::schnorr::{HashFunction, ShnorrProof};
+use crate::data::id::Identifier;
+use crate::model::Curve;
+use crate::service::tenant::{self, TenantError};
+use crate::store::ClientStore;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FiniteCyclicGroups {
+    pub elliptic_curves: Vec<Curve>,
+    /// Multiplicative groups modulo a prime, none of them is implemented yet
+    pub prime_modulus: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GetSpecResponse {
+    pub finite_cyclic_groups: FiniteCyclicGroups,
+    pub hash_functions: Vec<HashFunction>,
+}
+
+#[async_trait::async_trait]
+pub trait SpecService<S>
+where
+    S: ClientStore,
+{
+    /// Everything compiled in, the curves are the variants of [`ShnorrProof`]
+    fn compiled_spec(&self) -> GetSpecResponse {
+        GetSpecResponse {
+            finite_cyclic_groups: FiniteCyclicGroups {
+                elliptic_curves: ShnorrProof::CURVES.to_vec(),
+                prime_modulus: Vec::new(),
+            },
+            hash_functions: ShnorrProof::HASH_FUNCTIONS.to_vec(),
+        }
+    }
+
+    /// Spec accepted by the login of `client_id`, the compiled in one restricted to the curves allowed by
+    /// the effective settings of the client. Without a client it's the compiled in spec
+    async fn spec(&self, client_id: Option<Identifier>, store_state: S::State) -> Result<GetSpecResponse, TenantError> {
+        let mut spec = self.compiled_spec();
+        let Some(client_id) = client_id else {
+            return Ok(spec);
+        };
+        let lineage = tenant::lineage::<S>(store_state, client_id).await?;
+        tenant::check_enabled(&lineage)?;
+        let allowed_curves = tenant::settings_of(&lineage).allowed_curves;
+        spec.finite_cyclic_groups.elliptic_curves.retain(|curve| allowed_curves.contains(curve));
+        Ok(spec)
+    }
+}
+
+#[cfg(all(test, feature = "in-memory"))]
+mod tests {
+    use crate::model::ClientSettings;
+    use crate::store::memory::{InMemoryState, InMemoryStore};
+    use crate::store::{ClientUpdate, NewClient};
+
+    use super::*;
+
+    struct Spec;
+
+    impl SpecService<InMemoryStore> for Spec {}
+
+    #[tokio::test]
+    async fn spec_follows_the_client_settings() {
+        let state = InMemoryState::new();
+        let organisation = state.seed_client("organisation");
+        let application = NewClient { parent_id: Some(organisation.id), name: "app".to_string(), ..NewClient::default() };
+        let application = InMemoryStore::create_client(state.clone(), application).await.unwrap();
+
+        let spec = Spec.spec(None, state.clone()).await.unwrap();
+        assert_eq!(spec.finite_cyclic_groups.elliptic_curves, Curve::ALL);
+        assert_eq!(
+            serde_json::to_value(&spec).unwrap(),
+            serde_json::json!({
+                "finite_cyclic_groups": { "elliptic_curves": ["p256"], "prime_modulus": [] },
+                "hash_functions": ["sha512"],
+            }),
+        );
+        assert_eq!(Spec.spec(Some(application.id), state.clone()).await.unwrap(), spec);
+
+        let settings = ClientSettings { allowed_curves: Some(Vec::new()), ..ClientSettings::default() };
+        let update = ClientUpdate { settings: Some(settings), ..ClientUpdate::default() };
+        InMemoryStore::update_client(state.clone(), organisation.id, update).await.unwrap();
+        let restricted = Spec.spec(Some(application.id), state.clone()).await.unwrap();
+        assert!(restricted.finite_cyclic_groups.elliptic_curves.is_empty());
+        assert_eq!(restricted.hash_functions, spec.hash_functions);
+
+        let update = ClientUpdate { disabled: Some(true), ..ClientUpdate::default() };
+        InMemoryStore::update_client(state.clone(), organisation.id, update).await.unwrap();
+        assert!(matches!(Spec.spec(Some(application.id), state).await, Err(TenantError::Disabled(_))));
+    }
+}
@@ -0,0 +1,277 @@
+//! Tenant hierarchy, clients form a tree (organisations → applications) where settings and signing keys
+//! are inherited from the parents, disabling a client disables the whole subtree, and the users of a
+//! client can be managed from any of its ancestors
+
+use std::collections::HashSet;
+use std::time::Duration;
+
+use p256::ecdsa::SigningKey;
+
+use crate::data::id::Identifier;
+use crate::model::{Branding, Client, ClientSettings, Curve, User};
+use crate::store::{ClientStore, ClientUpdate, StoreError, Transaction, UserStore};
+
+/// Deepest hierarchy that is walked before giving up, real trees are only a few levels deep
+pub const MAX_DEPTH: usize = 32;
+pub const DEFAULT_ACCESS_TOKEN_LIFETIME: Duration = Duration::from_secs(15 * 60);
+pub const DEFAULT_REFRESH_TOKEN_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(thiserror::Error, Debug)]
+pub enum TenantError {
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error("the client {0} is part of a cycle, or the hierarchy is deeper than {MAX_DEPTH}")]
+    Cycle(Identifier),
+    #[error("the client {0} is disabled")]
+    Disabled(Identifier),
+}
+
+/// Settings of a client after inheriting from all of its parents and applying the defaults
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EffectiveSettings {
+    pub allowed_curves: Vec<Curve>,
+    pub access_token_lifetime: Duration,
+    pub refresh_token_lifetime: Duration,
+    pub branding: Branding,
+}
+
+impl From<ClientSettings> for EffectiveSettings {
+    fn from(settings: ClientSettings) -> Self {
+        Self {
+            allowed_curves: settings.allowed_curves.unwrap_or_else(|| Curve::ALL.to_vec()),
+            access_token_lifetime: settings.access_token_lifetime.unwrap_or(DEFAULT_ACCESS_TOKEN_LIFETIME),
+            refresh_token_lifetime: settings.refresh_token_lifetime.unwrap_or(DEFAULT_REFRESH_TOKEN_LIFETIME),
+            branding: settings.branding,
+        }
+    }
+}
+
+/// The client followed by its parents up to the root
+pub async fn lineage<S: ClientStore>(state: S::State, client_id: Identifier) -> Result<Vec<S::Client>, TenantError> {
+    let mut lineage = Vec::new();
+    let mut visited = HashSet::new();
+    let mut next = Some(client_id);
+    while let Some(id) = next {
+        if !visited.insert(id) || visited.len() > MAX_DEPTH {
+            return Err(TenantError::Cycle(id));
+        }
+        let client = S::get_client(state.clone(), id).await.map_err(Into::into)?;
+        next = client.get_parent_id();
+        lineage.push(client);
+    }
+    Ok(lineage)
+}
+
+/// All the clients below `client_id`, parents before their children
+pub async fn descendants<S: ClientStore>(state: S::State, client_id: Identifier) -> Result<Vec<S::Client>, TenantError> {
+    let mut descendants = Vec::new();
+    let mut visited = HashSet::from([client_id]);
+    let mut index = 0;
+    let mut parent_id = client_id;
+    loop {
+        for child in S::list_clients(state.clone(), Some(parent_id)).await.map_err(Into::into)? {
+            if !visited.insert(child.get_id()) {
+                return Err(TenantError::Cycle(child.get_id()));
+            }
+            descendants.push(child);
+        }
+        let Some(next) = descendants.get(index) else {
+            return Ok(descendants);
+        };
+        parent_id = next.get_id();
+        index += 1;
+    }
+}
+
+/// Settings of the first client of a [`lineage`]
+pub fn settings_of<C: Client>(lineage: &[C]) -> EffectiveSettings {
+    lineage
+        .iter()
+        .fold(ClientSettings::default(), |settings, client| settings.inherit(client.get_settings()))
+        .into()
+}
+
+/// Fails with [`TenantError::Disabled`] when any client of a [`lineage`] is disabled
+pub fn check_enabled<C: Client>(lineage: &[C]) -> Result<(), TenantError> {
+    match lineage.iter().find(|client| client.is_disabled()) {
+        Some(client) => Err(TenantError::Disabled(client.get_id())),
+        None => Ok(()),
+    }
+}
+
+/// Signing key of the closest client of a [`lineage`] that has one
+pub async fn signing_key_of<S: ClientStore>(state: S::State, lineage: &[S::Client]) -> Result<SigningKey, TenantError> {
+    for client in lineage {
+        match S::get_signing_key(state.clone(), client.get_id()).await {
+            Err(StoreError::NotFound) => continue,
+            result => return Ok(result?),
+        }
+    }
+    Err(StoreError::NotFound.into())
+}
+
+pub async fn effective_settings<S: ClientStore>(state: S::State, client_id: Identifier) -> Result<EffectiveSettings, TenantError> {
+    Ok(settings_of(&lineage::<S>(state, client_id).await?))
+}
+
+pub async fn effective_signing_key<S: ClientStore>(state: S::State, client_id: Identifier) -> Result<SigningKey, TenantError> {
+    let lineage = lineage::<S>(state.clone(), client_id).await?;
+    signing_key_of::<S>(state, &lineage).await
+}
+
+/// Fails with [`TenantError::Disabled`] when the client or any of its parents is disabled
+pub async fn ensure_enabled<S: ClientStore>(state: S::State, client_id: Identifier) -> Result<(), TenantError> {
+    check_enabled(&lineage::<S>(state, client_id).await?)
+}
+
+/// Moves the client under another parent, refusing to move it below one of its own descendants. The check
+/// and the move are one transaction, and the move fails with [`StoreError::VersionMismatch`] when the
+/// client changed since it was read
+pub async fn move_client<S>(state: S::State, client_id: Identifier, parent_id: Option<Identifier>) -> Result<S::Client, TenantError>
+where
+    S: ClientStore + Transaction,
+{
+    let transaction = S::begin(state).await.map_err(Into::into)?;
+    let client = S::get_client(transaction.clone(), client_id).await.map_err(Into::into)?;
+    if let Some(parent_id) = parent_id {
+        let parents = lineage::<S>(transaction.clone(), parent_id).await?;
+        if parents.iter().any(|parent| parent.get_id() == client_id) {
+            return Err(TenantError::Cycle(client_id));
+        }
+    }
+    let update = ClientUpdate { parent_id: Some(parent_id), expected_version: Some(client.get_version()), ..ClientUpdate::default() };
+    let client = S::update_client(transaction.clone(), client_id, update).await.map_err(Into::into)?;
+    S::commit(transaction).await.map_err(Into::into)?;
+    Ok(client)
+}
+
+/// True when `client_id` is `ancestor_id` or one of its descendants
+pub async fn is_within<S: ClientStore>(state: S::State, ancestor_id: Identifier, client_id: Identifier) -> Result<bool, TenantError> {
+    let lineage = lineage::<S>(state, client_id).await?;
+    Ok(lineage.iter().any(|client| client.get_id() == ancestor_id))
+}
+
+/// User as seen by an admin of `admin_client_id`, users of clients outside of its subtree are reported as
+/// not found
+pub async fn scoped_user<S>(state: S::State, admin_client_id: Identifier, user_id: Identifier) -> Result<S::User, TenantError>
+where
+    S: UserStore + ClientStore,
+{
+    let user = S::get_user(state.clone(), user_id).await.map_err(Into::into)?;
+    if !is_within::<S>(state, admin_client_id, user.get_client_id()).await? {
+        return Err(StoreError::NotFound.into());
+    }
+    Ok(user)
+}
+
+/// Users of `admin_client_id` and of all its descendants
+pub async fn scoped_users<S>(state: S::State, admin_client_id: Identifier) -> Result<Vec<S::User>, TenantError>
+where
+    S: UserStore + ClientStore,
+{
+    let mut users = S::list_users(state.clone(), admin_client_id).await.map_err(Into::into)?;
+    for client in descendants::<S>(state.clone(), admin_client_id).await? {
+        users.extend(S::list_users(state.clone(), client.get_id()).await.map_err(Into::into)?);
+    }
+    Ok(users)
+}
+
+#[cfg(all(test, feature = "in-memory"))]
+mod tests {
+    use crate::store::memory::{InMemoryState, InMemoryStore};
+    use crate::store::NewClient;
+
+    use super::*;
+
+    async fn create_client(state: &InMemoryState, parent_id: Option<Identifier>, settings: ClientSettings) -> Identifier {
+        let client = NewClient { parent_id, name: "client".to_string(), settings };
+        InMemoryStore::create_client(state.clone(), client).await.unwrap().id
+    }
+
+    #[tokio::test]
+    async fn settings_are_inherited() {
+        let state = InMemoryState::new();
+        let organisation = create_client(&state, None, ClientSettings {
+            access_token_lifetime: Some(Duration::from_secs(60)),
+            branding: Branding { display_name: Some("Acme".to_string()), ..Branding::default() },
+            ..ClientSettings::default()
+        }).await;
+        let application = create_client(&state, Some(organisation), ClientSettings {
+            allowed_curves: Some(vec![Curve::NistP256]),
+            branding: Branding { logo_url: Some("https://acme.test/logo.png".to_string()), ..Branding::default() },
+            ..ClientSettings::default()
+        }).await;
+
+        let settings = effective_settings::<InMemoryStore>(state.clone(), application).await.unwrap();
+        assert_eq!(settings, EffectiveSettings {
+            allowed_curves: vec![Curve::NistP256],
+            access_token_lifetime: Duration::from_secs(60),
+            refresh_token_lifetime: DEFAULT_REFRESH_TOKEN_LIFETIME,
+            branding: Branding {
+                display_name: Some("Acme".to_string()),
+                logo_url: Some("https://acme.test/logo.png".to_string()),
+                primary_color: None,
+            },
+        });
+
+        let signing_key = state.seed_signing_key(organisation);
+        assert_eq!(effective_signing_key::<InMemoryStore>(state.clone(), application).await.unwrap(), signing_key);
+        let own_key = state.seed_signing_key(application);
+        assert_eq!(effective_signing_key::<InMemoryStore>(state, application).await.unwrap(), own_key);
+    }
+
+    #[tokio::test]
+    async fn cycles_are_rejected() {
+        let state = InMemoryState::new();
+        let root = create_client(&state, None, ClientSettings::default()).await;
+        let child = create_client(&state, Some(root), ClientSettings::default()).await;
+        let grandchild = create_client(&state, Some(child), ClientSettings::default()).await;
+
+        assert!(matches!(move_client::<InMemoryStore>(state.clone(), root, Some(grandchild)).await, Err(TenantError::Cycle(_))));
+        assert!(matches!(move_client::<InMemoryStore>(state.clone(), root, Some(root)).await, Err(TenantError::Cycle(_))));
+
+        // A cycle written behind the hierarchy's back is still detected while walking it
+        let update = ClientUpdate { parent_id: Some(Some(grandchild)), ..ClientUpdate::default() };
+        InMemoryStore::update_client(state.clone(), root, update).await.unwrap();
+        assert!(matches!(lineage::<InMemoryStore>(state.clone(), child).await, Err(TenantError::Cycle(_))));
+        assert!(matches!(descendants::<InMemoryStore>(state, child).await, Err(TenantError::Cycle(_))));
+    }
+
+    #[tokio::test]
+    async fn disabling_cascades_to_children() {
+        let state = InMemoryState::new();
+        let root = create_client(&state, None, ClientSettings::default()).await;
+        let child = create_client(&state, Some(root), ClientSettings::default()).await;
+        let other = create_client(&state, None, ClientSettings::default()).await;
+        ensure_enabled::<InMemoryStore>(state.clone(), child).await.unwrap();
+
+        let update = ClientUpdate { disabled: Some(true), ..ClientUpdate::default() };
+        InMemoryStore::update_client(state.clone(), root, update).await.unwrap();
+        assert!(matches!(ensure_enabled::<InMemoryStore>(state.clone(), child).await, Err(TenantError::Disabled(id)) if id == root));
+
+        move_client::<InMemoryStore>(state.clone(), child, Some(other)).await.unwrap();
+        ensure_enabled::<InMemoryStore>(state, child).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn users_are_scoped_to_the_subtree() {
+        let state = InMemoryState::new();
+        let organisation = create_client(&state, None, ClientSettings::default()).await;
+        let application = create_client(&state, Some(organisation), ClientSettings::default()).await;
+        let other = create_client(&state, None, ClientSettings::default()).await;
+        let admin = state.seed_user(organisation, "admin@example.com");
+        let user = state.seed_user(application, "user@example.com");
+        let outsider = state.seed_user(other, "outsider@example.com");
+
+        let mut users = scoped_users::<InMemoryStore>(state.clone(), organisation).await.unwrap();
+        users.sort_by_key(|user| user.email.clone());
+        assert_eq!(users, vec![admin, user.clone()]);
+        assert_eq!(scoped_users::<InMemoryStore>(state.clone(), application).await.unwrap(), vec![user.clone()]);
+
+        assert_eq!(scoped_user::<InMemoryStore>(state.clone(), organisation, user.id).await.unwrap(), user);
+        assert!(matches!(
+            scoped_user::<InMemoryStore>(state, organisation, outsider.id).await,
+            Err(TenantError::Store(StoreError::NotFound))
+        ));
+    }
+}
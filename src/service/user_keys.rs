@@ -0,0 +1,343 @@
+//! Schnorr keys of the users. A user holds several public keys, usually one per device, each with a label
+//! and the times it was added and last used. The login only accepts proofs made with a key of the user
+//! that isn't revoked, see [`check_login_key`].
+//!
+//! [`rotate_key`] adds a key given a proof of an active key of the user and a proof of the new key, both
+//! over the same [`KeyRotationPayload`] naming the new key, so adding a key takes holding one already as
+//! well as the new one. The first key comes with the registration. Accounts without any key, created
+//! before the keys were recorded, can't log in with a proof until [`enroll_key`] gives them one.
+//!
+//! Revoking and enrolling a key aren't authenticated here, the caller must make sure the user is logged in
+
+use std::net::IpAddr;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+pub use crate::store::key_id;
+
+use crate::crypto::schnorr::ShnorrProof;
+use crate::data::id::Identifier;
+use crate::service::{update_credentials, UserLoginPayload};
+use crate::store::{StoreError, Transaction, UserCredentials, UserPublicKey, UserStore};
+
+/// Keys that aren't revoked a user can hold at once
+pub const MAX_ACTIVE_KEYS: usize = 16;
+pub const MAX_LABEL_LENGTH: usize = 64;
+/// Prefix of the rotation payloads, so their proofs can't pass for login proofs
+const ROTATION_CONTEXT: &[u8] = b"iam0 key rotation\0";
+
+#[derive(thiserror::Error, Debug)]
+pub enum UserKeyError {
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error("invalid proof")]
+    InvalidProof,
+    #[error("the key isn't registered to the user")]
+    UnknownKey,
+    #[error("the key is already registered")]
+    DuplicateKey,
+    #[error("the user already has {MAX_ACTIVE_KEYS} keys")]
+    TooManyKeys,
+    #[error("the last key of the user can't be revoked")]
+    LastKey,
+    #[error("the user already has a key, add the others with a key rotation")]
+    AlreadyEnrolled,
+    #[error("the label must have between 1 and {MAX_LABEL_LENGTH} printable characters")]
+    InvalidLabel,
+}
+
+/// What both proofs of a key rotation are made over
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyRotationPayload {
+    pub client_id: Identifier,
+    pub email: String,
+    /// Hex of the uncompressed SEC1 encoding of the new key
+    pub new_public_key: String,
+    pub label: String,
+}
+
+impl From<&KeyRotationPayload> for Vec<u8> {
+    fn from(value: &KeyRotationPayload) -> Self {
+        let mut bytes = ROTATION_CONTEXT.to_vec();
+        bytes.extend_from_slice(u128::from(value.client_id).to_le_bytes().as_ref());
+        // NOTE: Length prefixed so the boundaries between the fields can't be moved
+        for field in [&value.email, &value.new_public_key, &value.label] {
+            bytes.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(field.as_bytes());
+        }
+        bytes
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct KeyRotationRequest {
+    #[serde(flatten)]
+    pub payload: KeyRotationPayload,
+    /// Made with an active key of the user
+    pub proof: ShnorrProof,
+    /// Made with the new key
+    pub new_key_proof: ShnorrProof,
+
+    /// Address the request comes from, set by the server for the per IP limits
+    #[serde(skip)]
+    pub client_ip: Option<IpAddr>,
+}
+
+/// Key sent with the registration, proven over the [`UserLoginPayload`] of the new account
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewUserKey {
+    pub label: String,
+    #[serde(flatten)]
+    pub proof: ShnorrProof,
+}
+
+fn check_label(label: &str) -> Result<(), UserKeyError> {
+    let length = label.chars().count();
+    if length == 0 || length > MAX_LABEL_LENGTH || label.chars().any(char::is_control) {
+        return Err(UserKeyError::InvalidLabel);
+    }
+    Ok(())
+}
+
+fn public_key_of(proof: &ShnorrProof, label: String, now: SystemTime) -> UserPublicKey {
+    let public_key = proof.public_key();
+    UserPublicKey {
+        id: key_id(&public_key),
+        curve: proof.curve(),
+        public_key,
+        label,
+        created_at: now,
+        last_used_at: None,
+        revoked_at: None,
+    }
+}
+
+fn active_keys(credentials: &UserCredentials) -> impl Iterator<Item = &UserPublicKey> {
+    credentials.public_keys.iter().filter(|key| key.revoked_at.is_none())
+}
+
+fn add_key(credentials: &mut UserCredentials, key: UserPublicKey) -> Result<(), UserKeyError> {
+    if credentials.public_keys.iter().any(|stored| stored.id == key.id) {
+        return Err(UserKeyError::DuplicateKey);
+    }
+    if active_keys(credentials).count() >= MAX_ACTIVE_KEYS {
+        return Err(UserKeyError::TooManyKeys);
+    }
+    credentials.public_keys.push(key);
+    Ok(())
+}
+
+/// Checks the key sent with the registration of the account `payload` describes
+pub fn verify_new_key(key: &NewUserKey, payload: &UserLoginPayload, now: SystemTime) -> Result<UserPublicKey, UserKeyError> {
+    check_label(&key.label)?;
+    if !key.proof.verify(payload) {
+        return Err(UserKeyError::InvalidProof);
+    }
+    Ok(public_key_of(&key.proof, key.label.clone(), now))
+}
+
+/// Checks that the key of a verified login proof is an active key of the user and marks it as used. An
+/// account without keys has none to match
+pub async fn check_login_key<S>(state: S::State, user_id: Identifier, proof: &ShnorrProof, now: SystemTime) -> Result<UserPublicKey, UserKeyError>
+where
+    S: UserStore + Transaction,
+{
+    update_credentials::<S, _, _, _>(state, user_id, |credentials| {
+        let id = key_id(&proof.public_key());
+        let key = credentials
+            .public_keys
+            .iter_mut()
+            .find(|key| key.id == id && key.revoked_at.is_none())
+            .ok_or(UserKeyError::UnknownKey)?;
+        key.last_used_at = Some(now);
+        Ok(key.clone())
+    }).await
+}
+
+/// Adds the new key of the request to the user the payload names, see the [module](self) documentation
+pub async fn rotate_key<S>(state: S::State, user_id: Identifier, request: &KeyRotationRequest, now: SystemTime) -> Result<UserPublicKey, UserKeyError>
+where
+    S: UserStore + Transaction,
+{
+    check_label(&request.payload.label)?;
+    if !request.proof.verify(&request.payload)
+        || !request.new_key_proof.verify(&request.payload)
+        || hex::encode(request.new_key_proof.public_key()) != request.payload.new_public_key.to_lowercase()
+    {
+        return Err(UserKeyError::InvalidProof);
+    }
+    let new_key = public_key_of(&request.new_key_proof, request.payload.label.clone(), now);
+    let id = key_id(&request.proof.public_key());
+    update_credentials::<S, _, _, _>(state, user_id, |credentials| {
+        let key = credentials
+            .public_keys
+            .iter_mut()
+            .find(|key| key.id == id && key.revoked_at.is_none())
+            .ok_or(UserKeyError::UnknownKey)?;
+        key.last_used_at = Some(now);
+        add_key(credentials, new_key.clone())?;
+        Ok(new_key)
+    }).await
+}
+
+/// Gives its first key to an account without any, `payload` names the account like at the registration.
+/// The caller authenticates the user some other way, a passkey login or a check of the operator
+pub async fn enroll_key<S>(
+    state: S::State,
+    user_id: Identifier,
+    key: &NewUserKey,
+    payload: &UserLoginPayload,
+    now: SystemTime,
+) -> Result<UserPublicKey, UserKeyError>
+where
+    S: UserStore + Transaction,
+{
+    let key = verify_new_key(key, payload, now)?;
+    update_credentials::<S, _, _, _>(state, user_id, |credentials| {
+        if active_keys(credentials).next().is_some() {
+            return Err(UserKeyError::AlreadyEnrolled);
+        }
+        add_key(credentials, key.clone())?;
+        Ok(key)
+    }).await
+}
+
+/// Revokes the key with the id, the user must keep at least one active key. Revoking a revoked key
+/// changes nothing
+pub async fn revoke_key<S>(state: S::State, user_id: Identifier, id: &str, now: SystemTime) -> Result<UserPublicKey, UserKeyError>
+where
+    S: UserStore + Transaction,
+{
+    update_credentials::<S, _, _, _>(state, user_id, |credentials| {
+        let active = active_keys(credentials).count();
+        let key = credentials.public_keys.iter_mut().find(|key| key.id == id).ok_or(UserKeyError::UnknownKey)?;
+        if key.revoked_at.is_none() {
+            if active == 1 {
+                return Err(UserKeyError::LastKey);
+            }
+            key.revoked_at = Some(now);
+        }
+        Ok(key.clone())
+    }).await
+}
+
+/// Every key of the user, the revoked ones included
+pub async fn list_keys<S: UserStore>(state: S::State, user_id: Identifier) -> Result<Vec<UserPublicKey>, StoreError> {
+    let credentials = S::get_user_credentials(state, user_id).await.map_err(Into::into)?;
+    Ok(credentials.public_keys)
+}
+
+#[cfg(all(test, feature = "in-memory"))]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::client::UserKey;
+    use crate::model::Curve;
+    use crate::store::memory::{InMemoryState, InMemoryStore};
+
+    use super::*;
+
+    fn login_proof(key: &UserKey, client_id: Identifier) -> ShnorrProof {
+        key.prove(UserLoginPayload { client_id, email: "alice@example.com".to_string() }, None).proof
+    }
+
+    async fn enroll(state: &InMemoryState, user_id: Identifier, key: &UserKey, client_id: Identifier, now: SystemTime) -> Result<UserPublicKey, UserKeyError> {
+        let new_key = key.registration_key(client_id, "alice@example.com", "laptop");
+        let payload = UserLoginPayload { client_id, email: "alice@example.com".to_string() };
+        enroll_key::<InMemoryStore>(state.clone(), user_id, &new_key, &payload, now).await
+    }
+
+    #[tokio::test]
+    async fn rotation() {
+        let state = InMemoryState::new();
+        let client_id = state.seed_client("app").id;
+        let user = state.seed_user(client_id, "alice@example.com");
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let (laptop, phone, other) = (UserKey::generate(Curve::NistP256), UserKey::generate(Curve::NistP256), UserKey::generate(Curve::NistP256));
+
+        // An account without keys accepts no login until a key is enrolled, and only the first one is
+        let result = check_login_key::<InMemoryStore>(state.clone(), user.id, &login_proof(&laptop, client_id), now).await;
+        assert!(matches!(result, Err(UserKeyError::UnknownKey)));
+        let payload = UserLoginPayload { client_id, email: "alice@example.com".to_string() };
+        let other_client = laptop.registration_key(Identifier::from(42u128), "alice@example.com", "laptop");
+        let result = enroll_key::<InMemoryStore>(state.clone(), user.id, &other_client, &payload, now).await;
+        assert!(matches!(result, Err(UserKeyError::InvalidProof)));
+        let first = enroll(&state, user.id, &laptop, client_id, now).await.unwrap();
+        assert_eq!((first.label.as_str(), first.last_used_at), ("laptop", None));
+        assert_eq!(first.id, key_id(&laptop.public_key()));
+        assert!(matches!(enroll(&state, user.id, &phone, client_id, now).await, Err(UserKeyError::AlreadyEnrolled)));
+        check_login_key::<InMemoryStore>(state.clone(), user.id, &login_proof(&laptop, client_id), now).await.unwrap();
+        let result = check_login_key::<InMemoryStore>(state.clone(), user.id, &login_proof(&phone, client_id), now).await;
+        assert!(matches!(result, Err(UserKeyError::UnknownKey)));
+
+        let request = laptop.rotation_request(&phone, client_id, "alice@example.com", "phone");
+        // A proof of a key the user doesn't have can't add one
+        let forged = other.rotation_request(&phone, client_id, "alice@example.com", "phone");
+        let result = rotate_key::<InMemoryStore>(state.clone(), user.id, &forged, now).await;
+        assert!(matches!(result, Err(UserKeyError::UnknownKey)));
+        // Both proofs have to name the new key
+        let swapped = KeyRotationRequest { payload: KeyRotationPayload { label: "tablet".to_string(), ..request.payload.clone() }, ..laptop.rotation_request(&phone, client_id, "alice@example.com", "phone") };
+        let result = rotate_key::<InMemoryStore>(state.clone(), user.id, &swapped, now).await;
+        assert!(matches!(result, Err(UserKeyError::InvalidProof)));
+
+        let later = now + Duration::from_secs(60);
+        let added = rotate_key::<InMemoryStore>(state.clone(), user.id, &request, later).await.unwrap();
+        assert_eq!((added.label.as_str(), added.created_at, added.last_used_at), ("phone", later, None));
+        let result = rotate_key::<InMemoryStore>(state.clone(), user.id, &request, later).await;
+        assert!(matches!(result, Err(UserKeyError::DuplicateKey)));
+        check_login_key::<InMemoryStore>(state.clone(), user.id, &login_proof(&phone, client_id), later).await.unwrap();
+
+        let keys = list_keys::<InMemoryStore>(state.clone(), user.id).await.unwrap();
+        assert_eq!(keys.iter().map(|key| (key.label.as_str(), key.last_used_at)).collect::<Vec<_>>(), vec![
+            ("laptop", Some(later)),
+            ("phone", Some(later)),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn revocation() {
+        let state = InMemoryState::new();
+        let client_id = state.seed_client("app").id;
+        let user = state.seed_user(client_id, "alice@example.com");
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let (laptop, phone) = (UserKey::generate(Curve::NistP256), UserKey::generate(Curve::NistP256));
+        enroll(&state, user.id, &laptop, client_id, now).await.unwrap();
+        let request = laptop.rotation_request(&phone, client_id, "alice@example.com", "phone");
+        rotate_key::<InMemoryStore>(state.clone(), user.id, &request, now).await.unwrap();
+
+        let laptop_id = key_id(&laptop.public_key());
+        let revoked = revoke_key::<InMemoryStore>(state.clone(), user.id, &laptop_id, now).await.unwrap();
+        assert_eq!(revoked.revoked_at, Some(now));
+        assert_eq!(revoke_key::<InMemoryStore>(state.clone(), user.id, &laptop_id, now + Duration::from_secs(1)).await.unwrap(), revoked);
+        let result = check_login_key::<InMemoryStore>(state.clone(), user.id, &login_proof(&laptop, client_id), now).await;
+        assert!(matches!(result, Err(UserKeyError::UnknownKey)));
+        // Revoked keys can't rotate nor come back
+        let result = rotate_key::<InMemoryStore>(state.clone(), user.id, &request, now).await;
+        assert!(matches!(result, Err(UserKeyError::UnknownKey)));
+        let back = phone.rotation_request(&laptop, client_id, "alice@example.com", "laptop");
+        let result = rotate_key::<InMemoryStore>(state.clone(), user.id, &back, now).await;
+        assert!(matches!(result, Err(UserKeyError::DuplicateKey)));
+
+        let result = revoke_key::<InMemoryStore>(state.clone(), user.id, &key_id(&phone.public_key()), now).await;
+        assert!(matches!(result, Err(UserKeyError::LastKey)));
+        let result = revoke_key::<InMemoryStore>(state, user.id, "unknown", now).await;
+        assert!(matches!(result, Err(UserKeyError::UnknownKey)));
+    }
+
+    #[test]
+    fn labels() {
+        let key = UserKey::generate(Curve::NistP256);
+        let payload = UserLoginPayload { client_id: Identifier::from(42u128), email: "alice@example.com".to_string() };
+        let proof = key.prove(UserLoginPayload { client_id: payload.client_id, email: payload.email.clone() }, None).proof;
+        let now = UNIX_EPOCH;
+        for label in ["", "tab\there", &"x".repeat(MAX_LABEL_LENGTH + 1)] {
+            let new_key = NewUserKey { label: label.to_string(), proof: proof.clone() };
+            assert!(matches!(verify_new_key(&new_key, &payload, now), Err(UserKeyError::InvalidLabel)), "{label:?}");
+        }
+        let new_key = NewUserKey { label: "Alice's laptop".to_string(), proof };
+        assert_eq!(verify_new_key(&new_key, &payload, now).unwrap().public_key, key.public_key());
+        let other = UserLoginPayload { email: "bob@example.com".to_string(), ..payload };
+        assert!(matches!(verify_new_key(&new_key, &other, now), Err(UserKeyError::InvalidProof)));
+    }
+}
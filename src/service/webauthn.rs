@@ -0,0 +1,1036 @@
+//! Passkeys, WebAuthn Level 2 registration and authentication ceremonies with ES256 credentials. The
+//! options handed to the browser and the credentials it sends back use the JSON forms of
+//! `PublicKeyCredential.parseCreationOptionsFromJSON` and `PublicKeyCredential.toJSON`, binary fields
+//! are unpadded base64url.
+//!
+//! [`verify_registration`] and [`verify_assertion`] check a credential against the challenge of the
+//! ceremony, the relying party and for assertions the stored passkey. The `start_*` and `finish_*`
+//! functions keep the challenges as [`TokenKind::PasskeyChallenge`] tokens and the passkeys in the
+//! [`UserCredentials`](crate::store::UserCredentials) of the user. Only the `none` and `packed` attestation formats are accepted.
+//!
+//! Registering a passkey isn't authenticated here, the caller must make sure the user is logged in
+
+use std::time::{Duration, SystemTime};
+
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use base64::Engine;
+use ciborium::value::Value;
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{DerSignature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x509_cert::der::asn1::OctetStringRef;
+use x509_cert::der::oid::{AssociatedOid, ObjectIdentifier};
+use x509_cert::der::Decode;
+use x509_cert::ext::pkix::BasicConstraints;
+use x509_cert::Certificate;
+
+use crate::data::id::Identifier;
+use crate::model::User;
+use crate::service::oauth::{opaque_token, token_hash};
+use crate::service::update_credentials;
+use crate::store::{PasskeyCredential, StoreError, TokenKind, TokenRecord, TokenStore, Transaction, UserStore};
+
+/// Lifetime of the challenges, also the `timeout` suggested to the browser
+pub const CHALLENGE_LIFETIME: Duration = Duration::from_secs(5 * 60);
+/// ES256 in the COSE algorithm registry, the only algorithm accepted
+pub const COSE_ES256: i64 = -7;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+const FLAG_EXTENSION_DATA: u8 = 0x80;
+/// `id-fido-gen-ce-aaguid`, the extension of the attestation certificates naming the authenticator model
+const AAGUID_EXTENSION: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.45724.1.1.4");
+const PUBLIC_KEY: &str = "public-key";
+
+#[derive(thiserror::Error, Debug)]
+pub enum WebAuthnError {
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error("malformed credential: {0}")]
+    Malformed(&'static str),
+    #[error("unknown or expired challenge")]
+    InvalidChallenge,
+    #[error("the credential was created for another origin")]
+    OriginMismatch,
+    #[error("the credential was created for another relying party")]
+    RpIdMismatch,
+    #[error("the user wasn't present")]
+    UserNotPresent,
+    #[error("only ES256 credentials are supported")]
+    UnsupportedAlgorithm,
+    #[error("unsupported attestation format {0}")]
+    UnsupportedAttestation(String),
+    #[error("invalid attestation: {0}")]
+    InvalidAttestation(&'static str),
+    #[error("invalid signature")]
+    InvalidSignature,
+    #[error("the signature counter didn't increase, the authenticator may be cloned")]
+    CounterRegressed,
+    #[error("unknown credential")]
+    UnknownCredential,
+    #[error("the credential is already registered")]
+    DuplicateCredential,
+}
+
+/// The service accepting the passkeys. `id` is the domain the passkeys are bound to and `origin` the
+/// exact origin of the pages running the ceremonies, which must be `id` or one of its subdomains
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelyingParty {
+    pub id: String,
+    /// Shown by the browser when creating a passkey
+    pub name: String,
+    pub origin: String,
+}
+
+/// Response of `navigator.credentials.create()`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegistrationCredential {
+    pub id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+/// Response of `navigator.credentials.get()`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthenticationCredential {
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    #[serde(default)]
+    pub user_handle: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelyingPartyEntity {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserEntity {
+    /// Big endian bytes of the user id, returned as the `userHandle` of the assertions
+    pub id: String,
+    pub name: String,
+    pub display_name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CredentialParameters {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub alg: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CredentialDescriptor {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub id: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorSelection {
+    pub resident_key: String,
+    pub user_verification: String,
+}
+
+/// `publicKey` options of `navigator.credentials.create()`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreationOptions {
+    pub challenge: String,
+    pub rp: RelyingPartyEntity,
+    pub user: UserEntity,
+    pub pub_key_cred_params: Vec<CredentialParameters>,
+    /// Milliseconds
+    pub timeout: u64,
+    pub exclude_credentials: Vec<CredentialDescriptor>,
+    pub authenticator_selection: AuthenticatorSelection,
+    pub attestation: String,
+}
+
+/// `publicKey` options of `navigator.credentials.get()`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestOptions {
+    pub challenge: String,
+    /// Milliseconds
+    pub timeout: u64,
+    pub rp_id: String,
+    pub allow_credentials: Vec<CredentialDescriptor>,
+    pub user_verification: String,
+}
+
+/// Outcome of a verified assertion
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Assertion {
+    pub sign_count: u32,
+    /// The authenticator checked a PIN or biometric, making the passkey a second factor on its own
+    pub user_verified: bool,
+}
+
+/// User authenticated by [`finish_authentication`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PasskeyLogin {
+    pub user_id: Identifier,
+    pub client_id: Identifier,
+    pub user_verified: bool,
+}
+
+/// Data of the [`TokenKind::PasskeyChallenge`] tokens
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum Ceremony {
+    Registration,
+    Authentication,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+    #[serde(default)]
+    cross_origin: bool,
+}
+
+struct AttestedCredential {
+    aaguid: [u8; 16],
+    id: Vec<u8>,
+    public_key: VerifyingKey,
+}
+
+struct AuthenticatorData {
+    rp_id_hash: [u8; 32],
+    flags: u8,
+    sign_count: u32,
+    attested_credential: Option<AttestedCredential>,
+}
+
+fn decode(value: &str, field: &'static str) -> Result<Vec<u8>, WebAuthnError> {
+    BASE64_URL_SAFE_NO_PAD.decode(value).map_err(|_| WebAuthnError::Malformed(field))
+}
+
+fn encode(bytes: &[u8]) -> String {
+    BASE64_URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn user_handle(user_id: Identifier) -> [u8; 16] {
+    u128::from(user_id).to_be_bytes()
+}
+
+fn timeout() -> u64 {
+    CHALLENGE_LIFETIME.as_millis() as u64
+}
+
+fn descriptor(id: &[u8]) -> CredentialDescriptor {
+    CredentialDescriptor { kind: PUBLIC_KEY.to_string(), id: encode(id) }
+}
+
+fn parse_client_data(client_data_json: &[u8]) -> Result<ClientData, WebAuthnError> {
+    serde_json::from_slice(client_data_json).map_err(|_| WebAuthnError::Malformed("clientDataJSON"))
+}
+
+/// Checks the client data of the ceremony `kind`, `webauthn.create` or `webauthn.get`
+fn check_client_data(client_data_json: &[u8], kind: &str, rp: &RelyingParty, challenge: &[u8]) -> Result<(), WebAuthnError> {
+    let client_data = parse_client_data(client_data_json)?;
+    if client_data.kind != kind {
+        return Err(WebAuthnError::Malformed("unexpected client data type"));
+    }
+    if decode(&client_data.challenge, "challenge")? != challenge {
+        return Err(WebAuthnError::InvalidChallenge);
+    }
+    // NOTE: Cross origin iframes aren't supported, the origin must be the one of the relying party
+    if client_data.origin != rp.origin || client_data.cross_origin {
+        return Err(WebAuthnError::OriginMismatch);
+    }
+    Ok(())
+}
+
+fn map_get(map: &[(Value, Value)], key: i128) -> Option<&Value> {
+    map.iter().find(|(candidate, _)| matches!(candidate, Value::Integer(integer) if i128::from(*integer) == key)).map(|(_, value)| value)
+}
+
+fn text_map_get<'a>(map: &'a [(Value, Value)], key: &str) -> Option<&'a Value> {
+    map.iter().find(|(candidate, _)| candidate.as_text() == Some(key)).map(|(_, value)| value)
+}
+
+fn integer(value: Option<&Value>) -> Option<i128> {
+    value.and_then(Value::as_integer).map(i128::from)
+}
+
+/// EC2 key on P-256 of an ES256 credential, RFC 9053 section 7.1.1
+fn parse_cose_key(key: &Value) -> Result<VerifyingKey, WebAuthnError> {
+    let key = key.as_map().ok_or(WebAuthnError::Malformed("credential public key"))?;
+    if integer(map_get(key, 1)) != Some(2) || integer(map_get(key, 3)) != Some(COSE_ES256.into()) || integer(map_get(key, -1)) != Some(1) {
+        return Err(WebAuthnError::UnsupportedAlgorithm);
+    }
+    let coordinate = |label| {
+        map_get(key, label)
+            .and_then(Value::as_bytes)
+            .filter(|bytes| bytes.len() == 32)
+            .ok_or(WebAuthnError::Malformed("credential public key"))
+    };
+    let mut sec1 = vec![0x04];
+    sec1.extend_from_slice(coordinate(-2)?);
+    sec1.extend_from_slice(coordinate(-3)?);
+    VerifyingKey::from_sec1_bytes(&sec1).map_err(|_| WebAuthnError::Malformed("credential public key"))
+}
+
+/// Authenticator data layout of WebAuthn section 6.1
+fn parse_authenticator_data(bytes: &[u8]) -> Result<AuthenticatorData, WebAuthnError> {
+    let malformed = || WebAuthnError::Malformed("authenticator data");
+    if bytes.len() < 37 {
+        return Err(malformed());
+    }
+    let flags = bytes[32];
+    let mut data = AuthenticatorData {
+        rp_id_hash: bytes[..32].try_into().unwrap(),
+        flags,
+        sign_count: u32::from_be_bytes(bytes[33..37].try_into().unwrap()),
+        attested_credential: None,
+    };
+    let mut rest = &bytes[37..];
+    if flags & FLAG_ATTESTED_CREDENTIAL != 0 {
+        if rest.len() < 18 {
+            return Err(malformed());
+        }
+        let aaguid = rest[..16].try_into().unwrap();
+        let id_length = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+        let id = rest.get(18..18 + id_length).ok_or_else(malformed)?.to_vec();
+        rest = &rest[18 + id_length..];
+        // NOTE: The key has no length prefix, the reader advances `rest` past it
+        let key: Value = ciborium::from_reader(&mut rest).map_err(|_| malformed())?;
+        data.attested_credential = Some(AttestedCredential { aaguid, id, public_key: parse_cose_key(&key)? });
+    }
+    if flags & FLAG_EXTENSION_DATA != 0 {
+        let _extensions: Value = ciborium::from_reader(&mut rest).map_err(|_| malformed())?;
+    }
+    if !rest.is_empty() {
+        return Err(malformed());
+    }
+    Ok(data)
+}
+
+/// Checks the parts of the authenticator data every ceremony shares
+fn check_authenticator_data(data: &AuthenticatorData, rp: &RelyingParty) -> Result<(), WebAuthnError> {
+    if data.rp_id_hash[..] != Sha256::digest(rp.id.as_bytes())[..] {
+        return Err(WebAuthnError::RpIdMismatch);
+    }
+    if data.flags & FLAG_USER_PRESENT == 0 {
+        return Err(WebAuthnError::UserNotPresent);
+    }
+    Ok(())
+}
+
+fn verify_signature(public_key: &VerifyingKey, authenticator_data: &[u8], client_data_json: &[u8], signature: &[u8]) -> bool {
+    let mut message = authenticator_data.to_vec();
+    message.extend_from_slice(&Sha256::digest(client_data_json));
+    DerSignature::try_from(signature).is_ok_and(|signature| public_key.verify(&message, &signature).is_ok())
+}
+
+/// Authenticators without a counter always report zero, the others must report a higher count than the
+/// last one seen
+fn check_counter(stored: u32, received: u32) -> Result<(), WebAuthnError> {
+    if (stored != 0 || received != 0) && received <= stored {
+        return Err(WebAuthnError::CounterRegressed);
+    }
+    Ok(())
+}
+
+/// Packed attestation, WebAuthn section 8.2. Self attestation is signed with the credential key, full
+/// attestation with the key of the first `x5c` certificate
+fn verify_packed_attestation(
+    statement: &[(Value, Value)],
+    credential: &AttestedCredential,
+    signed: &[u8],
+    client_data_json: &[u8],
+) -> Result<(), WebAuthnError> {
+    if integer(text_map_get(statement, "alg")) != Some(COSE_ES256.into()) {
+        return Err(WebAuthnError::UnsupportedAlgorithm);
+    }
+    let signature = text_map_get(statement, "sig").and_then(Value::as_bytes).ok_or(WebAuthnError::InvalidAttestation("missing signature"))?;
+    let Some(chain) = text_map_get(statement, "x5c") else {
+        if !verify_signature(&credential.public_key, signed, client_data_json, signature) {
+            return Err(WebAuthnError::InvalidSignature);
+        }
+        return Ok(());
+    };
+
+    // NOTE: The chain isn't validated against trusted roots, the attestation proves the authenticator
+    // model only to relying parties that pin the roots of the vendors they accept
+    let certificate = chain
+        .as_array()
+        .and_then(|chain| chain.first())
+        .and_then(Value::as_bytes)
+        .ok_or(WebAuthnError::InvalidAttestation("missing attestation certificate"))?;
+    let certificate = Certificate::from_der(certificate).map_err(|_| WebAuthnError::InvalidAttestation("malformed attestation certificate"))?;
+    let certificate = certificate.tbs_certificate;
+    if certificate.version != x509_cert::Version::V3 {
+        return Err(WebAuthnError::InvalidAttestation("the attestation certificate must be X.509 version 3"));
+    }
+    if !certificate.subject.to_string().split(',').any(|attribute| attribute == "OU=Authenticator Attestation") {
+        return Err(WebAuthnError::InvalidAttestation("the attestation certificate isn't one of an authenticator"));
+    }
+    for extension in certificate.extensions.iter().flatten() {
+        if extension.extn_id == BasicConstraints::OID {
+            let constraints = BasicConstraints::from_der(extension.extn_value.as_bytes())
+                .map_err(|_| WebAuthnError::InvalidAttestation("malformed basic constraints"))?;
+            if constraints.ca {
+                return Err(WebAuthnError::InvalidAttestation("the attestation certificate is a CA"));
+            }
+        }
+        if extension.extn_id == AAGUID_EXTENSION {
+            let aaguid = OctetStringRef::from_der(extension.extn_value.as_bytes())
+                .map_err(|_| WebAuthnError::InvalidAttestation("malformed AAGUID extension"))?;
+            if aaguid.as_bytes() != credential.aaguid {
+                return Err(WebAuthnError::InvalidAttestation("the attestation certificate is of another authenticator model"));
+            }
+        }
+    }
+    let public_key = certificate.subject_public_key_info.subject_public_key.as_bytes()
+        .and_then(|key| VerifyingKey::from_sec1_bytes(key).ok())
+        .ok_or(WebAuthnError::UnsupportedAlgorithm)?;
+    if !verify_signature(&public_key, signed, client_data_json, signature) {
+        return Err(WebAuthnError::InvalidSignature);
+    }
+    Ok(())
+}
+
+/// Checks a new credential created for the `challenge` and returns the passkey to store
+pub fn verify_registration(
+    rp: &RelyingParty,
+    challenge: &[u8],
+    credential: &RegistrationCredential,
+) -> Result<PasskeyCredential, WebAuthnError> {
+    let client_data_json = decode(&credential.response.client_data_json, "clientDataJSON")?;
+    check_client_data(&client_data_json, "webauthn.create", rp, challenge)?;
+
+    let attestation_object = decode(&credential.response.attestation_object, "attestationObject")?;
+    let attestation: Value = ciborium::from_reader(attestation_object.as_slice()).map_err(|_| WebAuthnError::Malformed("attestationObject"))?;
+    let attestation = attestation.as_map().ok_or(WebAuthnError::Malformed("attestationObject"))?;
+    let format = text_map_get(attestation, "fmt").and_then(Value::as_text).ok_or(WebAuthnError::Malformed("attestationObject"))?;
+    let statement = text_map_get(attestation, "attStmt").and_then(Value::as_map).ok_or(WebAuthnError::Malformed("attestationObject"))?;
+    let authenticator_data = text_map_get(attestation, "authData").and_then(Value::as_bytes).ok_or(WebAuthnError::Malformed("attestationObject"))?;
+
+    let data = parse_authenticator_data(authenticator_data)?;
+    check_authenticator_data(&data, rp)?;
+    let attested = data.attested_credential.ok_or(WebAuthnError::Malformed("missing attested credential"))?;
+    if decode(&credential.id, "id")? != attested.id {
+        return Err(WebAuthnError::Malformed("the id doesn't match the attested credential"));
+    }
+
+    match format {
+        "none" if statement.is_empty() => {}
+        "none" => return Err(WebAuthnError::InvalidAttestation("the none attestation has a statement")),
+        "packed" => verify_packed_attestation(statement, &attested, authenticator_data, &client_data_json)?,
+        format => return Err(WebAuthnError::UnsupportedAttestation(format.to_string())),
+    }
+
+    Ok(PasskeyCredential {
+        id: attested.id,
+        public_key: attested.public_key.to_encoded_point(false).as_bytes().to_vec(),
+        sign_count: data.sign_count,
+        aaguid: attested.aaguid,
+    })
+}
+
+/// Checks an assertion of the `passkey` signed over the `challenge`
+pub fn verify_assertion(
+    rp: &RelyingParty,
+    challenge: &[u8],
+    passkey: &PasskeyCredential,
+    credential: &AuthenticationCredential,
+) -> Result<Assertion, WebAuthnError> {
+    if decode(&credential.id, "id")? != passkey.id {
+        return Err(WebAuthnError::UnknownCredential);
+    }
+    let client_data_json = decode(&credential.response.client_data_json, "clientDataJSON")?;
+    check_client_data(&client_data_json, "webauthn.get", rp, challenge)?;
+
+    let authenticator_data = decode(&credential.response.authenticator_data, "authenticatorData")?;
+    let data = parse_authenticator_data(&authenticator_data)?;
+    check_authenticator_data(&data, rp)?;
+
+    let public_key = VerifyingKey::from_sec1_bytes(&passkey.public_key).map_err(|_| WebAuthnError::Malformed("stored public key"))?;
+    let signature = decode(&credential.response.signature, "signature")?;
+    if !verify_signature(&public_key, &authenticator_data, &client_data_json, &signature) {
+        return Err(WebAuthnError::InvalidSignature);
+    }
+    check_counter(passkey.sign_count, data.sign_count)?;
+
+    Ok(Assertion { sign_count: data.sign_count, user_verified: data.flags & FLAG_USER_VERIFIED != 0 })
+}
+
+async fn store_challenge<S: TokenStore>(
+    state: S::State,
+    ceremony: Ceremony,
+    user_id: Identifier,
+    client_id: Identifier,
+    now: SystemTime,
+) -> Result<String, WebAuthnError> {
+    let challenge = opaque_token();
+    let record = TokenRecord {
+        hash: token_hash(&challenge),
+        kind: TokenKind::PasskeyChallenge,
+        user_id,
+        client_id,
+        expires_at: now + CHALLENGE_LIFETIME,
+        data: bincode::serialize(&ceremony).unwrap(),
+    };
+    S::insert_token(state, record).await.map_err(|error| WebAuthnError::Store(error.into()))?;
+    Ok(challenge)
+}
+
+/// Consumes the stored challenge the client data was signed over, so every challenge is used once
+async fn consume_challenge<S: TokenStore>(
+    state: S::State,
+    client_data_json: &str,
+    ceremony: Ceremony,
+    now: SystemTime,
+) -> Result<(TokenRecord, Vec<u8>), WebAuthnError> {
+    let challenge = parse_client_data(&decode(client_data_json, "clientDataJSON")?)?.challenge;
+    let record = match S::consume_token(state, &token_hash(&challenge)).await.map_err(Into::into) {
+        Ok(record) => record,
+        Err(StoreError::NotFound) => return Err(WebAuthnError::InvalidChallenge),
+        Err(error) => return Err(WebAuthnError::Store(error)),
+    };
+    if record.kind != TokenKind::PasskeyChallenge
+        || record.expires_at <= now
+        || bincode::deserialize::<Ceremony>(&record.data).ok() != Some(ceremony)
+    {
+        return Err(WebAuthnError::InvalidChallenge);
+    }
+    Ok((record, decode(&challenge, "challenge")?))
+}
+
+/// Options to create a passkey for the user, the passkeys it already has are excluded
+pub async fn start_registration<S>(
+    state: S::State,
+    rp: &RelyingParty,
+    user_id: Identifier,
+    now: SystemTime,
+) -> Result<CreationOptions, WebAuthnError>
+where
+    S: UserStore + TokenStore,
+{
+    let user = S::get_user(state.clone(), user_id).await.map_err(|error| WebAuthnError::Store(error.into()))?;
+    let credentials = S::get_user_credentials(state.clone(), user_id).await.map_err(|error| WebAuthnError::Store(error.into()))?;
+    let challenge = store_challenge::<S>(state, Ceremony::Registration, user_id, user.get_client_id(), now).await?;
+    Ok(CreationOptions {
+        challenge,
+        rp: RelyingPartyEntity { id: rp.id.clone(), name: rp.name.clone() },
+        user: UserEntity {
+            id: encode(&user_handle(user_id)),
+            name: user.get_email().to_string(),
+            display_name: user.get_email().to_string(),
+        },
+        pub_key_cred_params: vec![CredentialParameters { kind: PUBLIC_KEY.to_string(), alg: COSE_ES256 }],
+        timeout: timeout(),
+        exclude_credentials: credentials.passkeys.iter().map(|passkey| descriptor(&passkey.id)).collect(),
+        authenticator_selection: AuthenticatorSelection {
+            resident_key: "preferred".to_string(),
+            user_verification: "preferred".to_string(),
+        },
+        attestation: "direct".to_string(),
+    })
+}
+
+/// Verifies the credential created with the options of [`start_registration`] and adds it to the
+/// passkeys of the user
+pub async fn finish_registration<S>(
+    state: S::State,
+    rp: &RelyingParty,
+    user_id: Identifier,
+    credential: &RegistrationCredential,
+    now: SystemTime,
+) -> Result<PasskeyCredential, WebAuthnError>
+where
+    S: UserStore + TokenStore + Transaction,
+{
+    let (record, challenge) = consume_challenge::<S>(state.clone(), &credential.response.client_data_json, Ceremony::Registration, now).await?;
+    if record.user_id != user_id {
+        return Err(WebAuthnError::InvalidChallenge);
+    }
+    let passkey = verify_registration(rp, &challenge, credential)?;
+    // NOTE: Passkeys are looked up within a user, so the same credential id registered by another user
+    // can't be mistaken for this one
+    update_credentials::<S, _, _, _>(state, user_id, |credentials| {
+        if credentials.passkeys.iter().any(|stored| stored.id == passkey.id) {
+            return Err(WebAuthnError::DuplicateCredential);
+        }
+        credentials.passkeys.push(passkey.clone());
+        Ok(())
+    }).await?;
+    Ok(passkey)
+}
+
+/// Options to sign in with a passkey of the user. Unknown users and users without passkeys get options
+/// that look the same but can't succeed, so the response doesn't tell who is registered
+pub async fn start_authentication<S>(
+    state: S::State,
+    rp: &RelyingParty,
+    client_id: Identifier,
+    email: &str,
+    now: SystemTime,
+) -> Result<RequestOptions, WebAuthnError>
+where
+    S: UserStore + TokenStore,
+{
+    let options = |challenge, allow_credentials| RequestOptions {
+        challenge,
+        timeout: timeout(),
+        rp_id: rp.id.clone(),
+        allow_credentials,
+        user_verification: "preferred".to_string(),
+    };
+    let user = match S::get_user_by_email(state.clone(), client_id, email).await.map_err(Into::into) {
+        Ok(user) => Some(user),
+        Err(StoreError::NotFound) => None,
+        Err(error) => return Err(WebAuthnError::Store(error)),
+    };
+    let passkeys = match &user {
+        Some(user) => S::get_user_credentials(state.clone(), user.get_id()).await.map_err(|error| WebAuthnError::Store(error.into()))?.passkeys,
+        None => vec![],
+    };
+    match user {
+        Some(user) if !passkeys.is_empty() => {
+            let challenge = store_challenge::<S>(state, Ceremony::Authentication, user.get_id(), client_id, now).await?;
+            Ok(options(challenge, passkeys.iter().map(|passkey| descriptor(&passkey.id)).collect()))
+        }
+        _ => {
+            // NOTE: The made up credential id stays the same across requests for the same email, it isn't
+            // secret, a real one would only be told apart by a client that already knows the passkey
+            let mut hash = Sha256::new();
+            hash.update(u128::from(client_id).to_le_bytes());
+            hash.update(email.as_bytes());
+            Ok(options(opaque_token(), vec![descriptor(&hash.finalize()[..16])]))
+        }
+    }
+}
+
+/// Verifies an assertion signed over the challenge of [`start_authentication`] and stores the new
+/// signature counter of the passkey
+pub async fn finish_authentication<S>(
+    state: S::State,
+    rp: &RelyingParty,
+    credential: &AuthenticationCredential,
+    now: SystemTime,
+) -> Result<PasskeyLogin, WebAuthnError>
+where
+    S: UserStore + TokenStore + Transaction,
+{
+    let (record, challenge) = consume_challenge::<S>(state.clone(), &credential.response.client_data_json, Ceremony::Authentication, now).await?;
+    if let Some(handle) = &credential.response.user_handle {
+        if decode(handle, "userHandle")? != user_handle(record.user_id) {
+            return Err(WebAuthnError::UnknownCredential);
+        }
+    }
+    let id = decode(&credential.id, "id")?;
+    let assertion = update_credentials::<S, _, WebAuthnError, _>(state, record.user_id, |credentials| {
+        let passkey = credentials.passkeys.iter_mut().find(|passkey| passkey.id == id).ok_or(WebAuthnError::UnknownCredential)?;
+        let assertion = verify_assertion(rp, &challenge, passkey, credential)?;
+        passkey.sign_count = assertion.sign_count;
+        Ok(assertion)
+    }).await?;
+    Ok(PasskeyLogin { user_id: record.user_id, client_id: record.client_id, user_verified: assertion.user_verified })
+}
+
+/// Responses recorded from a software authenticator with a fixed P-256 key per credential, for the relying
+/// party of [`fixtures::rp`]. The challenges are 32 bytes of 1 to 6, in the order of [`fixtures::challenge`].
+/// The [`fixtures::Capture`]s come from real authenticators instead
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::*;
+
+    pub(crate) const NONE_REGISTRATION: &str = r#"{"id":"AQIDBAUGBwgJCgsMDQ4PEA","rawId":"AQIDBAUGBwgJCgsMDQ4PEA","type":"public-key","response":{"clientDataJSON":"eyJ0eXBlIjoid2ViYXV0aG4uY3JlYXRlIiwiY2hhbGxlbmdlIjoiQVFFQkFRRUJBUUVCQVFFQkFRRUJBUUVCQVFFQkFRRUJBUUVCQVFFQkFRRSIsIm9yaWdpbiI6Imh0dHBzOi8vZXhhbXBsZS5jb20iLCJjcm9zc09yaWdpbiI6ZmFsc2V9","attestationObject":"o2NmbXRkbm9uZWdhdHRTdG10oGhhdXRoRGF0YViUo3mm9u6vuaVeN4wRgDTidR5oL6ufLTCrE9ISVYbOGUdFAAAAAAAAAAAAAAAAAAAAAAAAAAAAEAECAwQFBgcICQoLDA0ODxClAQIDJiABIVggDcx2SMeKMRjyYShm_oPvGfQDBPYjOZ4SEaEPKz0cBc8iWCAw6AJ2d7y5EpQ-mf9xFA6pvCRxO-G3XDH_QFRyTE-dAA"}}"#;
+    pub(crate) const PACKED_SELF_REGISTRATION: &str = r#"{"id":"ERITFBUWFxgZGhscHR4fICEiIyQlJicoKSorLC0uLzA","rawId":"ERITFBUWFxgZGhscHR4fICEiIyQlJicoKSorLC0uLzA","type":"public-key","response":{"clientDataJSON":"eyJ0eXBlIjoid2ViYXV0aG4uY3JlYXRlIiwiY2hhbGxlbmdlIjoiQWdJQ0FnSUNBZ0lDQWdJQ0FnSUNBZ0lDQWdJQ0FnSUNBZ0lDQWdJQ0FnSSIsIm9yaWdpbiI6Imh0dHBzOi8vZXhhbXBsZS5jb20iLCJjcm9zc09yaWdpbiI6ZmFsc2V9","attestationObject":"o2NmbXRmcGFja2VkZ2F0dFN0bXSiY2FsZyZjc2lnWEYwRAIgGcjdacJXWly4zTJK-4a3myBzhcY_GKyGbeoLKnyKZE4CIHncaM0-N1y4aK2xGGAeKd9UlaWyTM0yX6IZQBq_U74vaGF1dGhEYXRhWKSjeab27q-5pV43jBGANOJ1Hmgvq58tMKsT0hJVhs4ZR0EAAAABah8LLD1OX2BxgpOktcbX6AAgERITFBUWFxgZGhscHR4fICEiIyQlJicoKSorLC0uLzClAQIDJiABIVggpi8EjzZzWYCcLUbCBJ19e_Jow8BzxHJ1PLGKJKitILEiWCDKzPgQS2ZnlcfzXaydxESzwsYZeBmMSYWZVbmZVtpe2w"}}"#;
+    pub(crate) const PACKED_X5C_REGISTRATION: &str = r#"{"id":"MTIzNDU2Nzg5Ojs8PT4_QA","rawId":"MTIzNDU2Nzg5Ojs8PT4_QA","type":"public-key","response":{"clientDataJSON":"eyJ0eXBlIjoid2ViYXV0aG4uY3JlYXRlIiwiY2hhbGxlbmdlIjoiQXdNREF3TURBd01EQXdNREF3TURBd01EQXdNREF3TURBd01EQXdNREF3TSIsIm9yaWdpbiI6Imh0dHBzOi8vZXhhbXBsZS5jb20iLCJjcm9zc09yaWdpbiI6ZmFsc2V9","attestationObject":"o2NmbXRmcGFja2VkZ2F0dFN0bXSjY2FsZyZjc2lnWEcwRQIgTe9QTfmqDWRXdWhYucKYW2cNtzeiZeFu50MWwwtVxGwCIQDT_v7EtRKfBZlv4K3GZfaacqr8I62nfPNInYz4BNFnLGN4NWOBWQG9MIIBuTCCAV6gAwIBAgIBAjAKBggqhkjOPQQDAjAjMSEwHwYDVQQDDBhFeGFtcGxlIEF0dGVzdGF0aW9uIFJvb3QwIBcNMjQwMTAxMDAwMDAwWhgPMjEyMzEyMDgwMDAwMDBaMHExCzAJBgNVBAYTAlVTMR8wHQYDVQQKDBZFeGFtcGxlIEF1dGhlbnRpY2F0b3JzMSIwIAYDVQQLDBlBdXRoZW50aWNhdG9yIEF0dGVzdGF0aW9uMR0wGwYDVQQDDBRFeGFtcGxlIEtleSBTZXJpZXMgMTBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABKhbOq1vOjRthVIxQctDThyvTGQrKzzJUssHpjXLah3xvL2ttmuujrJeypL4sItnvgLMc2FQ3X_80_AZyHX5_96jMzAxMAwGA1UdEwEB_wQCMAAwIQYLKwYBBAGC5RwBAQQEEgQQah8LLD1OX2BxgpOktcbX6DAKBggqhkjOPQQDAgNJADBGAiEAqhlAx2fRUjjQ2owJFT41cFxlNHxNDka1UcH6vp44yqECIQCLkOJiB3CLrFkadJJjVxfR-RSCzgCfW704Xud87v-5wGhhdXRoRGF0YViUo3mm9u6vuaVeN4wRgDTidR5oL6ufLTCrE9ISVYbOGUdFAAAAAGofCyw9Tl9gcYKTpLXG1-gAEDEyMzQ1Njc4OTo7PD0-P0ClAQIDJiABIVgghXDpXYWCUobbkseDF2eb3Y_-PJDQr4QpG_ZBMrZvzJkiWCDJJvCHIS11sfTbxdSZm0xWBa32bbgBpN43HNrTnrxV5Q"}}"#;
+    pub(crate) const PACKED_X5C_WRONG_AAGUID_REGISTRATION: &str = r#"{"id":"MTIzNDU2Nzg5Ojs8PT4_QA","rawId":"MTIzNDU2Nzg5Ojs8PT4_QA","type":"public-key","response":{"clientDataJSON":"eyJ0eXBlIjoid2ViYXV0aG4uY3JlYXRlIiwiY2hhbGxlbmdlIjoiQmdZR0JnWUdCZ1lHQmdZR0JnWUdCZ1lHQmdZR0JnWUdCZ1lHQmdZR0JnWSIsIm9yaWdpbiI6Imh0dHBzOi8vZXhhbXBsZS5jb20iLCJjcm9zc09yaWdpbiI6ZmFsc2V9","attestationObject":"o2NmbXRmcGFja2VkZ2F0dFN0bXSjY2FsZyZjc2lnWEcwRQIgRlZrav7dNQwOQgS5Gejcv_wHlYQO36Ewg3SjEEFPzkwCIQCKNjl6x1L0DhjJAnIWUlNwZkb5mhMSTTosYYEVoqJacmN4NWOBWQG8MIIBuDCCAV6gAwIBAgIBAjAKBggqhkjOPQQDAjAjMSEwHwYDVQQDDBhFeGFtcGxlIEF0dGVzdGF0aW9uIFJvb3QwIBcNMjQwMTAxMDAwMDAwWhgPMjEyMzEyMDgwMDAwMDBaMHExCzAJBgNVBAYTAlVTMR8wHQYDVQQKDBZFeGFtcGxlIEF1dGhlbnRpY2F0b3JzMSIwIAYDVQQLDBlBdXRoZW50aWNhdG9yIEF0dGVzdGF0aW9uMR0wGwYDVQQDDBRFeGFtcGxlIEtleSBTZXJpZXMgMTBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABKhbOq1vOjRthVIxQctDThyvTGQrKzzJUssHpjXLah3xvL2ttmuujrJeypL4sItnvgLMc2FQ3X_80_AZyHX5_96jMzAxMAwGA1UdEwEB_wQCMAAwIQYLKwYBBAGC5RwBAQQEEgQQAAAAAAAAAAAAAAAAAAAAADAKBggqhkjOPQQDAgNIADBFAiEAs2xQO4j4bMn4cS2RjT2sgcTwTjFue9KdMNVP5A7QpI8CIApua0f_jk6_wDYlhckf5qO_3x-0DrSyi8yGSDDqeMy6aGF1dGhEYXRhWJSjeab27q-5pV43jBGANOJ1Hmgvq58tMKsT0hJVhs4ZR0UAAAAAah8LLD1OX2BxgpOktcbX6AAQMTIzNDU2Nzg5Ojs8PT4_QKUBAgMmIAEhWCCFcOldhYJShtuSx4MXZ5vdj_48kNCvhCkb9kEytm_MmSJYIMkm8IchLXWx9NvF1JmbTFYFrfZtuAGk3jcc2tOevFXl"}}"#;
+    pub(crate) const NONE_ASSERTION: &str = r#"{"id":"AQIDBAUGBwgJCgsMDQ4PEA","rawId":"AQIDBAUGBwgJCgsMDQ4PEA","type":"public-key","response":{"clientDataJSON":"eyJ0eXBlIjoid2ViYXV0aG4uZ2V0IiwiY2hhbGxlbmdlIjoiQkFRRUJBUUVCQVFFQkFRRUJBUUVCQVFFQkFRRUJBUUVCQVFFQkFRRUJBUSIsIm9yaWdpbiI6Imh0dHBzOi8vZXhhbXBsZS5jb20iLCJjcm9zc09yaWdpbiI6ZmFsc2V9","authenticatorData":"o3mm9u6vuaVeN4wRgDTidR5oL6ufLTCrE9ISVYbOGUcFAAAAAA","signature":"MEUCIGwi7xLr8Od3ItXGrfCbHe2hn5MkGtF6L2p6DFq8uzFjAiEA63mDM0QNgrFA6QJvUPhY07SlWTXYwAAVb-KzD8aESvU"}}"#;
+    pub(crate) const PACKED_SELF_ASSERTION: &str = r#"{"id":"ERITFBUWFxgZGhscHR4fICEiIyQlJicoKSorLC0uLzA","rawId":"ERITFBUWFxgZGhscHR4fICEiIyQlJicoKSorLC0uLzA","type":"public-key","response":{"clientDataJSON":"eyJ0eXBlIjoid2ViYXV0aG4uZ2V0IiwiY2hhbGxlbmdlIjoiQlFVRkJRVUZCUVVGQlFVRkJRVUZCUVVGQlFVRkJRVUZCUVVGQlFVRkJRVSIsIm9yaWdpbiI6Imh0dHBzOi8vZXhhbXBsZS5jb20iLCJjcm9zc09yaWdpbiI6ZmFsc2V9","authenticatorData":"o3mm9u6vuaVeN4wRgDTidR5oL6ufLTCrE9ISVYbOGUcBAAAAAg","signature":"MEQCIGd7XA49UwIYYZCfCIF2pYssgCNRswcQak8AJO1lm-MeAiBZa2nfjXdKoEC4fUgato1vFzW1JUbPR0IxlDPBO64OiA"}}"#;
+    pub(crate) const AAGUID: [u8; 16] = [0x6a, 0x1f, 0x0b, 0x2c, 0x3d, 0x4e, 0x5f, 0x60, 0x71, 0x82, 0x93, 0xa4, 0xb5, 0xc6, 0xd7, 0xe8];
+
+    /// Registration made by a real authenticator for the page of `origin`, which handed out `challenge`. These
+    /// are test vectors of the webauthn-rs project, recorded on its test pages
+    pub(crate) struct Capture {
+        pub(crate) rp_id: &'static str,
+        pub(crate) origin: &'static str,
+        /// base64url, as in the creation options
+        pub(crate) challenge: &'static str,
+        pub(crate) credential: &'static str,
+    }
+
+    impl Capture {
+        pub(crate) fn rp(&self) -> RelyingParty {
+            RelyingParty { id: self.rp_id.to_string(), name: "Example".to_string(), origin: self.origin.to_string() }
+        }
+    }
+
+    /// Chrome on a Pixel 3a, `none` attestation
+    pub(crate) const PIXEL_3A_NONE: Capture = Capture {
+        rp_id: "webauthn.firstyear.id.au",
+        origin: "https://webauthn.firstyear.id.au",
+        challenge: "55Wztjbgks9UkS5jYthawNFik0HSiYuCSB5pzNbT6k0",
+        credential: r#"{"id":"AfzEi3UOVveYjwUwIFO3QuN9V0fomECvAYrD_8S5FAsUJqtGbwpgB9bEfphVOURzFQoEszkuULIj5fMvnTkt6cs","rawId":"AfzEi3UOVveYjwUwIFO3QuN9V0fomECvAYrD_8S5FAsUJqtGbwpgB9bEfphVOURzFQoEszkuULIj5fMvnTkt6cs","type":"public-key","response":{"clientDataJSON":"eyJ0eXBlIjoid2ViYXV0aG4uY3JlYXRlIiwiY2hhbGxlbmdlIjoiNTVXenRqYmdrczlVa1M1all0aGF3TkZpazBIU2lZdUNTQjVwek5iVDZrMCIsIm9yaWdpbiI6Imh0dHBzOlwvXC93ZWJhdXRobi5maXJzdHllYXIuaWQuYXUiLCJhbmRyb2lkUGFja2FnZU5hbWUiOiJjb20uYW5kcm9pZC5jaHJvbWUifQ","attestationObject":"o2NmbXRkbm9uZWdhdHRTdG10oGhhdXRoRGF0YVjFarm78N-aFvkduzO7sTL6-dF8eCxIJsbscOzuWNl-9SpFAAAAAAAAAAAAAAAAAAAAAAAAAAAAQQH8xIt1Dlb3mI8FMCBTt0LjfVdH6JhArwGKw__EuRQLFCarRm8KYAfWxH6YVTlEcxUKBLM5LlCyI-XzL505LenLpQECAyYgASFYII2OFisY2sjerzLYjLYvHsQh8V7cnpRcSL4A77wKqcRTIlggm7s0CUKEmkBBFp7Nng-9_pZ5Dm9y39uy6QJmDLgmgho"}}"#,
+    };
+    /// Chrome 77 with Touch ID on macOS 10.15, `packed` self attestation
+    pub(crate) const TOUCH_ID_PACKED_SELF: Capture = Capture {
+        rp_id: "localhost",
+        origin: "https://localhost:8443",
+        challenge: "lP6mWNAtG-_Vv15iM7lb_XRkdWMvVQ-lTyKwZuOg1Vo",
+        credential: r#"{"id":"ATk_7QKbi_ntSdp16LXeU6RDf9YnRLIDTCqEjJFzc6rKBhbqoSYccxNa","rawId":"ATk_7QKbi_ntSdp16LXeU6RDf9YnRLIDTCqEjJFzc6rKBhbqoSYccxNa","type":"public-key","response":{"clientDataJSON":"eyJjaGFsbGVuZ2UiOiJsUDZtV05BdEctX1Z2MTVpTTdsYl9YUmtkV012VlEtbFR5S3dadU9nMVZvIiwiZXh0cmFfa2V5c19tYXlfYmVfYWRkZWRfaGVyZSI6ImRvIG5vdCBjb21wYXJlIGNsaWVudERhdGFKU09OIGFnYWluc3QgYSB0ZW1wbGF0ZS4gU2VlIGh0dHBzOi8vZ29vLmdsL3lhYlBleCIsIm9yaWdpbiI6Imh0dHBzOi8vbG9jYWxob3N0Ojg0NDMiLCJ0eXBlIjoid2ViYXV0aG4uY3JlYXRlIn0","attestationObject":"o2NmbXRmcGFja2VkZ2F0dFN0bXSiY2FsZyZjc2lnWEcwRQIgLXPjBtVEhBH3KdUDFFk3LAd9EtHogllIf48vjX4wgfECIQCXOymmfg12FPMXEdwpSjjtmrvki4K8y0uYxqWN5Bw6DGhhdXRoRGF0YViuSZYN5YgOjGh0NBcPZHZgW4_krrmihjLHmVzzuoMdl2NFXaqejq3OAAI1vMYKZIsLJfHwVQMAKgE5P-0Cm4v57Unadei13lOkQ3_WJ0SyA0wqhIyRc3OqygYW6qEmHHMTWqUBAgMmIAEhWCDNRS_Gw52ow5PNrC9OdFTFNudDmZO6Y3wmM9N8e0tJICJYIC09iIH5_RrT5tbS0PIw3srdAxYDMGao7yWgu0JFIEzT"}}"#,
+    };
+    /// YubiKey 5 NFC, `packed` attestation with a certificate of the Yubico U2F root
+    pub(crate) const YUBIKEY_5_PACKED_X5C: Capture = Capture {
+        rp_id: "webauthn.firstyear.id.au",
+        origin: "https://webauthn.firstyear.id.au",
+        challenge: "qabSCYW_PPKKBAW5_qEsPF3Q3prQeYBORfDMArsoKdg",
+        credential: r#"{"id":"eKSmfhLUwwmJpuD2IKaTopbbWKFv-qZAE4LXa2FGmTtRpvioMpeFhI8RqdsOGlBoQxJehEQyWyu7ECwPkVL5Hg","rawId":"eKSmfhLUwwmJpuD2IKaTopbbWKFv-qZAE4LXa2FGmTtRpvioMpeFhI8RqdsOGlBoQxJehEQyWyu7ECwPkVL5Hg","type":"public-key","response":{"clientDataJSON":"eyJjaGFsbGVuZ2UiOiJxYWJTQ1lXX1BQS0tCQVc1X3FFc1BGM1EzcHJRZVlCT1JmRE1BcnNvS2RnIiwiY2xpZW50RXh0ZW5zaW9ucyI6e30sImhhc2hBbGdvcml0aG0iOiJTSEEtMjU2Iiwib3JpZ2luIjoiaHR0cHM6Ly93ZWJhdXRobi5maXJzdHllYXIuaWQuYXUiLCJ0eXBlIjoid2ViYXV0aG4uY3JlYXRlIn0","attestationObject":"o2NmbXRmcGFja2VkZ2F0dFN0bXSjY2FsZyZjc2lnWEcwRQIgW2gYNWvUDgxl8LB7rflbuJw_zvJCT5ddfDZNROTy0JYCIQDxuy3JLSHDIrEFYqDifFA_ZHttNfRqJAPgH4hedttVIWN4NWOBWQLBMIICvTCCAaWgAwIBAgIEHo-HNDANBgkqhkiG9w0BAQsFADAuMSwwKgYDVQQDEyNZdWJpY28gVTJGIFJvb3QgQ0EgU2VyaWFsIDQ1NzIwMDYzMTAgFw0xNDA4MDEwMDAwMDBaGA8yMDUwMDkwNDAwMDAwMFowbjELMAkGA1UEBhMCU0UxEjAQBgNVBAoMCVl1YmljbyBBQjEiMCAGA1UECwwZQXV0aGVudGljYXRvciBBdHRlc3RhdGlvbjEnMCUGA1UEAwweWXViaWNvIFUyRiBFRSBTZXJpYWwgNTEyNzIyNzQwMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEqHn4IzjtFJS6wHBLzH_GY9GycXFZdiQxAcdgURXXwVKeKBwcZzItOEtc1V3T6YGNX9hcIq8ybgxk_CCv4z8jZqNsMGowIgYJKwYBBAGCxAoCBBUxLjMuNi4xLjQuMS40MTQ4Mi4xLjcwEwYLKwYBBAGC5RwCAQEEBAMCBDAwIQYLKwYBBAGC5RwBAQQEEgQQL8BXn4ETR-qxFrtajbkgKjAMBgNVHRMBAf8EAjAAMA0GCSqGSIb3DQEBCwUAA4IBAQCGk_9i3w1XedR0jX_I0QInMYqOWA5qOlfBCOlOA8OFaLNmiU_OViS-Sj79fzQRiz2ZN0P3kqGYkWDI_JrgsE49-e4V4-iMBPyCqNy_WBjhCNzCloV3rnn_ZiuUc0497EWXMF1z5uVe4r65zZZ4ygk15TPrY4-OJvq7gXzaRB--mDGDKuX24q2ZL56720xiI4uPjXq0gdbTJjvNv55KV1UDcJiK1YE0QPoDLK22cjyt2PjXuoCfdbQ8_6Clua3RQjLvnZ4UgSY4IzxMpKhzufismOMroZFnYG4VkJ_N20ot_72uRiAkn5pmRqyB5IMtERn-v6pzGogtolp3gn1G0ZAXaGF1dGhEYXRhWMRqubvw35oW-R27M7uxMvr50Xx4LEgmxuxw7O5Y2X71KkUAAAACL8BXn4ETR-qxFrtajbkgKgBAeKSmfhLUwwmJpuD2IKaTopbbWKFv-qZAE4LXa2FGmTtRpvioMpeFhI8RqdsOGlBoQxJehEQyWyu7ECwPkVL5HqUBAgMmIAEhWCBT_WnxT3SKAIGfnEKUi7xtZmnlcZRV-63N21154_r-xyJYIGuwu6BK1zp6D6EQ94VOcK1DuFWr58xI_PbeP5F1Nfe6"}}"#,
+    };
+    /// `2fc0579f-8113-47ea-b116-bb5a8db9202a`
+    pub(crate) const YUBIKEY_5_AAGUID: [u8; 16] = [0x2f, 0xc0, 0x57, 0x9f, 0x81, 0x13, 0x47, 0xea, 0xb1, 0x16, 0xbb, 0x5a, 0x8d, 0xb9, 0x20, 0x2a];
+
+    pub(crate) fn rp() -> RelyingParty {
+        RelyingParty { id: "example.com".to_string(), name: "Example".to_string(), origin: "https://example.com".to_string() }
+    }
+
+    pub(crate) fn challenge(fixture: &str) -> Vec<u8> {
+        let byte = match fixture {
+            NONE_REGISTRATION => 1,
+            PACKED_SELF_REGISTRATION => 2,
+            PACKED_X5C_REGISTRATION => 3,
+            NONE_ASSERTION => 4,
+            PACKED_SELF_ASSERTION => 5,
+            PACKED_X5C_WRONG_AAGUID_REGISTRATION => 6,
+            _ => unreachable!(),
+        };
+        vec![byte; 32]
+    }
+
+    /// Stored challenge of the ceremony of the fixture, as if the options were handed out for it
+    #[cfg(feature = "in-memory")]
+    pub(crate) fn challenge_token(fixture: &str, user_id: Identifier, client_id: Identifier, now: SystemTime) -> TokenRecord {
+        let ceremony = if fixture.contains("attestationObject") { Ceremony::Registration } else { Ceremony::Authentication };
+        TokenRecord {
+            hash: token_hash(&encode(&challenge(fixture))),
+            kind: TokenKind::PasskeyChallenge,
+            user_id,
+            client_id,
+            expires_at: now + CHALLENGE_LIFETIME,
+            data: bincode::serialize(&ceremony).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures::*;
+    use super::*;
+
+    fn registration(fixture: &str) -> RegistrationCredential {
+        serde_json::from_str(fixture).unwrap()
+    }
+
+    fn assertion(fixture: &str) -> AuthenticationCredential {
+        serde_json::from_str(fixture).unwrap()
+    }
+
+    fn register(fixture: &str) -> PasskeyCredential {
+        verify_registration(&rp(), &challenge(fixture), &registration(fixture)).unwrap()
+    }
+
+    /// The attestation object of the credential after `change`
+    fn with_attestation(mut credential: RegistrationCredential, change: impl FnOnce(&mut Vec<(Value, Value)>)) -> RegistrationCredential {
+        let bytes = decode(&credential.response.attestation_object, "").unwrap();
+        let mut attestation: Value = ciborium::from_reader(bytes.as_slice()).unwrap();
+        change(attestation.as_map_mut().unwrap());
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&attestation, &mut bytes).unwrap();
+        credential.response.attestation_object = encode(&bytes);
+        credential
+    }
+
+    #[test]
+    fn registration_formats() {
+        let passkey = register(NONE_REGISTRATION);
+        assert_eq!(passkey.id, (1..=16).collect::<Vec<u8>>());
+        assert_eq!(passkey.public_key.len(), 65);
+        assert_eq!((passkey.sign_count, passkey.aaguid), (0, [0; 16]));
+
+        let passkey = register(PACKED_SELF_REGISTRATION);
+        assert_eq!((passkey.sign_count, passkey.aaguid), (1, AAGUID));
+        let passkey = register(PACKED_X5C_REGISTRATION);
+        assert_eq!((passkey.sign_count, passkey.aaguid), (0, AAGUID));
+
+        let fixture = PACKED_X5C_WRONG_AAGUID_REGISTRATION;
+        let result = verify_registration(&rp(), &challenge(fixture), &registration(fixture));
+        assert!(matches!(result, Err(WebAuthnError::InvalidAttestation(_))));
+    }
+
+    #[test]
+    fn real_authenticators() {
+        let verify = |capture: &Capture| {
+            let challenge = decode(capture.challenge, "").unwrap();
+            verify_registration(&capture.rp(), &challenge, &registration(capture.credential))
+        };
+        let passkey = verify(&PIXEL_3A_NONE).unwrap();
+        assert_eq!((passkey.id.len(), passkey.sign_count, passkey.aaguid), (65, 0, [0; 16]));
+        let passkey = verify(&TOUCH_ID_PACKED_SELF).unwrap();
+        assert_eq!(passkey.id, decode(&registration(TOUCH_ID_PACKED_SELF.credential).id, "").unwrap());
+        let passkey = verify(&YUBIKEY_5_PACKED_X5C).unwrap();
+        assert_eq!((passkey.sign_count, passkey.aaguid), (2, YUBIKEY_5_AAGUID));
+
+        // Each answers its own challenge only
+        let other = Capture { challenge: YUBIKEY_5_PACKED_X5C.challenge, ..PIXEL_3A_NONE };
+        assert!(matches!(verify(&other), Err(WebAuthnError::InvalidChallenge)));
+        let other = Capture { rp_id: "firstyear.id.au", ..YUBIKEY_5_PACKED_X5C };
+        assert!(matches!(verify(&other), Err(WebAuthnError::RpIdMismatch)));
+    }
+
+    #[test]
+    fn registration_checks() {
+        let credential = registration(PACKED_SELF_REGISTRATION);
+        let expected = challenge(PACKED_SELF_REGISTRATION);
+        let result = verify_registration(&rp(), &challenge(NONE_REGISTRATION), &credential);
+        assert!(matches!(result, Err(WebAuthnError::InvalidChallenge)));
+        let other_origin = RelyingParty { origin: "https://example.org".to_string(), ..rp() };
+        assert!(matches!(verify_registration(&other_origin, &expected, &credential), Err(WebAuthnError::OriginMismatch)));
+        let other_id = RelyingParty { id: "login.example.com".to_string(), ..rp() };
+        assert!(matches!(verify_registration(&other_id, &expected, &credential), Err(WebAuthnError::RpIdMismatch)));
+        // Assertions can't be used as registrations
+        let get = AuthenticationCredential { id: credential.id.clone(), ..assertion(PACKED_SELF_ASSERTION) };
+        let credential_of_get = RegistrationCredential {
+            response: AttestationResponse { client_data_json: get.response.client_data_json, ..credential.response.clone() },
+            ..credential.clone()
+        };
+        let result = verify_registration(&rp(), &challenge(PACKED_SELF_ASSERTION), &credential_of_get);
+        assert!(matches!(result, Err(WebAuthnError::Malformed(_))));
+
+        let tampered = with_attestation(credential.clone(), |attestation| {
+            let statement = attestation.iter_mut().find(|(key, _)| key.as_text() == Some("attStmt")).unwrap();
+            let signature = statement.1.as_map_mut().unwrap().iter_mut().find(|(key, _)| key.as_text() == Some("sig")).unwrap();
+            let signature = signature.1.as_bytes_mut().unwrap();
+            *signature.last_mut().unwrap() ^= 1;
+        });
+        assert!(matches!(verify_registration(&rp(), &expected, &tampered), Err(WebAuthnError::InvalidSignature)));
+        let other_format = with_attestation(credential.clone(), |attestation| {
+            attestation.iter_mut().find(|(key, _)| key.as_text() == Some("fmt")).unwrap().1 = Value::Text("fido-u2f".to_string());
+        });
+        assert!(matches!(verify_registration(&rp(), &expected, &other_format), Err(WebAuthnError::UnsupportedAttestation(_))));
+        let none_with_statement = with_attestation(credential, |attestation| {
+            attestation.iter_mut().find(|(key, _)| key.as_text() == Some("fmt")).unwrap().1 = Value::Text("none".to_string());
+        });
+        assert!(matches!(verify_registration(&rp(), &expected, &none_with_statement), Err(WebAuthnError::InvalidAttestation(_))));
+    }
+
+    #[test]
+    fn assertions() {
+        let passkey = register(NONE_REGISTRATION);
+        let result = verify_assertion(&rp(), &challenge(NONE_ASSERTION), &passkey, &assertion(NONE_ASSERTION));
+        assert_eq!(result.unwrap(), Assertion { sign_count: 0, user_verified: true });
+        // Authenticators without a counter can't be checked for clones
+        let result = verify_assertion(&rp(), &challenge(NONE_ASSERTION), &passkey, &assertion(NONE_ASSERTION));
+        assert!(result.is_ok());
+
+        let mut passkey = register(PACKED_SELF_REGISTRATION);
+        let credential = assertion(PACKED_SELF_ASSERTION);
+        let expected = challenge(PACKED_SELF_ASSERTION);
+        assert_eq!(verify_assertion(&rp(), &expected, &passkey, &credential).unwrap(), Assertion { sign_count: 2, user_verified: false });
+        let other_origin = RelyingParty { origin: "https://example.org".to_string(), ..rp() };
+        assert!(matches!(verify_assertion(&other_origin, &expected, &passkey, &credential), Err(WebAuthnError::OriginMismatch)));
+        let other_id = RelyingParty { id: "example.org".to_string(), ..rp() };
+        assert!(matches!(verify_assertion(&other_id, &expected, &passkey, &credential), Err(WebAuthnError::RpIdMismatch)));
+        let result = verify_assertion(&rp(), &challenge(NONE_ASSERTION), &passkey, &credential);
+        assert!(matches!(result, Err(WebAuthnError::InvalidChallenge)));
+        let result = verify_assertion(&rp(), &expected, &register(NONE_REGISTRATION), &credential);
+        assert!(matches!(result, Err(WebAuthnError::UnknownCredential)));
+
+        let mut signature = decode(&credential.response.signature, "").unwrap();
+        *signature.last_mut().unwrap() ^= 1;
+        let mut tampered = credential.clone();
+        tampered.response.signature = encode(&signature);
+        assert!(matches!(verify_assertion(&rp(), &expected, &passkey, &tampered), Err(WebAuthnError::InvalidSignature)));
+
+        // Replayed or from a clone that fell behind
+        for sign_count in [2, 7] {
+            passkey.sign_count = sign_count;
+            assert!(matches!(verify_assertion(&rp(), &expected, &passkey, &credential), Err(WebAuthnError::CounterRegressed)));
+        }
+    }
+
+    #[cfg(feature = "in-memory")]
+    #[tokio::test]
+    async fn ceremonies() {
+        use std::time::UNIX_EPOCH;
+
+        use crate::store::memory::{InMemoryState, InMemoryStore};
+        use crate::store::UserStore;
+
+        let state = InMemoryState::new();
+        let client = state.seed_client("app");
+        let user = state.seed_user(client.id, "alice@example.com");
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let seed_challenge = |fixture| InMemoryStore::insert_token(state.clone(), challenge_token(fixture, user.id, client.id, now));
+
+        let options = start_registration::<InMemoryStore>(state.clone(), &rp(), user.id, now).await.unwrap();
+        assert_eq!(options.user.name, "alice@example.com");
+        assert_eq!(decode(&options.user.id, "").unwrap(), u128::from(user.id).to_be_bytes());
+        assert!(options.exclude_credentials.is_empty());
+
+        let credential = registration(NONE_REGISTRATION);
+        let result = finish_registration::<InMemoryStore>(state.clone(), &rp(), user.id, &credential, now).await;
+        assert!(matches!(result, Err(WebAuthnError::InvalidChallenge)));
+        let record = challenge_token(NONE_REGISTRATION, user.id, client.id, now);
+        let record = TokenRecord { data: bincode::serialize(&Ceremony::Authentication).unwrap(), ..record };
+        InMemoryStore::insert_token(state.clone(), record).await.unwrap();
+        let result = finish_registration::<InMemoryStore>(state.clone(), &rp(), user.id, &credential, now).await;
+        assert!(matches!(result, Err(WebAuthnError::InvalidChallenge)));
+        seed_challenge(NONE_REGISTRATION).await.unwrap();
+        let result = finish_registration::<InMemoryStore>(state.clone(), &rp(), user.id, &credential, now + CHALLENGE_LIFETIME).await;
+        assert!(matches!(result, Err(WebAuthnError::InvalidChallenge)));
+        seed_challenge(NONE_REGISTRATION).await.unwrap();
+        finish_registration::<InMemoryStore>(state.clone(), &rp(), user.id, &credential, now).await.unwrap();
+        seed_challenge(NONE_REGISTRATION).await.unwrap();
+        let result = finish_registration::<InMemoryStore>(state.clone(), &rp(), user.id, &credential, now).await;
+        assert!(matches!(result, Err(WebAuthnError::DuplicateCredential)));
+        seed_challenge(PACKED_SELF_REGISTRATION).await.unwrap();
+        finish_registration::<InMemoryStore>(state.clone(), &rp(), user.id, &registration(PACKED_SELF_REGISTRATION), now).await.unwrap();
+
+        let options = start_registration::<InMemoryStore>(state.clone(), &rp(), user.id, now).await.unwrap();
+        let excluded: Vec<_> = options.exclude_credentials.iter().map(|descriptor| descriptor.id.as_str()).collect();
+        assert_eq!(excluded, [registration(NONE_REGISTRATION).id, registration(PACKED_SELF_REGISTRATION).id]);
+
+        // Unknown users get options as plausible as the real ones
+        let options = start_authentication::<InMemoryStore>(state.clone(), &rp(), client.id, "alice@example.com", now).await.unwrap();
+        assert_eq!(options.allow_credentials.len(), 2);
+        let unknown = start_authentication::<InMemoryStore>(state.clone(), &rp(), client.id, "mallory@example.com", now).await.unwrap();
+        let again = start_authentication::<InMemoryStore>(state.clone(), &rp(), client.id, "mallory@example.com", now).await.unwrap();
+        assert_eq!(unknown.allow_credentials.len(), 1);
+        assert_eq!(unknown.allow_credentials, again.allow_credentials);
+        assert_ne!(unknown.challenge, again.challenge);
+
+        seed_challenge(NONE_ASSERTION).await.unwrap();
+        let login = finish_authentication::<InMemoryStore>(state.clone(), &rp(), &assertion(NONE_ASSERTION), now).await.unwrap();
+        assert_eq!(login, PasskeyLogin { user_id: user.id, client_id: client.id, user_verified: true });
+        let result = finish_authentication::<InMemoryStore>(state.clone(), &rp(), &assertion(NONE_ASSERTION), now).await;
+        assert!(matches!(result, Err(WebAuthnError::InvalidChallenge)));
+
+        let mut credential = assertion(PACKED_SELF_ASSERTION);
+        credential.response.user_handle = Some(encode(&[0; 16]));
+        seed_challenge(PACKED_SELF_ASSERTION).await.unwrap();
+        let result = finish_authentication::<InMemoryStore>(state.clone(), &rp(), &credential, now).await;
+        assert!(matches!(result, Err(WebAuthnError::UnknownCredential)));
+        credential.response.user_handle = Some(encode(&u128::from(user.id).to_be_bytes()));
+        seed_challenge(PACKED_SELF_ASSERTION).await.unwrap();
+        let login = finish_authentication::<InMemoryStore>(state.clone(), &rp(), &credential, now).await.unwrap();
+        assert!(!login.user_verified);
+        // The counter was stored, the same assertion can't be replayed even with a new challenge
+        seed_challenge(PACKED_SELF_ASSERTION).await.unwrap();
+        let result = finish_authentication::<InMemoryStore>(state.clone(), &rp(), &credential, now).await;
+        assert!(matches!(result, Err(WebAuthnError::CounterRegressed)));
+        let credentials = InMemoryStore::get_user_credentials(state, user.id).await.unwrap();
+        assert_eq!(credentials.passkeys.iter().map(|passkey| passkey.sign_count).collect::<Vec<_>>(), [0, 2]);
+    }
+
+    #[cfg(feature = "in-memory")]
+    mod login {
+        use crate::crypto::totp;
+        use crate::service::tests::{Authentication, ISSUER};
+        use crate::service::{mfa, oidc, LoginError, PasskeyLoginRequest, SecondFactorRequest, UserAuthentication, UserLoginOutcome};
+        use crate::store::memory::{InMemoryState, InMemoryStore};
+        use crate::store::UserCredentials;
+
+        use super::*;
+
+        #[tokio::test]
+        async fn login_with_passkey() {
+            struct Passkeys(RelyingParty, SystemTime);
+
+            impl UserAuthentication<InMemoryStore> for Passkeys {
+                fn issuer(&self) -> &str {
+                    ISSUER
+                }
+
+                fn now(&self) -> SystemTime {
+                    self.1
+                }
+
+                fn relying_party(&self) -> Option<&RelyingParty> {
+                    Some(&self.0)
+                }
+            }
+
+            let state = InMemoryState::new();
+            let client = state.seed_client("app");
+            let user = state.seed_user(client.id, "alice@example.com");
+            let signing_key = state.seed_signing_key(client.id);
+            let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+            let passkeys = [fixtures::NONE_REGISTRATION, fixtures::PACKED_SELF_REGISTRATION].map(|fixture| {
+                let credential = serde_json::from_str(fixture).unwrap();
+                verify_registration(&fixtures::rp(), &fixtures::challenge(fixture), &credential).unwrap()
+            });
+            let credentials = UserCredentials { passkeys: passkeys.to_vec(), ..UserCredentials::default() };
+            InMemoryStore::set_user_credentials(state.clone(), user.id, credentials).await.unwrap();
+            let enrollment = mfa::enroll_totp::<InMemoryStore>(state.clone(), user.id, "ACME").await.unwrap();
+            let secret = totp::base32_decode(&enrollment.secret).unwrap();
+            mfa::confirm_totp::<InMemoryStore>(state.clone(), user.id, &totp::hotp(&secret, totp::time_step(now)), now).await.unwrap();
+            let login = |fixture| {
+                let record = fixtures::challenge_token(fixture, user.id, client.id, now);
+                let state = state.clone();
+                async move {
+                    InMemoryStore::insert_token(state, record).await.unwrap();
+                    PasskeyLoginRequest { credential: serde_json::from_str(fixture).unwrap(), nonce: None, client_ip: None }
+                }
+            };
+
+            let request = PasskeyLoginRequest { credential: serde_json::from_str(fixtures::NONE_ASSERTION).unwrap(), nonce: None, client_ip: None };
+            let error = Authentication.login_with_passkey(request, state.clone()).await.err();
+            assert_eq!(error, Some(LoginError::PasskeysNotConfigured));
+            // A verified user counts as both factors
+            let outcome = Passkeys(fixtures::rp(), now).login_with_passkey(login(fixtures::NONE_ASSERTION).await, state.clone()).await.unwrap();
+            let response = outcome.authenticated().unwrap();
+            let claims = oidc::verify_id_token(&VerifyingKey::from(&signing_key), &response.id_token, ISSUER, client.id, now).unwrap();
+            assert_eq!(claims.amr, [oidc::AMR_POP, oidc::AMR_USER, oidc::AMR_MFA]);
+            // The challenge was used
+            let request = PasskeyLoginRequest { credential: serde_json::from_str(fixtures::NONE_ASSERTION).unwrap(), nonce: None, client_ip: None };
+            let error = Passkeys(fixtures::rp(), now).login_with_passkey(request, state.clone()).await.err();
+            assert_eq!(error, Some(LoginError::InvalidPasskey));
+
+            // Otherwise the TOTP code is still needed
+            let later = now + totp::STEP;
+            let outcome = Passkeys(fixtures::rp(), later).login_with_passkey(login(fixtures::PACKED_SELF_ASSERTION).await, state.clone()).await.unwrap();
+            let UserLoginOutcome::SecondFactorRequired { mfa_token } = outcome else {
+                panic!("expected a pending login");
+            };
+            let request = SecondFactorRequest { mfa_token, code: totp::hotp(&secret, totp::time_step(later)), client_ip: None };
+            let response = Passkeys(fixtures::rp(), later).verify_second_factor(request, state.clone()).await.unwrap();
+            let claims = oidc::verify_id_token(&VerifyingKey::from(&signing_key), &response.id_token, ISSUER, client.id, later).unwrap();
+            assert_eq!(claims.amr, [oidc::AMR_POP, oidc::AMR_USER, oidc::AMR_OTP, oidc::AMR_MFA]);
+
+            let other_origin = RelyingParty { origin: "https://example.org".to_string(), ..fixtures::rp() };
+            let error = Passkeys(other_origin, now).login_with_passkey(login(fixtures::NONE_ASSERTION).await, state).await.err();
+            assert_eq!(error, Some(LoginError::InvalidPasskey));
+        }
+    }
+}
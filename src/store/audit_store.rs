@@ -0,0 +1,25 @@
+use crate::store::Store;
+
+/// Entry of the audit log. The store keeps the records as they are, chaining and signing them is done by
+/// the writer and checked by whoever reads them back
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// Position in the log, the first record is `0`
+    pub sequence: u64,
+    pub data: Vec<u8>,
+    pub previous_hash: Vec<u8>,
+    pub hash: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+/// Append-only log, records can't be changed or removed through the store
+#[async_trait::async_trait]
+pub trait AuditStore: Store {
+    /// Appends the record when its sequence is the next one, otherwise someone else appended first and
+    /// it fails with [`StoreError::VersionMismatch`](crate::store::StoreError::VersionMismatch)
+    async fn append_audit_record(state: Self::State, record: AuditRecord) -> Result<(), Self::Error>;
+    /// The record with the highest sequence, `None` when the log is empty
+    async fn last_audit_record(state: Self::State) -> Result<Option<AuditRecord>, Self::Error>;
+    /// Up to `limit` records starting at the sequence `from`, in order
+    async fn list_audit_records(state: Self::State, from: u64, limit: usize) -> Result<Vec<AuditRecord>, Self::Error>;
+}
@@ -0,0 +1,611 @@
+//! Read-through caching decorator for any store. [`CachedStore`] implements the same traits as the store
+//! it wraps, lookups of users and signing keys are answered from bounded LRU caches whose entries expire
+//! after a TTL, and writes made through it invalidate the entries they affect.
+//!
+//! Writes made by other processes, or through the wrapped state directly, are only picked up once the
+//! entries expire, so the TTL bounds how stale a lookup can be
+
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use lru::LruCache;
+use p256::ecdsa::SigningKey;
+
+use crate::crypto::secret::SecretBytes;
+use crate::data::id::Identifier;
+use crate::model::User;
+use crate::store::{
+    AuditRecord, AuditStore, ClientCredentials, ClientStore, ClientUpdate, Lockout, LockoutPolicy, NewClient, NewSession, NewUser, RateLimit,
+    RateLimitDecision, RateLimitKey, RateLimitStore, Session, SessionStore, Store, StoreError, TokenRecord, TokenStore,
+    Transaction, UserCredentials, UserMetadataOf, UserStore, UserUpdate,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Maximum number of entries of each cache, the least recently used ones are evicted first
+    pub capacity: NonZeroUsize,
+    pub ttl: Duration,
+    /// TTL of not found results, usually shorter so new entries show up sooner
+    pub negative_ttl: Duration,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: NonZeroUsize::new(10_000).unwrap(),
+            ttl: Duration::from_secs(60),
+            negative_ttl: Duration::from_secs(5),
+        }
+    }
+}
+
+struct Entry<V> {
+    value: V,
+    expires_at: Instant,
+}
+
+struct TtlCache<K: Hash + Eq, V> {
+    entries: Mutex<LruCache<K, Entry<V>>>,
+}
+
+impl<K: Hash + Eq, V> TtlCache<K, V> {
+    fn new(capacity: NonZeroUsize) -> Self {
+        Self { entries: Mutex::new(LruCache::new(capacity)) }
+    }
+
+    // NOTE: The caches are only touched for single lookups or inserts, a poisoned lock can't leave them
+    // in an inconsistent state
+    fn lock(&self) -> std::sync::MutexGuard<'_, LruCache<K, Entry<V>>> {
+        self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn get(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        let mut entries = self.lock();
+        match entries.get(key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.value.clone()),
+            Some(_) => {
+                entries.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, key: K, value: V, ttl: Duration) {
+        if ttl.is_zero() {
+            return;
+        }
+        let expires_at = Instant::now() + ttl;
+        self.lock().put(key, Entry { value, expires_at });
+    }
+
+    fn remove(&self, key: &K) {
+        self.lock().pop(key);
+    }
+
+    fn clear(&self) {
+        self.lock().clear();
+    }
+}
+
+struct Caches<S: UserStore> {
+    config: CacheConfig,
+    /// `None` caches a not found user
+    users: TtlCache<Identifier, Option<S::User>>,
+    /// Id of the user with the (client id, lowercase email), checked against the cached user before
+    /// trusting it so changing an email only needs to invalidate the user
+    emails: TtlCache<(Identifier, String), Option<Identifier>>,
+    signing_keys: TtlCache<Identifier, Option<SecretBytes>>,
+    parsed_keys: TtlCache<Identifier, SigningKey>,
+}
+
+impl<S: UserStore> Caches<S> {
+    fn ttl<T>(&self, value: &Option<T>) -> Duration {
+        match value {
+            Some(_) => self.config.ttl,
+            None => self.config.negative_ttl,
+        }
+    }
+
+    fn invalidate(&self, invalidation: &Invalidation) {
+        match invalidation {
+            Invalidation::User(id) => self.users.remove(id),
+            Invalidation::Email(client_id, email) => self.emails.remove(&(*client_id, email.clone())),
+            Invalidation::SigningKey(client_id) => {
+                self.signing_keys.remove(client_id);
+                self.parsed_keys.remove(client_id);
+            }
+            Invalidation::Users => {
+                self.users.clear();
+                self.emails.clear();
+            }
+        }
+    }
+}
+
+enum Invalidation {
+    User(Identifier),
+    /// Clears a not found email
+    Email(Identifier, String),
+    SigningKey(Identifier),
+    Users,
+}
+
+fn email_key(email: &str) -> String {
+    email.to_lowercase()
+}
+
+/// Caching decorator for the store `S`, see the [module documentation](self)
+pub struct CachedStore<S>(PhantomData<S>);
+
+pub struct CachedState<S: UserStore> {
+    inner: S::State,
+    caches: Arc<Caches<S>>,
+    /// Invalidations of the open transaction, applied again after commit so lookups that ran while it
+    /// was open don't keep the old values
+    transaction: Option<Arc<Mutex<Vec<Invalidation>>>>,
+}
+
+impl<S: UserStore> Clone for CachedState<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            caches: self.caches.clone(),
+            transaction: self.transaction.clone(),
+        }
+    }
+}
+
+impl<S: UserStore> CachedState<S> {
+    pub fn new(inner: S::State, config: CacheConfig) -> Self {
+        let caches = Caches {
+            config,
+            users: TtlCache::new(config.capacity),
+            emails: TtlCache::new(config.capacity),
+            signing_keys: TtlCache::new(config.capacity),
+            parsed_keys: TtlCache::new(config.capacity),
+        };
+        Self {
+            inner,
+            caches: Arc::new(caches),
+            transaction: None,
+        }
+    }
+
+    /// State of the wrapped store, changes made through it aren't seen until the cached entries expire
+    pub fn inner(&self) -> &S::State {
+        &self.inner
+    }
+
+    /// Drops every cached entry, for changes made outside of the cached state
+    pub fn clear(&self) {
+        self.caches.users.clear();
+        self.caches.emails.clear();
+        self.caches.signing_keys.clear();
+        self.caches.parsed_keys.clear();
+    }
+
+    /// Lookups inside of a transaction go straight to the store, they could see uncommitted changes
+    fn cacheable(&self) -> bool {
+        self.transaction.is_none()
+    }
+
+    fn invalidate(&self, invalidation: Invalidation) {
+        self.caches.invalidate(&invalidation);
+        if let Some(transaction) = &self.transaction {
+            transaction.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(invalidation);
+        }
+    }
+}
+
+/// Splits a lookup result into a cacheable value, keeping not found as `None`
+fn found<T, E: Into<StoreError>>(result: Result<T, E>) -> Result<Option<T>, StoreError> {
+    match result.map_err(Into::into) {
+        Ok(value) => Ok(Some(value)),
+        Err(StoreError::NotFound) => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+impl<S> Store for CachedStore<S>
+where
+    S: UserStore + ClientStore,
+    S::User: Clone,
+{
+    type Error = StoreError;
+    type State = CachedState<S>;
+}
+
+#[async_trait::async_trait]
+impl<S> UserStore for CachedStore<S>
+where
+    S: UserStore + ClientStore,
+    S::User: Clone,
+{
+    type User = S::User;
+
+    async fn create_user(state: Self::State, user: NewUser<UserMetadataOf<Self>>) -> Result<Self::User, Self::Error> {
+        let key = email_key(&user.email);
+        let user = S::create_user(state.inner.clone(), user).await.map_err(Into::into)?;
+        state.invalidate(Invalidation::Email(user.get_client_id(), key));
+        Ok(user)
+    }
+
+    async fn get_user(state: Self::State, id: Identifier) -> Result<Self::User, Self::Error> {
+        if !state.cacheable() {
+            return S::get_user(state.inner.clone(), id).await.map_err(Into::into);
+        }
+        if let Some(user) = state.caches.users.get(&id) {
+            return user.ok_or(StoreError::NotFound);
+        }
+        let user = found(S::get_user(state.inner.clone(), id).await)?;
+        state.caches.users.insert(id, user.clone(), state.caches.ttl(&user));
+        user.ok_or(StoreError::NotFound)
+    }
+
+    async fn get_user_by_email(state: Self::State, client_id: Identifier, email: &str) -> Result<Self::User, Self::Error> {
+        if !state.cacheable() {
+            return S::get_user_by_email(state.inner.clone(), client_id, email).await.map_err(Into::into);
+        }
+        let key = (client_id, email_key(email));
+        match state.caches.emails.get(&key) {
+            Some(None) => return Err(StoreError::NotFound),
+            Some(Some(id)) => {
+                if let Some(Some(user)) = state.caches.users.get(&id) {
+                    if user.get_client_id() == client_id && email_key(user.get_email()) == key.1 {
+                        return Ok(user);
+                    }
+                }
+            }
+            None => {}
+        }
+        let user = found(S::get_user_by_email(state.inner.clone(), client_id, email).await)?;
+        state.caches.emails.insert(key, user.as_ref().map(User::get_id), state.caches.ttl(&user));
+        if let Some(user) = &user {
+            state.caches.users.insert(user.get_id(), Some(user.clone()), state.caches.config.ttl);
+        }
+        user.ok_or(StoreError::NotFound)
+    }
+
+    async fn list_users(state: Self::State, client_id: Identifier) -> Result<Vec<Self::User>, Self::Error> {
+        S::list_users(state.inner.clone(), client_id).await.map_err(Into::into)
+    }
+
+    async fn update_user(state: Self::State, id: Identifier, update: UserUpdate<UserMetadataOf<Self>>) -> Result<Self::User, Self::Error> {
+        let user = S::update_user(state.inner.clone(), id, update).await;
+        state.invalidate(Invalidation::User(id));
+        let user = user.map_err(Into::into)?;
+        state.invalidate(Invalidation::Email(user.get_client_id(), email_key(user.get_email())));
+        Ok(user)
+    }
+
+    async fn delete_user(state: Self::State, id: Identifier) -> Result<(), Self::Error> {
+        S::delete_user(state.inner.clone(), id).await.map_err(Into::into)?;
+        state.invalidate(Invalidation::User(id));
+        Ok(())
+    }
+
+    // NOTE: Second factors aren't cached, the replay protection of the TOTP codes needs the last used step
+    async fn get_user_credentials(state: Self::State, user_id: Identifier) -> Result<UserCredentials, Self::Error> {
+        S::get_user_credentials(state.inner, user_id).await.map_err(Into::into)
+    }
+
+    async fn set_user_credentials(state: Self::State, user_id: Identifier, credentials: UserCredentials) -> Result<(), Self::Error> {
+        S::set_user_credentials(state.inner, user_id, credentials).await.map_err(Into::into)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> ClientStore for CachedStore<S>
+where
+    S: UserStore + ClientStore,
+    S::User: Clone,
+{
+    type Client = S::Client;
+
+    async fn create_client(state: Self::State, client: NewClient) -> Result<Self::Client, Self::Error> {
+        S::create_client(state.inner.clone(), client).await.map_err(Into::into)
+    }
+
+    async fn get_client(state: Self::State, id: Identifier) -> Result<Self::Client, Self::Error> {
+        S::get_client(state.inner.clone(), id).await.map_err(Into::into)
+    }
+
+    async fn list_clients(state: Self::State, parent_id: Option<Identifier>) -> Result<Vec<Self::Client>, Self::Error> {
+        S::list_clients(state.inner.clone(), parent_id).await.map_err(Into::into)
+    }
+
+    async fn update_client(state: Self::State, id: Identifier, update: ClientUpdate) -> Result<Self::Client, Self::Error> {
+        S::update_client(state.inner.clone(), id, update).await.map_err(Into::into)
+    }
+
+    async fn delete_client(state: Self::State, id: Identifier) -> Result<(), Self::Error> {
+        S::delete_client(state.inner.clone(), id).await.map_err(Into::into)?;
+        // NOTE: Users aren't indexed by client, deleting clients is rare enough to drop all of them
+        state.invalidate(Invalidation::Users);
+        state.invalidate(Invalidation::SigningKey(id));
+        Ok(())
+    }
+
+    async fn get_signing_key_bytes(state: Self::State, client_id: Identifier) -> Result<SecretBytes, Self::Error> {
+        if !state.cacheable() {
+            return S::get_signing_key_bytes(state.inner.clone(), client_id).await.map_err(Into::into);
+        }
+        if let Some(key) = state.caches.signing_keys.get(&client_id) {
+            return key.ok_or(StoreError::NotFound);
+        }
+        let key = found(S::get_signing_key_bytes(state.inner.clone(), client_id).await)?;
+        state.caches.signing_keys.insert(client_id, key.clone(), state.caches.ttl(&key));
+        key.ok_or(StoreError::NotFound)
+    }
+
+    async fn set_signing_key_bytes(state: Self::State, client_id: Identifier, key: SecretBytes) -> Result<(), Self::Error> {
+        let result = S::set_signing_key_bytes(state.inner.clone(), client_id, key).await.map_err(Into::into);
+        state.invalidate(Invalidation::SigningKey(client_id));
+        result
+    }
+
+    // NOTE: Credentials aren't cached, a rotated or revoked secret must stop working right away
+    async fn get_credentials(state: Self::State, client_id: Identifier) -> Result<ClientCredentials, Self::Error> {
+        S::get_credentials(state.inner, client_id).await.map_err(Into::into)
+    }
+
+    async fn set_credentials(state: Self::State, client_id: Identifier, credentials: ClientCredentials) -> Result<(), Self::Error> {
+        S::set_credentials(state.inner, client_id, credentials).await.map_err(Into::into)
+    }
+
+    async fn get_signing_key(state: Self::State, client_id: Identifier) -> Result<SigningKey, StoreError> {
+        if !state.cacheable() {
+            return S::get_signing_key(state.inner.clone(), client_id).await;
+        }
+        if let Some(key) = state.caches.parsed_keys.get(&client_id) {
+            return Ok(key);
+        }
+        let bytes = Self::get_signing_key_bytes(state.clone(), client_id).await?;
+        let key = SigningKey::from_slice(bytes.expose_secret()).map_err(|error| StoreError::Serialization(Box::new(error)))?;
+        state.caches.parsed_keys.insert(client_id, key.clone(), state.caches.config.ttl);
+        Ok(key)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> SessionStore for CachedStore<S>
+where
+    S: UserStore + ClientStore + SessionStore,
+    S::User: Clone,
+{
+    async fn create_session(state: Self::State, session: NewSession) -> Result<Session, Self::Error> {
+        S::create_session(state.inner.clone(), session).await.map_err(Into::into)
+    }
+
+    async fn get_session(state: Self::State, id: Identifier) -> Result<Session, Self::Error> {
+        S::get_session(state.inner.clone(), id).await.map_err(Into::into)
+    }
+
+    async fn delete_session(state: Self::State, id: Identifier) -> Result<(), Self::Error> {
+        S::delete_session(state.inner.clone(), id).await.map_err(Into::into)
+    }
+
+    async fn delete_user_sessions(state: Self::State, user_id: Identifier) -> Result<(), Self::Error> {
+        S::delete_user_sessions(state.inner.clone(), user_id).await.map_err(Into::into)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> TokenStore for CachedStore<S>
+where
+    S: UserStore + ClientStore + TokenStore,
+    S::User: Clone,
+{
+    async fn insert_token(state: Self::State, token: TokenRecord) -> Result<(), Self::Error> {
+        S::insert_token(state.inner.clone(), token).await.map_err(Into::into)
+    }
+
+    async fn get_token(state: Self::State, hash: &[u8]) -> Result<TokenRecord, Self::Error> {
+        S::get_token(state.inner.clone(), hash).await.map_err(Into::into)
+    }
+
+    async fn consume_token(state: Self::State, hash: &[u8]) -> Result<TokenRecord, Self::Error> {
+        S::consume_token(state.inner.clone(), hash).await.map_err(Into::into)
+    }
+
+    async fn revoke_user_tokens(state: Self::State, user_id: Identifier) -> Result<(), Self::Error> {
+        S::revoke_user_tokens(state.inner.clone(), user_id).await.map_err(Into::into)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> RateLimitStore for CachedStore<S>
+where
+    S: UserStore + ClientStore + RateLimitStore,
+    S::User: Clone,
+{
+    async fn acquire(state: Self::State, key: &RateLimitKey, limit: RateLimit, now: SystemTime) -> Result<RateLimitDecision, Self::Error> {
+        S::acquire(state.inner.clone(), key, limit, now).await.map_err(Into::into)
+    }
+
+    async fn get_lockout(state: Self::State, key: &RateLimitKey) -> Result<Lockout, Self::Error> {
+        S::get_lockout(state.inner.clone(), key).await.map_err(Into::into)
+    }
+
+    async fn record_failure(state: Self::State, key: &RateLimitKey, policy: LockoutPolicy, now: SystemTime) -> Result<Lockout, Self::Error> {
+        S::record_failure(state.inner.clone(), key, policy, now).await.map_err(Into::into)
+    }
+
+    async fn clear_failures(state: Self::State, key: &RateLimitKey) -> Result<(), Self::Error> {
+        S::clear_failures(state.inner.clone(), key).await.map_err(Into::into)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> AuditStore for CachedStore<S>
+where
+    S: UserStore + ClientStore + AuditStore,
+    S::User: Clone,
+{
+    async fn append_audit_record(state: Self::State, record: AuditRecord) -> Result<(), Self::Error> {
+        S::append_audit_record(state.inner.clone(), record).await.map_err(Into::into)
+    }
+
+    async fn last_audit_record(state: Self::State) -> Result<Option<AuditRecord>, Self::Error> {
+        S::last_audit_record(state.inner.clone()).await.map_err(Into::into)
+    }
+
+    async fn list_audit_records(state: Self::State, from: u64, limit: usize) -> Result<Vec<AuditRecord>, Self::Error> {
+        S::list_audit_records(state.inner.clone(), from, limit).await.map_err(Into::into)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> Transaction for CachedStore<S>
+where
+    S: UserStore + ClientStore + Transaction,
+    S::User: Clone,
+{
+    async fn begin(state: Self::State) -> Result<Self::State, Self::Error> {
+        let inner = S::begin(state.inner.clone()).await.map_err(Into::into)?;
+        Ok(CachedState {
+            inner,
+            caches: state.caches,
+            transaction: Some(Arc::default()),
+        })
+    }
+
+    async fn commit(state: Self::State) -> Result<(), Self::Error> {
+        S::commit(state.inner.clone()).await.map_err(Into::into)?;
+        if let Some(transaction) = &state.transaction {
+            let invalidations = std::mem::take(&mut *transaction.lock().unwrap_or_else(|poisoned| poisoned.into_inner()));
+            for invalidation in &invalidations {
+                state.caches.invalidate(invalidation);
+            }
+        }
+        Ok(())
+    }
+
+    async fn rollback(state: Self::State) -> Result<(), Self::Error> {
+        S::rollback(state.inner.clone()).await.map_err(Into::into)
+    }
+}
+
+#[cfg(all(test, feature = "in-memory"))]
+mod tests {
+    use crate::store::memory::{InMemoryState, InMemoryStore};
+
+    use super::*;
+
+    type Cached = CachedStore<InMemoryStore>;
+
+    fn cached_state(config: CacheConfig) -> CachedState<InMemoryStore> {
+        CachedState::new(InMemoryState::new(), config)
+    }
+
+    fn rename(email: &str) -> UserUpdate<UserMetadataOf<InMemoryStore>> {
+        UserUpdate { email: Some(email.to_string()), ..UserUpdate::default() }
+    }
+
+    #[tokio::test]
+    async fn lookups_are_cached_until_invalidated() {
+        let state = cached_state(CacheConfig::default());
+        let client = state.inner().seed_client("app");
+        let user = state.inner().seed_user(client.id, "alice@example.com");
+
+        let cached = Cached::get_user_by_email(state.clone(), client.id, "Alice@Example.com").await.unwrap();
+        assert_eq!(cached, user);
+
+        // Changes behind the cache aren't seen
+        InMemoryStore::update_user(state.inner().clone(), user.id, rename("bob@example.com")).await.unwrap();
+        let cached = Cached::get_user_by_email(state.clone(), client.id, "alice@example.com").await.unwrap();
+        assert_eq!(cached.email, "alice@example.com");
+        assert_eq!(Cached::get_user(state.clone(), user.id).await.unwrap().email, "alice@example.com");
+
+        // Changes through it are
+        let updated = Cached::update_user(state.clone(), user.id, rename("carol@example.com")).await.unwrap();
+        assert!(matches!(Cached::get_user_by_email(state.clone(), client.id, "alice@example.com").await, Err(StoreError::NotFound)));
+        assert_eq!(Cached::get_user_by_email(state.clone(), client.id, "carol@example.com").await.unwrap(), updated);
+        assert_eq!(Cached::get_user(state.clone(), user.id).await.unwrap(), updated);
+
+        Cached::delete_user(state.clone(), user.id).await.unwrap();
+        assert!(matches!(Cached::get_user(state.clone(), user.id).await, Err(StoreError::NotFound)));
+        assert!(matches!(Cached::get_user_by_email(state, client.id, "carol@example.com").await, Err(StoreError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn not_found_is_cached() {
+        let state = cached_state(CacheConfig::default());
+        let client = state.inner().seed_client("app");
+        assert!(matches!(Cached::get_user_by_email(state.clone(), client.id, "alice@example.com").await, Err(StoreError::NotFound)));
+
+        state.inner().seed_user(client.id, "alice@example.com");
+        assert!(matches!(Cached::get_user_by_email(state.clone(), client.id, "alice@example.com").await, Err(StoreError::NotFound)));
+
+        state.clear();
+        Cached::get_user_by_email(state.clone(), client.id, "alice@example.com").await.unwrap();
+
+        // Creating through the cache drops the negative entry
+        assert!(matches!(Cached::get_user_by_email(state.clone(), client.id, "bob@example.com").await, Err(StoreError::NotFound)));
+        let user = NewUser { client_id: client.id, email: "Bob@Example.com".to_string(), metadata: None };
+        let user = Cached::create_user(state.clone(), user).await.unwrap();
+        assert_eq!(Cached::get_user_by_email(state, client.id, "bob@example.com").await.unwrap(), user);
+    }
+
+    #[tokio::test]
+    async fn entries_expire() {
+        let config = CacheConfig { ttl: Duration::ZERO, negative_ttl: Duration::ZERO, ..CacheConfig::default() };
+        let state = cached_state(config);
+        let client = state.inner().seed_client("app");
+        let user = state.inner().seed_user(client.id, "alice@example.com");
+
+        Cached::get_user(state.clone(), user.id).await.unwrap();
+        InMemoryStore::update_user(state.inner().clone(), user.id, rename("bob@example.com")).await.unwrap();
+        assert_eq!(Cached::get_user(state, user.id).await.unwrap().email, "bob@example.com");
+    }
+
+    #[tokio::test]
+    async fn parsed_signing_keys_are_cached() {
+        let state = cached_state(CacheConfig::default());
+        let client = state.inner().seed_client("app");
+        let signing_key = state.inner().seed_signing_key(client.id);
+
+        assert_eq!(Cached::get_signing_key(state.clone(), client.id).await.unwrap(), signing_key);
+        InMemoryStore::set_signing_key_bytes(state.inner().clone(), client.id, SecretBytes::new(vec![0; 32])).await.unwrap();
+        assert_eq!(Cached::get_signing_key(state.clone(), client.id).await.unwrap(), signing_key);
+
+        Cached::set_signing_key_bytes(state.clone(), client.id, SecretBytes::new(vec![0; 32])).await.unwrap();
+        assert!(matches!(Cached::get_signing_key(state, client.id).await, Err(StoreError::Serialization(_))));
+    }
+
+    #[tokio::test]
+    async fn transactions_bypass_the_cache() {
+        let state = cached_state(CacheConfig::default());
+        let client = state.inner().seed_client("app");
+        let user = state.inner().seed_user(client.id, "alice@example.com");
+        Cached::get_user(state.clone(), user.id).await.unwrap();
+
+        let transaction = Cached::begin(state.clone()).await.unwrap();
+        Cached::update_user(transaction.clone(), user.id, rename("bob@example.com")).await.unwrap();
+        assert_eq!(Cached::get_user(transaction.clone(), user.id).await.unwrap().email, "bob@example.com");
+        // A lookup outside of the transaction refills the cache with the committed user
+        assert_eq!(Cached::get_user(state.clone(), user.id).await.unwrap().email, "alice@example.com");
+
+        Cached::commit(transaction).await.unwrap();
+        assert_eq!(Cached::get_user(state, user.id).await.unwrap().email, "bob@example.com");
+    }
+
+    #[cfg(feature = "testing")]
+    mod conformance {
+        use crate::store::memory::InMemoryState;
+
+        use super::*;
+
+        crate::store_conformance_tests!(
+            CachedStore<InMemoryStore>,
+            CachedState::<InMemoryStore>::new(InMemoryState::new(), CacheConfig::default())
+        );
+    }
+}
@@ -1,13 +1,69 @@
+use p256::ecdsa::SigningKey;
+use serde::{Deserialize, Serialize};
+
+use crate::crypto::secret::SecretBytes;
 use crate::data::id::Identifier;
-use crate::store::Store;
+use crate::model::{Client, ClientSettings};
+use crate::store::{Store, StoreError};
+
+#[derive(Debug, Clone, Default)]
+pub struct NewClient {
+    pub parent_id: Option<Identifier>,
+    pub name: String,
+    pub settings: ClientSettings,
+}
+
+/// Partial update of a client, the fields set to `None` are left untouched
+#[derive(Debug, Clone, Default)]
+pub struct ClientUpdate {
+    pub name: Option<String>,
+    pub parent_id: Option<Option<Identifier>>,
+    /// Replaces all the settings of the client
+    pub settings: Option<ClientSettings>,
+    pub disabled: Option<bool>,
+    /// Compare-and-swap guard, the update fails with [`crate::store::StoreError::VersionMismatch`] if the
+    /// stored client has another version
+    pub expected_version: Option<u64>,
+}
+
+/// Authentication material of a confidential client, public clients have none and can only use the grants
+/// that don't authenticate the client
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientCredentials {
+    /// PHC string of the Argon2id hash of the client secret
+    pub secret_hash: Option<String>,
+    /// SEC1 encoded P-256 key verifying the `private_key_jwt` client assertions
+    pub public_key: Option<Vec<u8>>,
+}
 
-pub struct UserQuery {
-    pub email: String,
-    pub id: Identifier
+impl ClientCredentials {
+    pub fn is_confidential(&self) -> bool {
+        self.secret_hash.is_some() || self.public_key.is_some()
+    }
 }
 
 #[async_trait::async_trait]
 pub trait ClientStore: Store {
-    async fn get_user_by_email(state: Self::State, email: &str) -> Result<UserQuery, Self::Error>;
-    async fn get_signing_key_bytes(state: Self::State) -> Result<Vec<u8>, Self::Error>;
-}
\ No newline at end of file
+    type Client: Client + Send + Sync;
+
+    async fn create_client(state: Self::State, client: NewClient) -> Result<Self::Client, Self::Error>;
+    async fn get_client(state: Self::State, id: Identifier) -> Result<Self::Client, Self::Error>;
+    /// Lists the direct children of `parent_id`, or the root clients when it's `None`
+    async fn list_clients(state: Self::State, parent_id: Option<Identifier>) -> Result<Vec<Self::Client>, Self::Error>;
+    async fn update_client(state: Self::State, id: Identifier, update: ClientUpdate) -> Result<Self::Client, Self::Error>;
+    async fn delete_client(state: Self::State, id: Identifier) -> Result<(), Self::Error>;
+
+    async fn get_signing_key_bytes(state: Self::State, client_id: Identifier) -> Result<SecretBytes, Self::Error>;
+    async fn set_signing_key_bytes(state: Self::State, client_id: Identifier, key: SecretBytes) -> Result<(), Self::Error>;
+
+    /// Credentials of an existing client, the default ones when they were never set
+    async fn get_credentials(state: Self::State, client_id: Identifier) -> Result<ClientCredentials, Self::Error>;
+    async fn set_credentials(state: Self::State, client_id: Identifier, credentials: ClientCredentials) -> Result<(), Self::Error>;
+
+    /// Signing key of the client parsed from [`ClientStore::get_signing_key_bytes`], fails with
+    /// [`StoreError::Serialization`] when the stored bytes aren't a valid P-256 scalar
+    async fn get_signing_key(state: Self::State, client_id: Identifier) -> Result<SigningKey, StoreError> {
+        let bytes = Self::get_signing_key_bytes(state, client_id).await.map_err(Into::into)?;
+        SigningKey::from_slice(bytes.expose_secret()).map_err(|error| StoreError::Serialization(Box::new(error)))
+    }
+}
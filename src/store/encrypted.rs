@@ -0,0 +1,465 @@
+//! Store decorator that keeps the signing keys and the TOTP secrets encrypted at rest. [`EncryptedStore`]
+//! seals them with the [`KeyRing`] before they reach the wrapped store and opens them again on the way
+//! out, every other operation is passed through untouched. Backends persisting to disk, like the sqlite
+//! one, seal them themselves with the same functions and take the key ring when they are opened
+
+use std::marker::PhantomData;
+use std::time::SystemTime;
+
+use crate::crypto::envelope::{EncryptedSecret, EnvelopeError, KeyRing};
+use crate::crypto::secret::SecretBytes;
+use crate::data::id::Identifier;
+use crate::model::{Client, User};
+use crate::store::{
+    AuditRecord, AuditStore, ClientCredentials, ClientStore, ClientUpdate, Lockout, LockoutPolicy, NewClient, NewSession, NewUser, RateLimit,
+    RateLimitDecision, RateLimitKey, RateLimitStore, Session, SessionStore, Store, StoreError, TokenRecord, TokenStore,
+    Transaction, UserCredentials, UserMetadataOf, UserStore, UserUpdate,
+};
+
+/// Encryption decorator for the store `S`, see the [module documentation](self)
+pub struct EncryptedStore<S>(PhantomData<S>);
+
+pub struct EncryptedState<S: Store> {
+    inner: S::State,
+    key_ring: KeyRing,
+}
+
+impl<S: Store> Clone for EncryptedState<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            key_ring: self.key_ring.clone(),
+        }
+    }
+}
+
+impl<S: Store> EncryptedState<S> {
+    pub fn new(inner: S::State, key_ring: KeyRing) -> Self {
+        Self { inner, key_ring }
+    }
+
+    pub fn inner(&self) -> &S::State {
+        &self.inner
+    }
+
+    pub fn key_ring(&self) -> &KeyRing {
+        &self.key_ring
+    }
+}
+
+impl From<EnvelopeError> for StoreError {
+    fn from(error: EnvelopeError) -> Self {
+        StoreError::Serialization(Box::new(error))
+    }
+}
+
+/// The client id is authenticated with the key, so a sealed key copied to another client doesn't open
+fn signing_key_aad(client_id: Identifier) -> [u8; 16] {
+    u128::from(client_id).to_be_bytes()
+}
+
+/// Bound to the user, and told apart from the signing key AAD so a sealed key can't pass for a secret
+fn totp_secret_aad(user_id: Identifier) -> Vec<u8> {
+    [b"totp".as_slice(), &u128::from(user_id).to_be_bytes()].concat()
+}
+
+pub(crate) fn seal_signing_key(key_ring: &KeyRing, client_id: Identifier, key: &SecretBytes) -> Result<SecretBytes, StoreError> {
+    let secret = key_ring.seal(key.expose_secret(), &signing_key_aad(client_id))?;
+    Ok(SecretBytes::new(secret.to_bytes()))
+}
+
+pub(crate) fn open_signing_key(key_ring: &KeyRing, client_id: Identifier, sealed: &SecretBytes) -> Result<SecretBytes, StoreError> {
+    let secret = EncryptedSecret::from_bytes(sealed.expose_secret())?;
+    Ok(key_ring.open(&secret, &signing_key_aad(client_id))?)
+}
+
+pub(crate) fn seal_totp_secret(key_ring: &KeyRing, user_id: Identifier, credentials: &mut UserCredentials) -> Result<(), StoreError> {
+    if let Some(totp) = &mut credentials.totp {
+        let secret = key_ring.seal(totp.secret.expose_secret(), &totp_secret_aad(user_id))?;
+        totp.secret = SecretBytes::new(secret.to_bytes());
+    }
+    Ok(())
+}
+
+pub(crate) fn open_totp_secret(key_ring: &KeyRing, user_id: Identifier, credentials: &mut UserCredentials) -> Result<(), StoreError> {
+    if let Some(totp) = &mut credentials.totp {
+        let secret = EncryptedSecret::from_bytes(totp.secret.expose_secret())?;
+        totp.secret = key_ring.open(&secret, &totp_secret_aad(user_id))?;
+    }
+    Ok(())
+}
+
+/// The sealed secret with its data key re-wrapped by the current KEK, `None` if it already uses it
+pub(crate) fn rewrap_secret(key_ring: &KeyRing, sealed: &SecretBytes) -> Result<Option<SecretBytes>, StoreError> {
+    let secret = key_ring.rewrap(&EncryptedSecret::from_bytes(sealed.expose_secret())?)?;
+    Ok(secret.map(|secret| SecretBytes::new(secret.to_bytes())))
+}
+
+impl<S> EncryptedStore<S>
+where
+    S: ClientStore,
+{
+    /// Re-wraps the signing key of every client that isn't using the current KEK yet, walking the whole
+    /// client hierarchy. Returns the number of re-wrapped keys
+    pub async fn rewrap_signing_keys(state: EncryptedState<S>) -> Result<usize, StoreError> {
+        let mut rewrapped = 0;
+        let mut parents = vec![None];
+        while let Some(parent_id) = parents.pop() {
+            let clients = S::list_clients(state.inner.clone(), parent_id).await.map_err(Into::into)?;
+            for client in clients {
+                parents.push(Some(client.get_id()));
+                let bytes = match S::get_signing_key_bytes(state.inner.clone(), client.get_id()).await.map_err(Into::into) {
+                    Ok(bytes) => bytes,
+                    Err(StoreError::NotFound) => continue,
+                    Err(error) => return Err(error),
+                };
+                if let Some(bytes) = rewrap_secret(&state.key_ring, &bytes)? {
+                    S::set_signing_key_bytes(state.inner.clone(), client.get_id(), bytes).await.map_err(Into::into)?;
+                    rewrapped += 1;
+                }
+            }
+        }
+        Ok(rewrapped)
+    }
+}
+
+impl<S> EncryptedStore<S>
+where
+    S: ClientStore + UserStore + Transaction,
+{
+    /// Re-wraps the TOTP secret of every user that isn't using the current KEK yet, walking the users of
+    /// the whole client hierarchy. Returns the number of re-wrapped secrets
+    pub async fn rewrap_totp_secrets(state: EncryptedState<S>) -> Result<usize, StoreError> {
+        let mut rewrapped = 0;
+        let mut parents = vec![None];
+        while let Some(parent_id) = parents.pop() {
+            let clients = S::list_clients(state.inner.clone(), parent_id).await.map_err(Into::into)?;
+            for client in clients {
+                parents.push(Some(client.get_id()));
+                for user in S::list_users(state.inner.clone(), client.get_id()).await.map_err(Into::into)? {
+                    let user_id = user.get_id();
+                    // NOTE: In a transaction so a login recording its TOTP step or a new key isn't overwritten
+                    let transaction = S::begin(state.inner.clone()).await.map_err(Into::into)?;
+                    let mut credentials = S::get_user_credentials(transaction.clone(), user_id).await.map_err(Into::into)?;
+                    let Some(totp) = &mut credentials.totp else {
+                        continue;
+                    };
+                    if let Some(secret) = rewrap_secret(&state.key_ring, &totp.secret)? {
+                        totp.secret = secret;
+                        S::set_user_credentials(transaction.clone(), user_id, credentials).await.map_err(Into::into)?;
+                        S::commit(transaction).await.map_err(Into::into)?;
+                        rewrapped += 1;
+                    }
+                }
+            }
+        }
+        Ok(rewrapped)
+    }
+}
+
+impl<S> Store for EncryptedStore<S>
+where
+    S: Store,
+{
+    type Error = StoreError;
+    type State = EncryptedState<S>;
+}
+
+#[async_trait::async_trait]
+impl<S> UserStore for EncryptedStore<S>
+where
+    S: UserStore,
+{
+    type User = S::User;
+
+    async fn create_user(state: Self::State, user: NewUser<UserMetadataOf<Self>>) -> Result<Self::User, Self::Error> {
+        S::create_user(state.inner, user).await.map_err(Into::into)
+    }
+
+    async fn get_user(state: Self::State, id: Identifier) -> Result<Self::User, Self::Error> {
+        S::get_user(state.inner, id).await.map_err(Into::into)
+    }
+
+    async fn get_user_by_email(state: Self::State, client_id: Identifier, email: &str) -> Result<Self::User, Self::Error> {
+        S::get_user_by_email(state.inner, client_id, email).await.map_err(Into::into)
+    }
+
+    async fn list_users(state: Self::State, client_id: Identifier) -> Result<Vec<Self::User>, Self::Error> {
+        S::list_users(state.inner, client_id).await.map_err(Into::into)
+    }
+
+    async fn update_user(state: Self::State, id: Identifier, update: UserUpdate<UserMetadataOf<Self>>) -> Result<Self::User, Self::Error> {
+        S::update_user(state.inner, id, update).await.map_err(Into::into)
+    }
+
+    async fn delete_user(state: Self::State, id: Identifier) -> Result<(), Self::Error> {
+        S::delete_user(state.inner, id).await.map_err(Into::into)
+    }
+
+    async fn get_user_credentials(state: Self::State, user_id: Identifier) -> Result<UserCredentials, Self::Error> {
+        let mut credentials = S::get_user_credentials(state.inner, user_id).await.map_err(Into::into)?;
+        open_totp_secret(&state.key_ring, user_id, &mut credentials)?;
+        Ok(credentials)
+    }
+
+    async fn set_user_credentials(state: Self::State, user_id: Identifier, mut credentials: UserCredentials) -> Result<(), Self::Error> {
+        seal_totp_secret(&state.key_ring, user_id, &mut credentials)?;
+        S::set_user_credentials(state.inner, user_id, credentials).await.map_err(Into::into)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> ClientStore for EncryptedStore<S>
+where
+    S: ClientStore,
+{
+    type Client = S::Client;
+
+    async fn create_client(state: Self::State, client: NewClient) -> Result<Self::Client, Self::Error> {
+        S::create_client(state.inner, client).await.map_err(Into::into)
+    }
+
+    async fn get_client(state: Self::State, id: Identifier) -> Result<Self::Client, Self::Error> {
+        S::get_client(state.inner, id).await.map_err(Into::into)
+    }
+
+    async fn list_clients(state: Self::State, parent_id: Option<Identifier>) -> Result<Vec<Self::Client>, Self::Error> {
+        S::list_clients(state.inner, parent_id).await.map_err(Into::into)
+    }
+
+    async fn update_client(state: Self::State, id: Identifier, update: ClientUpdate) -> Result<Self::Client, Self::Error> {
+        S::update_client(state.inner, id, update).await.map_err(Into::into)
+    }
+
+    async fn delete_client(state: Self::State, id: Identifier) -> Result<(), Self::Error> {
+        S::delete_client(state.inner, id).await.map_err(Into::into)
+    }
+
+    async fn get_signing_key_bytes(state: Self::State, client_id: Identifier) -> Result<SecretBytes, Self::Error> {
+        let bytes = S::get_signing_key_bytes(state.inner, client_id).await.map_err(Into::into)?;
+        open_signing_key(&state.key_ring, client_id, &bytes)
+    }
+
+    async fn set_signing_key_bytes(state: Self::State, client_id: Identifier, key: SecretBytes) -> Result<(), Self::Error> {
+        let bytes = seal_signing_key(&state.key_ring, client_id, &key)?;
+        S::set_signing_key_bytes(state.inner, client_id, bytes).await.map_err(Into::into)
+    }
+
+    // NOTE: Credentials are only hashes and public keys, they are stored as they are
+    async fn get_credentials(state: Self::State, client_id: Identifier) -> Result<ClientCredentials, Self::Error> {
+        S::get_credentials(state.inner, client_id).await.map_err(Into::into)
+    }
+
+    async fn set_credentials(state: Self::State, client_id: Identifier, credentials: ClientCredentials) -> Result<(), Self::Error> {
+        S::set_credentials(state.inner, client_id, credentials).await.map_err(Into::into)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> SessionStore for EncryptedStore<S>
+where
+    S: SessionStore,
+{
+    async fn create_session(state: Self::State, session: NewSession) -> Result<Session, Self::Error> {
+        S::create_session(state.inner, session).await.map_err(Into::into)
+    }
+
+    async fn get_session(state: Self::State, id: Identifier) -> Result<Session, Self::Error> {
+        S::get_session(state.inner, id).await.map_err(Into::into)
+    }
+
+    async fn delete_session(state: Self::State, id: Identifier) -> Result<(), Self::Error> {
+        S::delete_session(state.inner, id).await.map_err(Into::into)
+    }
+
+    async fn delete_user_sessions(state: Self::State, user_id: Identifier) -> Result<(), Self::Error> {
+        S::delete_user_sessions(state.inner, user_id).await.map_err(Into::into)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> TokenStore for EncryptedStore<S>
+where
+    S: TokenStore,
+{
+    async fn insert_token(state: Self::State, token: TokenRecord) -> Result<(), Self::Error> {
+        S::insert_token(state.inner, token).await.map_err(Into::into)
+    }
+
+    async fn get_token(state: Self::State, hash: &[u8]) -> Result<TokenRecord, Self::Error> {
+        S::get_token(state.inner, hash).await.map_err(Into::into)
+    }
+
+    async fn consume_token(state: Self::State, hash: &[u8]) -> Result<TokenRecord, Self::Error> {
+        S::consume_token(state.inner, hash).await.map_err(Into::into)
+    }
+
+    async fn revoke_user_tokens(state: Self::State, user_id: Identifier) -> Result<(), Self::Error> {
+        S::revoke_user_tokens(state.inner, user_id).await.map_err(Into::into)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> RateLimitStore for EncryptedStore<S>
+where
+    S: RateLimitStore,
+{
+    async fn acquire(state: Self::State, key: &RateLimitKey, limit: RateLimit, now: SystemTime) -> Result<RateLimitDecision, Self::Error> {
+        S::acquire(state.inner, key, limit, now).await.map_err(Into::into)
+    }
+
+    async fn get_lockout(state: Self::State, key: &RateLimitKey) -> Result<Lockout, Self::Error> {
+        S::get_lockout(state.inner, key).await.map_err(Into::into)
+    }
+
+    async fn record_failure(state: Self::State, key: &RateLimitKey, policy: LockoutPolicy, now: SystemTime) -> Result<Lockout, Self::Error> {
+        S::record_failure(state.inner, key, policy, now).await.map_err(Into::into)
+    }
+
+    async fn clear_failures(state: Self::State, key: &RateLimitKey) -> Result<(), Self::Error> {
+        S::clear_failures(state.inner, key).await.map_err(Into::into)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> AuditStore for EncryptedStore<S>
+where
+    S: AuditStore,
+{
+    async fn append_audit_record(state: Self::State, record: AuditRecord) -> Result<(), Self::Error> {
+        S::append_audit_record(state.inner, record).await.map_err(Into::into)
+    }
+
+    async fn last_audit_record(state: Self::State) -> Result<Option<AuditRecord>, Self::Error> {
+        S::last_audit_record(state.inner).await.map_err(Into::into)
+    }
+
+    async fn list_audit_records(state: Self::State, from: u64, limit: usize) -> Result<Vec<AuditRecord>, Self::Error> {
+        S::list_audit_records(state.inner, from, limit).await.map_err(Into::into)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> Transaction for EncryptedStore<S>
+where
+    S: Transaction,
+{
+    async fn begin(state: Self::State) -> Result<Self::State, Self::Error> {
+        let inner = S::begin(state.inner).await.map_err(Into::into)?;
+        Ok(EncryptedState { inner, key_ring: state.key_ring })
+    }
+
+    async fn commit(state: Self::State) -> Result<(), Self::Error> {
+        S::commit(state.inner).await.map_err(Into::into)
+    }
+
+    async fn rollback(state: Self::State) -> Result<(), Self::Error> {
+        S::rollback(state.inner).await.map_err(Into::into)
+    }
+}
+
+#[cfg(all(test, feature = "in-memory"))]
+mod tests {
+    use std::sync::Arc;
+
+    use p256::ecdsa::SigningKey;
+
+    use crate::crypto::envelope::{KeyEncryptionProvider, LocalKeyProvider};
+    use crate::store::memory::{InMemoryState, InMemoryStore};
+    use crate::store::TotpCredential;
+
+    use super::*;
+
+    type Encrypted = EncryptedStore<InMemoryStore>;
+
+    fn provider(key_id: &str) -> Arc<dyn KeyEncryptionProvider> {
+        Arc::new(LocalKeyProvider::generate(key_id))
+    }
+
+    #[tokio::test]
+    async fn signing_keys_are_encrypted_at_rest() {
+        let state = EncryptedState::<InMemoryStore>::new(InMemoryState::new(), KeyRing::new(provider("kek-1")));
+        let client = state.inner().seed_client("app");
+        let signing_key = SigningKey::random(&mut rand::thread_rng());
+        let key_bytes = signing_key.to_bytes().to_vec();
+
+        Encrypted::set_signing_key_bytes(state.clone(), client.id, SecretBytes::new(key_bytes.clone())).await.unwrap();
+        let stored = InMemoryStore::get_signing_key_bytes(state.inner().clone(), client.id).await.unwrap();
+        assert!(!stored.expose_secret().windows(key_bytes.len()).any(|window| window == key_bytes));
+        assert_eq!(Encrypted::get_signing_key(state.clone(), client.id).await.unwrap(), signing_key);
+
+        // Keys moved to another client don't open
+        let other = state.inner().seed_client("other");
+        InMemoryStore::set_signing_key_bytes(state.inner().clone(), other.id, stored).await.unwrap();
+        assert!(matches!(Encrypted::get_signing_key_bytes(state.clone(), other.id).await, Err(StoreError::Serialization(_))));
+
+        // Plaintext keys are rejected
+        InMemoryStore::set_signing_key_bytes(state.inner().clone(), other.id, SecretBytes::new(key_bytes)).await.unwrap();
+        assert!(matches!(Encrypted::get_signing_key_bytes(state, other.id).await, Err(StoreError::Serialization(_))));
+    }
+
+    #[tokio::test]
+    async fn rotation_rewraps_every_client() {
+        let old = provider("kek-1");
+        let inner = InMemoryState::new();
+        let state = EncryptedState::<InMemoryStore>::new(inner.clone(), KeyRing::new(old.clone()));
+        let parent = Encrypted::create_client(state.clone(), NewClient { parent_id: None, name: "parent".to_string(), ..NewClient::default() }).await.unwrap();
+        let child = Encrypted::create_client(state.clone(), NewClient { parent_id: Some(parent.id), name: "child".to_string(), ..NewClient::default() }).await.unwrap();
+        for client_id in [parent.id, child.id] {
+            Encrypted::set_signing_key_bytes(state.clone(), client_id, SecretBytes::new(vec![1; 32])).await.unwrap();
+        }
+
+        let state = EncryptedState::<InMemoryStore>::new(inner.clone(), KeyRing::new(provider("kek-2")).with_retired(old));
+        assert_eq!(Encrypted::rewrap_signing_keys(state.clone()).await.unwrap(), 2);
+        assert_eq!(Encrypted::rewrap_signing_keys(state.clone()).await.unwrap(), 0);
+
+        let state = EncryptedState::<InMemoryStore>::new(inner, KeyRing::new(state.key_ring().current().clone()));
+        for client_id in [parent.id, child.id] {
+            let key = Encrypted::get_signing_key_bytes(state.clone(), client_id).await.unwrap();
+            assert_eq!(key.expose_secret(), &vec![1; 32]);
+        }
+    }
+
+    #[tokio::test]
+    async fn totp_secrets_are_encrypted_at_rest() {
+        let old = provider("kek-1");
+        let inner = InMemoryState::new();
+        let state = EncryptedState::<InMemoryStore>::new(inner.clone(), KeyRing::new(old.clone()));
+        let client = inner.seed_client("app");
+        let user = inner.seed_user(client.id, "alice@example.com");
+        let totp = TotpCredential { secret: SecretBytes::new(b"12345678901234567890".to_vec()), confirmed: true, last_used_step: Some(7) };
+        let credentials = UserCredentials { totp: Some(totp), recovery_codes: vec![vec![1; 32]], ..UserCredentials::default() };
+        Encrypted::set_user_credentials(state.clone(), user.id, credentials).await.unwrap();
+
+        let stored = InMemoryStore::get_user_credentials(inner.clone(), user.id).await.unwrap();
+        let sealed = stored.totp.clone().unwrap();
+        assert!(!sealed.secret.expose_secret().windows(20).any(|window| window == b"12345678901234567890"));
+        assert_eq!((sealed.confirmed, sealed.last_used_step), (true, Some(7)));
+        assert_eq!(stored.recovery_codes, vec![vec![1; 32]]);
+
+        // Secrets moved to another user don't open
+        let other = inner.seed_user(client.id, "bob@example.com");
+        InMemoryStore::set_user_credentials(inner.clone(), other.id, stored).await.unwrap();
+        assert!(matches!(Encrypted::get_user_credentials(state, other.id).await, Err(StoreError::Serialization(_))));
+        InMemoryStore::set_user_credentials(inner.clone(), other.id, UserCredentials::default()).await.unwrap();
+
+        let state = EncryptedState::<InMemoryStore>::new(inner.clone(), KeyRing::new(provider("kek-2")).with_retired(old));
+        assert_eq!(Encrypted::rewrap_totp_secrets(state.clone()).await.unwrap(), 1);
+        let state = EncryptedState::<InMemoryStore>::new(inner, KeyRing::new(state.key_ring().current().clone()));
+        let credentials = Encrypted::get_user_credentials(state, user.id).await.unwrap();
+        assert_eq!(credentials.totp.unwrap().secret.expose_secret(), b"12345678901234567890");
+    }
+
+    #[cfg(feature = "testing")]
+    mod conformance {
+        use crate::crypto::envelope::LocalKeyProvider;
+        use crate::store::memory::InMemoryState;
+
+        use super::*;
+
+        crate::store_conformance_tests!(
+            EncryptedStore<InMemoryStore>,
+            EncryptedState::<InMemoryStore>::new(InMemoryState::new(), KeyRing::new(Arc::new(LocalKeyProvider::generate("kek"))))
+        );
+    }
+}
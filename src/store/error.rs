@@ -10,11 +10,43 @@ pub enum StoreError {
 
     /// Dedicated to database connection errors, unexpected and could crash
     #[error("connection error")]
-    ConnectionReset(Box<dyn std::error::Error>),
+    ConnectionReset(#[source] Box<dyn std::error::Error + Send + Sync>),
 
     #[error("element not found")]
     NotFound,
+
+    /// The element collides with an existing one on a unique field, like the email of a user
+    #[error("element already exists")]
+    Conflict,
+
+    /// The element was modified by someone else since it was read, the whole read-modify-write operation
+    /// must be repeated
+    #[error("element version mismatch")]
+    VersionMismatch,
+
+    /// The operation didn't complete in time, it may or may not have been applied
+    #[error("operation timed out")]
+    Timeout,
+
+    /// The backend can't serve the request right now, like a locked database, retrying later may succeed
+    #[error("store unavailable")]
+    Unavailable,
+
+    /// Stored data couldn't be encoded or decoded, retrying won't help
+    #[error("serialization error")]
+    Serialization(#[source] Box<dyn std::error::Error + Send + Sync>),
+
+    /// The store credentials don't allow the operation
+    #[error("permission denied")]
+    PermissionDenied,
 }
 
-unsafe impl Send for StoreError {}
-unsafe impl Sync for StoreError {}
\ No newline at end of file
+impl StoreError {
+    /// Whether repeating the operation that failed is safe and may succeed. Only the errors known to leave
+    /// nothing applied count, a [`StoreError::Timeout`] may have been applied and a
+    /// [`StoreError::ConnectionReset`] is also what the backends return for errors they can't classify, so
+    /// repeating them could apply a write twice or loop on a permanent failure
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::VersionMismatch | Self::Unavailable)
+    }
+}
@@ -0,0 +1,237 @@
+//! Store decorator recording a span and the latency of every call. [`InstrumentedStore`] wraps each
+//! operation of the inner store in a `store` span named after it and records the time it took on the
+//! [`STORE_CALL_SECONDS`] histogram, see [`telemetry`](crate::telemetry). Arguments aren't recorded, they
+//! hold emails, hashes and secrets
+
+use std::future::Future;
+use std::marker::PhantomData;
+use std::time::{Instant, SystemTime};
+
+use tracing::Instrument;
+
+use crate::crypto::secret::SecretBytes;
+use crate::data::id::Identifier;
+use crate::store::{
+    AuditRecord, AuditStore, ClientCredentials, ClientStore, ClientUpdate, Lockout, LockoutPolicy, NewClient, NewSession, NewUser, RateLimit,
+    RateLimitDecision, RateLimitKey, RateLimitStore, Session, SessionStore, Store, TokenRecord, TokenStore, Transaction, UserCredentials,
+    UserMetadataOf, UserStore, UserUpdate,
+};
+use crate::telemetry::STORE_CALL_SECONDS;
+
+/// Tracing decorator for the store `S`, see the [module documentation](self). It shares the state and
+/// errors of `S`
+pub struct InstrumentedStore<S>(PhantomData<S>);
+
+async fn observe<T, E>(operation: &'static str, call: impl Future<Output = Result<T, E>>) -> Result<T, E> {
+    let start = Instant::now();
+    let result = call.instrument(tracing::debug_span!("store", operation)).await;
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+    metrics::histogram!(STORE_CALL_SECONDS, "operation" => operation, "result" => outcome).record(start.elapsed().as_secs_f64());
+    result
+}
+
+impl<S> Store for InstrumentedStore<S>
+where
+    S: Store,
+{
+    type Error = S::Error;
+    type State = S::State;
+}
+
+#[async_trait::async_trait]
+impl<S> UserStore for InstrumentedStore<S>
+where
+    S: UserStore,
+{
+    type User = S::User;
+
+    async fn create_user(state: Self::State, user: NewUser<UserMetadataOf<Self>>) -> Result<Self::User, Self::Error> {
+        observe("create_user", S::create_user(state, user)).await
+    }
+
+    async fn get_user(state: Self::State, id: Identifier) -> Result<Self::User, Self::Error> {
+        observe("get_user", S::get_user(state, id)).await
+    }
+
+    async fn get_user_by_email(state: Self::State, client_id: Identifier, email: &str) -> Result<Self::User, Self::Error> {
+        observe("get_user_by_email", S::get_user_by_email(state, client_id, email)).await
+    }
+
+    async fn list_users(state: Self::State, client_id: Identifier) -> Result<Vec<Self::User>, Self::Error> {
+        observe("list_users", S::list_users(state, client_id)).await
+    }
+
+    async fn update_user(state: Self::State, id: Identifier, update: UserUpdate<UserMetadataOf<Self>>) -> Result<Self::User, Self::Error> {
+        observe("update_user", S::update_user(state, id, update)).await
+    }
+
+    async fn delete_user(state: Self::State, id: Identifier) -> Result<(), Self::Error> {
+        observe("delete_user", S::delete_user(state, id)).await
+    }
+
+    async fn get_user_credentials(state: Self::State, user_id: Identifier) -> Result<UserCredentials, Self::Error> {
+        observe("get_user_credentials", S::get_user_credentials(state, user_id)).await
+    }
+
+    async fn set_user_credentials(state: Self::State, user_id: Identifier, credentials: UserCredentials) -> Result<(), Self::Error> {
+        observe("set_user_credentials", S::set_user_credentials(state, user_id, credentials)).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> ClientStore for InstrumentedStore<S>
+where
+    S: ClientStore,
+{
+    type Client = S::Client;
+
+    async fn create_client(state: Self::State, client: NewClient) -> Result<Self::Client, Self::Error> {
+        observe("create_client", S::create_client(state, client)).await
+    }
+
+    async fn get_client(state: Self::State, id: Identifier) -> Result<Self::Client, Self::Error> {
+        observe("get_client", S::get_client(state, id)).await
+    }
+
+    async fn list_clients(state: Self::State, parent_id: Option<Identifier>) -> Result<Vec<Self::Client>, Self::Error> {
+        observe("list_clients", S::list_clients(state, parent_id)).await
+    }
+
+    async fn update_client(state: Self::State, id: Identifier, update: ClientUpdate) -> Result<Self::Client, Self::Error> {
+        observe("update_client", S::update_client(state, id, update)).await
+    }
+
+    async fn delete_client(state: Self::State, id: Identifier) -> Result<(), Self::Error> {
+        observe("delete_client", S::delete_client(state, id)).await
+    }
+
+    async fn get_signing_key_bytes(state: Self::State, client_id: Identifier) -> Result<SecretBytes, Self::Error> {
+        observe("get_signing_key_bytes", S::get_signing_key_bytes(state, client_id)).await
+    }
+
+    async fn set_signing_key_bytes(state: Self::State, client_id: Identifier, key: SecretBytes) -> Result<(), Self::Error> {
+        observe("set_signing_key_bytes", S::set_signing_key_bytes(state, client_id, key)).await
+    }
+
+    async fn get_credentials(state: Self::State, client_id: Identifier) -> Result<ClientCredentials, Self::Error> {
+        observe("get_credentials", S::get_credentials(state, client_id)).await
+    }
+
+    async fn set_credentials(state: Self::State, client_id: Identifier, credentials: ClientCredentials) -> Result<(), Self::Error> {
+        observe("set_credentials", S::set_credentials(state, client_id, credentials)).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> SessionStore for InstrumentedStore<S>
+where
+    S: SessionStore,
+{
+    async fn create_session(state: Self::State, session: NewSession) -> Result<Session, Self::Error> {
+        observe("create_session", S::create_session(state, session)).await
+    }
+
+    async fn get_session(state: Self::State, id: Identifier) -> Result<Session, Self::Error> {
+        observe("get_session", S::get_session(state, id)).await
+    }
+
+    async fn delete_session(state: Self::State, id: Identifier) -> Result<(), Self::Error> {
+        observe("delete_session", S::delete_session(state, id)).await
+    }
+
+    async fn delete_user_sessions(state: Self::State, user_id: Identifier) -> Result<(), Self::Error> {
+        observe("delete_user_sessions", S::delete_user_sessions(state, user_id)).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> TokenStore for InstrumentedStore<S>
+where
+    S: TokenStore,
+{
+    async fn insert_token(state: Self::State, token: TokenRecord) -> Result<(), Self::Error> {
+        observe("insert_token", S::insert_token(state, token)).await
+    }
+
+    async fn get_token(state: Self::State, hash: &[u8]) -> Result<TokenRecord, Self::Error> {
+        observe("get_token", S::get_token(state, hash)).await
+    }
+
+    async fn consume_token(state: Self::State, hash: &[u8]) -> Result<TokenRecord, Self::Error> {
+        observe("consume_token", S::consume_token(state, hash)).await
+    }
+
+    async fn revoke_user_tokens(state: Self::State, user_id: Identifier) -> Result<(), Self::Error> {
+        observe("revoke_user_tokens", S::revoke_user_tokens(state, user_id)).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> RateLimitStore for InstrumentedStore<S>
+where
+    S: RateLimitStore,
+{
+    async fn acquire(state: Self::State, key: &RateLimitKey, limit: RateLimit, now: SystemTime) -> Result<RateLimitDecision, Self::Error> {
+        observe("acquire", S::acquire(state, key, limit, now)).await
+    }
+
+    async fn get_lockout(state: Self::State, key: &RateLimitKey) -> Result<Lockout, Self::Error> {
+        observe("get_lockout", S::get_lockout(state, key)).await
+    }
+
+    async fn record_failure(state: Self::State, key: &RateLimitKey, policy: LockoutPolicy, now: SystemTime) -> Result<Lockout, Self::Error> {
+        observe("record_failure", S::record_failure(state, key, policy, now)).await
+    }
+
+    async fn clear_failures(state: Self::State, key: &RateLimitKey) -> Result<(), Self::Error> {
+        observe("clear_failures", S::clear_failures(state, key)).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> AuditStore for InstrumentedStore<S>
+where
+    S: AuditStore,
+{
+    async fn append_audit_record(state: Self::State, record: AuditRecord) -> Result<(), Self::Error> {
+        observe("append_audit_record", S::append_audit_record(state, record)).await
+    }
+
+    async fn last_audit_record(state: Self::State) -> Result<Option<AuditRecord>, Self::Error> {
+        observe("last_audit_record", S::last_audit_record(state)).await
+    }
+
+    async fn list_audit_records(state: Self::State, from: u64, limit: usize) -> Result<Vec<AuditRecord>, Self::Error> {
+        observe("list_audit_records", S::list_audit_records(state, from, limit)).await
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> Transaction for InstrumentedStore<S>
+where
+    S: Transaction,
+{
+    async fn begin(state: Self::State) -> Result<Self::State, Self::Error> {
+        observe("begin", S::begin(state)).await
+    }
+
+    async fn commit(state: Self::State) -> Result<(), Self::Error> {
+        observe("commit", S::commit(state)).await
+    }
+
+    async fn rollback(state: Self::State) -> Result<(), Self::Error> {
+        observe("rollback", S::rollback(state)).await
+    }
+}
+
+#[cfg(all(test, feature = "in-memory"))]
+mod tests {
+    #[cfg(feature = "testing")]
+    mod conformance {
+        use crate::store::memory::{InMemoryState, InMemoryStore};
+
+        use super::super::*;
+
+        crate::store_conformance_tests!(InstrumentedStore<InMemoryStore>, InMemoryState::new());
+    }
+}
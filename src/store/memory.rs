@@ -0,0 +1,678 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::SystemTime;
+
+use p256::ecdsa::SigningKey;
+
+use crate::crypto::secret::SecretBytes;
+use crate::data::id::{Identifier, IdentifierGenerator};
+use crate::model::{Client, ClientSettings, Curve, User};
+use crate::store::{
+    key_id, AuditRecord, AuditStore, Bucket, ClientCredentials, ClientStore, ClientUpdate, Lockout, LockoutPolicy, NewClient, NewSession,
+    NewUser, RateLimit, RateLimitDecision, RateLimitKey, RateLimitStore, Session, SessionStore, Store, StoreError, TokenRecord,
+    TokenStore, Transaction, UserCredentials, UserPublicKey, UserStore, UserUpdate,
+};
+
+/// Reference implementation of every store trait backed by process memory, meant for tests and local
+/// demos, all the data is lost once the last clone of the [`InMemoryState`] is dropped
+#[derive(Debug, Clone, Copy)]
+pub struct InMemoryStore;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InMemoryUser {
+    pub id: Identifier,
+    pub client_id: Identifier,
+    pub email: String,
+    pub metadata: Option<BTreeMap<String, String>>,
+    pub version: u64,
+}
+
+impl User for InMemoryUser {
+    type UserMetadata = BTreeMap<String, String>;
+
+    fn get_id(&self) -> Identifier {
+        self.id
+    }
+
+    fn get_client_id(&self) -> Identifier {
+        self.client_id
+    }
+
+    fn get_email(&self) -> &str {
+        &self.email
+    }
+
+    fn get_version(&self) -> u64 {
+        self.version
+    }
+
+    fn get_user_metadata(&self) -> Option<Self::UserMetadata> {
+        self.metadata.clone()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InMemoryClient {
+    pub id: Identifier,
+    pub parent_id: Option<Identifier>,
+    pub name: String,
+    pub settings: ClientSettings,
+    pub disabled: bool,
+    pub version: u64,
+}
+
+impl Client for InMemoryClient {
+    fn get_id(&self) -> Identifier {
+        self.id
+    }
+
+    fn get_parent_id(&self) -> Option<Identifier> {
+        self.parent_id
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_version(&self) -> u64 {
+        self.version
+    }
+
+    fn get_settings(&self) -> &ClientSettings {
+        &self.settings
+    }
+
+    fn is_disabled(&self) -> bool {
+        self.disabled
+    }
+}
+
+#[derive(Clone, Default)]
+struct InMemoryData {
+    /// Incremented on every write, used to detect conflicting transactions
+    revision: u64,
+    clients: HashMap<Identifier, InMemoryClient>,
+    users: HashMap<Identifier, InMemoryUser>,
+    signing_keys: HashMap<Identifier, SecretBytes>,
+    credentials: HashMap<Identifier, ClientCredentials>,
+    user_credentials: HashMap<Identifier, UserCredentials>,
+    sessions: HashMap<Identifier, Session>,
+    tokens: HashMap<Vec<u8>, TokenRecord>,
+}
+
+impl InMemoryData {
+    fn find_user_by_email(&self, client_id: Identifier, email: &str) -> Option<&InMemoryUser> {
+        let email = email.to_lowercase();
+        self.users
+            .values()
+            .find(|user| user.client_id == client_id && user.email.to_lowercase() == email)
+    }
+}
+
+/// Kept apart from [`InMemoryData`] so the attempts don't conflict with transactions
+#[derive(Default)]
+struct RateLimitData {
+    buckets: HashMap<RateLimitKey, Bucket>,
+    lockouts: HashMap<RateLimitKey, Lockout>,
+}
+
+/// Past this many keys the buckets that are full again and the forgotten failures are dropped, they are
+/// the same as missing ones
+const MAX_RATE_LIMIT_KEYS: usize = 10_000;
+
+/// Transactions work on a snapshot of the data taken by [`Transaction::begin`], that replaces the data
+/// on commit as long as nothing else was written in between
+struct InMemoryTransaction {
+    base: Arc<RwLock<InMemoryData>>,
+    revision: u64,
+    finished: AtomicBool,
+}
+
+#[derive(Clone)]
+pub struct InMemoryState {
+    data: Arc<RwLock<InMemoryData>>,
+    generator: Arc<Mutex<IdentifierGenerator>>,
+    rate_limits: Arc<Mutex<RateLimitData>>,
+    /// Not part of the transactions either, the log only grows
+    audit_log: Arc<Mutex<Vec<AuditRecord>>>,
+    transaction: Option<Arc<InMemoryTransaction>>,
+}
+
+impl Default for InMemoryState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InMemoryState {
+    pub fn new() -> Self {
+        Self::with_generator(IdentifierGenerator::new(0, 0))
+    }
+
+    pub fn with_generator(generator: IdentifierGenerator) -> Self {
+        Self {
+            data: Arc::new(RwLock::new(InMemoryData::default())),
+            generator: Arc::new(Mutex::new(generator)),
+            rate_limits: Arc::default(),
+            audit_log: Arc::default(),
+            transaction: None,
+        }
+    }
+
+    // NOTE: A poisoned lock only means that another thread panicked while holding it, every write below
+    // leaves the maps consistent before doing anything that could panic so it's fine to keep going
+    fn read(&self) -> RwLockReadGuard<'_, InMemoryData> {
+        self.data.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, InMemoryData> {
+        let mut data = self.data.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        data.revision += 1;
+        data
+    }
+
+    fn generate_id(&self) -> Identifier {
+        self.generator.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).generate()
+    }
+
+    fn rate_limits(&self) -> MutexGuard<'_, RateLimitData> {
+        self.rate_limits.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn audit_log(&self) -> MutexGuard<'_, Vec<AuditRecord>> {
+        self.audit_log.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Creates a root client with a freshly generated signing key
+    pub fn seed_client(&self, name: &str) -> InMemoryClient {
+        let client = InMemoryClient {
+            id: self.generate_id(),
+            parent_id: None,
+            name: name.to_string(),
+            settings: ClientSettings::default(),
+            disabled: false,
+            version: 1,
+        };
+        self.write().clients.insert(client.id, client.clone());
+        self.seed_signing_key(client.id);
+        client
+    }
+
+    /// Creates a user without metadata, panics if the email is already taken on the client
+    pub fn seed_user(&self, client_id: Identifier, email: &str) -> InMemoryUser {
+        let user = InMemoryUser {
+            id: self.generate_id(),
+            client_id,
+            email: email.to_string(),
+            metadata: None,
+            version: 1,
+        };
+        let mut data = self.write();
+        assert!(data.find_user_by_email(client_id, email).is_none(), "email already seeded");
+        data.users.insert(user.id, user.clone());
+        user
+    }
+
+    /// Adds an uncompressed SEC1 P-256 key to the user, seeded users have none and can't log in with a
+    /// proof until they get one
+    pub fn seed_user_key(&self, user_id: Identifier, public_key: &[u8]) {
+        let key = UserPublicKey {
+            id: key_id(public_key),
+            curve: Curve::NistP256,
+            public_key: public_key.to_vec(),
+            label: "seeded".to_string(),
+            created_at: SystemTime::now(),
+            last_used_at: None,
+            revoked_at: None,
+        };
+        self.write().user_credentials.entry(user_id).or_default().public_keys.push(key);
+    }
+
+    /// Generates and stores a new P-256 signing key for the client, replacing the previous one
+    pub fn seed_signing_key(&self, client_id: Identifier) -> SigningKey {
+        let signing_key = SigningKey::random(&mut rand::thread_rng());
+        self.write().signing_keys.insert(client_id, SecretBytes::new(signing_key.to_bytes().to_vec()));
+        signing_key
+    }
+}
+
+fn finished_transaction() -> StoreError {
+    StoreError::ConnectionReset("the transaction is already finished".into())
+}
+
+impl Store for InMemoryStore {
+    type Error = StoreError;
+    type State = InMemoryState;
+}
+
+#[async_trait::async_trait]
+impl UserStore for InMemoryStore {
+    type User = InMemoryUser;
+
+    async fn create_user(state: Self::State, user: NewUser<BTreeMap<String, String>>) -> Result<Self::User, Self::Error> {
+        let mut data = state.write();
+        if !data.clients.contains_key(&user.client_id) {
+            return Err(StoreError::NotFound);
+        }
+        if data.find_user_by_email(user.client_id, &user.email).is_some() {
+            return Err(StoreError::Conflict);
+        }
+        let user = InMemoryUser {
+            id: state.generate_id(),
+            client_id: user.client_id,
+            email: user.email,
+            metadata: user.metadata,
+            version: 1,
+        };
+        data.users.insert(user.id, user.clone());
+        Ok(user)
+    }
+
+    async fn get_user(state: Self::State, id: Identifier) -> Result<Self::User, Self::Error> {
+        state.read().users.get(&id).cloned().ok_or(StoreError::NotFound)
+    }
+
+    async fn get_user_by_email(state: Self::State, client_id: Identifier, email: &str) -> Result<Self::User, Self::Error> {
+        state.read().find_user_by_email(client_id, email).cloned().ok_or(StoreError::NotFound)
+    }
+
+    async fn list_users(state: Self::State, client_id: Identifier) -> Result<Vec<Self::User>, Self::Error> {
+        let mut users = state.read()
+            .users
+            .values()
+            .filter(|user| user.client_id == client_id)
+            .cloned()
+            .collect::<Vec<_>>();
+        users.sort_by_key(|user| u128::from(user.id));
+        Ok(users)
+    }
+
+    async fn update_user(state: Self::State, id: Identifier, update: UserUpdate<BTreeMap<String, String>>) -> Result<Self::User, Self::Error> {
+        let mut data = state.write();
+        let stored = data.users.get(&id).ok_or(StoreError::NotFound)?;
+        if update.expected_version.is_some_and(|version| version != stored.version) {
+            return Err(StoreError::VersionMismatch);
+        }
+        let client_id = stored.client_id;
+        if let Some(email) = &update.email {
+            if data.find_user_by_email(client_id, email).is_some_and(|other| other.id != id) {
+                return Err(StoreError::Conflict);
+            }
+        }
+        let user = data.users.get_mut(&id).ok_or(StoreError::NotFound)?;
+        if let Some(email) = update.email {
+            user.email = email;
+        }
+        if let Some(metadata) = update.metadata {
+            user.metadata = Some(metadata);
+        }
+        user.version += 1;
+        Ok(user.clone())
+    }
+
+    async fn delete_user(state: Self::State, id: Identifier) -> Result<(), Self::Error> {
+        let mut data = state.write();
+        data.users.remove(&id).ok_or(StoreError::NotFound)?;
+        data.user_credentials.remove(&id);
+        data.sessions.retain(|_, session| session.user_id != id);
+        data.tokens.retain(|_, token| token.user_id != id);
+        Ok(())
+    }
+
+    async fn get_user_credentials(state: Self::State, user_id: Identifier) -> Result<UserCredentials, Self::Error> {
+        let data = state.read();
+        if !data.users.contains_key(&user_id) {
+            return Err(StoreError::NotFound);
+        }
+        Ok(data.user_credentials.get(&user_id).cloned().unwrap_or_default())
+    }
+
+    async fn set_user_credentials(state: Self::State, user_id: Identifier, credentials: UserCredentials) -> Result<(), Self::Error> {
+        let mut data = state.write();
+        if !data.users.contains_key(&user_id) {
+            return Err(StoreError::NotFound);
+        }
+        data.user_credentials.insert(user_id, credentials);
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl ClientStore for InMemoryStore {
+    type Client = InMemoryClient;
+
+    async fn create_client(state: Self::State, client: NewClient) -> Result<Self::Client, Self::Error> {
+        let mut data = state.write();
+        if client.parent_id.is_some_and(|parent_id| !data.clients.contains_key(&parent_id)) {
+            return Err(StoreError::NotFound);
+        }
+        let client = InMemoryClient {
+            id: state.generate_id(),
+            parent_id: client.parent_id,
+            name: client.name,
+            settings: client.settings,
+            disabled: false,
+            version: 1,
+        };
+        data.clients.insert(client.id, client.clone());
+        Ok(client)
+    }
+
+    async fn get_client(state: Self::State, id: Identifier) -> Result<Self::Client, Self::Error> {
+        state.read().clients.get(&id).cloned().ok_or(StoreError::NotFound)
+    }
+
+    async fn list_clients(state: Self::State, parent_id: Option<Identifier>) -> Result<Vec<Self::Client>, Self::Error> {
+        let mut clients = state.read()
+            .clients
+            .values()
+            .filter(|client| client.parent_id == parent_id)
+            .cloned()
+            .collect::<Vec<_>>();
+        clients.sort_by_key(|client| u128::from(client.id));
+        Ok(clients)
+    }
+
+    async fn update_client(state: Self::State, id: Identifier, update: ClientUpdate) -> Result<Self::Client, Self::Error> {
+        let mut data = state.write();
+        if update.parent_id.flatten().is_some_and(|parent_id| !data.clients.contains_key(&parent_id)) {
+            return Err(StoreError::NotFound);
+        }
+        let client = data.clients.get_mut(&id).ok_or(StoreError::NotFound)?;
+        if update.expected_version.is_some_and(|version| version != client.version) {
+            return Err(StoreError::VersionMismatch);
+        }
+        if let Some(name) = update.name {
+            client.name = name;
+        }
+        if let Some(parent_id) = update.parent_id {
+            client.parent_id = parent_id;
+        }
+        if let Some(settings) = update.settings {
+            client.settings = settings;
+        }
+        if let Some(disabled) = update.disabled {
+            client.disabled = disabled;
+        }
+        client.version += 1;
+        Ok(client.clone())
+    }
+
+    async fn delete_client(state: Self::State, id: Identifier) -> Result<(), Self::Error> {
+        let mut data = state.write();
+        if !data.clients.contains_key(&id) {
+            return Err(StoreError::NotFound);
+        }
+        if data.clients.values().any(|client| client.parent_id == Some(id)) {
+            return Err(StoreError::Conflict);
+        }
+        data.clients.remove(&id);
+        data.users.retain(|_, user| user.client_id != id);
+        let data = &mut *data;
+        data.user_credentials.retain(|user_id, _| data.users.contains_key(user_id));
+        data.signing_keys.remove(&id);
+        data.credentials.remove(&id);
+        data.sessions.retain(|_, session| session.client_id != id);
+        data.tokens.retain(|_, token| token.client_id != id);
+        Ok(())
+    }
+
+    async fn get_signing_key_bytes(state: Self::State, client_id: Identifier) -> Result<SecretBytes, Self::Error> {
+        state.read().signing_keys.get(&client_id).cloned().ok_or(StoreError::NotFound)
+    }
+
+    async fn set_signing_key_bytes(state: Self::State, client_id: Identifier, key: SecretBytes) -> Result<(), Self::Error> {
+        let mut data = state.write();
+        if !data.clients.contains_key(&client_id) {
+            return Err(StoreError::NotFound);
+        }
+        data.signing_keys.insert(client_id, key);
+        Ok(())
+    }
+
+    async fn get_credentials(state: Self::State, client_id: Identifier) -> Result<ClientCredentials, Self::Error> {
+        let data = state.read();
+        if !data.clients.contains_key(&client_id) {
+            return Err(StoreError::NotFound);
+        }
+        Ok(data.credentials.get(&client_id).cloned().unwrap_or_default())
+    }
+
+    async fn set_credentials(state: Self::State, client_id: Identifier, credentials: ClientCredentials) -> Result<(), Self::Error> {
+        let mut data = state.write();
+        if !data.clients.contains_key(&client_id) {
+            return Err(StoreError::NotFound);
+        }
+        data.credentials.insert(client_id, credentials);
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl SessionStore for InMemoryStore {
+    async fn create_session(state: Self::State, session: NewSession) -> Result<Session, Self::Error> {
+        let mut data = state.write();
+        if !data.users.contains_key(&session.user_id) {
+            return Err(StoreError::NotFound);
+        }
+        let session = Session {
+            id: state.generate_id(),
+            user_id: session.user_id,
+            client_id: session.client_id,
+            expires_at: session.expires_at,
+        };
+        data.sessions.insert(session.id, session.clone());
+        Ok(session)
+    }
+
+    async fn get_session(state: Self::State, id: Identifier) -> Result<Session, Self::Error> {
+        state.read().sessions.get(&id).cloned().ok_or(StoreError::NotFound)
+    }
+
+    async fn delete_session(state: Self::State, id: Identifier) -> Result<(), Self::Error> {
+        state.write().sessions.remove(&id).map(|_| ()).ok_or(StoreError::NotFound)
+    }
+
+    async fn delete_user_sessions(state: Self::State, user_id: Identifier) -> Result<(), Self::Error> {
+        state.write().sessions.retain(|_, session| session.user_id != user_id);
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenStore for InMemoryStore {
+    async fn insert_token(state: Self::State, token: TokenRecord) -> Result<(), Self::Error> {
+        let mut data = state.write();
+        if !data.users.contains_key(&token.user_id) {
+            return Err(StoreError::NotFound);
+        }
+        if data.tokens.contains_key(&token.hash) {
+            return Err(StoreError::Conflict);
+        }
+        data.tokens.insert(token.hash.clone(), token);
+        Ok(())
+    }
+
+    async fn get_token(state: Self::State, hash: &[u8]) -> Result<TokenRecord, Self::Error> {
+        state.read().tokens.get(hash).cloned().ok_or(StoreError::NotFound)
+    }
+
+    async fn consume_token(state: Self::State, hash: &[u8]) -> Result<TokenRecord, Self::Error> {
+        state.write().tokens.remove(hash).ok_or(StoreError::NotFound)
+    }
+
+    async fn revoke_user_tokens(state: Self::State, user_id: Identifier) -> Result<(), Self::Error> {
+        state.write().tokens.retain(|_, token| token.user_id != user_id);
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl RateLimitStore for InMemoryStore {
+    async fn acquire(state: Self::State, key: &RateLimitKey, limit: RateLimit, now: SystemTime) -> Result<RateLimitDecision, Self::Error> {
+        let mut data = state.rate_limits();
+        let (bucket, decision) = limit.take(data.buckets.get(key).copied(), now);
+        data.buckets.insert(key.clone(), bucket);
+        if data.buckets.len() > MAX_RATE_LIMIT_KEYS {
+            let refill = limit.interval.saturating_mul(limit.burst);
+            // NOTE: Only the buckets of the same kind of key share the limit
+            let kind = std::mem::discriminant(key);
+            data.buckets.retain(|other, bucket| {
+                std::mem::discriminant(other) != kind || now.duration_since(bucket.updated_at).unwrap_or_default() < refill
+            });
+        }
+        Ok(decision)
+    }
+
+    async fn get_lockout(state: Self::State, key: &RateLimitKey) -> Result<Lockout, Self::Error> {
+        Ok(state.rate_limits().lockouts.get(key).copied().unwrap_or_default())
+    }
+
+    async fn record_failure(state: Self::State, key: &RateLimitKey, policy: LockoutPolicy, now: SystemTime) -> Result<Lockout, Self::Error> {
+        let mut data = state.rate_limits();
+        let lockout = policy.record_failure(data.lockouts.get(key).copied().unwrap_or_default(), now);
+        data.lockouts.insert(key.clone(), lockout);
+        if data.lockouts.len() > MAX_RATE_LIMIT_KEYS {
+            data.lockouts.retain(|_, lockout| {
+                lockout.last_failure.is_some_and(|last_failure| now.duration_since(last_failure).unwrap_or_default() < policy.reset_after)
+            });
+        }
+        Ok(lockout)
+    }
+
+    async fn clear_failures(state: Self::State, key: &RateLimitKey) -> Result<(), Self::Error> {
+        state.rate_limits().lockouts.remove(key);
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl AuditStore for InMemoryStore {
+    async fn append_audit_record(state: Self::State, record: AuditRecord) -> Result<(), Self::Error> {
+        let mut log = state.audit_log();
+        if record.sequence != log.len() as u64 {
+            return Err(StoreError::VersionMismatch);
+        }
+        log.push(record);
+        Ok(())
+    }
+
+    async fn last_audit_record(state: Self::State) -> Result<Option<AuditRecord>, Self::Error> {
+        Ok(state.audit_log().last().cloned())
+    }
+
+    async fn list_audit_records(state: Self::State, from: u64, limit: usize) -> Result<Vec<AuditRecord>, Self::Error> {
+        let log = state.audit_log();
+        let from = usize::try_from(from).unwrap_or(usize::MAX).min(log.len());
+        Ok(log[from..].iter().take(limit).cloned().collect())
+    }
+}
+
+#[async_trait::async_trait]
+impl Transaction for InMemoryStore {
+    async fn begin(state: Self::State) -> Result<Self::State, Self::Error> {
+        if state.transaction.is_some() {
+            return Err(StoreError::ConnectionReset("nested transactions are not supported".into()));
+        }
+        let data = state.read();
+        let transaction = InMemoryTransaction {
+            base: state.data.clone(),
+            revision: data.revision,
+            finished: AtomicBool::new(false),
+        };
+        Ok(InMemoryState {
+            data: Arc::new(RwLock::new(data.clone())),
+            generator: state.generator.clone(),
+            rate_limits: state.rate_limits.clone(),
+            audit_log: state.audit_log.clone(),
+            transaction: Some(Arc::new(transaction)),
+        })
+    }
+
+    async fn commit(state: Self::State) -> Result<(), Self::Error> {
+        let Some(transaction) = &state.transaction else {
+            return Ok(());
+        };
+        if transaction.finished.swap(true, Ordering::SeqCst) {
+            return Err(finished_transaction());
+        }
+        let mut base = transaction.base.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if base.revision != transaction.revision {
+            return Err(StoreError::VersionMismatch);
+        }
+        let mut data = state.read().clone();
+        data.revision = base.revision + 1;
+        *base = data;
+        Ok(())
+    }
+
+    async fn rollback(state: Self::State) -> Result<(), Self::Error> {
+        match &state.transaction {
+            Some(transaction) if transaction.finished.swap(true, Ordering::SeqCst) => Err(finished_transaction()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn user_crud() {
+        let state = InMemoryState::new();
+        let client = state.seed_client("app");
+        let new_user = NewUser {
+            client_id: client.id,
+            email: "alice@example.com".to_string(),
+            metadata: None,
+        };
+
+        let user = InMemoryStore::create_user(state.clone(), new_user.clone()).await.unwrap();
+        assert!(matches!(
+            InMemoryStore::create_user(state.clone(), NewUser { email: "ALICE@example.com".to_string(), ..new_user }).await,
+            Err(StoreError::Conflict)
+        ));
+        assert_eq!(InMemoryStore::get_user(state.clone(), user.id).await.unwrap(), user);
+
+        let metadata = BTreeMap::from([("username".to_string(), "alice".to_string())]);
+        let update = UserUpdate { metadata: Some(metadata.clone()), ..Default::default() };
+        let updated = InMemoryStore::update_user(state.clone(), user.id, update).await.unwrap();
+        assert_eq!(updated.get_user_metadata(), Some(metadata));
+        assert_eq!(InMemoryStore::list_users(state.clone(), client.id).await.unwrap(), vec![updated]);
+
+        InMemoryStore::delete_user(state.clone(), user.id).await.unwrap();
+        assert!(matches!(InMemoryStore::get_user(state, user.id).await, Err(StoreError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn client_crud() {
+        let state = InMemoryState::new();
+        let parent = state.seed_client("organisation");
+        let child = InMemoryStore::create_client(state.clone(), NewClient {
+            parent_id: Some(parent.id),
+            name: "app".to_string(),
+            ..NewClient::default()
+        }).await.unwrap();
+
+        assert_eq!(InMemoryStore::list_clients(state.clone(), Some(parent.id)).await.unwrap(), vec![child.clone()]);
+        assert_eq!(InMemoryStore::list_clients(state.clone(), None).await.unwrap(), vec![parent.clone()]);
+        assert!(InMemoryStore::get_signing_key_bytes(state.clone(), child.id).await.is_err());
+
+        let update = ClientUpdate { name: Some("renamed".to_string()), ..Default::default() };
+        let child = InMemoryStore::update_client(state.clone(), child.id, update).await.unwrap();
+        assert_eq!(child.get_name(), "renamed");
+
+        state.seed_user(child.id, "alice@example.com");
+        InMemoryStore::delete_client(state.clone(), child.id).await.unwrap();
+        assert!(InMemoryStore::list_users(state, child.id).await.unwrap().is_empty());
+    }
+
+    #[cfg(feature = "testing")]
+    mod conformance {
+        use super::*;
+
+        crate::store_conformance_tests!(InMemoryStore, InMemoryState::new());
+    }
+}
@@ -1,7 +1,26 @@
 mod user_store;
 mod client_store;
+mod session_store;
+mod token_store;
+mod rate_limit_store;
+mod audit_store;
 mod error;
+mod retry;
+mod transaction;
+pub mod encrypted;
+pub mod instrumented;
 
+#[cfg(feature = "cache")]
+pub mod cache;
+#[cfg(feature = "in-memory")]
+pub mod memory;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+#[cfg(feature = "testing")]
+pub mod testing;
+
+/// Root of the repository layer, every entity store shares the same error and state types so a single
+/// implementor can back all of them with the same connection pool or handle
 #[async_trait::async_trait]
 pub trait Store 
 where
@@ -13,4 +32,10 @@ where
 
 pub use user_store::*;
 pub use client_store::*;
-pub use error::StoreError;
\ No newline at end of file
+pub use session_store::*;
+pub use token_store::*;
+pub use rate_limit_store::*;
+pub use audit_store::*;
+pub use error::StoreError;
+pub use retry::{retry, RetryPolicy};
+pub use transaction::{atomically, Transaction};
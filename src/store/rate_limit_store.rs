@@ -0,0 +1,173 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+use crate::data::id::Identifier;
+use crate::store::Store;
+
+/// What the attempts are counted against
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RateLimitKey {
+    Ip(IpAddr),
+    /// Email typed in a login on the client, whether or not a user has it so the limits don't tell who is
+    /// registered. Built with [`RateLimitKey::email`]
+    Email { client_id: Identifier, email: String },
+    Client(Identifier),
+}
+
+impl RateLimitKey {
+    /// Emails are compared ignoring case, like the logins do
+    pub fn email(client_id: Identifier, email: &str) -> Self {
+        RateLimitKey::Email { client_id, email: email.to_lowercase() }
+    }
+}
+
+impl fmt::Display for RateLimitKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateLimitKey::Ip(ip) => write!(f, "ip:{ip}"),
+            RateLimitKey::Email { client_id, email } => write!(f, "email:{}:{email}", client_id.as_hex()),
+            RateLimitKey::Client(client_id) => write!(f, "client:{}", client_id.as_hex()),
+        }
+    }
+}
+
+/// Token bucket holding up to `burst` attempts, refilled with one every `interval`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub interval: Duration,
+}
+
+/// Attempts left in the bucket of a key as of `updated_at`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bucket {
+    pub tokens: f64,
+    pub updated_at: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitDecision {
+    Allowed,
+    Limited { retry_after: Duration },
+}
+
+impl RateLimit {
+    /// Takes an attempt out of the `bucket` at `now`, a missing bucket is a full one. Returns the bucket
+    /// to store, unchanged apart from the refill when the attempt isn't allowed
+    pub fn take(&self, bucket: Option<Bucket>, now: SystemTime) -> (Bucket, RateLimitDecision) {
+        let burst = f64::from(self.burst);
+        let tokens = match bucket {
+            Some(bucket) => {
+                let elapsed = now.duration_since(bucket.updated_at).unwrap_or_default();
+                (bucket.tokens + elapsed.as_secs_f64() / self.interval.as_secs_f64()).min(burst)
+            }
+            None => burst,
+        };
+        if tokens >= 1.0 {
+            return (Bucket { tokens: tokens - 1.0, updated_at: now }, RateLimitDecision::Allowed);
+        }
+        let retry_after = self.interval.mul_f64(1.0 - tokens);
+        (Bucket { tokens, updated_at: now }, RateLimitDecision::Limited { retry_after })
+    }
+}
+
+/// Consecutive failures of a key, see [`LockoutPolicy`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Lockout {
+    pub failures: u32,
+    pub last_failure: Option<SystemTime>,
+    pub locked_until: Option<SystemTime>,
+}
+
+impl Lockout {
+    /// Time left until the lockout ends, `None` when the key isn't locked at `now`
+    pub fn remaining(&self, now: SystemTime) -> Option<Duration> {
+        self.locked_until.and_then(|until| until.duration_since(now).ok()).filter(|remaining| !remaining.is_zero())
+    }
+}
+
+/// Progressive lockout, every failure past `max_failures` locks the key twice as long as the previous one
+/// starting at `base` and up to `max`. Failures are forgotten `reset_after` the last one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockoutPolicy {
+    pub max_failures: u32,
+    pub base: Duration,
+    pub max: Duration,
+    pub reset_after: Duration,
+}
+
+impl LockoutPolicy {
+    /// The lockout after one more failure at `now`
+    pub fn record_failure(&self, lockout: Lockout, now: SystemTime) -> Lockout {
+        let expired = lockout
+            .last_failure
+            .is_some_and(|last_failure| now.duration_since(last_failure).unwrap_or_default() >= self.reset_after);
+        let failures = if expired { 1 } else { lockout.failures.saturating_add(1) };
+        let locked_until = failures
+            .checked_sub(self.max_failures + 1)
+            .map(|exponent| now + self.base.saturating_mul(2u32.saturating_pow(exponent)).min(self.max));
+        Lockout { failures, last_failure: Some(now), locked_until }
+    }
+}
+
+/// Counters of the login throttling. They aren't part of transactions, an attempt counts even when
+/// whatever it was for gets rolled back
+#[async_trait::async_trait]
+pub trait RateLimitStore: Store {
+    /// Takes an attempt out of the bucket of the key, see [`RateLimit::take`]
+    async fn acquire(state: Self::State, key: &RateLimitKey, limit: RateLimit, now: SystemTime) -> Result<RateLimitDecision, Self::Error>;
+    /// The default lockout when no failure was recorded
+    async fn get_lockout(state: Self::State, key: &RateLimitKey) -> Result<Lockout, Self::Error>;
+    /// Records a failure with [`LockoutPolicy::record_failure`] and returns the new lockout
+    async fn record_failure(state: Self::State, key: &RateLimitKey, policy: LockoutPolicy, now: SystemTime) -> Result<Lockout, Self::Error>;
+    async fn clear_failures(state: Self::State, key: &RateLimitKey) -> Result<(), Self::Error>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seconds: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn token_bucket() {
+        let limit = RateLimit { burst: 2, interval: Duration::from_secs(10) };
+        let (bucket, decision) = limit.take(None, at(100));
+        assert_eq!(decision, RateLimitDecision::Allowed);
+        let (bucket, decision) = limit.take(Some(bucket), at(100));
+        assert_eq!(decision, RateLimitDecision::Allowed);
+        let (bucket, decision) = limit.take(Some(bucket), at(104));
+        assert_eq!(decision, RateLimitDecision::Limited { retry_after: Duration::from_secs(6) });
+        let (bucket, decision) = limit.take(Some(bucket), at(110));
+        assert_eq!(decision, RateLimitDecision::Allowed);
+        // Never more than the burst
+        let (_, decision) = limit.take(Some(bucket), at(1000));
+        assert_eq!(decision, RateLimitDecision::Allowed);
+        let (bucket, _) = limit.take(Some(Bucket { tokens: 0.0, updated_at: at(1000) }), at(2000));
+        assert_eq!(bucket.tokens, 1.0);
+    }
+
+    #[test]
+    fn progressive_lockout() {
+        let policy = LockoutPolicy {
+            max_failures: 2,
+            base: Duration::from_secs(60),
+            max: Duration::from_secs(200),
+            reset_after: Duration::from_secs(3600),
+        };
+        let mut lockout = Lockout::default();
+        let mut locks = vec![];
+        for _ in 0..6 {
+            lockout = policy.record_failure(lockout, at(1000));
+            locks.push(lockout.remaining(at(1000)).map(|remaining| remaining.as_secs()));
+        }
+        assert_eq!(locks, [None, None, Some(60), Some(120), Some(200), Some(200)]);
+        assert_eq!(lockout.remaining(at(1200)), None);
+
+        let lockout = policy.record_failure(lockout, at(1000 + 3600));
+        assert_eq!((lockout.failures, lockout.locked_until), (1, None));
+    }
+}
@@ -0,0 +1,157 @@
+use std::future::Future;
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::store::StoreError;
+
+/// Exponential backoff with full jitter, the n-th retry waits a random duration between zero and
+/// `min(max_backoff, initial_backoff * 2^n)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(25),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Upper bound of the wait before the retry number `retry`, starting at zero
+    pub fn backoff_ceiling(&self, retry: u32) -> Duration {
+        self.initial_backoff
+            .checked_mul(1 << retry.min(31))
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let ceiling = self.backoff_ceiling(retry);
+        ceiling.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
+
+/// Runs `operation` until it succeeds, fails with an error that isn't [retryable](StoreError::is_retryable)
+/// or the attempts run out, waiting between attempts with `sleep` so any async runtime can be used, e.g.
+/// `retry(&policy, tokio::time::sleep, || UserStore::get_user(state.clone(), id))`.
+///
+/// The operation is run from scratch on every attempt, wrap the whole read-modify-write so a
+/// [`StoreError::VersionMismatch`] re-reads the element
+pub async fn retry<T, E, Operation, OperationFuture, Sleep, SleepFuture>(
+    policy: &RetryPolicy,
+    mut sleep: Sleep,
+    mut operation: Operation,
+) -> Result<T, StoreError>
+where
+    E: Into<StoreError>,
+    Operation: FnMut() -> OperationFuture,
+    OperationFuture: Future<Output = Result<T, E>>,
+    Sleep: FnMut(Duration) -> SleepFuture,
+    SleepFuture: Future<Output = ()>,
+{
+    let mut retry = 0;
+    loop {
+        let error = match operation().await {
+            Ok(value) => return Ok(value),
+            Err(error) => error.into(),
+        };
+        if !error.is_retryable() || retry + 1 >= policy.max_attempts {
+            return Err(error);
+        }
+        sleep(policy.backoff(retry)).await;
+        retry += 1;
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    const _: () = {
+        const fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<StoreError>();
+    };
+
+    #[tokio::test]
+    async fn retries_until_success() {
+        let policy = RetryPolicy::default();
+        let sleeps = RefCell::new(Vec::new());
+        let attempts = RefCell::new(0);
+        let result = retry(
+            &policy,
+            |duration| {
+                sleeps.borrow_mut().push(duration);
+                async {}
+            },
+            || async {
+                *attempts.borrow_mut() += 1;
+                match *attempts.borrow() {
+                    1 => Err(StoreError::Unavailable),
+                    2 => Err(StoreError::VersionMismatch),
+                    _ => Ok(42),
+                }
+            },
+        ).await;
+
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(*attempts.borrow(), 3);
+        let sleeps = sleeps.into_inner();
+        assert_eq!(sleeps.len(), 2);
+        assert!(sleeps.iter().zip(0..).all(|(sleep, retry)| *sleep <= policy.backoff_ceiling(retry)));
+    }
+
+    #[tokio::test]
+    async fn gives_up() {
+        let policy = RetryPolicy { max_attempts: 4, ..Default::default() };
+        let attempts = RefCell::new(0);
+        let result: Result<(), _> = retry(&policy, |_| async {}, || async {
+            *attempts.borrow_mut() += 1;
+            Err(StoreError::VersionMismatch)
+        }).await;
+        assert!(matches!(result, Err(StoreError::VersionMismatch)));
+        assert_eq!(*attempts.borrow(), 4);
+
+        let attempts = RefCell::new(0);
+        let result: Result<(), _> = retry(&policy, |_| async {}, || async {
+            *attempts.borrow_mut() += 1;
+            Err(StoreError::Conflict)
+        }).await;
+        assert!(matches!(result, Err(StoreError::Conflict)));
+        assert_eq!(*attempts.borrow(), 1);
+
+        // Timeouts may have been applied and unclassified errors may be permanent
+        for error in [StoreError::Timeout, StoreError::ConnectionReset("unknown".into())] {
+            let error = RefCell::new(Some(error));
+            let attempts = RefCell::new(0);
+            let result: Result<(), _> = retry(&policy, |_| async {}, || async {
+                *attempts.borrow_mut() += 1;
+                Err(error.borrow_mut().take().unwrap())
+            }).await;
+            assert!(result.is_err());
+            assert_eq!(*attempts.borrow(), 1);
+        }
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let policy = RetryPolicy {
+            max_attempts: 100,
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(100),
+        };
+        assert_eq!(policy.backoff_ceiling(0), Duration::from_millis(10));
+        assert_eq!(policy.backoff_ceiling(2), Duration::from_millis(40));
+        assert_eq!(policy.backoff_ceiling(4), Duration::from_millis(100));
+        assert_eq!(policy.backoff_ceiling(80), Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,28 @@
+use std::time::SystemTime;
+
+use crate::data::id::Identifier;
+use crate::store::Store;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub id: Identifier,
+    pub user_id: Identifier,
+    pub client_id: Identifier,
+    pub expires_at: SystemTime,
+}
+
+#[derive(Debug, Clone)]
+pub struct NewSession {
+    pub user_id: Identifier,
+    pub client_id: Identifier,
+    pub expires_at: SystemTime,
+}
+
+/// Sessions are not removed by the store once they expire, checking `expires_at` is up to the caller
+#[async_trait::async_trait]
+pub trait SessionStore: Store {
+    async fn create_session(state: Self::State, session: NewSession) -> Result<Session, Self::Error>;
+    async fn get_session(state: Self::State, id: Identifier) -> Result<Session, Self::Error>;
+    async fn delete_session(state: Self::State, id: Identifier) -> Result<(), Self::Error>;
+    async fn delete_user_sessions(state: Self::State, user_id: Identifier) -> Result<(), Self::Error>;
+}
@@ -0,0 +1,1265 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
+use rusqlite::{ffi, params, Connection, ErrorCode, OptionalExtension, Row, ToSql};
+use tokio::sync::OwnedMutexGuard;
+
+use crate::crypto::envelope::KeyRing;
+use crate::crypto::secret::SecretBytes;
+use crate::data::id::{Identifier, IdentifierGenerator};
+use crate::model::{Client, ClientSettings, User};
+use crate::store::encrypted::{open_signing_key, open_totp_secret, rewrap_secret, seal_signing_key, seal_totp_secret};
+use crate::store::{
+    AuditRecord, AuditStore, Bucket, ClientCredentials, ClientStore, ClientUpdate, Lockout, LockoutPolicy, NewClient, NewSession,
+    NewUser, RateLimit, RateLimitDecision, RateLimitKey, RateLimitStore, Session, SessionStore, Store, StoreError, TokenKind,
+    TokenRecord, TokenStore, TotpCredential, Transaction, UserCredentials, UserStore, UserUpdate,
+};
+
+/// Every entry is applied once and in order inside of its own transaction, the index of the last applied
+/// migration is tracked with `PRAGMA user_version`. Never edit an entry that was already released, append
+/// a new one instead
+const MIGRATIONS: &[&str] = &[
+    r#"
+    CREATE TABLE clients (
+        id BLOB PRIMARY KEY NOT NULL,
+        parent_id BLOB REFERENCES clients (id),
+        name TEXT NOT NULL
+    );
+    CREATE INDEX clients_parent_id ON clients (parent_id);
+
+    CREATE TABLE client_keys (
+        client_id BLOB PRIMARY KEY NOT NULL REFERENCES clients (id) ON DELETE CASCADE,
+        signing_key BLOB NOT NULL
+    );
+
+    CREATE TABLE users (
+        id BLOB PRIMARY KEY NOT NULL,
+        client_id BLOB NOT NULL REFERENCES clients (id) ON DELETE CASCADE,
+        email TEXT NOT NULL,
+        email_key TEXT NOT NULL,
+        metadata BLOB
+    );
+    CREATE UNIQUE INDEX users_client_id_email ON users (client_id, email_key);
+
+    CREATE TABLE sessions (
+        id BLOB PRIMARY KEY NOT NULL,
+        user_id BLOB NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        client_id BLOB NOT NULL REFERENCES clients (id) ON DELETE CASCADE,
+        expires_at INTEGER NOT NULL
+    );
+    CREATE INDEX sessions_user_id ON sessions (user_id);
+
+    CREATE TABLE tokens (
+        hash BLOB PRIMARY KEY NOT NULL,
+        kind INTEGER NOT NULL,
+        user_id BLOB NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        client_id BLOB NOT NULL REFERENCES clients (id) ON DELETE CASCADE,
+        expires_at INTEGER NOT NULL,
+        data BLOB NOT NULL
+    );
+    CREATE INDEX tokens_user_id ON tokens (user_id);
+    "#,
+    r#"
+    ALTER TABLE clients ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+    ALTER TABLE users ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+    "#,
+    r#"
+    ALTER TABLE clients ADD COLUMN settings BLOB;
+    ALTER TABLE clients ADD COLUMN disabled INTEGER NOT NULL DEFAULT 0;
+    "#,
+    r#"
+    CREATE TABLE client_credentials (
+        client_id BLOB PRIMARY KEY NOT NULL REFERENCES clients (id) ON DELETE CASCADE,
+        secret_hash TEXT,
+        public_key BLOB
+    );
+    "#,
+    r#"
+    CREATE TABLE user_credentials (
+        user_id BLOB PRIMARY KEY NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+        totp_secret BLOB,
+        totp_confirmed INTEGER NOT NULL DEFAULT 0,
+        totp_last_used_step INTEGER,
+        recovery_codes BLOB NOT NULL
+    );
+    "#,
+    r#"
+    ALTER TABLE user_credentials ADD COLUMN passkeys BLOB;
+    "#,
+    r#"
+    CREATE TABLE rate_limit_buckets (
+        key TEXT PRIMARY KEY NOT NULL,
+        tokens REAL NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+
+    CREATE TABLE lockouts (
+        key TEXT PRIMARY KEY NOT NULL,
+        failures INTEGER NOT NULL,
+        last_failure INTEGER,
+        locked_until INTEGER
+    );
+    "#,
+    r#"
+    CREATE TABLE audit_log (
+        sequence INTEGER PRIMARY KEY NOT NULL,
+        data BLOB NOT NULL,
+        previous_hash BLOB NOT NULL,
+        hash BLOB NOT NULL,
+        signature BLOB NOT NULL
+    );
+    "#,
+    r#"
+    ALTER TABLE user_credentials ADD COLUMN public_keys BLOB;
+    "#,
+];
+
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Embedded store backed by SQLite, the schema is migrated when the database is opened. The signing keys
+/// and TOTP secrets are always sealed with the [`KeyRing`] of the state, like
+/// [`EncryptedStore`](crate::store::encrypted::EncryptedStore) does, so the database file never holds
+/// them in the clear.
+///
+/// NOTE: Queries run synchronously on the calling task, they are short but the executor thread is
+/// blocked while waiting for the database lock. The connection is shared by every clone of the state and
+/// an open [`Transaction`] holds it until it finishes, so the task that opened it must not use any other
+/// state in the meantime
+#[derive(Debug, Clone, Copy)]
+pub struct SqliteStore;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SqliteUser {
+    pub id: Identifier,
+    pub client_id: Identifier,
+    pub email: String,
+    pub metadata: Option<BTreeMap<String, String>>,
+    pub version: u64,
+}
+
+impl User for SqliteUser {
+    type UserMetadata = BTreeMap<String, String>;
+
+    fn get_id(&self) -> Identifier {
+        self.id
+    }
+
+    fn get_client_id(&self) -> Identifier {
+        self.client_id
+    }
+
+    fn get_email(&self) -> &str {
+        &self.email
+    }
+
+    fn get_version(&self) -> u64 {
+        self.version
+    }
+
+    fn get_user_metadata(&self) -> Option<Self::UserMetadata> {
+        self.metadata.clone()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SqliteClient {
+    pub id: Identifier,
+    pub parent_id: Option<Identifier>,
+    pub name: String,
+    pub settings: ClientSettings,
+    pub disabled: bool,
+    pub version: u64,
+}
+
+impl Client for SqliteClient {
+    fn get_id(&self) -> Identifier {
+        self.id
+    }
+
+    fn get_parent_id(&self) -> Option<Identifier> {
+        self.parent_id
+    }
+
+    fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    fn get_version(&self) -> u64 {
+        self.version
+    }
+
+    fn get_settings(&self) -> &ClientSettings {
+        &self.settings
+    }
+
+    fn is_disabled(&self) -> bool {
+        self.disabled
+    }
+}
+
+#[derive(Clone)]
+pub struct SqliteState {
+    connection: Arc<tokio::sync::Mutex<Connection>>,
+    generator: Arc<Mutex<IdentifierGenerator>>,
+    key_ring: KeyRing,
+    transaction: Option<Arc<SqliteTransaction>>,
+}
+
+/// Owns the connection lock from `BEGIN` until `COMMIT` or `ROLLBACK`
+struct SqliteTransaction {
+    connection: Mutex<Option<OwnedMutexGuard<Connection>>>,
+}
+
+impl SqliteTransaction {
+    fn finish(&self) -> Result<OwnedMutexGuard<Connection>, StoreError> {
+        self.connection
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .take()
+            .ok_or_else(finished_transaction)
+    }
+}
+
+impl Drop for SqliteTransaction {
+    fn drop(&mut self) {
+        let connection = self.connection.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(connection) = connection.take() {
+            let _ = connection.execute_batch("ROLLBACK");
+        }
+    }
+}
+
+fn finished_transaction() -> StoreError {
+    StoreError::ConnectionReset("the transaction is already finished".into())
+}
+
+impl SqliteState {
+    pub fn open(path: impl AsRef<Path>, key_ring: KeyRing) -> Result<Self, StoreError> {
+        Self::from_connection(Connection::open(path).map_err(map_error)?, IdentifierGenerator::new(0, 0), key_ring)
+    }
+
+    pub fn open_in_memory(key_ring: KeyRing) -> Result<Self, StoreError> {
+        Self::from_connection(Connection::open_in_memory().map_err(map_error)?, IdentifierGenerator::new(0, 0), key_ring)
+    }
+
+    pub fn from_connection(mut connection: Connection, generator: IdentifierGenerator, key_ring: KeyRing) -> Result<Self, StoreError> {
+        connection.busy_timeout(BUSY_TIMEOUT).map_err(map_error)?;
+        connection.pragma_update(None, "foreign_keys", true).map_err(map_error)?;
+        migrate(&mut connection)?;
+        Ok(Self {
+            connection: Arc::new(tokio::sync::Mutex::new(connection)),
+            generator: Arc::new(Mutex::new(generator)),
+            key_ring,
+            transaction: None,
+        })
+    }
+
+    pub fn key_ring(&self) -> &KeyRing {
+        &self.key_ring
+    }
+
+    /// Number of migrations applied to the database
+    pub async fn schema_version(&self) -> Result<usize, StoreError> {
+        self.with_connection(|connection| schema_version(connection)).await
+    }
+
+    async fn with_connection<T, Operation>(&self, operation: Operation) -> Result<T, StoreError>
+    where
+        Operation: FnOnce(&mut Connection) -> Result<T, StoreError> + Send,
+    {
+        match &self.transaction {
+            Some(transaction) => {
+                let mut connection = transaction.connection.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                operation(connection.as_mut().ok_or_else(finished_transaction)?)
+            }
+            None => operation(&mut *self.connection.lock().await),
+        }
+    }
+
+    fn generate_id(&self) -> Identifier {
+        self.generator.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).generate()
+    }
+}
+
+fn schema_version(connection: &Connection) -> Result<usize, StoreError> {
+    connection
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .map_err(map_error)
+}
+
+fn migrate(connection: &mut Connection) -> Result<(), StoreError> {
+    let version = schema_version(connection)?;
+    if version > MIGRATIONS.len() {
+        return Err(StoreError::ConnectionReset("the database schema is newer than this release".into()));
+    }
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction().map_err(map_error)?;
+        transaction.execute_batch(migration).map_err(map_error)?;
+        transaction.pragma_update(None, "user_version", index + 1).map_err(map_error)?;
+        transaction.commit().map_err(map_error)?;
+    }
+    Ok(())
+}
+
+fn map_error(error: rusqlite::Error) -> StoreError {
+    match &error {
+        rusqlite::Error::QueryReturnedNoRows => StoreError::NotFound,
+        rusqlite::Error::SqliteFailure(failure, _) => match failure.code {
+            // NOTE: Foreign keys are only violated on writes referencing a missing element, deletes of
+            // referenced elements are either cascaded or checked beforehand
+            ErrorCode::ConstraintViolation if failure.extended_code == ffi::SQLITE_CONSTRAINT_FOREIGNKEY => StoreError::NotFound,
+            ErrorCode::ConstraintViolation => StoreError::Conflict,
+            ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked => StoreError::Unavailable,
+            ErrorCode::PermissionDenied | ErrorCode::ReadOnly | ErrorCode::AuthorizationForStatementDenied => StoreError::PermissionDenied,
+            _ => StoreError::ConnectionReset(Box::new(error)),
+        },
+        rusqlite::Error::FromSqlConversionFailure(..) | rusqlite::Error::InvalidColumnType(..) | rusqlite::Error::IntegralValueOutOfRange(..) => {
+            StoreError::Serialization(Box::new(error))
+        }
+        _ => StoreError::ConnectionReset(Box::new(error)),
+    }
+}
+
+/// Identifiers are stored big endian so the byte order of the column matches the numeric one
+struct SqlId(Identifier);
+
+impl ToSql for SqlId {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(u128::from(self.0).to_be_bytes().to_vec()))
+    }
+}
+
+impl FromSql for SqlId {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let blob = value.as_blob()?;
+        let bytes: [u8; 16] = blob
+            .try_into()
+            .map_err(|_| FromSqlError::InvalidBlobSize { expected_size: 16, blob_size: blob.len() })?;
+        Ok(Self(u128::from_be_bytes(bytes).into()))
+    }
+}
+
+/// Milliseconds since the unix epoch
+struct SqlTime(SystemTime);
+
+impl ToSql for SqlTime {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let millis = self.0.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis();
+        Ok(ToSqlOutput::from(millis as i64))
+    }
+}
+
+impl FromSql for SqlTime {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let millis = value.as_i64()?;
+        let millis = u64::try_from(millis).map_err(|_| FromSqlError::OutOfRange(millis))?;
+        Ok(Self(SystemTime::UNIX_EPOCH + Duration::from_millis(millis)))
+    }
+}
+
+fn email_key(email: &str) -> String {
+    email.to_lowercase()
+}
+
+fn encode_metadata(metadata: &Option<BTreeMap<String, String>>) -> Option<Vec<u8>> {
+    metadata.as_ref().map(|metadata| bincode::serialize(metadata).unwrap())
+}
+
+fn encode_settings(settings: &ClientSettings) -> Vec<u8> {
+    bincode::serialize(settings).unwrap()
+}
+
+fn token_kind_to_sql(kind: TokenKind) -> i64 {
+    match kind {
+        TokenKind::Refresh => 0,
+        TokenKind::AuthorizationCode => 1,
+        TokenKind::PendingLogin => 2,
+        TokenKind::PasskeyChallenge => 3,
+    }
+}
+
+fn token_kind_from_sql(kind: i64) -> rusqlite::Result<TokenKind> {
+    match kind {
+        0 => Ok(TokenKind::Refresh),
+        1 => Ok(TokenKind::AuthorizationCode),
+        2 => Ok(TokenKind::PendingLogin),
+        3 => Ok(TokenKind::PasskeyChallenge),
+        _ => Err(rusqlite::Error::IntegralValueOutOfRange(1, kind)),
+    }
+}
+
+const USER_COLUMNS: &str = "id, client_id, email, metadata, version";
+
+fn user_from_row(row: &Row<'_>) -> rusqlite::Result<SqliteUser> {
+    let metadata = row.get::<_, Option<Vec<u8>>>(3)?
+        .map(|bytes| bincode::deserialize(&bytes))
+        .transpose()
+        .map_err(|error| rusqlite::Error::FromSqlConversionFailure(3, rusqlite::types::Type::Blob, error))?;
+    Ok(SqliteUser {
+        id: row.get::<_, SqlId>(0)?.0,
+        client_id: row.get::<_, SqlId>(1)?.0,
+        email: row.get(2)?,
+        metadata,
+        version: row.get(4)?,
+    })
+}
+
+fn user_credentials_from_row(row: &Row<'_>) -> rusqlite::Result<UserCredentials> {
+    let recovery_codes = row.get::<_, Option<Vec<u8>>>(3)?
+        .map(|bytes| bincode::deserialize(&bytes))
+        .transpose()
+        .map_err(|error| rusqlite::Error::FromSqlConversionFailure(3, rusqlite::types::Type::Blob, error))?;
+    let passkeys = row.get::<_, Option<Vec<u8>>>(4)?
+        .map(|bytes| bincode::deserialize(&bytes))
+        .transpose()
+        .map_err(|error| rusqlite::Error::FromSqlConversionFailure(4, rusqlite::types::Type::Blob, error))?;
+    let public_keys = row.get::<_, Option<Vec<u8>>>(5)?
+        .map(|bytes| bincode::deserialize(&bytes))
+        .transpose()
+        .map_err(|error| rusqlite::Error::FromSqlConversionFailure(5, rusqlite::types::Type::Blob, error))?;
+    let totp = row.get::<_, Option<Vec<u8>>>(0)?.map(|secret| -> rusqlite::Result<_> {
+        Ok(TotpCredential {
+            secret: SecretBytes::new(secret),
+            confirmed: row.get(1)?,
+            last_used_step: row.get(2)?,
+        })
+    });
+    Ok(UserCredentials {
+        totp: totp.transpose()?,
+        recovery_codes: recovery_codes.unwrap_or_default(),
+        passkeys: passkeys.unwrap_or_default(),
+        public_keys: public_keys.unwrap_or_default(),
+    })
+}
+
+const CLIENT_COLUMNS: &str = "id, parent_id, name, version, settings, disabled";
+
+fn client_from_row(row: &Row<'_>) -> rusqlite::Result<SqliteClient> {
+    let settings = row.get::<_, Option<Vec<u8>>>(4)?
+        .map(|bytes| bincode::deserialize(&bytes))
+        .transpose()
+        .map_err(|error| rusqlite::Error::FromSqlConversionFailure(4, rusqlite::types::Type::Blob, error))?;
+    Ok(SqliteClient {
+        id: row.get::<_, SqlId>(0)?.0,
+        parent_id: row.get::<_, Option<SqlId>>(1)?.map(|id| id.0),
+        name: row.get(2)?,
+        version: row.get(3)?,
+        settings: settings.unwrap_or_default(),
+        disabled: row.get(5)?,
+    })
+}
+
+const SESSION_COLUMNS: &str = "id, user_id, client_id, expires_at";
+
+fn session_from_row(row: &Row<'_>) -> rusqlite::Result<Session> {
+    Ok(Session {
+        id: row.get::<_, SqlId>(0)?.0,
+        user_id: row.get::<_, SqlId>(1)?.0,
+        client_id: row.get::<_, SqlId>(2)?.0,
+        expires_at: row.get::<_, SqlTime>(3)?.0,
+    })
+}
+
+const TOKEN_COLUMNS: &str = "hash, kind, user_id, client_id, expires_at, data";
+
+fn token_from_row(row: &Row<'_>) -> rusqlite::Result<TokenRecord> {
+    Ok(TokenRecord {
+        hash: row.get(0)?,
+        kind: token_kind_from_sql(row.get(1)?)?,
+        user_id: row.get::<_, SqlId>(2)?.0,
+        client_id: row.get::<_, SqlId>(3)?.0,
+        expires_at: row.get::<_, SqlTime>(4)?.0,
+        data: row.get(5)?,
+    })
+}
+
+/// Fails with [`StoreError::NotFound`] when a statement didn't touch any row
+fn expect_changes(changes: usize) -> Result<(), StoreError> {
+    if changes == 0 {
+        return Err(StoreError::NotFound);
+    }
+    Ok(())
+}
+
+/// Tells apart why a compare-and-swap update didn't touch the row of `table` with the given `id`
+fn missing_or_mismatch(connection: &Connection, table: &str, id: Identifier) -> StoreError {
+    let exists = connection
+        .query_row(&format!("SELECT 1 FROM {table} WHERE id = ?1"), params![SqlId(id)], |_| Ok(()))
+        .optional();
+    match exists {
+        Ok(Some(())) => StoreError::VersionMismatch,
+        Ok(None) => StoreError::NotFound,
+        Err(error) => map_error(error),
+    }
+}
+
+impl Store for SqliteStore {
+    type Error = StoreError;
+    type State = SqliteState;
+}
+
+impl SqliteStore {
+    /// Re-wraps every signing key and TOTP secret that isn't using the current KEK of the key ring yet, in
+    /// a single transaction. Returns the number of re-wrapped secrets
+    pub async fn rewrap_secrets(state: SqliteState) -> Result<usize, StoreError> {
+        let key_ring = state.key_ring.clone();
+        state.with_connection(|connection| {
+            let savepoint = connection.savepoint().map_err(map_error)?;
+            let mut rewrapped = 0;
+            let keys = savepoint
+                .prepare("SELECT client_id, signing_key FROM client_keys")
+                .and_then(|mut statement| {
+                    statement.query_map([], |row| Ok((row.get::<_, SqlId>(0)?, SecretBytes::new(row.get(1)?))))?.collect::<Result<Vec<_>, _>>()
+                })
+                .map_err(map_error)?;
+            for (client_id, key) in keys {
+                if let Some(key) = rewrap_secret(&key_ring, &key)? {
+                    savepoint
+                        .execute("UPDATE client_keys SET signing_key = ?2 WHERE client_id = ?1", params![client_id, key.expose_secret()])
+                        .map_err(map_error)?;
+                    rewrapped += 1;
+                }
+            }
+            let secrets = savepoint
+                .prepare("SELECT user_id, totp_secret FROM user_credentials WHERE totp_secret IS NOT NULL")
+                .and_then(|mut statement| {
+                    statement.query_map([], |row| Ok((row.get::<_, SqlId>(0)?, SecretBytes::new(row.get(1)?))))?.collect::<Result<Vec<_>, _>>()
+                })
+                .map_err(map_error)?;
+            for (user_id, secret) in secrets {
+                if let Some(secret) = rewrap_secret(&key_ring, &secret)? {
+                    savepoint
+                        .execute("UPDATE user_credentials SET totp_secret = ?2 WHERE user_id = ?1", params![user_id, secret.expose_secret()])
+                        .map_err(map_error)?;
+                    rewrapped += 1;
+                }
+            }
+            savepoint.commit().map_err(map_error)?;
+            Ok(rewrapped)
+        }).await
+    }
+}
+
+#[async_trait::async_trait]
+impl UserStore for SqliteStore {
+    type User = SqliteUser;
+
+    async fn create_user(state: Self::State, user: NewUser<BTreeMap<String, String>>) -> Result<Self::User, Self::Error> {
+        let user = SqliteUser {
+            id: state.generate_id(),
+            client_id: user.client_id,
+            email: user.email,
+            metadata: user.metadata,
+            version: 1,
+        };
+        state.with_connection(|connection| {
+            connection
+                .execute(
+                    "INSERT INTO users (id, client_id, email, email_key, metadata) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![SqlId(user.id), SqlId(user.client_id), user.email, email_key(&user.email), encode_metadata(&user.metadata)],
+                )
+                .map_err(map_error)
+        }).await?;
+        Ok(user)
+    }
+
+    async fn get_user(state: Self::State, id: Identifier) -> Result<Self::User, Self::Error> {
+        state.with_connection(|connection| {
+            connection
+                .query_row(&format!("SELECT {USER_COLUMNS} FROM users WHERE id = ?1"), params![SqlId(id)], user_from_row)
+                .map_err(map_error)
+        }).await
+    }
+
+    async fn get_user_by_email(state: Self::State, client_id: Identifier, email: &str) -> Result<Self::User, Self::Error> {
+        state.with_connection(|connection| {
+            connection
+                .query_row(
+                    &format!("SELECT {USER_COLUMNS} FROM users WHERE client_id = ?1 AND email_key = ?2"),
+                    params![SqlId(client_id), email_key(email)],
+                    user_from_row,
+                )
+                .map_err(map_error)
+        }).await
+    }
+
+    async fn list_users(state: Self::State, client_id: Identifier) -> Result<Vec<Self::User>, Self::Error> {
+        state.with_connection(|connection| {
+            let mut statement = connection
+                .prepare_cached(&format!("SELECT {USER_COLUMNS} FROM users WHERE client_id = ?1 ORDER BY id"))
+                .map_err(map_error)?;
+            let users = statement
+                .query_map(params![SqlId(client_id)], user_from_row)
+                .map_err(map_error)?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(map_error)?;
+            Ok(users)
+        }).await
+    }
+
+    async fn update_user(state: Self::State, id: Identifier, update: UserUpdate<BTreeMap<String, String>>) -> Result<Self::User, Self::Error> {
+        let email_key = update.email.as_deref().map(email_key);
+        state.with_connection(|connection| {
+            let user = connection
+                .query_row(
+                    &format!("
+                        UPDATE users
+                        SET email = COALESCE(?2, email), email_key = COALESCE(?3, email_key), metadata = COALESCE(?4, metadata),
+                            version = version + 1
+                        WHERE id = ?1 AND (?5 IS NULL OR version = ?5)
+                        RETURNING {USER_COLUMNS}
+                    "),
+                    params![SqlId(id), update.email, email_key, encode_metadata(&update.metadata), update.expected_version],
+                    user_from_row,
+                )
+                .optional()
+                .map_err(map_error)?;
+            user.ok_or_else(|| missing_or_mismatch(connection, "users", id))
+        }).await
+    }
+
+    async fn delete_user(state: Self::State, id: Identifier) -> Result<(), Self::Error> {
+        state.with_connection(|connection| {
+            let changes = connection
+                .execute("DELETE FROM users WHERE id = ?1", params![SqlId(id)])
+                .map_err(map_error)?;
+            expect_changes(changes)
+        }).await
+    }
+
+    async fn get_user_credentials(state: Self::State, user_id: Identifier) -> Result<UserCredentials, Self::Error> {
+        let mut credentials = state.with_connection(|connection| {
+            connection
+                .query_row(
+                    "SELECT credentials.totp_secret, COALESCE(credentials.totp_confirmed, 0), credentials.totp_last_used_step,
+                            credentials.recovery_codes, credentials.passkeys, credentials.public_keys
+                     FROM users
+                     LEFT JOIN user_credentials credentials ON credentials.user_id = users.id
+                     WHERE users.id = ?1",
+                    params![SqlId(user_id)],
+                    user_credentials_from_row,
+                )
+                .map_err(map_error)
+        }).await?;
+        open_totp_secret(&state.key_ring, user_id, &mut credentials)?;
+        Ok(credentials)
+    }
+
+    async fn set_user_credentials(state: Self::State, user_id: Identifier, mut credentials: UserCredentials) -> Result<(), Self::Error> {
+        seal_totp_secret(&state.key_ring, user_id, &mut credentials)?;
+        let totp = credentials.totp.as_ref();
+        state.with_connection(|connection| {
+            connection
+                .execute(
+                    "INSERT INTO user_credentials (user_id, totp_secret, totp_confirmed, totp_last_used_step, recovery_codes, passkeys, public_keys)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                     ON CONFLICT (user_id) DO UPDATE SET
+                        totp_secret = excluded.totp_secret, totp_confirmed = excluded.totp_confirmed,
+                        totp_last_used_step = excluded.totp_last_used_step, recovery_codes = excluded.recovery_codes,
+                        passkeys = excluded.passkeys, public_keys = excluded.public_keys",
+                    params![
+                        SqlId(user_id),
+                        totp.map(|totp| totp.secret.expose_secret()),
+                        totp.is_some_and(|totp| totp.confirmed),
+                        totp.and_then(|totp| totp.last_used_step),
+                        bincode::serialize(&credentials.recovery_codes).unwrap(),
+                        bincode::serialize(&credentials.passkeys).unwrap(),
+                        bincode::serialize(&credentials.public_keys).unwrap(),
+                    ],
+                )
+                .map_err(map_error)?;
+            Ok(())
+        }).await
+    }
+}
+
+#[async_trait::async_trait]
+impl ClientStore for SqliteStore {
+    type Client = SqliteClient;
+
+    async fn create_client(state: Self::State, client: NewClient) -> Result<Self::Client, Self::Error> {
+        let client = SqliteClient {
+            id: state.generate_id(),
+            parent_id: client.parent_id,
+            name: client.name,
+            settings: client.settings,
+            disabled: false,
+            version: 1,
+        };
+        state.with_connection(|connection| {
+            connection
+                .execute(
+                    "INSERT INTO clients (id, parent_id, name, settings) VALUES (?1, ?2, ?3, ?4)",
+                    params![SqlId(client.id), client.parent_id.map(SqlId), client.name, encode_settings(&client.settings)],
+                )
+                .map_err(map_error)
+        }).await?;
+        Ok(client)
+    }
+
+    async fn get_client(state: Self::State, id: Identifier) -> Result<Self::Client, Self::Error> {
+        state.with_connection(|connection| {
+            connection
+                .query_row(&format!("SELECT {CLIENT_COLUMNS} FROM clients WHERE id = ?1"), params![SqlId(id)], client_from_row)
+                .map_err(map_error)
+        }).await
+    }
+
+    async fn list_clients(state: Self::State, parent_id: Option<Identifier>) -> Result<Vec<Self::Client>, Self::Error> {
+        state.with_connection(|connection| {
+            let mut statement = connection
+                .prepare_cached(&format!("SELECT {CLIENT_COLUMNS} FROM clients WHERE parent_id IS ?1 ORDER BY id"))
+                .map_err(map_error)?;
+            let clients = statement
+                .query_map(params![parent_id.map(SqlId)], client_from_row)
+                .map_err(map_error)?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(map_error)?;
+            Ok(clients)
+        }).await
+    }
+
+    async fn update_client(state: Self::State, id: Identifier, update: ClientUpdate) -> Result<Self::Client, Self::Error> {
+        state.with_connection(|connection| {
+            let client = connection
+                .query_row(
+                    &format!("
+                        UPDATE clients
+                        SET name = COALESCE(?2, name), parent_id = CASE WHEN ?3 THEN ?4 ELSE parent_id END,
+                            settings = COALESCE(?6, settings), disabled = COALESCE(?7, disabled),
+                            version = version + 1
+                        WHERE id = ?1 AND (?5 IS NULL OR version = ?5)
+                        RETURNING {CLIENT_COLUMNS}
+                    "),
+                    params![
+                        SqlId(id),
+                        update.name,
+                        update.parent_id.is_some(),
+                        update.parent_id.flatten().map(SqlId),
+                        update.expected_version,
+                        update.settings.as_ref().map(encode_settings),
+                        update.disabled,
+                    ],
+                    client_from_row,
+                )
+                .optional()
+                .map_err(map_error)?;
+            client.ok_or_else(|| missing_or_mismatch(connection, "clients", id))
+        }).await
+    }
+
+    async fn delete_client(state: Self::State, id: Identifier) -> Result<(), Self::Error> {
+        state.with_connection(|connection| {
+            // NOTE: A savepoint instead of a transaction so it also works inside of a `Transaction`
+            let savepoint = connection.savepoint().map_err(map_error)?;
+            let has_children = savepoint
+                .query_row("SELECT 1 FROM clients WHERE parent_id = ?1 LIMIT 1", params![SqlId(id)], |_| Ok(()))
+                .optional()
+                .map_err(map_error)?
+                .is_some();
+            if has_children {
+                return Err(StoreError::Conflict);
+            }
+            let changes = savepoint
+                .execute("DELETE FROM clients WHERE id = ?1", params![SqlId(id)])
+                .map_err(map_error)?;
+            expect_changes(changes)?;
+            savepoint.commit().map_err(map_error)
+        }).await
+    }
+
+    async fn get_signing_key_bytes(state: Self::State, client_id: Identifier) -> Result<SecretBytes, Self::Error> {
+        let sealed = state.with_connection(|connection| {
+            connection
+                .query_row("SELECT signing_key FROM client_keys WHERE client_id = ?1", params![SqlId(client_id)], |row| row.get(0))
+                .map(SecretBytes::new)
+                .map_err(map_error)
+        }).await?;
+        open_signing_key(&state.key_ring, client_id, &sealed)
+    }
+
+    async fn set_signing_key_bytes(state: Self::State, client_id: Identifier, key: SecretBytes) -> Result<(), Self::Error> {
+        let key = seal_signing_key(&state.key_ring, client_id, &key)?;
+        state.with_connection(|connection| {
+            connection
+                .execute(
+                    "INSERT INTO client_keys (client_id, signing_key) VALUES (?1, ?2)
+                     ON CONFLICT (client_id) DO UPDATE SET signing_key = excluded.signing_key",
+                    params![SqlId(client_id), key.expose_secret()],
+                )
+                .map_err(map_error)?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_credentials(state: Self::State, client_id: Identifier) -> Result<ClientCredentials, Self::Error> {
+        state.with_connection(|connection| {
+            connection
+                .query_row(
+                    "SELECT credentials.secret_hash, credentials.public_key FROM clients
+                     LEFT JOIN client_credentials credentials ON credentials.client_id = clients.id
+                     WHERE clients.id = ?1",
+                    params![SqlId(client_id)],
+                    |row| Ok(ClientCredentials { secret_hash: row.get(0)?, public_key: row.get(1)? }),
+                )
+                .map_err(map_error)
+        }).await
+    }
+
+    async fn set_credentials(state: Self::State, client_id: Identifier, credentials: ClientCredentials) -> Result<(), Self::Error> {
+        state.with_connection(|connection| {
+            connection
+                .execute(
+                    "INSERT INTO client_credentials (client_id, secret_hash, public_key) VALUES (?1, ?2, ?3)
+                     ON CONFLICT (client_id) DO UPDATE SET secret_hash = excluded.secret_hash, public_key = excluded.public_key",
+                    params![SqlId(client_id), credentials.secret_hash, credentials.public_key],
+                )
+                .map_err(map_error)?;
+            Ok(())
+        }).await
+    }
+}
+
+#[async_trait::async_trait]
+impl SessionStore for SqliteStore {
+    async fn create_session(state: Self::State, session: NewSession) -> Result<Session, Self::Error> {
+        let session = Session {
+            id: state.generate_id(),
+            user_id: session.user_id,
+            client_id: session.client_id,
+            expires_at: session.expires_at,
+        };
+        state.with_connection(|connection| {
+            connection
+                .execute(
+                    "INSERT INTO sessions (id, user_id, client_id, expires_at) VALUES (?1, ?2, ?3, ?4)",
+                    params![SqlId(session.id), SqlId(session.user_id), SqlId(session.client_id), SqlTime(session.expires_at)],
+                )
+                .map_err(map_error)
+        }).await?;
+        Ok(session)
+    }
+
+    async fn get_session(state: Self::State, id: Identifier) -> Result<Session, Self::Error> {
+        state.with_connection(|connection| {
+            connection
+                .query_row(&format!("SELECT {SESSION_COLUMNS} FROM sessions WHERE id = ?1"), params![SqlId(id)], session_from_row)
+                .map_err(map_error)
+        }).await
+    }
+
+    async fn delete_session(state: Self::State, id: Identifier) -> Result<(), Self::Error> {
+        state.with_connection(|connection| {
+            let changes = connection
+                .execute("DELETE FROM sessions WHERE id = ?1", params![SqlId(id)])
+                .map_err(map_error)?;
+            expect_changes(changes)
+        }).await
+    }
+
+    async fn delete_user_sessions(state: Self::State, user_id: Identifier) -> Result<(), Self::Error> {
+        state.with_connection(|connection| {
+            connection
+                .execute("DELETE FROM sessions WHERE user_id = ?1", params![SqlId(user_id)])
+                .map_err(map_error)?;
+            Ok(())
+        }).await
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenStore for SqliteStore {
+    async fn insert_token(state: Self::State, token: TokenRecord) -> Result<(), Self::Error> {
+        state.with_connection(|connection| {
+            connection
+                .execute(
+                    &format!("INSERT INTO tokens ({TOKEN_COLUMNS}) VALUES (?1, ?2, ?3, ?4, ?5, ?6)"),
+                    params![
+                        token.hash,
+                        token_kind_to_sql(token.kind),
+                        SqlId(token.user_id),
+                        SqlId(token.client_id),
+                        SqlTime(token.expires_at),
+                        token.data,
+                    ],
+                )
+                .map_err(map_error)?;
+            Ok(())
+        }).await
+    }
+
+    async fn get_token(state: Self::State, hash: &[u8]) -> Result<TokenRecord, Self::Error> {
+        state.with_connection(|connection| {
+            connection
+                .query_row(&format!("SELECT {TOKEN_COLUMNS} FROM tokens WHERE hash = ?1"), params![hash], token_from_row)
+                .map_err(map_error)
+        }).await
+    }
+
+    async fn consume_token(state: Self::State, hash: &[u8]) -> Result<TokenRecord, Self::Error> {
+        state.with_connection(|connection| {
+            connection
+                .query_row(&format!("DELETE FROM tokens WHERE hash = ?1 RETURNING {TOKEN_COLUMNS}"), params![hash], token_from_row)
+                .map_err(map_error)
+        }).await
+    }
+
+    async fn revoke_user_tokens(state: Self::State, user_id: Identifier) -> Result<(), Self::Error> {
+        state.with_connection(|connection| {
+            connection
+                .execute("DELETE FROM tokens WHERE user_id = ?1", params![SqlId(user_id)])
+                .map_err(map_error)?;
+            Ok(())
+        }).await
+    }
+}
+
+fn lockout_from_row(row: &Row<'_>) -> rusqlite::Result<Lockout> {
+    Ok(Lockout {
+        failures: row.get(0)?,
+        last_failure: row.get::<_, Option<SqlTime>>(1)?.map(|time| time.0),
+        locked_until: row.get::<_, Option<SqlTime>>(2)?.map(|time| time.0),
+    })
+}
+
+fn get_lockout(connection: &Connection, key: &RateLimitKey) -> Result<Lockout, StoreError> {
+    connection
+        .query_row("SELECT failures, last_failure, locked_until FROM lockouts WHERE key = ?1", params![key.to_string()], lockout_from_row)
+        .optional()
+        .map_err(map_error)
+        .map(Option::unwrap_or_default)
+}
+
+// NOTE: Every operation reads and writes back under the connection lock, so concurrent attempts on the
+// same key are serialized
+#[async_trait::async_trait]
+impl RateLimitStore for SqliteStore {
+    async fn acquire(state: Self::State, key: &RateLimitKey, limit: RateLimit, now: SystemTime) -> Result<RateLimitDecision, Self::Error> {
+        state.with_connection(|connection| {
+            let key = key.to_string();
+            let bucket = connection
+                .query_row("SELECT tokens, updated_at FROM rate_limit_buckets WHERE key = ?1", params![key], |row| {
+                    Ok(Bucket { tokens: row.get(0)?, updated_at: row.get::<_, SqlTime>(1)?.0 })
+                })
+                .optional()
+                .map_err(map_error)?;
+            let (bucket, decision) = limit.take(bucket, now);
+            connection
+                .execute(
+                    "INSERT INTO rate_limit_buckets (key, tokens, updated_at) VALUES (?1, ?2, ?3)
+                     ON CONFLICT (key) DO UPDATE SET tokens = excluded.tokens, updated_at = excluded.updated_at",
+                    params![key, bucket.tokens, SqlTime(bucket.updated_at)],
+                )
+                .map_err(map_error)?;
+            Ok(decision)
+        }).await
+    }
+
+    async fn get_lockout(state: Self::State, key: &RateLimitKey) -> Result<Lockout, Self::Error> {
+        state.with_connection(|connection| get_lockout(connection, key)).await
+    }
+
+    async fn record_failure(state: Self::State, key: &RateLimitKey, policy: LockoutPolicy, now: SystemTime) -> Result<Lockout, Self::Error> {
+        state.with_connection(|connection| {
+            let lockout = policy.record_failure(get_lockout(connection, key)?, now);
+            connection
+                .execute(
+                    "INSERT INTO lockouts (key, failures, last_failure, locked_until) VALUES (?1, ?2, ?3, ?4)
+                     ON CONFLICT (key) DO UPDATE SET
+                        failures = excluded.failures, last_failure = excluded.last_failure, locked_until = excluded.locked_until",
+                    params![key.to_string(), lockout.failures, lockout.last_failure.map(SqlTime), lockout.locked_until.map(SqlTime)],
+                )
+                .map_err(map_error)?;
+            Ok(lockout)
+        }).await
+    }
+
+    async fn clear_failures(state: Self::State, key: &RateLimitKey) -> Result<(), Self::Error> {
+        state.with_connection(|connection| {
+            connection
+                .execute("DELETE FROM lockouts WHERE key = ?1", params![key.to_string()])
+                .map_err(map_error)?;
+            Ok(())
+        }).await
+    }
+}
+
+fn audit_record_from_row(row: &rusqlite::Row) -> rusqlite::Result<AuditRecord> {
+    Ok(AuditRecord {
+        sequence: row.get(0)?,
+        data: row.get(1)?,
+        previous_hash: row.get(2)?,
+        hash: row.get(3)?,
+        signature: row.get(4)?,
+    })
+}
+
+#[async_trait::async_trait]
+impl AuditStore for SqliteStore {
+    async fn append_audit_record(state: Self::State, record: AuditRecord) -> Result<(), Self::Error> {
+        state.with_connection(|connection| {
+            // NOTE: A single statement so other processes writing to the same database can't leave a gap
+            let inserted = connection
+                .execute(
+                    "INSERT INTO audit_log (sequence, data, previous_hash, hash, signature)
+                     SELECT ?1, ?2, ?3, ?4, ?5 WHERE ?1 = (SELECT COALESCE(MAX(sequence) + 1, 0) FROM audit_log)",
+                    params![record.sequence, record.data, record.previous_hash, record.hash, record.signature],
+                )
+                .map_err(|error| match map_error(error) {
+                    StoreError::Conflict => StoreError::VersionMismatch,
+                    error => error,
+                })?;
+            match inserted {
+                0 => Err(StoreError::VersionMismatch),
+                _ => Ok(()),
+            }
+        }).await
+    }
+
+    async fn last_audit_record(state: Self::State) -> Result<Option<AuditRecord>, Self::Error> {
+        state.with_connection(|connection| {
+            connection
+                .query_row(
+                    "SELECT sequence, data, previous_hash, hash, signature FROM audit_log ORDER BY sequence DESC LIMIT 1",
+                    [],
+                    audit_record_from_row,
+                )
+                .optional()
+                .map_err(map_error)
+        }).await
+    }
+
+    async fn list_audit_records(state: Self::State, from: u64, limit: usize) -> Result<Vec<AuditRecord>, Self::Error> {
+        state.with_connection(|connection| {
+            let mut statement = connection
+                .prepare_cached("SELECT sequence, data, previous_hash, hash, signature FROM audit_log WHERE sequence >= ?1 ORDER BY sequence LIMIT ?2")
+                .map_err(map_error)?;
+            let records = statement
+                .query_map(params![from, i64::try_from(limit).unwrap_or(i64::MAX)], audit_record_from_row)
+                .map_err(map_error)?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(map_error)?;
+            Ok(records)
+        }).await
+    }
+}
+
+#[async_trait::async_trait]
+impl Transaction for SqliteStore {
+    async fn begin(state: Self::State) -> Result<Self::State, Self::Error> {
+        if state.transaction.is_some() {
+            return Err(StoreError::ConnectionReset("nested transactions are not supported".into()));
+        }
+        let connection = state.connection.clone().lock_owned().await;
+        // NOTE: Immediate so the write lock is taken now, instead of failing later when upgrading from a
+        // read lock while another connection writes
+        connection.execute_batch("BEGIN IMMEDIATE").map_err(map_error)?;
+        Ok(SqliteState {
+            transaction: Some(Arc::new(SqliteTransaction { connection: Mutex::new(Some(connection)) })),
+            ..state
+        })
+    }
+
+    async fn commit(state: Self::State) -> Result<(), Self::Error> {
+        let Some(transaction) = &state.transaction else {
+            return Ok(());
+        };
+        let connection = transaction.finish()?;
+        if let Err(error) = connection.execute_batch("COMMIT") {
+            let _ = connection.execute_batch("ROLLBACK");
+            return Err(map_error(error));
+        }
+        Ok(())
+    }
+
+    async fn rollback(state: Self::State) -> Result<(), Self::Error> {
+        let Some(transaction) = &state.transaction else {
+            return Ok(());
+        };
+        transaction.finish()?.execute_batch("ROLLBACK").map_err(map_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::envelope::{KeyEncryptionProvider, LocalKeyProvider};
+
+    use super::*;
+
+    fn key_ring() -> KeyRing {
+        KeyRing::new(Arc::new(LocalKeyProvider::generate("test")))
+    }
+
+    fn new_user(client_id: Identifier, email: &str) -> NewUser<BTreeMap<String, String>> {
+        NewUser {
+            client_id,
+            email: email.to_string(),
+            metadata: None,
+        }
+    }
+
+    async fn seed_client(state: &SqliteState) -> SqliteClient {
+        SqliteStore::create_client(state.clone(), NewClient { parent_id: None, name: "app".to_string(), ..NewClient::default() })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn migrations_are_applied_once() {
+        let path = std::env::temp_dir().join(format!("iam0-{}.sqlite", IdentifierGenerator::new(0, 0).generate()));
+        let state = SqliteState::open(&path, key_ring()).unwrap();
+        assert_eq!(state.schema_version().await.unwrap(), MIGRATIONS.len());
+        let client = seed_client(&state).await;
+        drop(state);
+
+        let state = SqliteState::open(&path, key_ring()).unwrap();
+        assert_eq!(state.schema_version().await.unwrap(), MIGRATIONS.len());
+        assert_eq!(SqliteStore::get_client(state.clone(), client.id).await.unwrap(), client);
+        drop(state);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn unique_email_per_client() {
+        let state = SqliteState::open_in_memory(key_ring()).unwrap();
+        let client = seed_client(&state).await;
+        let other = seed_client(&state).await;
+
+        let user = SqliteStore::create_user(state.clone(), new_user(client.id, "alice@example.com")).await.unwrap();
+        assert!(matches!(
+            SqliteStore::create_user(state.clone(), new_user(client.id, "ALICE@example.com")).await,
+            Err(StoreError::Conflict)
+        ));
+        SqliteStore::create_user(state.clone(), new_user(other.id, "alice@example.com")).await.unwrap();
+        assert_eq!(SqliteStore::get_user_by_email(state.clone(), client.id, "Alice@Example.com").await.unwrap(), user);
+    }
+
+    #[tokio::test]
+    async fn constraint_violations_are_mapped() {
+        let state = SqliteState::open_in_memory(key_ring()).unwrap();
+        let missing = IdentifierGenerator::new(0, 0).generate();
+        assert!(matches!(
+            SqliteStore::create_user(state.clone(), new_user(missing, "alice@example.com")).await,
+            Err(StoreError::NotFound)
+        ));
+        assert!(matches!(SqliteStore::set_signing_key_bytes(state.clone(), missing, SecretBytes::new(vec![1])).await, Err(StoreError::NotFound)));
+        assert!(matches!(SqliteStore::delete_user(state.clone(), missing).await, Err(StoreError::NotFound)));
+
+        let parent = seed_client(&state).await;
+        SqliteStore::create_client(state.clone(), NewClient { parent_id: Some(parent.id), name: "child".to_string(), ..NewClient::default() })
+            .await
+            .unwrap();
+        assert!(matches!(SqliteStore::delete_client(state.clone(), parent.id).await, Err(StoreError::Conflict)));
+    }
+
+    #[tokio::test]
+    async fn busy_database_is_unavailable() {
+        let path = std::env::temp_dir().join(format!("iam0-{}.sqlite", IdentifierGenerator::new(0, 0).generate()));
+        let state = SqliteState::open(&path, key_ring()).unwrap();
+        let mut locker = Connection::open(&path).unwrap();
+        let transaction = locker.transaction_with_behavior(rusqlite::TransactionBehavior::Exclusive).unwrap();
+
+        state.with_connection(|connection| connection.busy_timeout(Duration::ZERO).map_err(map_error)).await.unwrap();
+        assert!(matches!(
+            SqliteStore::create_client(state.clone(), NewClient { parent_id: None, name: "app".to_string(), ..NewClient::default() }).await,
+            Err(StoreError::Unavailable)
+        ));
+        drop(transaction);
+        drop(state);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn read_only_and_corrupt_data_are_mapped() {
+        let path = std::env::temp_dir().join(format!("iam0-{}.sqlite", IdentifierGenerator::new(0, 0).generate()));
+        let state = SqliteState::open(&path, key_ring()).unwrap();
+        let client = seed_client(&state).await;
+        let user = SqliteStore::create_user(state.clone(), new_user(client.id, "alice@example.com")).await.unwrap();
+        state.with_connection(|connection| {
+            connection
+                .execute("UPDATE users SET metadata = x'ff' WHERE id = ?1", params![SqlId(user.id)])
+                .map_err(map_error)
+        }).await.unwrap();
+        let error = SqliteStore::get_user(state.clone(), user.id).await.unwrap_err();
+        assert!(matches!(error, StoreError::Serialization(_)));
+        assert!(!error.is_retryable());
+        drop(state);
+
+        let connection = Connection::open_with_flags(&path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY).unwrap();
+        let state = SqliteState::from_connection(connection, IdentifierGenerator::new(0, 0), key_ring()).unwrap();
+        assert!(matches!(
+            SqliteStore::create_client(state.clone(), NewClient { parent_id: None, name: "app".to_string(), ..NewClient::default() }).await,
+            Err(StoreError::PermissionDenied)
+        ));
+        drop(state);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn tokens_are_single_use() {
+        let state = SqliteState::open_in_memory(key_ring()).unwrap();
+        let client = seed_client(&state).await;
+        let user = SqliteStore::create_user(state.clone(), new_user(client.id, "alice@example.com")).await.unwrap();
+        let token = TokenRecord {
+            hash: vec![1, 2, 3],
+            kind: TokenKind::AuthorizationCode,
+            user_id: user.id,
+            client_id: client.id,
+            expires_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1),
+            data: vec![4, 5],
+        };
+
+        SqliteStore::insert_token(state.clone(), token.clone()).await.unwrap();
+        assert!(matches!(SqliteStore::insert_token(state.clone(), token.clone()).await, Err(StoreError::Conflict)));
+        assert_eq!(SqliteStore::consume_token(state.clone(), &token.hash).await.unwrap(), token);
+        assert!(matches!(SqliteStore::consume_token(state.clone(), &token.hash).await, Err(StoreError::NotFound)));
+
+        let session = SqliteStore::create_session(state.clone(), NewSession {
+            user_id: user.id,
+            client_id: client.id,
+            expires_at: token.expires_at,
+        }).await.unwrap();
+        SqliteStore::delete_user(state.clone(), user.id).await.unwrap();
+        assert!(matches!(SqliteStore::get_session(state, session.id).await, Err(StoreError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn secrets_are_sealed_at_rest() {
+        let state = SqliteState::open_in_memory(key_ring()).unwrap();
+        let client = seed_client(&state).await;
+        let user = SqliteStore::create_user(state.clone(), new_user(client.id, "alice@example.com")).await.unwrap();
+        SqliteStore::set_signing_key_bytes(state.clone(), client.id, SecretBytes::new(b"signing key".to_vec())).await.unwrap();
+        let totp = TotpCredential { secret: SecretBytes::new(b"totp secret".to_vec()), confirmed: true, last_used_step: None };
+        let credentials = UserCredentials { totp: Some(totp), ..UserCredentials::default() };
+        SqliteStore::set_user_credentials(state.clone(), user.id, credentials).await.unwrap();
+
+        let raw = |query: &'static str| state.with_connection(move |connection| {
+            connection.query_row(query, [], |row| row.get::<_, Vec<u8>>(0)).map_err(map_error)
+        });
+        let signing_key = raw("SELECT signing_key FROM client_keys").await.unwrap();
+        assert!(!signing_key.windows(11).any(|window| window == b"signing key"));
+        let totp_secret = raw("SELECT totp_secret FROM user_credentials").await.unwrap();
+        assert!(!totp_secret.windows(11).any(|window| window == b"totp secret"));
+
+        let old: Arc<dyn KeyEncryptionProvider> = state.key_ring().current().clone();
+        let rotated = SqliteState { key_ring: KeyRing::new(Arc::new(LocalKeyProvider::generate("rotated"))).with_retired(old), ..state.clone() };
+        assert_eq!(SqliteStore::rewrap_secrets(rotated.clone()).await.unwrap(), 2);
+        assert_eq!(SqliteStore::rewrap_secrets(rotated.clone()).await.unwrap(), 0);
+        let rotated = SqliteState { key_ring: KeyRing::new(rotated.key_ring().current().clone()), ..rotated };
+        let key = SqliteStore::get_signing_key_bytes(rotated.clone(), client.id).await.unwrap();
+        assert_eq!(key.expose_secret(), b"signing key");
+        let credentials = SqliteStore::get_user_credentials(rotated, user.id).await.unwrap();
+        assert_eq!(credentials.totp.unwrap().secret.expose_secret(), b"totp secret");
+        assert!(matches!(SqliteStore::get_signing_key_bytes(state, client.id).await, Err(StoreError::Serialization(_))));
+    }
+
+    #[cfg(feature = "testing")]
+    mod conformance {
+        use super::*;
+
+        crate::store_conformance_tests!(SqliteStore, SqliteState::open_in_memory(key_ring()).unwrap());
+    }
+}
@@ -0,0 +1,645 @@
+//! Conformance checks for store implementations, every check receives a fresh state and panics when the
+//! store doesn't behave as the traits expect. Every check is bound on the traits it uses only, the easiest way
+//! to run all of them is the [`store_conformance_tests`](crate::store_conformance_tests) macro
+//!
+//! ```ignore
+//! mod conformance {
+//!     iam0_core::store_conformance_tests!(MyStore, MyState::connect_for_tests().await);
+//! }
+//! ```
+
+use std::time::{Duration, SystemTime};
+
+use tokio::task::JoinSet;
+
+use crate::crypto::secret::SecretBytes;
+use crate::data::id::{Identifier, IdentifierGenerator};
+use crate::model::{Client, Curve, User};
+use crate::store::{
+    atomically, AuditRecord, AuditStore, ClientCredentials, ClientStore, ClientUpdate, Lockout, LockoutPolicy, NewClient, NewSession, NewUser, PasskeyCredential,
+    RateLimit, RateLimitDecision, RateLimitKey, RateLimitStore, RetryPolicy, SessionStore, StoreError, TokenKind, TokenRecord,
+    TokenStore, TotpCredential, Transaction, UserCredentials, UserPublicKey, UserStore, UserUpdate,
+};
+
+const CONCURRENCY: usize = 16;
+
+/// Generates a `#[tokio::test]` for every conformance check, `$state` is evaluated once per test inside of
+/// an async context. The tests run on several worker threads so the concurrent checks actually race, the
+/// calling crate needs `tokio` with the `macros` and `rt-multi-thread` features
+///
+/// A store implementing only some of the traits lists the groups of checks it passes, one per trait:
+/// `clients`, `users`, `sessions`, `tokens`, `transactions`, `rate_limits` and `audit`. The checks of a group
+/// may also need the traits the stored elements depend on, users belong to clients for example
+///
+/// ```ignore
+/// iam0_core::store_conformance_tests!(MyStore, MyState::connect_for_tests().await; clients, users);
+/// ```
+#[macro_export]
+macro_rules! store_conformance_tests {
+    ($store:ty, $state:expr) => {
+        $crate::store_conformance_tests!($store, $state; clients, users, sessions, tokens, transactions, rate_limits, audit);
+    };
+    ($store:ty, $state:expr; $($group:ident),+ $(,)?) => {
+        $(
+            $crate::store_conformance_tests!(@group $group, $store, $state);
+        )+
+    };
+    (@group clients, $store:ty, $state:expr) => {
+        $crate::store_conformance_tests!(@tests $store, $state, client_hierarchy, client_credentials);
+    };
+    (@group users, $store:ty, $state:expr) => {
+        $crate::store_conformance_tests!(
+            @tests $store, $state,
+            not_found,
+            unique_email,
+            case_insensitive_email,
+            concurrent_creates,
+            concurrent_updates,
+            user_credentials,
+            optimistic_concurrency,
+        );
+    };
+    (@group sessions, $store:ty, $state:expr) => {
+        $crate::store_conformance_tests!(@tests $store, $state, cascading_deletes);
+    };
+    (@group tokens, $store:ty, $state:expr) => {
+        $crate::store_conformance_tests!(@tests $store, $state, single_use_tokens);
+    };
+    (@group transactions, $store:ty, $state:expr) => {
+        $crate::store_conformance_tests!(@tests $store, $state, transactions, concurrent_transactions);
+    };
+    (@group rate_limits, $store:ty, $state:expr) => {
+        $crate::store_conformance_tests!(@tests $store, $state, rate_limits);
+    };
+    (@group audit, $store:ty, $state:expr) => {
+        $crate::store_conformance_tests!(@tests $store, $state, audit_log);
+    };
+    (@tests $store:ty, $state:expr, $($check:ident),* $(,)?) => {
+        $(
+            #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+            async fn $check() {
+                $crate::store::testing::$check::<$store>($state).await;
+            }
+        )*
+    };
+}
+
+fn expect<T, E: Into<StoreError>>(result: Result<T, E>, context: &str) -> T {
+    match result {
+        Ok(value) => value,
+        Err(error) => panic!("{context}: {}", error.into()),
+    }
+}
+
+fn expect_error<T, E: Into<StoreError>>(result: Result<T, E>, context: &str) -> StoreError {
+    match result {
+        Ok(_) => panic!("{context}: expected an error"),
+        Err(error) => error.into(),
+    }
+}
+
+fn unknown_id() -> Identifier {
+    IdentifierGenerator::new(u16::MAX, u16::MAX).generate()
+}
+
+fn new_user<S: UserStore>(client_id: Identifier, email: &str) -> NewUser<crate::store::UserMetadataOf<S>> {
+    NewUser {
+        client_id,
+        email: email.to_string(),
+        metadata: None,
+    }
+}
+
+async fn create_client<S: ClientStore>(state: &S::State, parent_id: Option<Identifier>) -> S::Client {
+    let client = S::create_client(state.clone(), NewClient { parent_id, name: "conformance".to_string(), ..NewClient::default() }).await;
+    expect(client, "create_client")
+}
+
+async fn create_user<S: UserStore>(state: &S::State, client_id: Identifier, email: &str) -> S::User {
+    expect(S::create_user(state.clone(), new_user::<S>(client_id, email)).await, "create_user")
+}
+
+/// Lookups, updates and deletes of missing elements fail with [`StoreError::NotFound`]
+pub async fn not_found<S>(state: S::State)
+where
+    S: UserStore + ClientStore,
+{
+    let missing = unknown_id();
+    let client = create_client::<S>(&state, None).await;
+
+    let checks = [
+        ("get_user", expect_error(S::get_user(state.clone(), missing).await, "get_user")),
+        ("get_user_by_email", expect_error(S::get_user_by_email(state.clone(), client.get_id(), "missing@example.com").await, "get_user_by_email")),
+        ("update_user", expect_error(S::update_user(state.clone(), missing, UserUpdate::default()).await, "update_user")),
+        ("delete_user", expect_error(S::delete_user(state.clone(), missing).await, "delete_user")),
+        ("create_user", expect_error(S::create_user(state.clone(), new_user::<S>(missing, "alice@example.com")).await, "create_user")),
+        ("get_client", expect_error(S::get_client(state.clone(), missing).await, "get_client")),
+        ("update_client", expect_error(S::update_client(state.clone(), missing, ClientUpdate::default()).await, "update_client")),
+        ("delete_client", expect_error(S::delete_client(state.clone(), missing).await, "delete_client")),
+        ("get_signing_key_bytes", expect_error(S::get_signing_key_bytes(state.clone(), client.get_id()).await, "get_signing_key_bytes")),
+        ("set_signing_key_bytes", expect_error(S::set_signing_key_bytes(state.clone(), missing, SecretBytes::new(vec![1])).await, "set_signing_key_bytes")),
+        ("get_credentials", expect_error(S::get_credentials(state.clone(), missing).await, "get_credentials")),
+        ("set_credentials", expect_error(S::set_credentials(state.clone(), missing, ClientCredentials::default()).await, "set_credentials")),
+        ("get_user_credentials", expect_error(S::get_user_credentials(state.clone(), missing).await, "get_user_credentials")),
+        ("set_user_credentials", expect_error(S::set_user_credentials(state.clone(), missing, UserCredentials::default()).await, "set_user_credentials")),
+    ];
+    for (operation, error) in checks {
+        assert!(matches!(error, StoreError::NotFound), "{operation}: expected not found, got {error}");
+    }
+    assert!(expect(S::list_users(state.clone(), client.get_id()).await, "list_users").is_empty());
+}
+
+/// Emails are unique per client, but the same email can be used on different clients
+pub async fn unique_email<S>(state: S::State)
+where
+    S: UserStore + ClientStore,
+{
+    let client = create_client::<S>(&state, None).await;
+    let other_client = create_client::<S>(&state, None).await;
+    let user = create_user::<S>(&state, client.get_id(), "alice@example.com").await;
+    let other = create_user::<S>(&state, client.get_id(), "bob@example.com").await;
+    create_user::<S>(&state, other_client.get_id(), "alice@example.com").await;
+
+    let error = expect_error(S::create_user(state.clone(), new_user::<S>(client.get_id(), "alice@example.com")).await, "create_user");
+    assert!(matches!(error, StoreError::Conflict), "duplicated create: expected conflict, got {error}");
+
+    let update = UserUpdate { email: Some("alice@example.com".to_string()), metadata: None, expected_version: None };
+    let error = expect_error(S::update_user(state.clone(), other.get_id(), update).await, "update_user");
+    assert!(matches!(error, StoreError::Conflict), "duplicated update: expected conflict, got {error}");
+
+    let update = UserUpdate { email: Some("alice@example.com".to_string()), metadata: None, expected_version: None };
+    let user = expect(S::update_user(state.clone(), user.get_id(), update).await, "update_user with its own email");
+    assert_eq!(user.get_email(), "alice@example.com");
+}
+
+/// Lookups and uniqueness checks ignore the case of the email, but the stored email keeps it
+pub async fn case_insensitive_email<S>(state: S::State)
+where
+    S: UserStore + ClientStore,
+{
+    let client = create_client::<S>(&state, None).await;
+    let user = create_user::<S>(&state, client.get_id(), "Alice@Example.com").await;
+
+    for email in ["alice@example.com", "ALICE@EXAMPLE.COM", "Alice@Example.com"] {
+        let found = expect(S::get_user_by_email(state.clone(), client.get_id(), email).await, "get_user_by_email");
+        assert_eq!(found.get_id(), user.get_id());
+        assert_eq!(found.get_email(), "Alice@Example.com");
+    }
+
+    let error = expect_error(S::create_user(state.clone(), new_user::<S>(client.get_id(), "aLiCe@eXaMpLe.CoM")).await, "create_user");
+    assert!(matches!(error, StoreError::Conflict), "expected conflict, got {error}");
+}
+
+/// Concurrent creation of users with the same email only lets one of them through
+pub async fn concurrent_creates<S>(state: S::State)
+where
+    S: UserStore + ClientStore,
+{
+    let client = create_client::<S>(&state, None).await;
+
+    let mut tasks = JoinSet::new();
+    for _ in 0..CONCURRENCY {
+        let state = state.clone();
+        let user = new_user::<S>(client.get_id(), "alice@example.com");
+        tasks.spawn(async move { S::create_user(state, user).await.map(|_| ()).map_err(Into::into) });
+    }
+
+    let mut created = 0;
+    while let Some(result) = tasks.join_next().await {
+        match result.unwrap() {
+            Ok(()) => created += 1,
+            Err(StoreError::Conflict) => {}
+            Err(error) => panic!("concurrent create_user: expected conflict, got {error}"),
+        }
+    }
+    assert_eq!(created, 1);
+    assert_eq!(expect(S::list_users(state, client.get_id()).await, "list_users").len(), 1);
+}
+
+/// Concurrent updates of the same user never fail and the result is one of the written values
+pub async fn concurrent_updates<S>(state: S::State)
+where
+    S: UserStore + ClientStore,
+{
+    let client = create_client::<S>(&state, None).await;
+    let user = create_user::<S>(&state, client.get_id(), "user@example.com").await;
+    let emails = (0..CONCURRENCY).map(|index| format!("user-{index}@example.com")).collect::<Vec<_>>();
+
+    let user_id = user.get_id();
+    let mut tasks = JoinSet::new();
+    for email in emails.iter().cloned() {
+        let state = state.clone();
+        let update = UserUpdate { email: Some(email), metadata: None, expected_version: None };
+        tasks.spawn(async move { S::update_user(state, user_id, update).await.map(|_| ()).map_err(Into::into) });
+    }
+    while let Some(result) = tasks.join_next().await {
+        if let Err(error) = result.unwrap() {
+            panic!("concurrent update_user: {error}");
+        }
+    }
+
+    let user = expect(S::get_user(state.clone(), user_id).await, "get_user");
+    assert!(emails.iter().any(|email| email == user.get_email()));
+    for email in emails.iter().filter(|email| *email != user.get_email()) {
+        let error = expect_error(S::get_user_by_email(state.clone(), client.get_id(), email).await, "get_user_by_email");
+        assert!(matches!(error, StoreError::NotFound), "stale email {email}: expected not found, got {error}");
+    }
+}
+
+/// Children are listed under their parent, and a client with children can't be deleted
+pub async fn client_hierarchy<S>(state: S::State)
+where
+    S: ClientStore,
+{
+    let parent = create_client::<S>(&state, None).await;
+    let child = create_client::<S>(&state, Some(parent.get_id())).await;
+    assert_eq!(child.get_parent_id(), Some(parent.get_id()));
+
+    let children = expect(S::list_clients(state.clone(), Some(parent.get_id())).await, "list_clients");
+    assert_eq!(children.iter().map(Client::get_id).collect::<Vec<_>>(), vec![child.get_id()]);
+    let roots = expect(S::list_clients(state.clone(), None).await, "list_clients");
+    assert!(roots.iter().all(|client| client.get_id() != child.get_id()));
+
+    let error = expect_error(S::create_client(state.clone(), NewClient { parent_id: Some(unknown_id()), name: "orphan".to_string(), ..NewClient::default() }).await, "create_client");
+    assert!(matches!(error, StoreError::NotFound), "unknown parent: expected not found, got {error}");
+
+    let error = expect_error(S::delete_client(state.clone(), parent.get_id()).await, "delete_client");
+    assert!(matches!(error, StoreError::Conflict), "client with children: expected conflict, got {error}");
+
+    let update = ClientUpdate { parent_id: Some(None), ..ClientUpdate::default() };
+    let child = expect(S::update_client(state.clone(), child.get_id(), update).await, "update_client");
+    assert_eq!(child.get_parent_id(), None);
+    expect(S::delete_client(state.clone(), parent.get_id()).await, "delete_client");
+}
+
+/// Tokens can't be inserted twice and only one concurrent consumer gets them, missing ones aren't found
+pub async fn single_use_tokens<S>(state: S::State)
+where
+    S: TokenStore + UserStore + ClientStore,
+{
+    let client = create_client::<S>(&state, None).await;
+    let user = create_user::<S>(&state, client.get_id(), "alice@example.com").await;
+    let token = TokenRecord {
+        hash: b"conformance".to_vec(),
+        kind: TokenKind::Refresh,
+        user_id: user.get_id(),
+        client_id: client.get_id(),
+        expires_at: SystemTime::UNIX_EPOCH + Duration::from_secs(60),
+        data: b"data".to_vec(),
+    };
+
+    for (operation, error) in [
+        ("get_token", expect_error(S::get_token(state.clone(), &token.hash).await, "get_token")),
+        ("consume_token", expect_error(S::consume_token(state.clone(), &token.hash).await, "consume_token")),
+    ] {
+        assert!(matches!(error, StoreError::NotFound), "{operation}: expected not found, got {error}");
+    }
+
+    expect(S::insert_token(state.clone(), token.clone()).await, "insert_token");
+    let error = expect_error(S::insert_token(state.clone(), token.clone()).await, "insert_token");
+    assert!(matches!(error, StoreError::Conflict), "duplicated token: expected conflict, got {error}");
+    assert_eq!(expect(S::get_token(state.clone(), &token.hash).await, "get_token"), token);
+
+    let mut tasks = JoinSet::new();
+    for _ in 0..CONCURRENCY {
+        let state = state.clone();
+        let hash = token.hash.clone();
+        tasks.spawn(async move { S::consume_token(state, &hash).await.map_err(Into::into) });
+    }
+    let mut consumed = 0;
+    while let Some(result) = tasks.join_next().await {
+        match result.unwrap() {
+            Ok(record) => {
+                assert_eq!(record, token);
+                consumed += 1;
+            }
+            Err(StoreError::NotFound) => {}
+            Err(error) => panic!("concurrent consume_token: expected not found, got {error}"),
+        }
+    }
+    assert_eq!(consumed, 1);
+}
+
+/// Missing sessions aren't found, and deleting users or clients removes everything that belongs to them
+pub async fn cascading_deletes<S>(state: S::State)
+where
+    S: SessionStore + UserStore + ClientStore,
+{
+    let client = create_client::<S>(&state, None).await;
+    let user = create_user::<S>(&state, client.get_id(), "alice@example.com").await;
+    expect(S::set_signing_key_bytes(state.clone(), client.get_id(), SecretBytes::new(vec![1, 2, 3])).await, "set_signing_key_bytes");
+    let key = expect(S::get_signing_key_bytes(state.clone(), client.get_id()).await, "get_signing_key_bytes");
+    assert_eq!(key.expose_secret(), &[1, 2, 3]);
+
+    let session = NewSession {
+        user_id: user.get_id(),
+        client_id: client.get_id(),
+        expires_at: SystemTime::UNIX_EPOCH + Duration::from_secs(60),
+    };
+    for (operation, error) in [
+        ("get_session", expect_error(S::get_session(state.clone(), unknown_id()).await, "get_session")),
+        ("delete_session", expect_error(S::delete_session(state.clone(), unknown_id()).await, "delete_session")),
+    ] {
+        assert!(matches!(error, StoreError::NotFound), "{operation}: expected not found, got {error}");
+    }
+    let session = expect(S::create_session(state.clone(), session).await, "create_session");
+    assert_eq!(expect(S::get_session(state.clone(), session.id).await, "get_session"), session);
+
+    expect(S::delete_user(state.clone(), user.get_id()).await, "delete_user");
+    let error = expect_error(S::get_session(state.clone(), session.id).await, "get_session");
+    assert!(matches!(error, StoreError::NotFound), "session of deleted user: expected not found, got {error}");
+
+    let user = create_user::<S>(&state, client.get_id(), "alice@example.com").await;
+    expect(S::delete_client(state.clone(), client.get_id()).await, "delete_client");
+    let error = expect_error(S::get_user(state.clone(), user.get_id()).await, "get_user");
+    assert!(matches!(error, StoreError::NotFound), "user of deleted client: expected not found, got {error}");
+    let error = expect_error(S::get_signing_key_bytes(state.clone(), client.get_id()).await, "get_signing_key_bytes");
+    assert!(matches!(error, StoreError::NotFound), "key of deleted client: expected not found, got {error}");
+}
+
+/// Clients start without credentials, and the stored ones are replaced as a whole and deleted with the client
+pub async fn client_credentials<S>(state: S::State)
+where
+    S: ClientStore,
+{
+    let client = create_client::<S>(&state, None).await;
+    let credentials = expect(S::get_credentials(state.clone(), client.get_id()).await, "get_credentials");
+    assert_eq!(credentials, ClientCredentials::default());
+
+    let credentials = ClientCredentials {
+        secret_hash: Some("$argon2id$v=19$m=19456,t=2,p=1$c2FsdA$aGFzaA".to_string()),
+        public_key: Some(vec![4, 1, 2, 3]),
+    };
+    expect(S::set_credentials(state.clone(), client.get_id(), credentials.clone()).await, "set_credentials");
+    assert_eq!(expect(S::get_credentials(state.clone(), client.get_id()).await, "get_credentials"), credentials);
+
+    let credentials = ClientCredentials { secret_hash: None, ..credentials };
+    expect(S::set_credentials(state.clone(), client.get_id(), credentials.clone()).await, "set_credentials");
+    assert_eq!(expect(S::get_credentials(state.clone(), client.get_id()).await, "get_credentials"), credentials);
+
+    expect(S::delete_client(state.clone(), client.get_id()).await, "delete_client");
+    let error = expect_error(S::get_credentials(state.clone(), client.get_id()).await, "get_credentials");
+    assert!(matches!(error, StoreError::NotFound), "credentials of deleted client: expected not found, got {error}");
+}
+
+/// Users start without credentials, and the stored ones are replaced as a whole and deleted with the user
+pub async fn user_credentials<S>(state: S::State)
+where
+    S: UserStore + ClientStore,
+{
+    type Totp = (Vec<u8>, bool, Option<u64>);
+    fn summary(credentials: &UserCredentials) -> (Option<Totp>, Vec<Vec<u8>>, Vec<PasskeyCredential>, Vec<UserPublicKey>) {
+        let totp = credentials.totp.as_ref().map(|totp| (totp.secret.expose_secret().clone(), totp.confirmed, totp.last_used_step));
+        (totp, credentials.recovery_codes.clone(), credentials.passkeys.clone(), credentials.public_keys.clone())
+    }
+
+    let client = create_client::<S>(&state, None).await;
+    let user = create_user::<S>(&state, client.get_id(), "alice@example.com").await;
+    let credentials = expect(S::get_user_credentials(state.clone(), user.get_id()).await, "get_user_credentials");
+    assert_eq!(summary(&credentials), (None, vec![], vec![], vec![]));
+
+    let totp = TotpCredential { secret: SecretBytes::new(vec![7; 20]), confirmed: false, last_used_step: None };
+    let credentials = UserCredentials { totp: Some(totp), ..UserCredentials::default() };
+    expect(S::set_user_credentials(state.clone(), user.get_id(), credentials.clone()).await, "set_user_credentials");
+    let stored = expect(S::get_user_credentials(state.clone(), user.get_id()).await, "get_user_credentials");
+    assert_eq!(summary(&stored), summary(&credentials));
+
+    let totp = TotpCredential { secret: SecretBytes::new(vec![7; 20]), confirmed: true, last_used_step: Some(u64::from(u32::MAX) + 1) };
+    let passkey = PasskeyCredential { id: vec![9; 16], public_key: vec![4; 65], sign_count: u32::MAX, aaguid: [3; 16] };
+    let public_key = UserPublicKey {
+        id: "00112233445566778899aabbccddeeff".to_string(),
+        curve: Curve::NistP256,
+        public_key: vec![4; 65],
+        label: "laptop".to_string(),
+        created_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        last_used_at: Some(SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_123)),
+        revoked_at: None,
+    };
+    let credentials = UserCredentials {
+        totp: Some(totp),
+        recovery_codes: vec![vec![1; 32], vec![2; 32]],
+        passkeys: vec![passkey],
+        public_keys: vec![public_key],
+    };
+    expect(S::set_user_credentials(state.clone(), user.get_id(), credentials.clone()).await, "set_user_credentials");
+    let stored = expect(S::get_user_credentials(state.clone(), user.get_id()).await, "get_user_credentials");
+    assert_eq!(summary(&stored), summary(&credentials));
+
+    expect(S::set_user_credentials(state.clone(), user.get_id(), UserCredentials::default()).await, "set_user_credentials");
+    let stored = expect(S::get_user_credentials(state.clone(), user.get_id()).await, "get_user_credentials");
+    assert_eq!(summary(&stored), (None, vec![], vec![], vec![]));
+
+    expect(S::set_user_credentials(state.clone(), user.get_id(), credentials).await, "set_user_credentials");
+    expect(S::delete_user(state.clone(), user.get_id()).await, "delete_user");
+    let error = expect_error(S::get_user_credentials(state.clone(), user.get_id()).await, "get_user_credentials");
+    assert!(matches!(error, StoreError::NotFound), "credentials of deleted user: expected not found, got {error}");
+}
+
+/// Every write bumps the version, and updates with a stale expected version fail with
+/// [`StoreError::VersionMismatch`] without changing anything
+pub async fn optimistic_concurrency<S>(state: S::State)
+where
+    S: UserStore + ClientStore,
+{
+    let client = create_client::<S>(&state, None).await;
+    let user = create_user::<S>(&state, client.get_id(), "alice@example.com").await;
+
+    let update = UserUpdate { expected_version: Some(user.get_version()), ..UserUpdate::default() };
+    let updated = expect(S::update_user(state.clone(), user.get_id(), update).await, "update_user");
+    assert!(updated.get_version() > user.get_version());
+
+    let update = UserUpdate {
+        email: Some("bob@example.com".to_string()),
+        metadata: None,
+        expected_version: Some(user.get_version()),
+    };
+    let error = expect_error(S::update_user(state.clone(), user.get_id(), update).await, "update_user");
+    assert!(matches!(error, StoreError::VersionMismatch), "stale user version: expected version mismatch, got {error}");
+    let current = expect(S::get_user(state.clone(), user.get_id()).await, "get_user");
+    assert_eq!((current.get_email(), current.get_version()), ("alice@example.com", updated.get_version()));
+
+    let update = ClientUpdate { name: Some("renamed".to_string()), expected_version: Some(client.get_version()), ..ClientUpdate::default() };
+    let renamed = expect(S::update_client(state.clone(), client.get_id(), update).await, "update_client");
+    assert!(renamed.get_version() > client.get_version());
+    let update = ClientUpdate { name: Some("stale".to_string()), expected_version: Some(client.get_version()), ..ClientUpdate::default() };
+    let error = expect_error(S::update_client(state.clone(), client.get_id(), update).await, "update_client");
+    assert!(matches!(error, StoreError::VersionMismatch), "stale client version: expected version mismatch, got {error}");
+
+    let update = UserUpdate { expected_version: Some(1), ..UserUpdate::default() };
+    let error = expect_error(S::update_user(state.clone(), unknown_id(), update).await, "update_user");
+    assert!(matches!(error, StoreError::NotFound), "missing user: expected not found, got {error}");
+}
+
+/// Committed changes are visible, rolled back and dropped ones are discarded
+pub async fn transactions<S>(state: S::State)
+where
+    S: Transaction + UserStore + ClientStore,
+{
+    let client = create_client::<S>(&state, None).await;
+
+    let transaction = expect(S::begin(state.clone()).await, "begin");
+    let committed = create_user::<S>(&transaction, client.get_id(), "committed@example.com").await;
+    expect(S::get_user(transaction.clone(), committed.get_id()).await, "get_user inside of the transaction");
+    expect(S::commit(transaction).await, "commit");
+    expect(S::get_user(state.clone(), committed.get_id()).await, "get_user after commit");
+
+    let transaction = expect(S::begin(state.clone()).await, "begin");
+    let rolled_back = create_user::<S>(&transaction, client.get_id(), "rolled-back@example.com").await;
+    expect(S::delete_user(transaction.clone(), committed.get_id()).await, "delete_user");
+    expect(S::rollback(transaction).await, "rollback");
+    let error = expect_error(S::get_user(state.clone(), rolled_back.get_id()).await, "get_user after rollback");
+    assert!(matches!(error, StoreError::NotFound), "rolled back create: expected not found, got {error}");
+    expect(S::get_user(state.clone(), committed.get_id()).await, "get_user after rolled back delete");
+
+    let transaction = expect(S::begin(state.clone()).await, "begin");
+    let dropped = create_user::<S>(&transaction, client.get_id(), "dropped@example.com").await;
+    drop(transaction);
+    let error = expect_error(S::get_user(state.clone(), dropped.get_id()).await, "get_user after drop");
+    assert!(matches!(error, StoreError::NotFound), "dropped create: expected not found, got {error}");
+
+    let result = atomically::<S, (), _, _, _, _>(state.clone(), &RetryPolicy::default(), |_| async {}, |transaction| {
+        let client_id = client.get_id();
+        async move {
+            S::create_user(transaction, new_user::<S>(client_id, "failed@example.com")).await.map_err(Into::into)?;
+            Err(StoreError::PermissionDenied)
+        }
+    }).await;
+    assert!(matches!(result, Err(StoreError::PermissionDenied)));
+    let error = expect_error(S::get_user_by_email(state.clone(), client.get_id(), "failed@example.com").await, "get_user_by_email");
+    assert!(matches!(error, StoreError::NotFound), "failed operation: expected not found, got {error}");
+}
+
+/// Read-modify-write cycles run with [`atomically`] don't lose updates when they race each other
+pub async fn concurrent_transactions<S>(state: S::State)
+where
+    S: Transaction + ClientStore,
+{
+    let client = create_client::<S>(&state, None).await;
+    let client_id = client.get_id();
+    let update = ClientUpdate { name: Some("0".to_string()), ..ClientUpdate::default() };
+    expect(S::update_client(state.clone(), client_id, update).await, "update_client");
+
+    let policy = RetryPolicy { max_attempts: CONCURRENCY as u32 * 4, ..RetryPolicy::default() };
+    let mut tasks = JoinSet::new();
+    for _ in 0..CONCURRENCY {
+        let state = state.clone();
+        tasks.spawn(async move {
+            atomically::<S, (), _, _, _, _>(state, &policy, |_| tokio::task::yield_now(), |transaction| async move {
+                let client = S::get_client(transaction.clone(), client_id).await.map_err(Into::into)?;
+                let counter = client.get_name().parse::<usize>().expect("the name is a counter");
+                let update = ClientUpdate { name: Some((counter + 1).to_string()), ..ClientUpdate::default() };
+                S::update_client(transaction, client_id, update).await.map_err(Into::into)?;
+                Ok(())
+            }).await
+        });
+    }
+    while let Some(result) = tasks.join_next().await {
+        if let Err(error) = result.unwrap() {
+            panic!("concurrent atomically: {error}");
+        }
+    }
+
+    let client = expect(S::get_client(state, client_id).await, "get_client");
+    assert_eq!(client.get_name(), CONCURRENCY.to_string());
+}
+
+/// Every key has its own bucket and lockout, and concurrent attempts never take more than the burst
+pub async fn rate_limits<S>(state: S::State)
+where
+    S: RateLimitStore,
+{
+    let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+    let limit = RateLimit { burst: 3, interval: Duration::from_secs(10) };
+    let key = RateLimitKey::Ip("192.0.2.1".parse().unwrap());
+    let client_id = unknown_id();
+    let other = RateLimitKey::email(client_id, "Alice@example.com");
+
+    let mut tasks = JoinSet::new();
+    for _ in 0..CONCURRENCY {
+        let state = state.clone();
+        let key = key.clone();
+        tasks.spawn(async move { S::acquire(state, &key, limit, now).await.map_err(Into::into) });
+    }
+    let mut allowed = 0;
+    while let Some(result) = tasks.join_next().await {
+        match expect(result.unwrap(), "concurrent acquire") {
+            RateLimitDecision::Allowed => allowed += 1,
+            RateLimitDecision::Limited { retry_after } => assert_eq!(retry_after, limit.interval),
+        }
+    }
+    assert_eq!(allowed, limit.burst);
+    assert_eq!(expect(S::acquire(state.clone(), &other, limit, now).await, "acquire"), RateLimitDecision::Allowed);
+    let later = now + limit.interval;
+    assert_eq!(expect(S::acquire(state.clone(), &key, limit, later).await, "acquire"), RateLimitDecision::Allowed);
+    let decision = expect(S::acquire(state.clone(), &key, limit, later).await, "acquire");
+    assert_eq!(decision, RateLimitDecision::Limited { retry_after: limit.interval });
+
+    let policy = LockoutPolicy {
+        max_failures: 1,
+        base: Duration::from_secs(60),
+        max: Duration::from_secs(3600),
+        reset_after: Duration::from_secs(86400),
+    };
+    assert_eq!(expect(S::get_lockout(state.clone(), &other).await, "get_lockout"), Lockout::default());
+    expect(S::record_failure(state.clone(), &other, policy, now).await, "record_failure");
+    let lockout = expect(S::record_failure(state.clone(), &other, policy, now).await, "record_failure");
+    assert_eq!(lockout, Lockout { failures: 2, last_failure: Some(now), locked_until: Some(now + policy.base) });
+    assert_eq!(expect(S::get_lockout(state.clone(), &other).await, "get_lockout"), lockout);
+    // Emails are keyed ignoring case
+    let uppercase = RateLimitKey::email(client_id, "ALICE@EXAMPLE.COM");
+    assert_eq!(expect(S::get_lockout(state.clone(), &uppercase).await, "get_lockout"), lockout);
+    assert_eq!(expect(S::get_lockout(state.clone(), &key).await, "get_lockout"), Lockout::default());
+
+    expect(S::clear_failures(state.clone(), &other).await, "clear_failures");
+    assert_eq!(expect(S::get_lockout(state.clone(), &other).await, "get_lockout"), Lockout::default());
+    expect(S::clear_failures(state, &other).await, "clear_failures");
+}
+
+fn audit_record(sequence: u64) -> AuditRecord {
+    AuditRecord {
+        sequence,
+        data: format!("event {sequence}").into_bytes(),
+        previous_hash: vec![sequence as u8; 32],
+        hash: vec![sequence as u8 + 1; 32],
+        signature: vec![0xAB; 64],
+    }
+}
+
+/// Records are only appended at the next sequence, also when many writers race for it
+pub async fn audit_log<S>(state: S::State)
+where
+    S: AuditStore,
+{
+    assert_eq!(expect(S::last_audit_record(state.clone()).await, "last_audit_record"), None);
+    let error = expect_error(S::append_audit_record(state.clone(), audit_record(1)).await, "append_audit_record");
+    assert!(matches!(error, StoreError::VersionMismatch), "gap: expected version mismatch, got {error}");
+    expect(S::append_audit_record(state.clone(), audit_record(0)).await, "append_audit_record");
+    let error = expect_error(S::append_audit_record(state.clone(), audit_record(0)).await, "append_audit_record");
+    assert!(matches!(error, StoreError::VersionMismatch), "stale sequence: expected version mismatch, got {error}");
+    assert_eq!(expect(S::last_audit_record(state.clone()).await, "last_audit_record"), Some(audit_record(0)));
+
+    let mut tasks = JoinSet::new();
+    for _ in 0..CONCURRENCY {
+        let state = state.clone();
+        tasks.spawn(async move {
+            loop {
+                let last = S::last_audit_record(state.clone()).await.map_err(Into::into)?;
+                let sequence = last.map_or(0, |record| record.sequence + 1);
+                match S::append_audit_record(state.clone(), audit_record(sequence)).await.map_err(Into::into) {
+                    Err(StoreError::VersionMismatch) => tokio::task::yield_now().await,
+                    result => return result,
+                }
+            }
+        });
+    }
+    while let Some(result) = tasks.join_next().await {
+        expect(result.unwrap(), "concurrent append_audit_record");
+    }
+
+    let records = expect(S::list_audit_records(state.clone(), 0, usize::MAX).await, "list_audit_records");
+    assert_eq!(records, (0..=CONCURRENCY as u64).map(audit_record).collect::<Vec<_>>());
+    let records = expect(S::list_audit_records(state.clone(), 3, 2).await, "list_audit_records");
+    assert_eq!(records, [audit_record(3), audit_record(4)]);
+    let records = expect(S::list_audit_records(state, CONCURRENCY as u64 + 1, 10).await, "list_audit_records");
+    assert!(records.is_empty());
+}
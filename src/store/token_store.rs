@@ -0,0 +1,35 @@
+use std::time::SystemTime;
+
+use crate::data::id::Identifier;
+use crate::store::Store;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TokenKind {
+    Refresh,
+    AuthorizationCode,
+    /// Login waiting for the second factor of the user
+    PendingLogin,
+    /// Challenge of a WebAuthn ceremony
+    PasskeyChallenge,
+}
+
+/// Opaque tokens are never stored in plain, only a hash of them, the `data` is free for the service that
+/// issued the token to store whatever it needs to redeem it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TokenRecord {
+    pub hash: Vec<u8>,
+    pub kind: TokenKind,
+    pub user_id: Identifier,
+    pub client_id: Identifier,
+    pub expires_at: SystemTime,
+    pub data: Vec<u8>,
+}
+
+#[async_trait::async_trait]
+pub trait TokenStore: Store {
+    async fn insert_token(state: Self::State, token: TokenRecord) -> Result<(), Self::Error>;
+    async fn get_token(state: Self::State, hash: &[u8]) -> Result<TokenRecord, Self::Error>;
+    /// Removes and returns the token, only one of many concurrent calls with the same hash can succeed
+    async fn consume_token(state: Self::State, hash: &[u8]) -> Result<TokenRecord, Self::Error>;
+    async fn revoke_user_tokens(state: Self::State, user_id: Identifier) -> Result<(), Self::Error>;
+}
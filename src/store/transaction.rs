@@ -0,0 +1,80 @@
+use std::future::Future;
+use std::time::Duration;
+
+use crate::store::{RetryPolicy, Store, StoreError};
+
+/// Groups store operations so they are applied all together or not at all. [`Transaction::begin`] returns
+/// a new state that can be passed to any store function, the changes made through it are only visible to
+/// it until [`Transaction::commit`] is called.
+///
+/// The transaction state must not be used after commit or rollback, and dropping it without committing
+/// rolls it back. Committing or rolling back a state that didn't come from `begin` does nothing
+#[async_trait::async_trait]
+pub trait Transaction: Store {
+    async fn begin(state: Self::State) -> Result<Self::State, Self::Error>;
+    /// Fails with [`StoreError::VersionMismatch`] when the changes conflict with another transaction
+    async fn commit(state: Self::State) -> Result<(), Self::Error>;
+    async fn rollback(state: Self::State) -> Result<(), Self::Error>;
+}
+
+/// Runs `operation` inside of a transaction that is committed when it succeeds and rolled back when it
+/// fails, the whole transaction is retried following the `policy` for [retryable](StoreError::is_retryable)
+/// errors
+pub async fn atomically<S, T, Operation, OperationFuture, Sleep, SleepFuture>(
+    state: S::State,
+    policy: &RetryPolicy,
+    mut sleep: Sleep,
+    mut operation: Operation,
+) -> Result<T, StoreError>
+where
+    S: Transaction,
+    Operation: FnMut(S::State) -> OperationFuture,
+    OperationFuture: Future<Output = Result<T, StoreError>>,
+    Sleep: FnMut(Duration) -> SleepFuture,
+    SleepFuture: Future<Output = ()>,
+{
+    let mut retry = 0;
+    loop {
+        let result = async {
+            let transaction = S::begin(state.clone()).await.map_err(Into::into)?;
+            match operation(transaction.clone()).await {
+                Ok(value) => {
+                    S::commit(transaction).await.map_err(Into::into)?;
+                    Ok(value)
+                }
+                Err(error) => {
+                    // NOTE: The error of the operation is the one worth returning, a failed rollback is only logged
+                    if let Err(rollback_error) = S::rollback(transaction).await.map_err(Into::<StoreError>::into) {
+                        tracing::warn!(error = %rollback_error, "failed to roll back transaction");
+                    }
+                    Err(error)
+                }
+            }
+        }.await;
+        match result {
+            Err(error) if error.is_retryable() && retry + 1 < policy.max_attempts => {
+                sleep(policy.backoff(retry)).await;
+                retry += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(all(test, feature = "in-memory"))]
+mod tests {
+    use crate::store::memory::{InMemoryState, InMemoryStore};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn failed_rollbacks_keep_the_error() {
+        // NOTE: The operation finishes the transaction itself, so the rollback after its error fails
+        let policy = RetryPolicy::default();
+        let result = atomically::<InMemoryStore, (), _, _, _, _>(InMemoryState::new(), &policy, |_| async {}, |transaction| async {
+            InMemoryStore::commit(transaction).await?;
+            Err(StoreError::PermissionDenied)
+        }).await;
+        assert!(matches!(result, Err(StoreError::PermissionDenied)));
+    }
+}
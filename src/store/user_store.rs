@@ -1,5 +1,111 @@
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::crypto::secret::SecretBytes;
+use crate::data::id::Identifier;
+use crate::model::{Curve, User};
 use crate::store::Store;
 
+/// Metadata type of the users handled by the store `S`
+pub type UserMetadataOf<S> = <<S as UserStore>::User as User>::UserMetadata;
+
+#[derive(Debug, Clone)]
+pub struct NewUser<M> {
+    pub client_id: Identifier,
+    pub email: String,
+    pub metadata: Option<M>,
+}
+
+/// Partial update of a user, the fields set to `None` are left untouched
+#[derive(Debug, Clone)]
+pub struct UserUpdate<M> {
+    pub email: Option<String>,
+    pub metadata: Option<M>,
+    /// Compare-and-swap guard, the update fails with [`crate::store::StoreError::VersionMismatch`] if the
+    /// stored user has another version
+    pub expected_version: Option<u64>,
+}
+
+impl<M> Default for UserUpdate<M> {
+    fn default() -> Self {
+        Self {
+            email: None,
+            metadata: None,
+            expected_version: None,
+        }
+    }
+}
+
+/// TOTP second factor of a user, see [`crate::crypto::totp`]
+#[derive(Debug, Clone, Default)]
+pub struct TotpCredential {
+    /// Shared HMAC key, sealed by the [`EncryptedStore`](crate::store::encrypted::EncryptedStore)
+    pub secret: SecretBytes,
+    /// Enrolled secrets only protect the login once the user entered a code generated with them
+    pub confirmed: bool,
+    /// Time step of the last accepted code, the codes of this step and older ones are rejected
+    pub last_used_step: Option<u64>,
+}
+
+/// WebAuthn credential of a user, see [`crate::service::webauthn`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PasskeyCredential {
+    /// Credential id chosen by the authenticator
+    pub id: Vec<u8>,
+    /// SEC1 encoded P-256 key, ES256 is the only accepted algorithm
+    pub public_key: Vec<u8>,
+    /// Signature counter of the last assertion, stays at zero for authenticators without a counter
+    pub sign_count: u32,
+    /// Model of the authenticator, all zeros when it isn't disclosed
+    pub aaguid: [u8; 16],
+}
+
+/// Schnorr public key of a user, usually one per device, see [`crate::service::user_keys`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserPublicKey {
+    /// Fingerprint of the key, see [`key_id`]
+    pub id: String,
+    pub curve: Curve,
+    /// Uncompressed SEC1 encoding, the `public_key` of the proofs
+    pub public_key: Vec<u8>,
+    pub label: String,
+    pub created_at: SystemTime,
+    pub last_used_at: Option<SystemTime>,
+    /// Revoked keys are kept so they can't be added again
+    pub revoked_at: Option<SystemTime>,
+}
+
+/// Hex of the first 16 bytes of the SHA-256 of the SEC1 encoded key, the same key always gets the same id
+pub fn key_id(public_key: &[u8]) -> String {
+    hex::encode(&Sha256::digest(public_key)[..16])
+}
+
+/// Authentication factors of a user, replaced as a whole on every change
+#[derive(Debug, Clone, Default)]
+pub struct UserCredentials {
+    pub totp: Option<TotpCredential>,
+    /// SHA-256 hashes of the recovery codes that weren't used yet
+    pub recovery_codes: Vec<Vec<u8>>,
+    pub passkeys: Vec<PasskeyCredential>,
+    pub public_keys: Vec<UserPublicKey>,
+}
+
+/// Users belong to a single client and their email is unique inside of it, emails are always compared
+/// case insensitively
 #[async_trait::async_trait]
 pub trait UserStore: Store {
-}
\ No newline at end of file
+    type User: User + Send + Sync;
+
+    async fn create_user(state: Self::State, user: NewUser<UserMetadataOf<Self>>) -> Result<Self::User, Self::Error>;
+    async fn get_user(state: Self::State, id: Identifier) -> Result<Self::User, Self::Error>;
+    async fn get_user_by_email(state: Self::State, client_id: Identifier, email: &str) -> Result<Self::User, Self::Error>;
+    async fn list_users(state: Self::State, client_id: Identifier) -> Result<Vec<Self::User>, Self::Error>;
+    async fn update_user(state: Self::State, id: Identifier, update: UserUpdate<UserMetadataOf<Self>>) -> Result<Self::User, Self::Error>;
+    async fn delete_user(state: Self::State, id: Identifier) -> Result<(), Self::Error>;
+
+    /// Credentials of an existing user, the default ones when they were never set
+    async fn get_user_credentials(state: Self::State, user_id: Identifier) -> Result<UserCredentials, Self::Error>;
+    async fn set_user_credentials(state: Self::State, user_id: Identifier, credentials: UserCredentials) -> Result<(), Self::Error>;
+}
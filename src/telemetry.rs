@@ -0,0 +1,155 @@
+//! Tracing and metrics of the services. Spans are emitted with [`tracing`] and metrics through the
+//! [`metrics`] facade, both do nothing until the application installs a subscriber and a recorder, like
+//! `tracing-subscriber` and `metrics-exporter-prometheus`. [`describe_metrics`] registers the units and
+//! descriptions of the metrics below with the recorder.
+//!
+//! Spans never carry secrets, proofs or codes, and emails only go through [`redact_email`]. The `reason`
+//! labels come from [`FailureReason`] so they can't grow with user input
+
+use std::sync::OnceLock;
+use std::time::Instant;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+static REDACTION_KEY: OnceLock<Hmac<Sha256>> = OnceLock::new();
+
+/// Counter of the authentication events by `action`, `outcome` and `reason`, the same events the
+/// [`AuditSink`](crate::service::audit::AuditSink) is given
+pub const AUTH_EVENTS: &str = "iam0_auth_events_total";
+/// Counter of the events the [`AuditSink`](crate::service::audit::AuditSink) failed to record, by `action`
+pub const DROPPED_AUDIT_EVENTS: &str = "iam0_audit_events_dropped_total";
+/// Histogram of the time spent checking a Schnorr proof, by `curve`
+pub const PROOF_VERIFICATION_SECONDS: &str = "iam0_proof_verification_seconds";
+/// Histogram of the time spent signing the tokens of a login or token request
+pub const TOKEN_SIGNING_SECONDS: &str = "iam0_token_signing_seconds";
+/// Histogram of the store calls by `operation` and `result`, see
+/// [`InstrumentedStore`](crate::store::instrumented::InstrumentedStore)
+pub const STORE_CALL_SECONDS: &str = "iam0_store_call_seconds";
+
+/// Label of a failure on [`AUTH_EVENTS`], it has to come from a small fixed set
+pub trait FailureReason {
+    fn failure_reason(&self) -> &str;
+}
+
+pub fn describe_metrics() {
+    metrics::describe_counter!(AUTH_EVENTS, metrics::Unit::Count, "Authentication events by action, outcome and reason");
+    metrics::describe_counter!(DROPPED_AUDIT_EVENTS, metrics::Unit::Count, "Audit events the sink failed to record by action");
+    metrics::describe_histogram!(PROOF_VERIFICATION_SECONDS, metrics::Unit::Seconds, "Time spent checking a login proof");
+    metrics::describe_histogram!(TOKEN_SIGNING_SECONDS, metrics::Unit::Seconds, "Time spent signing tokens");
+    metrics::describe_histogram!(STORE_CALL_SECONDS, metrics::Unit::Seconds, "Latency of the store calls by operation");
+}
+
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("the redaction key is already set")]
+pub struct RedactionKeyAlreadySet;
+
+/// Sets the deployment secret [`redact_email`] keys its hashes with, once at startup like the recorder
+/// and the subscriber. The secret shouldn't be shared with anything else
+pub fn set_redaction_key(key: &[u8]) -> Result<(), RedactionKeyAlreadySet> {
+    let mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    REDACTION_KEY.set(mac).map_err(|_| RedactionKeyAlreadySet)
+}
+
+/// Email as it may appear in spans and logs. The domain is kept and the local part is replaced with an
+/// HMAC under the key of [`set_redaction_key`], so the attempts on an account can still be told apart by
+/// whoever reads the logs but not guessed from a list of addresses. Without a key the local part is dropped
+pub fn redact_email(email: &str) -> String {
+    let (local, domain) = email.rsplit_once('@').unwrap_or((email, ""));
+    let local = match REDACTION_KEY.get() {
+        Some(mac) => {
+            let mut mac = mac.clone();
+            mac.update(local.to_lowercase().as_bytes());
+            hex::encode(&mac.finalize().into_bytes()[..8])
+        }
+        None => "*".to_string(),
+    };
+    format!("{local}@{}", domain.to_lowercase())
+}
+
+/// Runs `f` and records how long it took on the histogram `name`
+pub(crate) fn timed<T>(name: &'static str, labels: &[(&'static str, &'static str)], f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let value = f();
+    metrics::histogram!(name, labels).record(start.elapsed().as_secs_f64());
+    value
+}
+
+/// Recorder of the tests checking the metrics
+#[cfg(all(test, feature = "in-memory"))]
+pub(crate) mod fixtures {
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    use metrics::{Counter, CounterFn, Gauge, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit};
+
+    /// Recorder keeping the counters, to check them with [`metrics::set_default_local_recorder`]
+    #[derive(Default)]
+    pub(crate) struct TestRecorder {
+        counters: Mutex<BTreeMap<String, Arc<TestCounter>>>,
+    }
+
+    #[derive(Default)]
+    struct TestCounter(Mutex<u64>);
+
+    impl CounterFn for TestCounter {
+        fn increment(&self, value: u64) {
+            *self.0.lock().unwrap() += value;
+        }
+
+        fn absolute(&self, value: u64) {
+            *self.0.lock().unwrap() = value;
+        }
+    }
+
+    impl TestRecorder {
+        /// Value of the counter, the key is the name followed by the labels like `name{a=b,c=d}`
+        pub(crate) fn counter(&self, key: &str) -> u64 {
+            self.counters.lock().unwrap().get(key).map_or(0, |counter| *counter.0.lock().unwrap())
+        }
+    }
+
+    impl Recorder for TestRecorder {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            let labels: Vec<_> = key.labels().map(|label| format!("{}={}", label.key(), label.value())).collect();
+            let name = format!("{}{{{}}}", key.name(), labels.join(","));
+            Counter::from_arc(self.counters.lock().unwrap().entry(name).or_default().clone())
+        }
+
+        fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, _: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::noop()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::Digest;
+
+    use super::*;
+
+    // NOTE: The only test setting the key, the unkeyed redaction is checked before
+    #[test]
+    fn redacted_emails() {
+        assert_eq!(redact_email("Alice@Example.com"), "*@example.com");
+        assert_eq!(redact_email("no-domain"), "*@");
+
+        set_redaction_key(b"deployment secret").unwrap();
+        assert_eq!(set_redaction_key(b"other secret"), Err(RedactionKeyAlreadySet));
+        let redacted = redact_email("Alice@Example.com");
+        assert_eq!(redacted, redact_email("alice@example.com"));
+        assert!(redacted.ends_with("@example.com") && !redacted.contains("alice"), "{redacted}");
+        assert_ne!(redacted, redact_email("bob@example.com"));
+        // An unkeyed hash of the local part could be found from a list of addresses
+        assert!(!redacted.starts_with(&hex::encode(&Sha256::digest(b"alice")[..4])));
+        assert_eq!(redact_email("no-domain").len(), "0000000000000000@".len());
+    }
+}
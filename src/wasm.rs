@@ -0,0 +1,112 @@
+//! Browser bindings of the [`client`](crate::client) module for the login page. Keys and identifiers
+//! cross the boundary as hex strings and the requests as JSON, ready to be sent with `fetch`
+
+use serde_json::Value;
+use wasm_bindgen::prelude::*;
+
+use crate::client::{self, ClientError, UserKey};
+use crate::data::id::Identifier;
+use crate::model::Curve;
+use crate::service::spec::GetSpecResponse;
+
+fn curve(name: &str) -> Result<Curve, ClientError> {
+    Curve::from_name(name).ok_or_else(|| ClientError::UnknownCurve(name.to_string()))
+}
+
+fn identifier(hex: &str) -> Result<Identifier, JsError> {
+    Identifier::from_hex(hex).ok_or_else(|| JsError::new("the client_id isn't a hex identifier"))
+}
+
+fn user_key(curve_name: &str, private_key: &str) -> Result<UserKey, JsError> {
+    let bytes = crate::crypto::secret::Secret::new(hex::decode(private_key).map_err(|_| ClientError::InvalidKey)?);
+    Ok(UserKey::from_bytes(curve(curve_name)?, bytes.expose_secret())?)
+}
+
+/// New private key on the first compiled in curve the `getSpec` response accepts, `[curve, key]`
+#[wasm_bindgen(js_name = generateKey)]
+pub fn generate_key(spec_json: &str) -> Result<Vec<String>, JsError> {
+    let spec: GetSpecResponse = serde_json::from_str(spec_json)?;
+    let key = UserKey::generate(client::choose_curve(&spec)?);
+    Ok(vec![key.curve().name().to_string(), hex::encode(key.to_bytes().expose_secret())])
+}
+
+/// Private key derived from the password of the user, see [`UserKey::from_password`]
+#[wasm_bindgen(js_name = deriveKey)]
+pub fn derive_key(curve_name: &str, password: &str, client_id: &str, email: &str) -> Result<String, JsError> {
+    let salt = client::password_salt(identifier(client_id)?, email);
+    let key = UserKey::from_password(curve(curve_name)?, password.as_bytes(), &salt)?;
+    Ok(hex::encode(key.to_bytes().expose_secret()))
+}
+
+/// Uncompressed SEC1 public key of a private key
+#[wasm_bindgen(js_name = publicKey)]
+pub fn public_key(curve_name: &str, private_key: &str) -> Result<String, JsError> {
+    Ok(hex::encode(user_key(curve_name, private_key)?.public_key()))
+}
+
+/// JSON body of `POST /login`, checked against the `getSpec` response of the client
+#[wasm_bindgen(js_name = loginRequest)]
+pub fn login_request(
+    spec_json: &str,
+    curve_name: &str,
+    private_key: &str,
+    client_id: &str,
+    email: &str,
+    nonce: Option<String>,
+) -> Result<String, JsError> {
+    let spec: GetSpecResponse = serde_json::from_str(spec_json)?;
+    let request = user_key(curve_name, private_key)?.login_request(&spec, identifier(client_id)?, email, nonce)?;
+    Ok(serde_json::to_string(&request)?)
+}
+
+/// JSON body of `POST /register` with the key as the first key of the user, `metadata_json` is copied as is
+#[wasm_bindgen(js_name = registerRequest)]
+pub fn register_request(
+    curve_name: &str,
+    private_key: &str,
+    label: &str,
+    client_id: &str,
+    email: &str,
+    metadata_json: Option<String>,
+) -> Result<String, JsError> {
+    let metadata = metadata_json.map(|metadata| serde_json::from_str::<Value>(&metadata)).transpose()?;
+    let key = user_key(curve_name, private_key)?;
+    Ok(serde_json::to_string(&client::register_request(&key, label, identifier(client_id)?, email, metadata))?)
+}
+
+/// Runs with `wasm-pack test --node -- --features wasm` or a headless browser
+#[cfg(all(test, target_arch = "wasm32"))]
+mod tests {
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    use crate::service::{UserLoginPayload, UserLoginRequest, UserRegisterRequest};
+
+    use super::*;
+
+    const SPEC: &str = r#"{"finite_cyclic_groups":{"elliptic_curves":["p256"],"prime_modulus":[]},"hash_functions":["sha512"]}"#;
+
+    #[wasm_bindgen_test]
+    fn login_requests_verify() {
+        let client_id = Identifier::from(42u128).as_hex();
+        let [curve, private_key] = <[String; 2]>::try_from(generate_key(SPEC).unwrap()).unwrap();
+        assert_eq!(curve, "p256");
+        let request = login_request(SPEC, &curve, &private_key, &client_id, "alice@example.com", None).unwrap();
+        let request: UserLoginRequest = serde_json::from_str(&request).unwrap();
+        assert!(request.proof.verify(&request.payload));
+
+        let derived = derive_key(&curve, "correct horse", &client_id, "alice@example.com").unwrap();
+        assert_eq!(public_key(&curve, &derived).unwrap(), public_key(&curve, &derive_key(&curve, "correct horse", &client_id, "Alice@example.com").unwrap()).unwrap());
+
+        let register = register_request(&curve, &private_key, "laptop", &client_id, "alice@example.com", Some(r#"{"name":"Alice"}"#.to_string())).unwrap();
+        assert!(register.contains(r#""metadata":{"name":"Alice"}"#));
+        let register: UserRegisterRequest<Value> = serde_json::from_str(&register).unwrap();
+        let payload = UserLoginPayload { client_id: register.client_id, email: register.email };
+        assert!(register.key.proof.verify(&payload));
+    }
+
+    #[wasm_bindgen_test]
+    fn unknown_curves() {
+        assert_eq!(curve("p256"), Ok(Curve::NistP256));
+        assert_eq!(curve("secp256k1"), Err(ClientError::UnknownCurve("secp256k1".to_string())));
+    }
+}